@@ -62,6 +62,16 @@ lazy_static! {
 	pub static ref K_PRIVATE: String = env::var("K_PRIVATE").unwrap_or_else(|_| "keys/private.k".into());
 	pub static ref K_PUBLIC: String = env::var("K_PUBLIC").unwrap_or_else(|_| "keys/public.k".into());
 	pub static ref K_SECRET: String = env::var("K_SECRET").unwrap_or_else(|_| "keys/secret.k".into());
+	/// Ids of revoked tokens, auth writes it and every slepau reads it
+	pub static ref K_REVOKED: String = env::var("K_REVOKED").unwrap_or_else(|_| "keys/revoked.json".into());
+	/// Public keys rotated out by `gen_key --rotate`, still good for verifying the tokens they signed
+	pub static ref K_KEYRING: String = env::var("K_KEYRING").unwrap_or_else(|_| "keys/keyring.json".into());
+	/// Private keys rotated out, keep it only where `K_PRIVATE` is, they can issue `v4.local` tokens
+	pub static ref K_KEYRING_PRIVATE: String = env::var("K_KEYRING_PRIVATE").unwrap_or_else(|_| "keys/keyring.private.json".into());
+	/// Auth slepau, asked for a new `auth` cookie when there's only a `refresh` one
+	pub static ref AUTH_URL: String = env::var("AUTH_URL").unwrap_or_else(|_| "http://localhost:4001".into());
+	/// Shared by auth and the slepaus acting on behalf of users, lets them ask auth for their tokens
+	pub static ref SERVICE_SECRET: Option<String> = env::var("SERVICE_SECRET").ok().filter(|s| !s.is_empty());
 	/// Use this file as your db storage
 	pub static ref DB_PATH: String = env::var("DB_PATH").unwrap_or_else(|_| "db.json".into());
 	/// Fetches magic bean if set
@@ -108,15 +118,18 @@ pub enum DbError {
 	InvalidSite(&'static str),
 	InvalidUsername(&'static str),
 	InvalidPassword(&'static str),
-	InvalidChunk(&'static str),
+	InvalidChunk(String),
 	Custom(String),
 	NotFound,
+	/// Too many failed tries, secs until the next one is allowed
+	LockedOut(u64),
 }
 impl IntoResponse for DbError {
 	fn into_response(self) -> axum::response::Response {
 		(
 			match self {
 				DbError::Custom(_) => StatusCode::INTERNAL_SERVER_ERROR,
+				DbError::LockedOut(_) => StatusCode::TOO_MANY_REQUESTS,
 				_ => StatusCode::FORBIDDEN,
 			},
 			(match self {
@@ -1,6 +1,7 @@
 use std::net::{IpAddr, Ipv4Addr};
 
 use chrono::Utc;
+use serde::{Deserialize, Serialize};
 use sonnerie::{record, Record};
 
 use crate::{proquint::Proquint, sonnerie::{commit, transaction}};
@@ -76,3 +77,102 @@ pub fn log_ip_user_id_bytes(name: &str, ip: IpAddr, user: &str, id: u64, size: u
 	.unwrap();
 	commit(t);
 }
+
+/// How a security event went
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+	Ok,
+	Failed,
+	/// Refused before even trying, like a locked out login
+	Locked,
+}
+impl Outcome {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Outcome::Ok => "ok",
+			Outcome::Failed => "failed",
+			Outcome::Locked => "locked",
+		}
+	}
+	fn parse(v: &str) -> Option<Self> {
+		[Outcome::Ok, Outcome::Failed, Outcome::Locked]
+			.into_iter()
+			.find(|o| o.as_str() == v)
+	}
+}
+
+/// Longest user agent kept, they're free text
+const USER_AGENT_MAX: usize = 256;
+/// Site column of events that didn't happen on a site, no `Proquint<u32>` is this big
+const NO_SITE: u64 = u64::MAX;
+
+/**
+ * Who did what from where, and how it went.
+ *
+ * These are the only fields that get logged, there's nowhere to put
+ * a password, code or token, so they can't end up in the logs.
+ */
+pub struct SecurityEvent<'a> {
+	/// Record key, like `auth_login`
+	pub action: &'a str,
+	pub ip: IpAddr,
+	pub user: &'a str,
+	/// Site it happened on, `None` for admins
+	pub site: Option<u32>,
+	pub user_agent: &'a str,
+	pub outcome: Outcome,
+}
+impl SecurityEvent<'_> {
+	pub fn log(&self) {
+		let user_agent = self
+			.user_agent
+			.chars()
+			.filter(|c| !c.is_control())
+			.take(USER_AGENT_MAX)
+			.collect::<String>();
+		let mut t = transaction();
+		t.add_record(
+			self.action,
+			Utc::now().naive_utc(),
+			record(ip_to_u32(self.ip))
+				.add(self.user)
+				.add(self.site.map(u64::from).unwrap_or(NO_SITE))
+				.add(self.outcome.as_str())
+				.add(user_agent.as_str()),
+		)
+		.unwrap();
+		commit(t);
+	}
+}
+
+/// A [SecurityEvent] as read back
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct SecurityRecord {
+	pub action: String,
+	/// In secs
+	pub time: u64,
+	pub ip: String,
+	pub user: String,
+	pub site: Option<u32>,
+	pub outcome: Outcome,
+	pub user_agent: String,
+}
+impl SecurityRecord {
+	/// None for records that weren't logged as a [SecurityEvent]
+	pub fn from_record(r: &Record) -> Option<Self> {
+		if r.format() != "usUss" {
+			return None;
+		}
+		let site = r.get::<u64>(2);
+		Some(Self {
+			action: r.key().into(),
+			time: r.timestamp_nanos() / 1_000_000_000,
+			ip: Ipv4Addr::from(r.get::<u32>(0)).to_string(),
+			user: r.get::<String>(1),
+			site: (site != NO_SITE).then_some(site as u32),
+			outcome: Outcome::parse(r.get::<&str>(3))?,
+			user_agent: r.get::<String>(4),
+		})
+	}
+}
@@ -1,4 +1,6 @@
-use common::utils::{K_PRIVATE, K_PUBLIC, K_SECRET};
+use auth::validate::keyring::{self, OldKey};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use common::utils::{get_secs, K_KEYRING, K_KEYRING_PRIVATE, K_PRIVATE, K_PUBLIC, K_SECRET, SECS_IN_DAY};
 use pasetors::{
 	keys::{AsymmetricKeyPair, AsymmetricPublicKey, AsymmetricSecretKey, Generate, SymmetricKey},
 	version4::V4,
@@ -10,14 +12,26 @@ fn main() {
 	Generates a private/public/secret key if nonexistent.\n\
 	On K_PRIVATE:'{}', K_PUBLIC:'{}', and K_SECRET:'{}'\n\
 	\n\
-	--force  will generate and write always\n\
+	--force        will generate and write always, logging everybody out\n\
+	--rotate       moves the current keys to K_KEYRING:'{}' (public) and\n\
+	               K_KEYRING_PRIVATE:'{}' (private) and generates new ones,\n\
+	               tokens they issued stay valid, restart auth to issue with the new ones\n\
+	--retire DAYS  drops keys rotated out more than DAYS ago, with the tokens they issued\n\
 	",
 		K_PRIVATE.as_str(),
 		K_PUBLIC.as_str(),
-		K_SECRET.as_str()
+		K_SECRET.as_str(),
+		K_KEYRING.as_str(),
+		K_KEYRING_PRIVATE.as_str()
 	);
 
-	let force = std::env::args_os().any(|a| a == "--force");
+	let args = std::env::args().collect::<Vec<_>>();
+	let force = args.iter().any(|a| a == "--force");
+	let rotate = args.iter().any(|a| a == "--rotate");
+	let retire = args
+		.iter()
+		.position(|a| a == "--retire")
+		.map(|i| args.get(i + 1).and_then(|d| d.parse::<u64>().ok()).expect("--retire takes a number of days"));
 
 	fn generate() -> AsymmetricKeyPair<V4> {
 		eprint!("generating...");
@@ -32,22 +46,54 @@ fn main() {
 		kp
 	}
 
+	let private = std::fs::read(K_PRIVATE.as_str())
+		.ok()
+		.filter(|b| SymmetricKey::<V4>::from(b.as_slice()).is_ok());
+	let public = std::fs::read(K_PUBLIC.as_str())
+		.ok()
+		.filter(|b| AsymmetricPublicKey::<V4>::from(b.as_slice()).is_ok());
+	let secret = std::fs::read(K_SECRET.as_str())
+		.ok()
+		.filter(|b| AsymmetricSecretKey::<V4>::from(b.as_slice()).is_ok());
+
+	let keyrings = [K_KEYRING.as_str(), K_KEYRING_PRIVATE.as_str()];
+
+	if let Some(days) = retire {
+		for path in keyrings {
+			let mut keys = keyring::read(path);
+			let before = keys.len();
+			keys.retain(|k| k.rotated + days * SECS_IN_DAY > get_secs());
+			keyring::write(path, &keys).unwrap();
+			println!("Retired {} key(s) at '{path}', {} left.", before - keys.len(), keys.len());
+		}
+		if days < 365 {
+			println!("API tokens can last up to 365 days, the ones issued by retired keys stop working.");
+		}
+	}
+
 	// let kp;
-	if force {
+	if rotate {
+		let (Some(private), Some(public)) = (private, public) else {
+			panic!("No keys to rotate, run without --rotate to generate them.");
+		};
+		let rotated = get_secs();
+		let mut count = 0;
+		// Saved before the new keys, so there's never a moment the old ones are nowhere
+		for (path, key) in keyrings.into_iter().zip([public, private]) {
+			let mut keys = keyring::read(path);
+			keys.push(OldKey {
+				rotated,
+				key: URL_SAFE_NO_PAD.encode(key),
+			});
+			keyring::write(path, &keys).unwrap();
+			count = keys.len();
+		}
 		generate();
-	} else if std::fs::read(K_PRIVATE.as_str())
-		.ok()
-		.and_then(|b| SymmetricKey::<V4>::from(b.as_slice()).ok())
-		.is_some()
-		&& std::fs::read(K_PUBLIC.as_str())
-			.ok()
-			.and_then(|b| AsymmetricPublicKey::<V4>::from(b.as_slice()).ok())
-			.is_some()
-		&& std::fs::read(K_SECRET.as_str())
-			.ok()
-			.and_then(|b| AsymmetricSecretKey::<V4>::from(b.as_slice()).ok())
-			.is_some()
-	{
+		println!("Rotated, {count} old key(s) in the keyrings. Restart auth to issue with the new keys.");
+		println!("Copy K_KEYRING to slepaus that verify tokens, K_KEYRING_PRIVATE only where K_PRIVATE goes.");
+	} else if force {
+		generate();
+	} else if private.is_some() && public.is_some() && secret.is_some() {
 		println!("Keys found!");
 	} else {
 		eprint!("Keys not found! ");
@@ -1,6 +1,6 @@
 use serde::{Deserialize, Serialize};
 use std::{
-	collections::HashMap,
+	collections::{BTreeMap, HashMap},
 	sync::{Arc, RwLock},
 };
 
@@ -14,6 +14,8 @@ pub struct DBAuthData {
 	pub sites: Vec<Site>,
 	pub hosts: Vec<(String, SiteId)>,
 	pub admins: Vec<AdminData>,
+	#[serde(default)]
+	pub revoked: BTreeMap<String, u64>,
 }
 
 impl From<&DBAuth> for DBAuthData {
@@ -41,6 +43,7 @@ impl From<&DBAuth> for DBAuthData {
 				.iter()
 				.filter_map(|(h, s)| s.upgrade().map(|s| (h.to_owned(), s.read().unwrap().id)))
 				.collect(),
+			revoked: value.revoked.clone(),
 		}
 	}
 }
@@ -76,7 +79,14 @@ impl From<DBAuthData> for DBAuth {
 			.map(|(h, id)| (h, Arc::downgrade(sites.get(&id).unwrap())))
 			.collect();
 
-		Self { sites, admins, hosts }
+		Self {
+			sites,
+			admins,
+			hosts,
+			revoked: value.revoked,
+			oidc_codes: Default::default(),
+			lockouts: Default::default(),
+		}
 	}
 }
 impl Serialize for DBAuth {
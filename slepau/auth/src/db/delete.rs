@@ -12,7 +12,10 @@ impl DBAuth {
 			.ok_or(DbError::AuthError)?;
 
 		// Figure out if it's an admin
-		self.admins.remove(admin).map(|_| ()).ok_or(DbError::NotFound)
+		let admin = self.admins.remove(admin).ok_or(DbError::NotFound)?;
+		let signed_out = admin.write().unwrap().user.sign_out();
+		self.revoke_all(signed_out);
+		Ok(())
 	}
 
 	pub fn del_site(&mut self, admin: &str, site_id: SiteId) -> Result<(), DbError> {
@@ -31,8 +34,17 @@ impl DBAuth {
 			}
 		}
 
-		// Remove said site
-		self.sites.remove(&site_id);
+		// Remove said site, signing out its users
+		if let Some(site) = self.sites.remove(&site_id) {
+			let signed_out = site
+				.write()
+				.unwrap()
+				.users
+				.values_mut()
+				.flat_map(|u| u.sign_out())
+				.collect();
+			self.revoke_all(signed_out);
+		}
 
 		Ok(())
 	}
@@ -48,9 +60,12 @@ impl DBAuth {
 			.filter_map(|v| v.upgrade())
 			.find(|v| v.read().unwrap().id == site_id)
 			.ok_or(DbError::NotFound)?;
+		drop(admin);
 
 		// Remove user
-		site.write().unwrap().users.remove(user).ok_or(DbError::NotFound)?;
+		let mut user = site.write().unwrap().users.remove(user).ok_or(DbError::NotFound)?;
+		// Their sessions and API tokens shouldn't outlive them
+		self.revoke_all(user.sign_out());
 
 		Ok(())
 	}
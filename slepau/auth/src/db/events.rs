@@ -0,0 +1,80 @@
+use common::{
+	sonnerie::db,
+	utils::{get_secs, DbError, SECS_IN_DAY},
+	vreji::{Outcome, SecurityRecord},
+};
+use serde::Deserialize;
+use sonnerie::Wildcard;
+
+use super::{site::SiteId, DBAuth};
+
+/// Most events returned at once
+const EVENTS_MAX: usize = 1000;
+/// How far back events are read without a `since`
+const EVENTS_WINDOW: u64 = 30 * SECS_IN_DAY;
+
+/// `/sites/:site_id/events?since=1700000000&limit=100&user=nina&action=auth_login%&outcome=failed`
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct EventsQuery {
+	/// In secs, the last 30 days if 0
+	pub since: u64,
+	pub limit: usize,
+	pub user: Option<String>,
+	/// Sonnerie wildcard over actions, they all start with `auth_`
+	pub action: String,
+	pub outcome: Option<Outcome>,
+}
+impl Default for EventsQuery {
+	fn default() -> Self {
+		Self {
+			since: 0,
+			limit: 100,
+			user: None,
+			action: "auth_%".into(),
+			outcome: None,
+		}
+	}
+}
+
+/// Events on `site` that match `query`, most recent first
+pub fn filter(records: impl Iterator<Item = SecurityRecord>, site: SiteId, query: &EventsQuery) -> Vec<SecurityRecord> {
+	let mut events = records
+		.filter(|r| r.site == Some(site.inner()) && r.time >= query.since)
+		.filter(|r| query.user.as_ref().map(|u| *u == r.user).unwrap_or(true))
+		.filter(|r| query.outcome.map(|o| o == r.outcome).unwrap_or(true))
+		.collect::<Vec<_>>();
+	events.sort_by_key(|r| std::cmp::Reverse(r.time));
+	events.truncate(query.limit.min(EVENTS_MAX));
+	events
+}
+
+/// Security events on a site, only reads records since `query.since`.
+///
+/// Reads the log, so check with [DBAuth::events_allowed] and drop the DB lock first.
+pub fn events(site_id: SiteId, query: &EventsQuery) -> Vec<SecurityRecord> {
+	let mut query = query.clone();
+	if query.since == 0 {
+		query.since = get_secs().saturating_sub(EVENTS_WINDOW);
+	}
+	let since = query.since.saturating_mul(1_000_000_000);
+
+	let db = db();
+	let records = db
+		.get_filter(&Wildcard::new(&query.action))
+		.into_iter()
+		.filter(|r| r.timestamp_nanos() >= since);
+	filter(records.filter_map(|r| SecurityRecord::from_record(&r)), site_id, &query)
+}
+
+impl DBAuth {
+	/// Can admin see a site's security events
+	pub fn events_allowed(&self, admin: &str, site_id: SiteId, query: &EventsQuery) -> Result<(), DbError> {
+		self.admin_site(admin, site_id)?;
+		// Only auth's own records, other slepaus log to the same place
+		if !query.action.starts_with("auth_") {
+			return Err(DbError::AuthError);
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+
+use common::utils::{get_secs, DbError, SECS_IN_DAY};
+use serde::{Deserialize, Serialize};
+
+use super::{site::SiteId, DBAuth};
+
+/// How many failed logins a site puts up with
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(default)]
+pub struct Lockout {
+	/// Failures on a username before every next try has to wait, doubling each time
+	pub free: u32,
+	/// Secs to wait after the first failure past `free`
+	pub backoff: u64,
+	/// Failures on a username that lock it out
+	pub max: u32,
+	/// Failures from an IP that lock it out, whatever usernames it tried
+	pub max_ip: u32,
+	/// Secs a lockout lasts, failures older than this are forgotten
+	pub secs: u64,
+}
+impl Default for Lockout {
+	fn default() -> Self {
+		Self {
+			free: 3,
+			backoff: 1,
+			max: 10,
+			max_ip: 50,
+			secs: 15 * 60,
+		}
+	}
+}
+
+impl Lockout {
+	/// A policy that locks out, but not on the first failure
+	pub fn check(&self) -> Result<(), DbError> {
+		if self.secs == 0 {
+			return Err(DbError::InvalidSite("Lockouts have to last at least a second."));
+		}
+		if self.max < 2 || self.max_ip < 2 {
+			return Err(DbError::InvalidSite("Lockouts have to allow at least one failure."));
+		}
+		Ok(())
+	}
+}
+
+/// Failed logins in a row, for a username or an IP
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct Failures {
+	pub count: u32,
+	/// Last failure (in secs)
+	pub last: u64,
+	/// No tries until then (in secs)
+	pub until: u64,
+}
+impl Failures {
+	/// Secs left until trying again is allowed
+	fn wait(&self, now: u64) -> Option<u64> {
+		(self.until > now).then(|| self.until - now)
+	}
+	fn fail(&mut self, free: u32, max: u32, policy: &Lockout, now: u64) {
+		if self.last + policy.secs <= now {
+			*self = Default::default();
+		}
+		self.count += 1;
+		self.last = now;
+		if self.count >= max {
+			self.until = now + policy.secs;
+		} else if self.count > free {
+			let doublings = (self.count - free - 1).min(32);
+			self.until = now + policy.backoff.saturating_mul(1 << doublings).min(policy.secs);
+		}
+	}
+}
+
+/// Failures by site and username, and by site and IP. Not saved.
+#[derive(Default)]
+pub struct Lockouts {
+	users: HashMap<(Option<SiteId>, String), Failures>,
+	ips: HashMap<(Option<SiteId>, String), Failures>,
+}
+
+/// This we show admins
+#[derive(Serialize)]
+pub struct LockoutView {
+	/// Username or IP
+	pub key: String,
+	pub ip: bool,
+	#[serde(flatten)]
+	pub failures: Failures,
+}
+
+impl DBAuth {
+	/// Lockout policy of a site, admins logging in get the default
+	pub fn lockout(&self, site: Option<SiteId>) -> Lockout {
+		site
+			.and_then(|s| self.sites.get(&s))
+			.map(|s| s.read().unwrap().lockout)
+			.unwrap_or_default()
+	}
+
+	/// Errors with the secs left if `user` or `ip` can't try logging in yet
+	pub fn login_allowed(&self, user: &str, ip: &str, site: Option<SiteId>) -> Result<(), DbError> {
+		let now = get_secs();
+		let wait = [
+			self.lockouts.users.get(&(site, user.to_owned())),
+			self.lockouts.ips.get(&(site, ip.to_owned())),
+		]
+		.into_iter()
+		.flatten()
+		.filter_map(|f| f.wait(now))
+		.max();
+		match wait {
+			Some(secs) => Err(DbError::LockedOut(secs)),
+			None => Ok(()),
+		}
+	}
+
+	/// Counts a failed login against both `user` and `ip`, whether the user exists or not
+	pub fn login_failed(&mut self, user: &str, ip: &str, site: Option<SiteId>) {
+		let policy = self.lockout(site);
+		let now = get_secs();
+		let lockouts = &mut self.lockouts;
+		lockouts.users.retain(|_, f| f.last + SECS_IN_DAY > now);
+		lockouts.ips.retain(|_, f| f.last + SECS_IN_DAY > now);
+
+		lockouts
+			.users
+			.entry((site, user.to_owned()))
+			.or_default()
+			.fail(policy.free, policy.max, &policy, now);
+		lockouts
+			.ips
+			.entry((site, ip.to_owned()))
+			.or_default()
+			.fail(policy.max_ip, policy.max_ip, &policy, now);
+	}
+
+	/// Forgets the username's failures, not the IP's, else one good account would reset it
+	pub fn login_succeeded(&mut self, user: &str, site: Option<SiteId>) {
+		self.lockouts.users.remove(&(site, user.to_owned()));
+	}
+
+	/// Usernames and IPs with failures on an admin's site
+	pub fn lockouts_get(&self, admin: &str, site_id: SiteId) -> Result<Vec<LockoutView>, DbError> {
+		self.admin_site(admin, site_id)?;
+		let view = |ip: bool| {
+			move |((_, key), failures): (&(Option<SiteId>, String), &Failures)| LockoutView {
+				key: key.to_owned(),
+				ip,
+				failures: failures.to_owned(),
+			}
+		};
+		let users = self.lockouts.users.iter().filter(|((s, _), _)| *s == Some(site_id));
+		let ips = self.lockouts.ips.iter().filter(|((s, _), _)| *s == Some(site_id));
+		Ok(users.map(view(false)).chain(ips.map(view(true))).collect())
+	}
+
+	/// Unlocks a username or IP on an admin's site
+	pub fn lockout_del(&mut self, admin: &str, site_id: SiteId, key: &str) -> Result<(), DbError> {
+		self.admin_site(admin, site_id)?;
+		let key = (Some(site_id), key.to_owned());
+		let user = self.lockouts.users.remove(&key);
+		let ip = self.lockouts.ips.remove(&key);
+		user.or(ip).map(|_| ()).ok_or(DbError::NotFound)
+	}
+}
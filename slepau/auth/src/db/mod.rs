@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use common::utils::{hostname_normalize, DbError, LockedAtomic, LockedWeak};
 
@@ -10,9 +10,19 @@ pub mod site;
 pub mod stats;
 
 pub mod delete;
+pub mod events;
 pub mod get;
+pub mod lockout;
 pub mod modify;
 pub mod new;
+pub mod oidc;
+pub mod reset;
+pub mod session;
+pub mod token;
+pub mod totp;
+
+/// A successful login: user, their site, is admin, is super and max age of the token (in secs)
+pub type Login = (User, Option<Site>, bool, bool, usize);
 
 #[derive(Default)]
 pub struct DBAuth {
@@ -22,6 +32,12 @@ pub struct DBAuth {
 	pub hosts: HashMap<String, LockedWeak<Site>>,
 	/// UserName -> Admin
 	pub admins: HashMap<String, LockedAtomic<Admin>>,
+	/// Revoked token ids -> when they expire (in secs)
+	pub revoked: BTreeMap<String, u64>,
+	/// OpenID Connect authorization codes, not saved
+	pub oidc_codes: HashMap<String, oidc::AuthCode>,
+	/// Failed logins by username and IP, not saved
+	pub lockouts: lockout::Lockouts,
 }
 
 impl DBAuth {
@@ -37,37 +53,81 @@ impl DBAuth {
 		)
 	}
 
-	pub fn login(
-		&self,
-		user: &str,
-		pass: &str,
-		site: Option<SiteId>,
-	) -> Result<(User, Option<Site>, bool, bool, usize), DbError> {
+	/// Site a host was configured for, with the host as configured. Never falls back to `any`.
+	pub fn configured_site(&self, host: &str) -> Result<(String, SiteId), DbError> {
+		let host = hostname_normalize(host);
+		self
+			.hosts
+			.get(host)
+			.and_then(|s| s.upgrade())
+			.map(|s| (host.to_owned(), s.read().unwrap().id))
+			.ok_or(DbError::InvalidHost)
+	}
+
+	pub fn login(&self, user: &str, pass: &str, site: Option<SiteId>) -> Result<Login, DbError> {
+		if let Some(site) = site {
+			let site = self.sites.get(&site).ok_or(DbError::InvalidSite("No site found."))?;
+			let site = site.read().unwrap();
+			match site.users.get(user) {
+				Some(user) => user.verify_login(pass)?,
+				None => return User::verify_nobody(pass),
+			}
+		} else {
+			match self.admins.get(user) {
+				Some(admin) => admin.read().unwrap().user.verify_login(pass)?,
+				None => return User::verify_nobody(pass),
+			}
+		}
+		self.logged_in(user, site)
+	}
+	/// What goes in the token of a user that already proved who they are
+	pub(crate) fn logged_in(&self, user: &str, site: Option<SiteId>) -> Result<Login, DbError> {
 		if let Some(site) = site {
 			let site = self.sites.get(&site).ok_or(DbError::InvalidSite("No site found."))?;
 			let site = site.read().unwrap();
 			let user = site.users.get(user).ok_or(DbError::AuthError)?;
-			user.verify_login(pass)?;
 			Ok((user.clone(), Some(site.clone()), false, false, site.max_age))
 		} else {
 			let admin = self.admins.get(user).ok_or(DbError::AuthError)?;
 			let admin = admin.read().unwrap();
-			let user = &admin.user;
-			user.verify_login(pass)?;
-			Ok((user.clone(), None, true, admin._super, 60 * 60 * 24))
+			Ok((admin.user.clone(), None, true, admin._super, 60 * 60 * 24))
 		}
 	}
-	/// Reset a user password
-	/// Admins should call with no old_pass to skip password check.
-	pub fn reset(&mut self, user: &str, pass: &str, old_pass: Option<&str>, site: Option<SiteId>) -> Result<(), DbError> {
+	/// Runs `f` on a site's user, or on an admin if there's no site
+	pub(crate) fn with_user<T>(
+		&self,
+		user: &str,
+		site: Option<SiteId>,
+		f: impl FnOnce(&mut User) -> Result<T, DbError>,
+	) -> Result<T, DbError> {
 		if let Some(site) = site {
-			let mut site = self.sites.get(&site).ok_or(DbError::NotFound)?.write().unwrap();
-			let user = site.users.get_mut(user).ok_or(DbError::AuthError)?;
-			user.reset_pass(old_pass, pass)
+			let site = self.sites.get(&site).ok_or(DbError::InvalidSite("No site found."))?;
+			let mut site = site.write().unwrap();
+			f(site.users.get_mut(user).ok_or(DbError::AuthError)?)
 		} else {
 			let admin = self.admins.get(user).ok_or(DbError::AuthError)?;
-			admin.write().unwrap().user.reset_pass(old_pass, pass)
+			let mut admin = admin.write().unwrap();
+			f(&mut admin.user)
+		}
+	}
+	/// Reset a user password, signing out every session
+	/// Admins should call with no old_pass to skip password check.
+	pub fn reset(&mut self, user: &str, pass: &str, old_pass: Option<&str>, site: Option<SiteId>) -> Result<(), DbError> {
+		let exists = match site {
+			Some(site) => self.sites.get(&site).ok_or(DbError::NotFound)?.read().unwrap().users.contains_key(user),
+			None => self.admins.contains_key(user),
+		};
+		if !exists {
+			// Same argon2 work as a user that exists
+			return old_pass.map_or(Err(DbError::AuthError), User::verify_nobody);
 		}
+		let sessions = self.with_user(user, site, |u| {
+			u.reset_pass(old_pass, pass)?;
+			// There's no session "", so that's all of them
+			Ok(u.sessions_remove_others(""))
+		})?;
+		self.revoke_all(sessions.into_iter().map(|s| (s.id, s.expires)).collect());
+		Ok(())
 	}
 	/// Try finding user photo in users from provided site
 	/// if none are found, search admin users instead
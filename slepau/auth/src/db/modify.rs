@@ -26,6 +26,10 @@ impl DBAuth {
 			}
 		}
 
+		if let Some(lockout) = &v.lockout {
+			lockout.check()?;
+		}
+
 		let site = self.sites.get(&site_id).unwrap();
 		let site_weak = Arc::downgrade(site);
 		// Remove all hosts that point to the site
@@ -57,6 +61,15 @@ impl DBAuth {
 			site.max_age = v.max_age;
 			site.name = v.name;
 			site.claims = claims;
+			if let Some(two_factor) = v.two_factor {
+				site.two_factor = two_factor;
+			}
+			if let Some(lockout) = v.lockout {
+				site.lockout = lockout;
+			}
+			if let Some(reset_mail) = v.reset_mail {
+				site.reset_mail = reset_mail;
+			}
 		}
 		Ok(())
 	}
@@ -100,7 +113,7 @@ impl DBAuth {
 		}
 
 		// Modify admin
-		{
+		let signed_out = {
 			let mut admin = admin.write().unwrap();
 
 			admin.user.active = v.active;
@@ -110,7 +123,9 @@ impl DBAuth {
 				.map(|v| v.expect("Site should be good, we checked ^"))
 				.collect();
 			admin._super = v._super;
-		}
+			if v.active { vec![] } else { admin.user.sign_out() }
+		};
+		self.revoke_all(signed_out);
 
 		Ok(())
 	}
@@ -126,8 +141,10 @@ impl DBAuth {
 			.find(|v| v.read().unwrap().id == site_id)
 			.ok_or(DbError::NotFound)?;
 
+		drop(admin);
+
 		// Modify user
-		{
+		let signed_out = {
 			let mut site = site.write().unwrap();
 			let user = site.users.get_mut(user).ok_or(DbError::NotFound)?;
 			if let Some(active) = v.active {
@@ -154,7 +171,10 @@ impl DBAuth {
 			if let Some(pass) = v.pass {
 				user.reset_pass(None, &pass)?;
 			}
-		}
+			if user.active { vec![] } else { user.sign_out() }
+		};
+		// Deactivated users shouldn't stay logged in
+		self.revoke_all(signed_out);
 
 		Ok(())
 	}
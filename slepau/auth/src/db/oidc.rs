@@ -0,0 +1,185 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use common::{
+	proquint::Proquint,
+	utils::{get_secs, DbError, LockedAtomic},
+};
+use rand::distributions::{Alphanumeric, DistString};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::user::User;
+
+use super::{
+	site::{Site, SiteId},
+	DBAuth, Login,
+};
+
+/// Secs an authorization code can be traded for tokens
+const CODE_AGE: u64 = 60;
+const MAX_CLIENTS: usize = 50;
+
+/// An app that logs in a site's users through OpenID Connect
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct OidcClient {
+	pub id: String,
+	pub name: String,
+	/// Where users can be sent back to, compared exactly
+	pub redirect_uris: Vec<String>,
+	/// Argon2 hash, public clients don't have one and rely on PKCE alone
+	#[serde(default)]
+	secret: String,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct OidcClientIn {
+	pub name: String,
+	pub redirect_uris: Vec<String>,
+	/// For apps that can't keep a secret, like single page ones
+	pub public: bool,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct OidcClientView {
+	pub id: String,
+	pub name: String,
+	pub redirect_uris: Vec<String>,
+	pub public: bool,
+}
+impl From<&OidcClient> for OidcClientView {
+	fn from(value: &OidcClient) -> Self {
+		Self {
+			id: value.id.clone(),
+			name: value.name.clone(),
+			redirect_uris: value.redirect_uris.clone(),
+			public: value.secret.is_empty(),
+		}
+	}
+}
+
+/// A user's consent to log into a client, traded once at the token endpoint
+#[derive(Clone, Debug)]
+pub struct AuthCode {
+	pub client_id: String,
+	pub site: SiteId,
+	pub user: String,
+	pub redirect_uri: String,
+	/// PKCE `S256` challenge
+	pub challenge: String,
+	pub nonce: Option<String>,
+	pub scopes: Vec<String>,
+	/// In secs
+	pub expires: u64,
+}
+
+impl DBAuth {
+	/// A site the admin manages
+	pub(crate) fn admin_site(&self, admin: &str, site_id: SiteId) -> Result<LockedAtomic<Site>, DbError> {
+		let admin = self.admins.get(admin).ok_or(DbError::AuthError)?;
+		let admin = admin.read().unwrap();
+		admin
+			.sites
+			.iter()
+			.filter_map(|s| s.upgrade())
+			.find(|s| s.read().unwrap().id == site_id)
+			.ok_or(DbError::AuthError)
+	}
+
+	pub fn oidc_clients(&self, admin: &str, site_id: SiteId) -> Result<Vec<OidcClientView>, DbError> {
+		let site = self.admin_site(admin, site_id)?;
+		let site = site.read().unwrap();
+		Ok(site.clients.values().map(OidcClientView::from).collect())
+	}
+
+	/// Registers a client, returns it with its secret, which is only shown this once
+	pub fn oidc_client_new(
+		&self,
+		admin: &str,
+		site_id: SiteId,
+		client: OidcClientIn,
+	) -> Result<(OidcClientView, Option<String>), DbError> {
+		let name = client.name.trim();
+		if name.is_empty() || name.chars().count() > 64 {
+			return Err(DbError::Custom("Client name has to be 1-64 characters long.".into()));
+		}
+		if client.redirect_uris.is_empty()
+			|| client
+				.redirect_uris
+				.iter()
+				.any(|u| !(u.starts_with("https://") || u.starts_with("http://")) || u.contains('#'))
+		{
+			return Err(DbError::Custom("Redirect uris have to be http(s) urls without a fragment.".into()));
+		}
+
+		let site = self.admin_site(admin, site_id)?;
+		let mut site = site.write().unwrap();
+		if site.clients.len() >= MAX_CLIENTS {
+			return Err(DbError::Custom(format!("Can't have more than {MAX_CLIENTS} clients.")));
+		}
+		let secret = (!client.public).then(|| Alphanumeric.sample_string(&mut rand::thread_rng(), 43));
+		let client = OidcClient {
+			id: Proquint::<u64>::default().to_quint(),
+			name: name.to_owned(),
+			redirect_uris: client.redirect_uris,
+			secret: secret.as_deref().map(User::hash_secret).unwrap_or_default(),
+		};
+		site.clients.insert(client.id.clone(), client.clone());
+		Ok(((&client).into(), secret))
+	}
+
+	pub fn oidc_client_del(&self, admin: &str, site_id: SiteId, id: &str) -> Result<(), DbError> {
+		let site = self.admin_site(admin, site_id)?;
+		let mut site = site.write().unwrap();
+		site.clients.remove(id).map(|_| ()).ok_or(DbError::NotFound)
+	}
+
+	pub fn oidc_client(&self, site_id: SiteId, id: &str) -> Result<OidcClient, DbError> {
+		let site = self.sites.get(&site_id).ok_or(DbError::NotFound)?;
+		let site = site.read().unwrap();
+		site.clients.get(id).cloned().ok_or(DbError::NotFound)
+	}
+
+	/// Stores an authorization code for a user that's already logged in, returns the code
+	pub fn oidc_code_new(&mut self, code: AuthCode) -> String {
+		let now = get_secs();
+		self.oidc_codes.retain(|_, c| c.expires > now);
+		let id = Alphanumeric.sample_string(&mut rand::thread_rng(), 43);
+		self.oidc_codes.insert(
+			id.clone(),
+			AuthCode {
+				expires: now + CODE_AGE,
+				..code
+			},
+		);
+		id
+	}
+
+	/// Trades an authorization code for what goes in the tokens, codes work only once
+	pub fn oidc_code_take(
+		&mut self,
+		code: &str,
+		client_id: &str,
+		client_secret: Option<&str>,
+		redirect_uri: &str,
+		verifier: &str,
+	) -> Result<(AuthCode, Login), DbError> {
+		let code = self.oidc_codes.remove(code).ok_or(DbError::AuthError)?;
+		if code.expires <= get_secs() || code.client_id != client_id || code.redirect_uri != redirect_uri {
+			return Err(DbError::AuthError);
+		}
+		// PKCE, only S256
+		if URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes())) != code.challenge {
+			return Err(DbError::AuthError);
+		}
+		let client = self.oidc_client(code.site, client_id).map_err(|_| DbError::AuthError)?;
+		if !client.secret.is_empty() && !client_secret.is_some_and(|s| User::verify_secret(&client.secret, s)) {
+			return Err(DbError::AuthError);
+		}
+
+		let login = self.logged_in(&code.user, Some(code.site))?;
+		if !login.0.active {
+			return Err(DbError::AuthError);
+		}
+		Ok((code, login))
+	}
+}
@@ -0,0 +1,47 @@
+use common::utils::DbError;
+
+use crate::{mail::Mail, user::totp::uri_escape};
+
+use super::{site::SiteId, DBAuth};
+
+impl DBAuth {
+	/// Starts a password reset for a site user found by username or email, returning the mail with its link.
+	///
+	/// None if there's no such active user with an email, which callers shouldn't tell apart.
+	pub fn reset_begin(&self, site_id: SiteId, who: &str, url: &str) -> Result<Option<Mail>, DbError> {
+		let site = self.sites.get(&site_id).ok_or(DbError::InvalidSite("No site found."))?;
+		let mut site = site.write().unwrap();
+		let (name, template) = (site.name.clone(), site.reset_mail.clone());
+
+		let who = who.trim();
+		let Some(user) = site
+			.users
+			.values_mut()
+			.find(|u| u.user == who || u.email().is_some_and(|e| e.eq_ignore_ascii_case(who)))
+		else {
+			return Ok(None);
+		};
+		let Some(email) = user.email().filter(|_| user.active) else {
+			return Ok(None);
+		};
+
+		let token = user.reset_begin();
+		let link = format!("{url}/login?user={}&reset={token}", uri_escape(&user.user));
+		Ok(Some(template.render(
+			&email,
+			&[("user", &user.user), ("site", &name), ("link", &link)],
+		)))
+	}
+
+	/// Sets a new password with an emailed token, signing out every session and API token
+	pub fn reset_finish(&mut self, site_id: SiteId, user: &str, token: &str, pass: &str) -> Result<(), DbError> {
+		let signed_out = self.with_user(user, Some(site_id), |u| {
+			u.reset_finish(token, pass)?;
+			Ok(u.sign_out())
+		})?;
+		self.revoke_all(signed_out);
+		// Whoever got locked out trying can log in now
+		self.login_succeeded(user, Some(site_id));
+		Ok(())
+	}
+}
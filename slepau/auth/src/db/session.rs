@@ -0,0 +1,73 @@
+use common::utils::DbError;
+
+use crate::user::session::{Refresh, Session};
+
+use super::{site::SiteId, DBAuth, Login};
+
+impl DBAuth {
+	/// Registers a login, its id goes in the token as `jti`.
+	///
+	/// Revokes the sessions it pushed out, returns how many there were.
+	pub fn session_new(&mut self, user: &str, site: Option<SiteId>, session: Session) -> Result<usize, DbError> {
+		let evicted = self.with_user(user, site, |u| Ok(u.session_add(session)))?;
+		let count = evicted.len();
+		self.revoke_all(evicted.into_iter().map(|s| (s.id, s.expires)).collect());
+		Ok(count)
+	}
+
+	pub fn sessions_get(&self, user: &str, site: Option<SiteId>) -> Result<Vec<Session>, DbError> {
+		self.with_user(user, site, |u| Ok(u.sessions()))
+	}
+
+	pub fn session_seen(&self, user: &str, site: Option<SiteId>, id: &str, ip: &str) -> Result<(), DbError> {
+		self.with_user(user, site, |u| {
+			u.session_seen(id, ip);
+			Ok(())
+		})
+	}
+
+	/// Trades a refresh token for a fresh login, and the next refresh token unless it was a racing request.
+	///
+	/// Reusing an old refresh token means it leaked, so the whole session gets revoked.
+	pub fn session_refresh(
+		&mut self,
+		user: &str,
+		site: Option<SiteId>,
+		id: &str,
+		secret: &str,
+		ip: &str,
+	) -> Result<(Login, Option<String>), DbError> {
+		let refresh = self.with_user(user, site, |u| {
+			if !u.active {
+				return Err(DbError::AuthError);
+			}
+			u.session_refresh(id, secret, ip)
+		})?;
+		match refresh {
+			Refresh::Rotated(secret) => Ok((self.logged_in(user, site)?, Some(secret))),
+			Refresh::Grace => Ok((self.logged_in(user, site)?, None)),
+			Refresh::Reused(session) => {
+				self.revoke(&session.id, session.expires);
+				Err(DbError::AuthError)
+			}
+		}
+	}
+
+	pub fn session_del(&mut self, user: &str, site: Option<SiteId>, id: &str) -> Result<(), DbError> {
+		let session = self.with_user(user, site, |u| u.session_remove(id).ok_or(DbError::NotFound))?;
+		self.revoke(&session.id, session.expires);
+		Ok(())
+	}
+
+	/// Signs out every session of a user but `keep`, returns how many there were
+	pub fn sessions_del_others(&mut self, user: &str, site: Option<SiteId>, keep: &str) -> Result<usize, DbError> {
+		let sessions = self.with_user(user, site, |u| Ok(u.sessions_remove_others(keep)))?;
+		let count = sessions.len();
+		self.revoke_all(sessions.into_iter().map(|s| (s.id, s.expires)).collect());
+		Ok(count)
+	}
+
+	pub fn revoke_all(&mut self, ids: Vec<(String, u64)>) {
+		ids.iter().for_each(|(jti, expires)| self.revoke(jti, *expires));
+	}
+}
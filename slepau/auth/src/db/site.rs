@@ -2,9 +2,11 @@ use common::{proquint::Proquint, utils::LockedWeak};
 use serde_json::Value;
 use std::collections::BTreeMap;
 
-use crate::user::User;
+use crate::{mail::MailTemplate, user::User};
 use serde::{Deserialize, Serialize};
 
+use super::{lockout::Lockout, oidc::OidcClient};
+
 #[derive(Clone, Debug)]
 pub struct Admin {
 	pub user: User,
@@ -57,6 +59,28 @@ impl From<&Admin> for AdminView {
 }
 
 pub type SiteId = Proquint<u32>;
+
+/// Who has to log in with a second factor
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TwoFactor {
+	/// Only users that enabled it
+	#[default]
+	Optional,
+	/// Admins logging in from this site's hosts
+	Admins,
+	Everyone,
+}
+impl TwoFactor {
+	pub fn requires(&self, is_admin: bool) -> bool {
+		match self {
+			TwoFactor::Optional => false,
+			TwoFactor::Admins => is_admin,
+			TwoFactor::Everyone => true,
+		}
+	}
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(default)]
 pub struct Site {
@@ -78,6 +102,18 @@ pub struct Site {
 	///
 	/// You can set things like
 	pub claims: BTreeMap<String, Value>,
+
+	/// Users without two-factor have to enroll on their next login
+	pub two_factor: TwoFactor,
+
+	/// OpenID Connect clients, by id
+	pub clients: BTreeMap<String, OidcClient>,
+
+	/// Failed logins it takes to slow down and lock out a username or IP
+	pub lockout: Lockout,
+
+	/// Email with the link of a password reset
+	pub reset_mail: MailTemplate,
 }
 impl Default for Site {
 	fn default() -> Self {
@@ -88,6 +124,10 @@ impl Default for Site {
 			name: Default::default(),
 			allow_admin: false,
 			claims: Default::default(),
+			two_factor: Default::default(),
+			clients: Default::default(),
+			lockout: Default::default(),
+			reset_mail: Default::default(),
 		}
 	}
 }
@@ -97,6 +137,10 @@ pub struct SiteSet {
 	pub hosts: Vec<String>,
 	pub max_age: usize,
 	pub claims: BTreeMap<String, Value>,
+	/// These stay as they are when left out
+	pub two_factor: Option<TwoFactor>,
+	pub lockout: Option<Lockout>,
+	pub reset_mail: Option<MailTemplate>,
 }
 #[derive(Serialize)]
 pub struct SiteView {
@@ -106,6 +150,9 @@ pub struct SiteView {
 	pub users: usize,
 	pub max_age: usize,
 	pub claims: BTreeMap<String, Value>,
+	pub two_factor: TwoFactor,
+	pub lockout: Lockout,
+	pub reset_mail: MailTemplate,
 }
 
 impl From<&Site> for SiteView {
@@ -117,6 +164,9 @@ impl From<&Site> for SiteView {
 			max_age: value.max_age,
 			hosts: Default::default(),
 			claims: value.claims.to_owned(),
+			two_factor: value.two_factor,
+			lockout: value.lockout,
+			reset_mail: value.reset_mail.to_owned(),
 		}
 	}
 }
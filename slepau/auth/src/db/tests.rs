@@ -161,3 +161,483 @@ fn modify() {
 		);
 	}
 }
+
+#[test]
+fn totp() {
+	use super::site::TwoFactor;
+	use crate::user::totp::code;
+	use common::utils::get_secs;
+
+	let mut db = DBAuth::default();
+	db.new_admin("john_s", "john_s").unwrap();
+	let site_id = db.new_site("john_s").unwrap();
+	db.new_user("nina", "nina's pass", site_id).unwrap();
+	db.new_user("isa", "isa's pass", site_id).unwrap();
+
+	let (user, ..) = db.login("nina", "nina's pass", Some(site_id)).unwrap();
+	assert!(!db.totp_needed(&user, false, Some(site_id)), "Optional by default");
+
+	let setup = db.totp_begin("nina", Some(site_id)).unwrap();
+	assert!(setup.uri.starts_with("otpauth://totp/talebox:nina?secret="));
+	assert!(db.totp_confirm("nina", Some(site_id), "000000x").is_err());
+	let now = get_secs();
+	let recovery = db.totp_confirm("nina", Some(site_id), &code(&setup.secret, now)).unwrap();
+	assert_eq!(recovery.len(), 10);
+	assert!(db.totp_begin("nina", Some(site_id)).is_err(), "Already enabled");
+
+	let (user, ..) = db.login("nina", "nina's pass", Some(site_id)).unwrap();
+	assert!(db.totp_needed(&user, false, Some(site_id)));
+	assert!(
+		db.login_totp("nina", &code(&setup.secret, now), Some(site_id)).is_err(),
+		"Codes only work once"
+	);
+	assert!(db.login_totp("nina", &code(&setup.secret, now + 30), Some(site_id)).is_ok());
+	assert!(db.login_totp("nina", &recovery[0], Some(site_id)).is_ok());
+	assert!(db.login_totp("nina", &recovery[0], Some(site_id)).is_err(), "Recovery codes work once");
+	assert_eq!(db.totp_status("nina", Some(site_id)).unwrap().recovery_left, 9);
+
+	// Site policy
+	db.sites[&site_id].write().unwrap().two_factor = TwoFactor::Admins;
+	let (user, ..) = db.login("isa", "isa's pass", Some(site_id)).unwrap();
+	let (admin, ..) = db.login("john_s", "john_s", None).unwrap();
+	assert!(!db.totp_needed(&user, false, Some(site_id)));
+	assert!(db.totp_needed(&admin, true, Some(site_id)));
+	db.sites[&site_id].write().unwrap().two_factor = TwoFactor::Everyone;
+	assert!(db.totp_needed(&user, false, Some(site_id)));
+
+	// Enrolling on login hands out recovery codes
+	let setup = db.totp_begin("isa", Some(site_id)).unwrap();
+	let (_, recovery) = db.login_totp("isa", &code(&setup.secret, get_secs()), Some(site_id)).unwrap();
+	assert_eq!(recovery.map(|r| r.len()), Some(10));
+
+	assert!(db.totp_disable("isa", Some(site_id), "123").is_err());
+}
+
+#[test]
+fn tokens() {
+	use crate::user::token::ApiTokenIn;
+	use auth::UserClaims;
+
+	let mut db = DBAuth::default();
+	db.new_admin("john_s", "john_s").unwrap();
+	let site_id = db.new_site("john_s").unwrap();
+	db.new_user("nina", "nina's pass", site_id).unwrap();
+
+	let token = |name: &str, scopes: &[&str], days: u64| ApiTokenIn {
+		name: name.into(),
+		scopes: scopes.iter().map(|s| s.to_string()).collect(),
+		days,
+	};
+	assert!(db.token_new("nina", Some(site_id), token("", &["chunk:read"], 30)).is_err());
+	assert!(db.token_new("nina", Some(site_id), token("ci", &["chunk"], 30)).is_err());
+	assert!(db.token_new("nina", Some(site_id), token("ci", &["chunk:read"], 0)).is_err());
+	let (ci, (user, ..)) = db
+		.token_new("nina", Some(site_id), token("ci", &["media:write", "chunk:read"], 30))
+		.unwrap();
+	assert_eq!(user.user, "nina");
+	assert_eq!(ci.scopes, vec!["chunk:read", "media:write"]);
+	let (backup, _) = db.token_new("nina", Some(site_id), token("backup", &["chunk:read"], 7)).unwrap();
+	assert_eq!(db.tokens_get("nina", Some(site_id)).unwrap().len(), 2);
+
+	assert!(db.token_del("nina", Some(site_id), "nothing").is_err());
+	db.token_del("nina", Some(site_id), &ci.id).unwrap();
+	assert!(db.revoked.contains_key(&ci.id));
+	assert_eq!(db.tokens_get("nina", Some(site_id)).unwrap(), vec![backup.clone()]);
+	// Deleted users' tokens are revoked
+	db.del_user("john_s", site_id, "nina").unwrap();
+	assert!(db.revoked.contains_key(&backup.id));
+
+	let claims = UserClaims {
+		scopes: Some(ci.scopes),
+		..Default::default()
+	};
+	assert!(claims.has_scope("chunk:read"));
+	assert!(!claims.has_scope("chunk:write"));
+	assert!(claims.has_scope("media:read"), "Write implies read");
+	assert!(UserClaims::default().has_scope("samn:command"), "Logins can do anything");
+}
+
+#[test]
+fn sessions() {
+	use crate::user::{
+		session::{Session, MAX_SESSIONS},
+		UserSet,
+	};
+
+	let mut db = DBAuth::default();
+	db.new_admin("john_s", "john_s").unwrap();
+	let site_id = db.new_site("john_s").unwrap();
+	db.new_user("nina", "nina's pass", site_id).unwrap();
+
+	let laptop = Session::new("Firefox", "10.0.0.2", 60);
+	let phone = Session::new("Safari", "10.0.0.3", 60);
+	let tablet = Session::new("Chrome", "10.0.0.4", 60);
+	for session in [&laptop, &phone, &tablet] {
+		db.session_new("nina", Some(site_id), session.clone()).unwrap();
+	}
+	assert_eq!(db.sessions_get("nina", Some(site_id)).unwrap().len(), 3);
+
+	db.session_seen("nina", Some(site_id), &phone.id, "10.0.0.9").unwrap();
+	let seen = db.sessions_get("nina", Some(site_id)).unwrap();
+	assert_eq!(seen.iter().find(|s| s.id == phone.id).unwrap().ip, "10.0.0.9");
+
+	// Logging out revokes the token
+	assert!(db.session_del("nina", Some(site_id), "nothing").is_err());
+	db.session_del("nina", Some(site_id), &tablet.id).unwrap();
+	assert!(db.revoked.contains_key(&tablet.id));
+
+	// Sign out other sessions
+	assert_eq!(db.sessions_del_others("nina", Some(site_id), &laptop.id).unwrap(), 1);
+	assert!(db.revoked.contains_key(&phone.id));
+	assert!(!db.revoked.contains_key(&laptop.id));
+	assert_eq!(db.sessions_get("nina", Some(site_id)).unwrap(), vec![laptop.clone()]);
+
+	// Sessions pushed out past the limit are revoked too
+	let mut all = vec![laptop.id.clone()];
+	for _ in 0..MAX_SESSIONS {
+		let session = Session::new("Firefox", "10.0.0.5", 60);
+		all.push(session.id.clone());
+		db.session_new("nina", Some(site_id), session).unwrap();
+	}
+	let left = db.sessions_get("nina", Some(site_id)).unwrap();
+	assert_eq!(left.len(), MAX_SESSIONS);
+	let evicted = all.iter().filter(|id| !left.iter().any(|s| &s.id == *id)).collect::<Vec<_>>();
+	assert_eq!(evicted.len(), 1);
+	assert!(db.revoked.contains_key(evicted[0]));
+
+	// Deactivated users are signed out everywhere
+	let deactivate = UserSet {
+		active: Some(false),
+		claims: None,
+		pass: None,
+	};
+	db.mod_user("john_s", site_id, "nina", deactivate).unwrap();
+	assert!(db.revoked.contains_key(&laptop.id));
+	assert!(db.sessions_get("nina", Some(site_id)).unwrap().is_empty());
+}
+
+#[test]
+fn refresh() {
+	use crate::user::session::Session;
+
+	let mut db = DBAuth::default();
+	db.new_admin("john_s", "john_s").unwrap();
+	let site_id = db.new_site("john_s").unwrap();
+	db.new_user("nina", "nina's pass", site_id).unwrap();
+
+	let mut session = Session::new("Firefox", "10.0.0.2", 60);
+	session.rotate();
+	db.session_new("nina", Some(site_id), session.clone()).unwrap();
+
+	assert!(db.session_refresh("nina", Some(site_id), &session.id, "guess", "10.0.0.2").is_err());
+	// A wrong token is a reuse too
+	assert!(db.revoked.contains_key(&session.id));
+	assert!(db.sessions_get("nina", Some(site_id)).unwrap().is_empty());
+
+	let mut session = Session::new("Firefox", "10.0.0.2", 60);
+	let first = session.rotate();
+	db.session_new("nina", Some(site_id), session.clone()).unwrap();
+	let ((user, ..), second) = db
+		.session_refresh("nina", Some(site_id), &session.id, &first, "10.0.0.3")
+		.unwrap();
+	assert_eq!(user.user, "nina");
+	let second = second.unwrap();
+	assert_ne!(second, first);
+	assert_eq!(db.sessions_get("nina", Some(site_id)).unwrap()[0].ip, "10.0.0.3");
+
+	// A request racing the rotation still gets an access token, but no new refresh one
+	let (_, none) = db
+		.session_refresh("nina", Some(site_id), &session.id, &first, "10.0.0.3")
+		.unwrap();
+	assert!(none.is_none());
+	let (_, third) = db
+		.session_refresh("nina", Some(site_id), &session.id, &second, "10.0.0.3")
+		.unwrap();
+	assert!(third.is_some());
+
+	// Older tokens aren't racing anything, they leaked
+	assert!(db.session_refresh("nina", Some(site_id), &session.id, &first, "10.0.0.4").is_err());
+	assert!(db.revoked.contains_key(&session.id));
+	assert!(db.session_refresh("nina", Some(site_id), &session.id, &third.unwrap(), "10.0.0.4").is_err());
+}
+
+#[test]
+fn oidc() {
+	use crate::db::oidc::{AuthCode, OidcClientIn};
+	use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+	use sha2::{Digest, Sha256};
+
+	let mut db = DBAuth::default();
+	db.new_admin("john_s", "john_s").unwrap();
+	db.new_admin("mary", "mary").unwrap();
+	let site_id = db.new_site("john_s").unwrap();
+	db.new_user("nina", "nina's pass", site_id).unwrap();
+
+	let client = |public: bool, redirect_uris: &[&str]| OidcClientIn {
+		name: "wiki".into(),
+		redirect_uris: redirect_uris.iter().map(|u| u.to_string()).collect(),
+		public,
+	};
+	assert!(db.oidc_client_new("john_s", site_id, client(false, &[])).is_err());
+	assert!(db.oidc_client_new("john_s", site_id, client(false, &["wiki.local/cb"])).is_err());
+	assert!(
+		db.oidc_client_new("mary", site_id, client(false, &["https://wiki.local/cb"])).is_err(),
+		"Not mary's site"
+	);
+	let (wiki, secret) = db
+		.oidc_client_new("john_s", site_id, client(false, &["https://wiki.local/cb"]))
+		.unwrap();
+	let secret = secret.unwrap();
+	let (spa, none) = db
+		.oidc_client_new("john_s", site_id, client(true, &["https://spa.local/"]))
+		.unwrap();
+	assert!(none.is_none() && spa.public);
+	assert_eq!(db.oidc_clients("john_s", site_id).unwrap().len(), 2);
+
+	let verifier = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+	let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+	let mut code = |client_id: &str, redirect_uri: &str| {
+		db.oidc_code_new(AuthCode {
+			client_id: client_id.into(),
+			site: site_id,
+			user: "nina".into(),
+			redirect_uri: redirect_uri.into(),
+			challenge: challenge.clone(),
+			nonce: Some("n-0S6_WzA2Mj".into()),
+			scopes: vec!["openid".into()],
+			expires: 0,
+		})
+	};
+	let wiki_code = code(&wiki.id, "https://wiki.local/cb");
+	let spa_code = code(&spa.id, "https://spa.local/");
+	let wrong_secret = code(&wiki.id, "https://wiki.local/cb");
+	let wrong_verifier = code(&wiki.id, "https://wiki.local/cb");
+
+	let take = |db: &mut DBAuth, code: &str, client: &str, secret: Option<&str>, redirect: &str, verifier: &str| {
+		db.oidc_code_take(code, client, secret, redirect, verifier)
+	};
+	let (taken, (user, ..)) = take(&mut db, &wiki_code, &wiki.id, Some(&secret), "https://wiki.local/cb", verifier).unwrap();
+	assert_eq!(user.user, "nina");
+	assert_eq!(taken.nonce.as_deref(), Some("n-0S6_WzA2Mj"));
+	assert!(
+		take(&mut db, &wiki_code, &wiki.id, Some(&secret), "https://wiki.local/cb", verifier).is_err(),
+		"Codes work once"
+	);
+	assert!(take(&mut db, &spa_code, &spa.id, None, "https://spa.local/", verifier).is_ok());
+	assert!(take(&mut db, &wrong_secret, &wiki.id, Some("nope"), "https://wiki.local/cb", verifier).is_err());
+	assert!(take(&mut db, &wrong_verifier, &wiki.id, Some(&secret), "https://wiki.local/cb", "nope").is_err());
+
+	db.oidc_client_del("john_s", site_id, &spa.id).unwrap();
+	assert_eq!(db.oidc_clients("john_s", site_id).unwrap(), vec![wiki]);
+}
+
+#[test]
+fn lockout() {
+	use crate::db::lockout::Lockout;
+
+	let mut db = DBAuth::default();
+	db.new_admin("john_s", "john_s").unwrap();
+	db.new_admin("mary", "mary").unwrap();
+	let site_id = db.new_site("john_s").unwrap();
+	db.new_user("nina", "nina's pass", site_id).unwrap();
+	db.sites[&site_id].write().unwrap().lockout = Lockout {
+		free: 2,
+		backoff: 60,
+		max: 4,
+		max_ip: 5,
+		secs: 15 * 60,
+	};
+	let site = Some(site_id);
+
+	// Missing users fail the same way
+	assert_eq!(db.login("nobody", "pass", site).unwrap_err(), DbError::AuthError);
+
+	for _ in 0..2 {
+		assert!(db.login_allowed("nina", "10.0.0.1", site).is_ok());
+		db.login_failed("nina", "10.0.0.1", site);
+	}
+	assert!(db.login_allowed("nina", "10.0.0.1", site).is_ok(), "Some failures are free");
+	db.login_failed("nina", "10.0.0.1", site);
+	assert!(matches!(db.login_allowed("nina", "10.0.0.2", site), Err(DbError::LockedOut(s)) if s > 0 && s <= 60));
+	assert!(db.login_allowed("isa", "10.0.0.1", site).is_ok(), "Other users can still try from there");
+	assert!(db.login_allowed("nina", "10.0.0.1", None).is_ok(), "Only on this site");
+
+	db.login_failed("nina", "10.0.0.1", site);
+	assert!(matches!(db.login_allowed("nina", "10.0.0.1", site), Err(DbError::LockedOut(s)) if s > 60));
+
+	// Spraying usernames from an IP locks the IP
+	db.login_failed("isa", "10.0.0.1", site);
+	assert!(matches!(db.login_allowed("ana", "10.0.0.1", site), Err(DbError::LockedOut(_))));
+	assert!(db.login_allowed("ana", "10.0.0.2", site).is_ok());
+
+	assert!(db.lockouts_get("mary", site_id).is_err(), "Not mary's site");
+	let lockouts = db.lockouts_get("john_s", site_id).unwrap();
+	assert_eq!(lockouts.len(), 3);
+	assert!(lockouts.iter().any(|l| l.key == "nina" && !l.ip && l.failures.count == 4));
+
+	db.lockout_del("john_s", site_id, "nina").unwrap();
+	db.lockout_del("john_s", site_id, "10.0.0.1").unwrap();
+	assert_eq!(db.lockout_del("john_s", site_id, "nina"), Err(DbError::NotFound));
+	assert!(db.login_allowed("nina", "10.0.0.1", site).is_ok());
+
+	// Logging in forgets the username's failures
+	db.login_failed("nina", "10.0.0.3", site);
+	db.login_succeeded("nina", site);
+	assert!(db.lockouts_get("john_s", site_id).unwrap().iter().all(|l| l.key != "nina"));
+
+	// Policies that never lock or always lock aren't taken, leaving it out keeps the current one
+	let policy = db.sites[&site_id].read().unwrap().lockout;
+	let site_set = |lockout| crate::db::site::SiteSet {
+		name: "Notes".into(),
+		hosts: vec![],
+		max_age: 60,
+		claims: Default::default(),
+		two_factor: None,
+		lockout,
+		reset_mail: None,
+	};
+	for lockout in [Lockout { secs: 0, ..policy }, Lockout { max: 0, ..policy }] {
+		assert!(db.mod_site("john_s", site_id, site_set(Some(lockout))).is_err());
+	}
+	db.mod_site("john_s", site_id, site_set(None)).unwrap();
+	assert_eq!(db.sites[&site_id].read().unwrap().lockout, policy);
+}
+
+#[test]
+fn events() {
+	use crate::db::events::{filter, EventsQuery};
+	use common::vreji::{Outcome, SecurityRecord};
+
+	let site_id = SiteId::from(7);
+	let record = |time: u64, user: &str, site: Option<u32>, outcome: Outcome| SecurityRecord {
+		action: "auth_login".into(),
+		time,
+		ip: "10.0.0.1".into(),
+		user: user.into(),
+		site,
+		outcome,
+		user_agent: "curl/8.0".into(),
+	};
+	let records = || {
+		vec![
+			record(10, "nina", Some(7), Outcome::Failed),
+			record(20, "nina", Some(7), Outcome::Ok),
+			record(30, "isa", Some(7), Outcome::Locked),
+			record(40, "nina", Some(8), Outcome::Ok),
+			record(50, "john_s", None, Outcome::Ok),
+		]
+		.into_iter()
+	};
+
+	let events = filter(records(), site_id, &EventsQuery::default());
+	assert_eq!(events.iter().map(|e| e.time).collect::<Vec<_>>(), vec![30, 20, 10], "Only this site's, recent first");
+
+	let query = EventsQuery {
+		user: Some("nina".into()),
+		outcome: Some(Outcome::Failed),
+		..Default::default()
+	};
+	assert_eq!(filter(records(), site_id, &query), vec![record(10, "nina", Some(7), Outcome::Failed)]);
+
+	let query = EventsQuery {
+		since: 15,
+		limit: 1,
+		..Default::default()
+	};
+	assert_eq!(filter(records(), site_id, &query).len(), 1);
+
+	let mut db = DBAuth::default();
+	db.new_admin("john_s", "john_s").unwrap();
+	let site_id = db.new_site("john_s").unwrap();
+	let query = EventsQuery {
+		action: "chunk_%".into(),
+		..Default::default()
+	};
+	assert_eq!(db.events_allowed("john_s", site_id, &query).unwrap_err(), DbError::AuthError);
+	assert!(db.events_allowed("john_s", site_id, &EventsQuery::default()).is_ok());
+}
+
+#[tokio::test]
+async fn reset() {
+	use crate::{
+		db::site::SiteSet,
+		mail::{MailTemplate, Mailer},
+		user::session::Session,
+	};
+	use std::sync::{Arc, Mutex};
+
+	let mut db = DBAuth::default();
+	db.new_admin("john_s", "john_s").unwrap();
+	let site_id = db.new_site("john_s").unwrap();
+	db.new_user("nina", "nina's pass", site_id).unwrap();
+	db.new_user("isa", "isa's pass", site_id).unwrap();
+	db.sites[&site_id].write().unwrap().users.get_mut("nina").unwrap().claims =
+		[("email".to_string(), json!("Nina@example.com"))].into();
+	db.sites[&site_id].write().unwrap().reset_mail = MailTemplate {
+		subject: "{site}: new password for {user}".into(),
+		body: "Go to {link}".into(),
+	};
+	db.session_new("nina", Some(site_id), Session::new("firefox", "10.0.0.1", 3600)).unwrap();
+
+	// Links only go to configured hosts, never through `any`
+	let site = |hosts: &[&str]| SiteSet {
+		name: "Notes".into(),
+		hosts: hosts.iter().map(|h| h.to_string()).collect(),
+		max_age: 60 * 60 * 24,
+		claims: Default::default(),
+		two_factor: None,
+		lockout: None,
+		reset_mail: None,
+	};
+	db.mod_site("john_s", site_id, site(&["any"])).unwrap();
+	assert_eq!(db.configured_site("evil.com"), Err(DbError::InvalidHost));
+	db.mod_site("john_s", site_id, site(&["talebox.local"])).unwrap();
+	assert_eq!(db.configured_site("talebox.local"), Ok(("talebox.local".into(), site_id)));
+	assert_eq!(db.configured_site("evil.com"), Err(DbError::InvalidHost));
+	db.sites[&site_id].write().unwrap().name = Default::default();
+
+	// Nobody to send it to
+	assert_eq!(db.reset_begin(site_id, "isa", "http://talebox.local").unwrap(), None);
+	assert_eq!(db.reset_begin(site_id, "ana", "http://talebox.local").unwrap(), None);
+
+	let mails = Arc::new(Mutex::new(vec![]));
+	let mailer = Mailer::Capture(mails.clone());
+	let first = db.reset_begin(site_id, "nina", "http://talebox.local").unwrap().unwrap();
+	let mail = db.reset_begin(site_id, "nina@EXAMPLE.com", "http://talebox.local").unwrap().unwrap();
+	mailer.send(mail).await.unwrap();
+
+	let mail = mails.lock().unwrap().pop().unwrap();
+	assert_eq!(mail.to, "Nina@example.com");
+	assert_eq!(mail.subject, ": new password for nina");
+	let link = mail.body.strip_prefix("Go to ").unwrap();
+	assert!(link.starts_with("http://talebox.local/login?user=nina&reset="));
+	let token = link.split("reset=").nth(1).unwrap();
+
+	let old = first.body.split("reset=").nth(1).unwrap();
+	assert!(db.reset_finish(site_id, "nina", old, "nina's new pass").is_err(), "Replaced by the next one");
+	assert!(db.reset_finish(site_id, "isa", token, "nina's new pass").is_err());
+	assert!(db.reset_finish(site_id, "nina", token, "short").is_err());
+	let api_token = crate::user::token::ApiTokenIn {
+		name: "ci".into(),
+		scopes: vec!["chunk:read".into()],
+		days: 30,
+	};
+	db.token_new("nina", Some(site_id), api_token).unwrap();
+	db.reset_finish(site_id, "nina", token, "nina's new pass").unwrap();
+	assert!(db.reset_finish(site_id, "nina", token, "nina's newer pass").is_err(), "Tokens work once");
+
+	assert!(db.login("nina", "nina's pass", Some(site_id)).is_err());
+	assert!(db.login("nina", "nina's new pass", Some(site_id)).is_ok());
+	assert!(db.sessions_get("nina", Some(site_id)).unwrap().is_empty());
+	assert!(db.tokens_get("nina", Some(site_id)).unwrap().is_empty(), "API tokens too");
+	assert_eq!(db.revoked.len(), 2);
+
+	// Changing it with the old one signs out everywhere too
+	db.session_new("nina", Some(site_id), Session::new("firefox", "10.0.0.1", 3600)).unwrap();
+	let change = |db: &mut DBAuth, user: &str, old: &str| db.reset(user, "nina's newest pass", Some(old), Some(site_id));
+	assert_eq!(change(&mut db, "nina", "nina's pass"), Err(DbError::AuthError));
+	assert_eq!(change(&mut db, "ana", "ana's pass"), Err(DbError::AuthError));
+	change(&mut db, "nina", "nina's new pass").unwrap();
+	assert!(db.sessions_get("nina", Some(site_id)).unwrap().is_empty());
+	assert_eq!(db.revoked.len(), 3);
+}
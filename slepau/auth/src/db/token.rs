@@ -0,0 +1,42 @@
+use common::utils::{get_secs, DbError};
+
+use crate::user::token::{ApiToken, ApiTokenIn};
+
+use super::{site::SiteId, DBAuth, Login};
+
+impl DBAuth {
+	pub fn tokens_get(&self, user: &str, site: Option<SiteId>) -> Result<Vec<ApiToken>, DbError> {
+		self.with_user(user, site, |u| Ok(u.tokens()))
+	}
+
+	/// Creates an API token, returns it with the login it carries
+	pub fn token_new(
+		&self,
+		user: &str,
+		site: Option<SiteId>,
+		token: ApiTokenIn,
+	) -> Result<(ApiToken, Login), DbError> {
+		let token = self.with_user(user, site, |u| {
+			if !u.active {
+				return Err(DbError::AuthError);
+			}
+			u.token_add(token)
+		})?;
+		Ok((token, self.logged_in(user, site)?))
+	}
+
+	pub fn token_del(&mut self, user: &str, site: Option<SiteId>, id: &str) -> Result<(), DbError> {
+		let token = self.with_user(user, site, |u| u.token_remove(id))?;
+		self.revoke(&token.id, token.expires);
+		Ok(())
+	}
+
+	/// Lists a token id as revoked until it'd expire anyway, dropping the ones that already did
+	pub fn revoke(&mut self, jti: &str, expires: u64) {
+		let now = get_secs();
+		self.revoked.retain(|_, e| *e > now);
+		if expires > now {
+			self.revoked.insert(jti.to_owned(), expires);
+		}
+	}
+}
@@ -0,0 +1,82 @@
+use common::utils::DbError;
+use serde::Serialize;
+
+use crate::user::{totp::TotpSetup, User};
+
+use super::{
+	site::{SiteId, TwoFactor},
+	DBAuth, Login,
+};
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct TotpStatus {
+	pub enabled: bool,
+	pub recovery_left: usize,
+}
+
+impl DBAuth {
+	/// Two-factor policy of the site a host belongs to
+	pub fn two_factor(&self, host_site: Option<SiteId>) -> TwoFactor {
+		host_site
+			.and_then(|s| self.sites.get(&s))
+			.map(|s| s.read().unwrap().two_factor)
+			.unwrap_or_default()
+	}
+
+	/// Does this login need a second step, either because the user enabled it or the site requires it
+	pub fn totp_needed(&self, user: &User, is_admin: bool, host_site: Option<SiteId>) -> bool {
+		user.totp_enabled() || self.two_factor(host_site).requires(is_admin)
+	}
+
+	/// What authenticators show the code as
+	fn issuer(&self, site: Option<SiteId>) -> String {
+		site
+			.and_then(|s| self.sites.get(&s))
+			.map(|s| s.read().unwrap().name.clone())
+			.filter(|n| !n.is_empty())
+			.unwrap_or_else(|| "talebox".into())
+	}
+
+	pub fn totp_status(&self, user: &str, site: Option<SiteId>) -> Result<TotpStatus, DbError> {
+		self.with_user(user, site, |u| {
+			Ok(TotpStatus {
+				enabled: u.totp_enabled(),
+				recovery_left: u.totp_recovery_left(),
+			})
+		})
+	}
+
+	pub fn totp_begin(&self, user: &str, site: Option<SiteId>) -> Result<TotpSetup, DbError> {
+		let issuer = self.issuer(site);
+		self.with_user(user, site, |u| u.totp_begin(&issuer))
+	}
+
+	pub fn totp_confirm(&self, user: &str, site: Option<SiteId>, code: &str) -> Result<Vec<String>, DbError> {
+		self.with_user(user, site, |u| u.totp_confirm(code))
+	}
+
+	pub fn totp_disable(&self, user: &str, site: Option<SiteId>, code: &str) -> Result<(), DbError> {
+		self.with_user(user, site, |u| u.totp_disable(code))
+	}
+
+	/// Second step of a login whose password was already checked.
+	///
+	/// Users enrolling because the site requires it confirm their first code here, and get their recovery codes.
+	pub fn login_totp(
+		&self,
+		user: &str,
+		code: &str,
+		site: Option<SiteId>,
+	) -> Result<(Login, Option<Vec<String>>), DbError> {
+		let recovery = self.with_user(user, site, |u| {
+			if u.totp_enabled() {
+				u.verify_totp(code).map(|_| None)
+			} else if u.active {
+				u.totp_confirm(code).map(Some)
+			} else {
+				Err(DbError::AuthError)
+			}
+		})?;
+		Ok((self.logged_in(user, site)?, recovery))
+	}
+}
@@ -1,4 +1,4 @@
-use auth::UserClaims;
+use auth::{validate::revoked, UserClaims};
 use axum::{
 	extract::{Extension, Path, Query},
 	response::IntoResponse,
@@ -7,10 +7,13 @@ use axum::{
 use common::utils::{DbError, LockedAtomic};
 use log::info;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 
 use crate::{
 	db::{
+		events::{self, EventsQuery},
 		get::AnyFilter,
+		oidc::OidcClientIn,
 		site::{AdminSet, SiteId, SiteSet},
 		DBAuth,
 	},
@@ -109,7 +112,10 @@ pub async fn put_admin(
 	Extension(user_claims): Extension<UserClaims>,
 	Json(v): Json<AdminSet>,
 ) -> Result<impl IntoResponse, DbError> {
-	db.write().unwrap().mod_admin(&user_claims.user, &user_id, v)
+	let mut db = db.write().unwrap();
+	db.mod_admin(&user_claims.user, &user_id, v)?;
+	revoked::write(&db.revoked);
+	Ok(())
 }
 pub async fn put_site(
 	Path(site_id): Path<SiteId>,
@@ -125,7 +131,10 @@ pub async fn put_user(
 	Extension(user_claims): Extension<UserClaims>,
 	Json(v): Json<UserSet>,
 ) -> Result<impl IntoResponse, DbError> {
-	db.write().unwrap().mod_user(&user_claims.user, site_id, &user_id, v)
+	let mut db = db.write().unwrap();
+	db.mod_user(&user_claims.user, site_id, &user_id, v)?;
+	revoked::write(&db.revoked);
+	Ok(())
 }
 
 // DEL
@@ -134,19 +143,85 @@ pub async fn del_admin(
 	Extension(db): Extension<LockedAtomic<DBAuth>>,
 	Extension(user_claims): Extension<UserClaims>,
 ) -> Result<impl IntoResponse, DbError> {
-	db.write().unwrap().del_admin(&user_claims.user, &user_id)
+	let mut db = db.write().unwrap();
+	db.del_admin(&user_claims.user, &user_id)?;
+	revoked::write(&db.revoked);
+	Ok(())
 }
 pub async fn del_site(
 	Path(site_id): Path<SiteId>,
 	Extension(db): Extension<LockedAtomic<DBAuth>>,
 	Extension(user_claims): Extension<UserClaims>,
 ) -> Result<impl IntoResponse, DbError> {
-	db.write().unwrap().del_site(&user_claims.user, site_id)
+	let mut db = db.write().unwrap();
+	db.del_site(&user_claims.user, site_id)?;
+	revoked::write(&db.revoked);
+	Ok(())
 }
 pub async fn del_user(
 	Path((site_id, user_id)): Path<(SiteId, String)>,
 	Extension(db): Extension<LockedAtomic<DBAuth>>,
 	Extension(user_claims): Extension<UserClaims>,
 ) -> Result<impl IntoResponse, DbError> {
-	db.write().unwrap().del_user(&user_claims.user, site_id, &user_id)
+	let mut db = db.write().unwrap();
+	db.del_user(&user_claims.user, site_id, &user_id)?;
+	revoked::write(&db.revoked);
+	Ok(())
+}
+
+// OIDC clients
+pub async fn get_clients(
+	Path(site_id): Path<SiteId>,
+	Extension(db): Extension<LockedAtomic<DBAuth>>,
+	Extension(user_claims): Extension<UserClaims>,
+) -> Result<impl IntoResponse, DbError> {
+	Ok(Json(db.read().unwrap().oidc_clients(&user_claims.user, site_id)?))
+}
+/// Registers a client, its secret is only returned this once
+pub async fn post_client(
+	Path(site_id): Path<SiteId>,
+	Extension(db): Extension<LockedAtomic<DBAuth>>,
+	Extension(user_claims): Extension<UserClaims>,
+	Json(client): Json<OidcClientIn>,
+) -> Result<impl IntoResponse, DbError> {
+	let (client, secret) = db.read().unwrap().oidc_client_new(&user_claims.user, site_id, client)?;
+	info!("OIDC client '{}' registered by {}", client.name, user_claims.user);
+	Ok(Json(json!({ "client": client, "secret": secret })))
+}
+pub async fn del_client(
+	Path((site_id, id)): Path<(SiteId, String)>,
+	Extension(db): Extension<LockedAtomic<DBAuth>>,
+	Extension(user_claims): Extension<UserClaims>,
+) -> Result<impl IntoResponse, DbError> {
+	db.read().unwrap().oidc_client_del(&user_claims.user, site_id, &id)
+}
+
+// Lockouts
+pub async fn get_lockouts(
+	Path(site_id): Path<SiteId>,
+	Extension(db): Extension<LockedAtomic<DBAuth>>,
+	Extension(user_claims): Extension<UserClaims>,
+) -> Result<impl IntoResponse, DbError> {
+	Ok(Json(db.read().unwrap().lockouts_get(&user_claims.user, site_id)?))
+}
+/// Unlocks a username or IP
+pub async fn del_lockout(
+	Path((site_id, key)): Path<(SiteId, String)>,
+	Extension(db): Extension<LockedAtomic<DBAuth>>,
+	Extension(user_claims): Extension<UserClaims>,
+) -> Result<impl IntoResponse, DbError> {
+	db.write().unwrap().lockout_del(&user_claims.user, site_id, &key)?;
+	info!("Lockout on '{}' lifted by {}", key, user_claims.user);
+	Ok(())
+}
+
+/// Security events on a site, like failed logins
+pub async fn get_events(
+	Path(site_id): Path<SiteId>,
+	Query(query): Query<EventsQuery>,
+	Extension(db): Extension<LockedAtomic<DBAuth>>,
+	Extension(user_claims): Extension<UserClaims>,
+) -> Result<impl IntoResponse, DbError> {
+	db.read().unwrap().events_allowed(&user_claims.user, site_id, &query)?;
+	Ok(Json(events::events(site_id, &query)))
 }
@@ -0,0 +1,99 @@
+use auth::validate::revoked;
+use axum::{extract::Extension, headers, response::IntoResponse, Json, TypedHeader};
+use common::{
+	utils::{DbError, LockedAtomic, SECURE, URL},
+	vreji::Outcome,
+};
+use log::{error, info};
+use serde::Deserialize;
+
+use axum_client_ip::InsecureClientIp;
+type ClientIp = InsecureClientIp;
+
+use crate::{db::DBAuth, mail::Mailer};
+
+use super::event;
+
+/// Where links for a configured host go, `URL` if that's the host auth is served on
+fn site_url(host: &str) -> String {
+	let url = URL.trim_end_matches('/');
+	let url_host = url.split_once("://").and_then(|(_, u)| u.split(['/', ':']).next());
+	if url_host == Some(host) {
+		url.to_owned()
+	} else {
+		format!("{}://{host}", if *SECURE { "https" } else { "http" })
+	}
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct ResetRequestBody {
+	/// Username or email
+	user: String,
+}
+
+/// Emails a password reset link, if there's such a user with an email.
+///
+/// Answers the same either way, so it can't be used to find out who has an account.
+pub async fn reset_request(
+	TypedHeader(host): TypedHeader<headers::Host>,
+	Extension(db): Extension<LockedAtomic<DBAuth>>,
+	Extension(mailer): Extension<Mailer>,
+	user_agent: Option<TypedHeader<headers::UserAgent>>,
+	ip: ClientIp,
+	Json(body): Json<ResetRequestBody>,
+) -> Result<impl IntoResponse, DbError> {
+	// The link carries the token, so it only ever points at a host an admin configured, never at `Host` as sent
+	let (site_host, site_id) = db.read().unwrap().configured_site(host.hostname())?;
+	let url = site_url(&site_host);
+	let mail = db.read().unwrap().reset_begin(site_id, &body.user, &url)?;
+	let outcome = if mail.is_some() { Outcome::Ok } else { Outcome::Failed };
+	event("auth_reset_request", ip.0, &user_agent, &body.user, Some(site_id), outcome);
+
+	if let Some(mail) = mail {
+		// In the background, so answering takes as long whether it's sent or not
+		tokio::spawn(async move {
+			if let Err(err) = mailer.send(mail).await {
+				error!("Couldn't send a reset mail: {err:?}.");
+			}
+		});
+	}
+	Ok("If there's an account with an email, a reset link is on its way.")
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct ResetConfirmBody {
+	user: String,
+	/// From the emailed link
+	token: String,
+	pass: String,
+}
+
+/// Sets a new password with the token of a reset link, signing out every session
+pub async fn reset_confirm(
+	TypedHeader(host): TypedHeader<headers::Host>,
+	Extension(db): Extension<LockedAtomic<DBAuth>>,
+	user_agent: Option<TypedHeader<headers::UserAgent>>,
+	ip: ClientIp,
+	Json(body): Json<ResetConfirmBody>,
+) -> Result<impl IntoResponse, DbError> {
+	let mut db = db.write().unwrap();
+	let (_, site_id) = db.host_to_site_id(host.hostname());
+	let site_id = site_id.ok_or(DbError::NotFound)?;
+
+	let result = db.reset_finish(site_id, &body.user, &body.token, &body.pass);
+	let outcome = match &result {
+		Ok(_) => {
+			revoked::write(&db.revoked);
+			info!("User password reset by email '{}'.", body.user);
+			Outcome::Ok
+		}
+		Err(err) => {
+			error!("Failed password reset by email for '{}': {err:?}.", body.user);
+			Outcome::Failed
+		}
+	};
+	event("auth_reset_confirm", ip.0, &user_agent, &body.user, Some(site_id), outcome);
+	result.map(|_| "User pass reset.")
+}
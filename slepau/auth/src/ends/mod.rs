@@ -1,31 +1,47 @@
-use auth::{validate::KPR, UserClaims};
+use auth::{
+	validate::{encrypt, revoked, seal},
+	UserClaims,
+};
 use axum::{
 	extract::{Extension, Path, Query},
 	headers,
 	http::header,
-	http::HeaderMap,
-	response::IntoResponse,
+	http::{HeaderMap, HeaderName},
+	response::{IntoResponse, Response},
 	Json, TypedHeader,
 };
 use common::{
 	utils::{get_secs, hostname_normalize, DbError, LockedAtomic},
-	vreji::log_ip_user,
+	vreji::{Outcome, SecurityEvent},
 };
 use hyper::StatusCode;
 
 use log::{error, info};
-use pasetors::{claims::Claims, local};
+use pasetors::claims::Claims;
 use serde::Deserialize;
-use serde_json::Value;
+use serde_json::{json, Value};
 
 use axum_client_ip::InsecureClientIp;
 type ClientIp = InsecureClientIp;
 
+use std::net::IpAddr;
 use time::{format_description::well_known::Rfc3339, OffsetDateTime};
 
 pub mod admin;
-
-use crate::db::{site::SiteSet, DBAuth};
+pub mod forgot;
+pub mod oidc;
+pub mod service;
+pub mod session;
+pub mod token;
+pub mod totp;
+
+use crate::{
+	db::{
+		site::{SiteId, SiteSet},
+		DBAuth, Login,
+	},
+	user::session::Session,
+};
 
 #[derive(Deserialize, Default)]
 #[serde(default)]
@@ -45,14 +61,16 @@ pub async fn login(
 	TypedHeader(host): TypedHeader<headers::Host>,
 	Query(query): Query<LoginQuery>,
 	Extension(db): Extension<LockedAtomic<DBAuth>>,
+	user_agent: Option<TypedHeader<headers::UserAgent>>,
 	ip: ClientIp,
 	Json(body): Json<AuthBody>,
-) -> Result<impl IntoResponse, DbError> {
-	let db = db.write().unwrap();
+) -> Result<Response, DbError> {
+	let mut db = db.write().unwrap();
 	let user = body.user;
 	let pass = body.pass;
 
-	let (host, mut site_id) = db.host_to_site_id(host.hostname());
+	let (host, host_site) = db.host_to_site_id(host.hostname());
+	let mut site_id = host_site;
 	// Throw error if no site found and user doesn't wanna login as admin.
 	// To prevent an inadvert login as admin.
 	if query.admin {
@@ -61,85 +79,221 @@ pub async fn login(
 		return Err(DbError::InvalidSite("No site setup yet for this host. Contact admin."));
 	}
 
-	db.login(&user, &pass, site_id)
-		.map(|(user, site, is_admin, is_super, max_age)| {
-			// Create token
-			let mut claims = Claims::new().unwrap();
-			// Set Issuer
-			claims.issuer("talebox").unwrap();
-			// Set Audience
-			claims.audience(&host).unwrap();
-
-			// Add site claims except 'admin' and 'super'
-			if let Some(site) = site {
-				site
-					.claims
-					.into_iter()
-					.filter(|(k, _)| k != "admin" && k != "super")
-					.for_each(|(k, v)| {
-						claims.add_additional(&k, v).ok();
-					});
-			}
-
-			// Add user claims except 'admin' and 'super'
-			user
-				.claims
-				.into_iter()
-				.filter(|(k, _)| k != "admin" && k != "super")
-				.for_each(|(k, v)| {
-					claims.add_additional(&k, v).ok();
-				});
-
-			claims.add_additional("user", user.user.clone()).unwrap();
+	if let Err(err) = db.login_allowed(&user, &ip.0.to_string(), site_id) {
+		event("auth_login", ip.0, &user_agent, &user, site_id, Outcome::Locked);
+		return Err(err);
+	}
+	let login = db.login(&user, &pass, site_id);
+	if login.is_err() {
+		db.login_failed(&user, &ip.0.to_string(), site_id);
+	}
 
-			if is_admin {
-				claims.add_additional("admin", is_admin).unwrap();
-			}
-			if is_super {
-				claims.add_additional("super", is_super).unwrap();
+	login
+		.and_then(|login| {
+			let (logged, _, is_admin, _, _) = &login;
+			if db.totp_needed(logged, *is_admin, host_site) {
+				// Password was good, a second step gets the cookie
+				let enroll = if logged.totp_enabled() {
+					None
+				} else {
+					Some(db.totp_begin(&user, site_id)?)
+				};
+				event("auth_login_totp", ip.0, &user_agent, &user, site_id, Outcome::Ok);
+				return Ok(Json(json!({
+					"totp": totp::pending_token(&host, &user, site_id),
+					"enroll": enroll,
+				}))
+				.into_response());
 			}
 
-			let iat = OffsetDateTime::from_unix_timestamp(get_secs().try_into().unwrap())
-				.unwrap()
-				.format(&Rfc3339)
-				.unwrap();
-			let exp = get_secs() + max_age as u64; // 7 days
-			let exp = OffsetDateTime::from_unix_timestamp(exp.try_into().unwrap())
-				.unwrap()
-				.format(&Rfc3339)
-				.unwrap();
-
-			claims.not_before(&iat).unwrap();
-			claims.issued_at(&iat).unwrap();
-			claims.expiration(&exp).unwrap();
-
-			// Generate the keys and sign the claims.
-			// let pub_token = private::sign(&KP.secret, &claims, None, None).unwrap();
-			let pub_token = local::encrypt(&KPR, &claims, None, None).unwrap();
-
-			log_ip_user("auth_login", ip.0, &user.user);
-			// let user_claims = UserClaims::from(&claims);
-			// (
-			[(
-				header::SET_COOKIE,
-				format!(
-					"auth={pub_token}; Domain={host}; Path=/; SameSite=Lax; Max-Age={max_age}; HttpOnly; {}",
-					// Allowing unsecure cookies, depending on URL for this is problematic :\ 
-					// if *SECURE { " Secure;" } else 
-					{ "" }
-				),
-			)]
-			// )
+			let cookies = login_cookies(&mut db, &host, login, &device(&user_agent), &ip.0.to_string())?;
+			db.login_succeeded(&user, site_id);
+			event("auth_login", ip.0, &user_agent, &user, site_id, Outcome::Ok);
+			Ok(cookies.into_response())
 		})
 		.inspect_err(|err| {
-			error!("Failed login for '{}' with pass '{}': {:?}.", &user, &pass, &err);
-			log_ip_user("auth_login_error", ip.0, &user);
+			error!("Failed login for '{}': {:?}.", &user, &err);
+			event("auth_login", ip.0, &user_agent, &user, site_id, Outcome::Failed);
 		})
 }
 
+/// What a session shows as its device
+pub(crate) fn device(user_agent: &Option<TypedHeader<headers::UserAgent>>) -> String {
+	user_agent.as_ref().map(|u| u.0.to_string()).unwrap_or_default()
+}
+
+/// Logs a security event to vreji, `site` is None for admins
+pub(crate) fn event(
+	action: &str,
+	ip: IpAddr,
+	user_agent: &Option<TypedHeader<headers::UserAgent>>,
+	user: &str,
+	site: Option<SiteId>,
+	outcome: Outcome,
+) {
+	SecurityEvent {
+		action,
+		ip,
+		user,
+		site: site.map(|s| s.inner()),
+		user_agent: &device(user_agent),
+		outcome,
+	}
+	.log();
+}
+
+/// Secs an `auth` cookie lasts, the `refresh` one gets new ones until the site's `max_age`
+pub(crate) const ACCESS_AGE: u64 = 15 * 60;
+/// Bound into refresh tokens, so they never decrypt as an `auth` cookie
+pub(crate) const REFRESH_ASSERTION: &[u8] = b"refresh";
+
+pub(crate) fn rfc3339(secs: u64) -> String {
+	OffsetDateTime::from_unix_timestamp(secs.try_into().unwrap())
+		.unwrap()
+		.format(&Rfc3339)
+		.unwrap()
+}
+
+/// Cookies for a successful login, registered as a new session
+pub(crate) fn login_cookies(
+	db: &mut DBAuth,
+	host: &str,
+	login: Login,
+	device: &str,
+	ip: &str,
+) -> Result<[(HeaderName, String); 2], DbError> {
+	let max_age = login.4 as u64;
+	let mut session = Session::new(device, ip, max_age);
+	let secret = session.rotate();
+	let (user, site) = (login.0.user.clone(), login.1.as_ref().map(|s| s.id));
+	if db.session_new(&user, site, session.clone())? > 0 {
+		revoked::write(&db.revoked);
+	}
+
+	Ok([
+		(header::SET_COOKIE, access_cookie(host, login, &session.id)),
+		(
+			header::SET_COOKIE,
+			refresh_cookie(host, &user, site, &session.id, &secret, session.expires),
+		),
+	])
+}
+
+/// `auth` cookie, a short lived token of session `jti`
+pub(crate) fn access_cookie(host: &str, login: Login, jti: &str) -> String {
+	let max_age = ACCESS_AGE.min(login.4 as u64);
+	let mut claims = login_claims(host, login, max_age);
+	claims.token_identifier(jti).unwrap();
+
+	let pub_token = seal(&claims);
+
+	format!(
+		"auth={pub_token}; Domain={host}; Path=/; SameSite=Lax; Max-Age={max_age}; HttpOnly; {}",
+		// Allowing unsecure cookies, depending on URL for this is problematic :\ 
+		// if *SECURE { " Secure;" } else 
+		{ "" }
+	)
+}
+
+/// `refresh` cookie, traded at `/refresh` for a new `auth` cookie and the next `refresh` one, until the session `expires`
+pub(crate) fn refresh_cookie(
+	host: &str,
+	user: &str,
+	site: Option<SiteId>,
+	jti: &str,
+	secret: &str,
+	expires: u64,
+) -> String {
+	let mut claims = Claims::new().unwrap();
+	claims.issuer("talebox").unwrap();
+	claims.audience(host).unwrap();
+	claims.token_identifier(jti).unwrap();
+	claims.add_additional("user", user).unwrap();
+	claims.add_additional("site", serde_json::to_value(site).unwrap()).unwrap();
+	claims.add_additional("secret", secret).unwrap();
+	claims.issued_at(&rfc3339(get_secs())).unwrap();
+	claims.expiration(&rfc3339(expires)).unwrap();
+	let token = encrypt(&claims, Some(REFRESH_ASSERTION));
+
+	let max_age = expires.saturating_sub(get_secs());
+	format!("refresh={token}; Domain={host}; Path=/; SameSite=Lax; Max-Age={max_age}; HttpOnly; ")
+}
+
+/// Token claims for a successful login, expiring in `max_age` secs
+pub(crate) fn login_claims(
+	host: &str,
+	(user, site, is_admin, is_super, _): Login,
+	max_age: u64,
+) -> Claims {
+	// Create token
+	let mut claims = Claims::new().unwrap();
+	// Set Issuer
+	claims.issuer("talebox").unwrap();
+	// Set Audience
+	claims.audience(host).unwrap();
+
+	// Add site claims except 'admin' and 'super'
+	if let Some(site) = site {
+		site
+			.claims
+			.into_iter()
+			.filter(|(k, _)| k != "admin" && k != "super")
+			.for_each(|(k, v)| {
+				claims.add_additional(&k, v).ok();
+			});
+	}
+
+	// Add user claims except 'admin' and 'super'
+	user
+		.claims
+		.into_iter()
+		.filter(|(k, _)| k != "admin" && k != "super")
+		.for_each(|(k, v)| {
+			claims.add_additional(&k, v).ok();
+		});
+
+	claims.add_additional("user", user.user.clone()).unwrap();
+
+	if is_admin {
+		claims.add_additional("admin", is_admin).unwrap();
+	}
+	if is_super {
+		claims.add_additional("super", is_super).unwrap();
+	}
+
+	let iat = OffsetDateTime::from_unix_timestamp(get_secs().try_into().unwrap())
+		.unwrap()
+		.format(&Rfc3339)
+		.unwrap();
+	let exp = get_secs() + max_age; // 7 days
+	let exp = OffsetDateTime::from_unix_timestamp(exp.try_into().unwrap())
+		.unwrap()
+		.format(&Rfc3339)
+		.unwrap();
+
+	claims.not_before(&iat).unwrap();
+	claims.issued_at(&iat).unwrap();
+	claims.expiration(&exp).unwrap();
+	claims
+}
+
+/// Site of the logged in user, None for admins
+pub(crate) fn user_site(
+	db: &DBAuth,
+	host: &headers::Host,
+	user_claims: &UserClaims,
+) -> Result<Option<SiteId>, DbError> {
+	if user_claims.admin {
+		return Ok(None);
+	}
+	let (_, site_id) = db.host_to_site_id(host.hostname());
+	site_id.map(Some).ok_or(DbError::NotFound)
+}
+
 pub async fn register(
 	TypedHeader(host): TypedHeader<headers::Host>,
 	Extension(db): Extension<LockedAtomic<DBAuth>>,
+	user_agent: Option<TypedHeader<headers::UserAgent>>,
 	ip: ClientIp,
 	Json(body): Json<AuthBody>,
 ) -> Result<impl IntoResponse, DbError> {
@@ -158,9 +312,13 @@ pub async fn register(
 				hosts: vec!["any".into()],
 				max_age: 60 * 60 * 24,
 				claims: Default::default(),
+				two_factor: None,
+				lockout: None,
+				reset_mail: None,
 			},
 		)?;
 		info!("Super admin created '{user}' + New Default site '{site_id}'.");
+		event("auth_register", ip.0, &user_agent, &user, None, Outcome::Ok);
 		return Ok("Super admin created + New Default site.");
 	}
 
@@ -170,12 +328,12 @@ pub async fn register(
 	db.new_user(&user, &pass, site_id)
 		.map(|_| {
 			info!("User created '{}'.", &user);
-			log_ip_user("auth_register", ip.0, &user);
+			event("auth_register", ip.0, &user_agent, &user, Some(site_id), Outcome::Ok);
 			"User created."
 		})
 		.inspect_err(|err| {
-			error!("Failed register for '{}' with pass '{}': {:?}.", &user, &pass, &err);
-			log_ip_user("auth_register_error", ip.0, &user);
+			error!("Failed register for '{}': {:?}.", &user, &err);
+			event("auth_register", ip.0, &user_agent, &user, Some(site_id), Outcome::Failed);
 		})
 }
 
@@ -183,6 +341,7 @@ pub async fn reset(
 	TypedHeader(host): TypedHeader<headers::Host>,
 	Extension(user_claims): Extension<UserClaims>,
 	Extension(db): Extension<LockedAtomic<DBAuth>>,
+	user_agent: Option<TypedHeader<headers::UserAgent>>,
 	ip: ClientIp,
 	Json(body): Json<AuthBody>,
 ) -> Result<impl IntoResponse, DbError> {
@@ -198,25 +357,55 @@ pub async fn reset(
 	let (_, site_id) = db.host_to_site_id(host.hostname());
 	let site_id = site_id.ok_or(DbError::NotFound)?;
 
-	db.reset(&user, &pass, old_pass.as_deref(), Some(site_id))
+	// Checking the old password is a login like any other
+	let checked = old_pass.is_some();
+	if checked {
+		if let Err(err) = db.login_allowed(&user, &ip.0.to_string(), Some(site_id)) {
+			event("auth_reset", ip.0, &user_agent, &user, Some(site_id), Outcome::Locked);
+			return Err(err);
+		}
+	}
+	let reset = db.reset(&user, &pass, old_pass.as_deref(), Some(site_id));
+	match &reset {
+		Ok(_) => {
+			revoked::write(&db.revoked);
+			if checked {
+				db.login_succeeded(&user, Some(site_id));
+			}
+		}
+		Err(DbError::AuthError) if checked => db.login_failed(&user, &ip.0.to_string(), Some(site_id)),
+		Err(_) => {}
+	}
+
+	reset
 		.map(|_| {
 			info!("User password reset '{user}'.");
-			log_ip_user("auth_reset", ip.0, &user);
+			event("auth_reset", ip.0, &user_agent, &user, Some(site_id), Outcome::Ok);
 			"User pass reset."
 		})
 		.map_err(|err| {
 			error!("Failed password reset for '{user}': {err:?}.");
-			log_ip_user("auth_reset_error", ip.0, &user);
+			event("auth_reset", ip.0, &user_agent, &user, Some(site_id), Outcome::Failed);
 			err
 		})
 }
 
 pub async fn user(
-	Extension(_db): Extension<LockedAtomic<DBAuth>>,
+	TypedHeader(host): TypedHeader<headers::Host>,
+	Extension(db): Extension<LockedAtomic<DBAuth>>,
 	Extension(user_claims): Extension<UserClaims>,
+	user_agent: Option<TypedHeader<headers::UserAgent>>,
 	ip: ClientIp,
 ) -> impl IntoResponse {
-	log_ip_user("auth_get_user", ip.0, &user_claims.user);
+	let site_id = {
+		let db = db.read().unwrap();
+		let site_id = user_site(&db, &host, &user_claims).ok();
+		if let Some(site_id) = site_id {
+			db.session_seen(&user_claims.user, site_id, &user_claims.jti, &ip.0.to_string()).ok();
+		}
+		site_id.flatten()
+	};
+	event("auth_get_user", ip.0, &user_agent, &user_claims.user, site_id, Outcome::Ok);
 	Json(user_claims)
 }
 
@@ -257,15 +446,33 @@ pub async fn user_patch(
 	Ok(())
 }
 
+/// Makes the browser forget its `refresh` cookie
+pub(crate) fn refresh_clear(host: &str) -> String {
+	format!("refresh=; Domain={host}; Path=/; SameSite=Lax; Max-Age=0; HttpOnly; ")
+}
+
 pub async fn logout(
 	TypedHeader(host): TypedHeader<headers::Host>,
+	Extension(db): Extension<LockedAtomic<DBAuth>>,
 	Extension(user_claims): Extension<UserClaims>,
+	user_agent: Option<TypedHeader<headers::UserAgent>>,
 	ip: ClientIp,
 	headers: HeaderMap,
 ) -> impl IntoResponse {
+	// Clearing the cookie isn't enough, a copy of the token would still work
+	let site_id = {
+		let mut db = db.write().unwrap();
+		let site_id = user_site(&db, &host, &user_claims).ok();
+		if let Some(site_id) = site_id {
+			if db.session_del(&user_claims.user, site_id, &user_claims.jti).is_ok() {
+				revoked::write(&db.revoked);
+			}
+		}
+		site_id.flatten()
+	};
 	// let host_full = host.hostname();
 	let host = hostname_normalize(host.hostname());
-	log_ip_user("auth_logout", ip.0, &user_claims.user);
+	event("auth_logout", ip.0, &user_agent, &user_claims.user, site_id, Outcome::Ok);
 
 	let referer = headers.get("Referer").map(|v| v.to_str().unwrap()).unwrap_or_default();
 	(
@@ -281,6 +488,7 @@ pub async fn logout(
 					 { "" }
 				),
 			),
+			(header::SET_COOKIE, refresh_clear(host)),
 			// (
 			// 	header::SET_COOKIE,
 			// 	format!(
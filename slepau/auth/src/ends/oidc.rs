@@ -0,0 +1,266 @@
+use auth::{
+	validate::{keyring, keys, seal, KP},
+	UserClaims,
+};
+use axum::{
+	extract::{Extension, OriginalUri, Query},
+	headers::{self, authorization::Basic, Authorization},
+	http::{header, StatusCode},
+	response::{IntoResponse, Redirect, Response},
+	Form, Json, TypedHeader,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use common::{
+	proquint::Proquint,
+	utils::{get_secs, DbError, LockedAtomic, SECURE},
+	vreji::Outcome,
+};
+use pasetors::{claims::Claims, token::TrustedToken};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+use axum_client_ip::InsecureClientIp;
+type ClientIp = InsecureClientIp;
+
+use crate::{
+	db::{oidc::AuthCode, DBAuth},
+	user::totp::uri_escape,
+};
+
+use super::{event, login_claims, ACCESS_AGE};
+
+/// Claims that aren't the user's to set
+const RESERVED: &[&str] = &["iss", "sub", "aud", "exp", "nbf", "iat", "jti", "nonce", "user", "scopes"];
+
+/// Where this auth is reached on `host`, OIDC clients compare it exactly
+fn issuer(host: &headers::Host) -> String {
+	let scheme = if *SECURE { "https" } else { "http" };
+	match host.port() {
+		Some(port) => format!("{scheme}://{}:{port}", host.hostname()),
+		None => format!("{scheme}://{}", host.hostname()),
+	}
+}
+
+/// JWT signed with `KP` (Ed25519), what OIDC clients expect instead of a PASETO
+fn jwt(claims: &Value) -> String {
+	let header = json!({ "alg": "EdDSA", "typ": "JWT", "kid": keyring::public_id(&KP.public) });
+	let input = format!(
+		"{}.{}",
+		URL_SAFE_NO_PAD.encode(header.to_string()),
+		URL_SAFE_NO_PAD.encode(claims.to_string())
+	);
+	let key = ed25519_compact::SecretKey::from_slice(KP.secret.as_bytes()).unwrap();
+	let signature = key.sign(input.as_bytes(), None);
+	format!("{input}.{}", URL_SAFE_NO_PAD.encode(signature.as_ref()))
+}
+
+/// `sub` and `preferred_username`, plus the site's and user's claims with the `profile` scope
+fn user_info(claims: &Claims, scopes: Option<&[String]>) -> Map<String, Value> {
+	let claims: Map<String, Value> = serde_json::from_str(&claims.to_string().unwrap()).unwrap();
+	let user = claims.get("user").cloned().unwrap_or_default();
+	let mut info = Map::new();
+	if scopes.map(|s| s.iter().any(|s| s == "profile")).unwrap_or(true) {
+		info.extend(claims.into_iter().filter(|(k, _)| !RESERVED.contains(&k.as_str())));
+	}
+	info.insert("sub".into(), user.clone());
+	info.insert("preferred_username".into(), user);
+	info
+}
+
+pub async fn discovery(TypedHeader(host): TypedHeader<headers::Host>) -> impl IntoResponse {
+	let issuer = issuer(&host);
+	Json(json!({
+		"issuer": issuer,
+		"authorization_endpoint": format!("{issuer}/oidc/authorize"),
+		"token_endpoint": format!("{issuer}/oidc/token"),
+		"userinfo_endpoint": format!("{issuer}/oidc/userinfo"),
+		"jwks_uri": format!("{issuer}/oidc/jwks"),
+		"response_types_supported": ["code"],
+		"grant_types_supported": ["authorization_code"],
+		"subject_types_supported": ["public"],
+		"id_token_signing_alg_values_supported": ["EdDSA"],
+		"scopes_supported": ["openid", "profile"],
+		"token_endpoint_auth_methods_supported": ["client_secret_basic", "client_secret_post", "none"],
+		"code_challenge_methods_supported": ["S256"],
+		"claims_supported": ["sub", "preferred_username", "iss", "aud", "exp", "iat", "nonce"],
+	}))
+}
+
+/// Keys tokens are signed with, for OIDC clients and for slepaus verifying `v4.public` tokens
+pub async fn jwks() -> impl IntoResponse {
+	Json(json!({ "keys": keyring::public_keys().iter().map(keys::jwk).collect::<Vec<_>>() }))
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct AuthorizeQuery {
+	response_type: String,
+	client_id: String,
+	redirect_uri: String,
+	scope: String,
+	state: Option<String>,
+	nonce: Option<String>,
+	code_challenge: String,
+	code_challenge_method: String,
+}
+
+/// Sends the browser back to the client
+fn back(redirect_uri: &str, state: &Option<String>, params: &[(&str, &str)]) -> Response {
+	let separator = if redirect_uri.contains('?') { '&' } else { '?' };
+	let query = params
+		.iter()
+		.copied()
+		.chain(state.as_deref().map(|s| ("state", s)))
+		.map(|(k, v)| format!("{k}={}", uri_escape(v)))
+		.collect::<Vec<_>>()
+		.join("&");
+	Redirect::to(&format!("{redirect_uri}{separator}{query}")).into_response()
+}
+
+/// Hands a logged in site user's browser back to the client with a code, or sends it to log in first.
+///
+/// Clients are registered by the site's admins, so there's no consent screen.
+pub async fn authorize(
+	TypedHeader(host): TypedHeader<headers::Host>,
+	OriginalUri(uri): OriginalUri,
+	Query(query): Query<AuthorizeQuery>,
+	Extension(db): Extension<LockedAtomic<DBAuth>>,
+	Extension(user_claims): Extension<UserClaims>,
+	user_agent: Option<TypedHeader<headers::UserAgent>>,
+	ip: ClientIp,
+) -> Result<Response, DbError> {
+	let mut db = db.write().unwrap();
+	let (_, site_id) = db.host_to_site_id(host.hostname());
+	let site_id = site_id.ok_or(DbError::InvalidSite("No site setup yet for this host. Contact admin."))?;
+	let client = db.oidc_client(site_id, &query.client_id)?;
+	// Never send the browser anywhere the client didn't register
+	if !client.redirect_uris.contains(&query.redirect_uri) {
+		return Err(DbError::AuthError);
+	}
+
+	let reply = |params: &[(&str, &str)]| back(&query.redirect_uri, &query.state, params);
+	let scopes = query.scope.split_whitespace().map(|s| s.to_owned()).collect::<Vec<_>>();
+	if query.response_type != "code" {
+		return Ok(reply(&[("error", "unsupported_response_type")]));
+	}
+	if !scopes.iter().any(|s| s == "openid") || query.code_challenge.is_empty() || query.code_challenge_method != "S256" {
+		return Ok(reply(&[
+			("error", "invalid_request"),
+			("error_description", "The openid scope and an S256 code_challenge are required."),
+		]));
+	}
+
+	// Site users only, through a login and not an API token
+	if user_claims.user == "public" || user_claims.admin || user_claims.scopes.is_some() {
+		return Ok(Redirect::to(&format!("/login?redirect={}", uri_escape(&uri.to_string()))).into_response());
+	}
+
+	let code = db.oidc_code_new(AuthCode {
+		client_id: client.id,
+		site: site_id,
+		user: user_claims.user.clone(),
+		redirect_uri: query.redirect_uri.clone(),
+		challenge: query.code_challenge.clone(),
+		nonce: query.nonce.clone(),
+		scopes,
+		expires: 0,
+	});
+	event("auth_oidc_authorize", ip.0, &user_agent, &user_claims.user, Some(site_id), Outcome::Ok);
+	Ok(reply(&[("code", &code)]))
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct TokenForm {
+	grant_type: String,
+	code: String,
+	redirect_uri: String,
+	client_id: String,
+	client_secret: Option<String>,
+	code_verifier: String,
+}
+
+fn token_error(error: &str) -> Response {
+	(
+		StatusCode::BAD_REQUEST,
+		[(header::CACHE_CONTROL, "no-store")],
+		Json(json!({ "error": error })),
+	)
+		.into_response()
+}
+
+/// Trades an authorization code for an access token, good for `userinfo`, and an ID token
+pub async fn token(
+	TypedHeader(host): TypedHeader<headers::Host>,
+	basic: Option<TypedHeader<Authorization<Basic>>>,
+	Extension(db): Extension<LockedAtomic<DBAuth>>,
+	user_agent: Option<TypedHeader<headers::UserAgent>>,
+	ip: ClientIp,
+	Form(form): Form<TokenForm>,
+) -> Response {
+	if form.grant_type != "authorization_code" {
+		return token_error("unsupported_grant_type");
+	}
+	let (client_id, client_secret) = match &basic {
+		Some(TypedHeader(basic)) => (basic.username().to_owned(), Some(basic.password().to_owned())),
+		None => (form.client_id.clone(), form.client_secret.clone()),
+	};
+
+	let issuer = issuer(&host);
+	let (code, login) = {
+		let mut db = db.write().unwrap();
+		match db.oidc_code_take(
+			&form.code,
+			&client_id,
+			client_secret.as_deref(),
+			&form.redirect_uri,
+			&form.code_verifier,
+		) {
+			Ok(taken) => taken,
+			Err(_) => return token_error("invalid_grant"),
+		}
+	};
+	let audience = common::utils::hostname_normalize(host.hostname());
+
+	// Only good for reading from auth, like `userinfo`
+	let mut claims = login_claims(audience, login, ACCESS_AGE);
+	claims.token_identifier(&Proquint::<u64>::default().to_quint()).unwrap();
+	let scopes = ["auth:read".to_owned()].into_iter().chain(code.scopes.iter().cloned()).collect::<Vec<_>>();
+	claims.add_additional("scopes", scopes).unwrap();
+	let access_token = seal(&claims);
+
+	let now = get_secs();
+	let mut id_claims = user_info(&claims, Some(code.scopes.as_slice()));
+	id_claims.insert("iss".into(), issuer.into());
+	id_claims.insert("aud".into(), code.client_id.clone().into());
+	id_claims.insert("iat".into(), now.into());
+	id_claims.insert("exp".into(), (now + ACCESS_AGE).into());
+	if let Some(nonce) = code.nonce {
+		id_claims.insert("nonce".into(), nonce.into());
+	}
+
+	event("auth_oidc_token", ip.0, &user_agent, &code.user, Some(code.site), Outcome::Ok);
+	(
+		[(header::CACHE_CONTROL, "no-store")],
+		Json(json!({
+			"access_token": access_token,
+			"token_type": "Bearer",
+			"expires_in": ACCESS_AGE,
+			"scope": code.scopes.join(" "),
+			"id_token": jwt(&Value::Object(id_claims)),
+		})),
+	)
+		.into_response()
+}
+
+pub async fn userinfo(
+	Extension(user_claims): Extension<UserClaims>,
+	token: Option<Extension<TrustedToken>>,
+) -> Response {
+	match token.as_ref().and_then(|t| t.payload_claims()) {
+		Some(claims) if user_claims.user != "public" => {
+			Json(user_info(claims, user_claims.scopes.as_deref())).into_response()
+		}
+		_ => (StatusCode::UNAUTHORIZED, [(header::WWW_AUTHENTICATE, "Bearer")]).into_response(),
+	}
+}
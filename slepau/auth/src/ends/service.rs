@@ -0,0 +1,77 @@
+use auth::validate::{
+	seal,
+	service::{SERVICE_MAX_SECS, SERVICE_SCOPES},
+};
+use axum::{
+	extract::Extension,
+	headers::{self, authorization::Bearer, Authorization},
+	response::IntoResponse,
+	Json, TypedHeader,
+};
+use common::utils::{DbError, LockedAtomic, SERVICE_SECRET};
+use lazy_static::lazy_static;
+use log::error;
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::env;
+
+use crate::db::DBAuth;
+
+use super::login_claims;
+
+lazy_static! {
+	/// Host of the one site service tokens are made for, none are if unset
+	static ref SERVICE_HOST: Option<String> = env::var("SERVICE_HOST").ok().filter(|s| !s.is_empty());
+}
+
+#[derive(Deserialize)]
+pub struct ServiceTokenIn {
+	user: String,
+	secs: u64,
+	/// Some of [SERVICE_SCOPES]
+	scopes: Vec<String>,
+}
+
+/// Token of a site's user for a slepau acting on their behalf, with the claims a login of theirs gets.
+///
+/// Only for callers holding `SERVICE_SECRET`, only on `SERVICE_HOST`'s site and only with [SERVICE_SCOPES],
+/// so a leaked secret can't act as anyone anywhere. Never for admins, so never with admin/super claims.
+pub async fn service_token(
+	TypedHeader(host): TypedHeader<headers::Host>,
+	bearer: Option<TypedHeader<Authorization<Bearer>>>,
+	Extension(db): Extension<LockedAtomic<DBAuth>>,
+	Json(body): Json<ServiceTokenIn>,
+) -> Result<impl IntoResponse, DbError> {
+	// Hashed so the comparison takes the same time however much of it matches
+	let allowed = match (SERVICE_SECRET.as_ref(), bearer) {
+		(Some(secret), Some(TypedHeader(bearer))) => {
+			Sha256::digest(bearer.token().as_bytes()) == Sha256::digest(secret.as_bytes())
+		}
+		_ => false,
+	};
+	if !allowed {
+		error!("Service token for '{}' asked without SERVICE_SECRET.", body.user);
+		return Err(DbError::AuthError);
+	}
+
+	if body.scopes.is_empty() || body.scopes.iter().any(|s| !SERVICE_SCOPES.contains(&s.as_str())) {
+		return Err(DbError::AuthError);
+	}
+
+	let db = db.read().unwrap();
+	let service_host = SERVICE_HOST.as_ref().ok_or(DbError::InvalidSite("No SERVICE_HOST set."))?;
+	let (_, service_site) = db.configured_site(service_host)?;
+	let (host, site_id) = db.host_to_site_id(host.hostname());
+	if site_id != Some(service_site) {
+		error!("Service token for '{}' asked on '{host}', not SERVICE_HOST's site.", body.user);
+		return Err(DbError::InvalidSite("Service tokens are only for SERVICE_HOST's site."));
+	}
+	let login = db.logged_in(&body.user, Some(service_site))?;
+	if !login.0.active {
+		return Err(DbError::AuthError);
+	}
+	let mut claims = login_claims(&host, login, body.secs.min(SERVICE_MAX_SECS));
+	claims.add_additional("scopes", body.scopes).unwrap();
+	Ok(Json(json!({ "token": seal(&claims) })))
+}
@@ -0,0 +1,168 @@
+use auth::{
+	validate::{decrypt, revoked},
+	UserClaims,
+};
+use axum::{
+	extract::{Extension, Path},
+	headers,
+	http::header,
+	response::{AppendHeaders, IntoResponse, Response},
+	Json, TypedHeader,
+};
+use common::{
+	utils::{get_secs, DbError, LockedAtomic},
+	vreji::Outcome,
+};
+use pasetors::claims::ClaimsValidationRules;
+use serde::{Deserialize, Serialize};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use axum_client_ip::InsecureClientIp;
+type ClientIp = InsecureClientIp;
+
+use crate::{
+	db::{site::SiteId, DBAuth},
+	user::session::Session,
+};
+
+use super::{access_cookie, event, refresh_cookie, refresh_clear, user_site, REFRESH_ASSERTION};
+
+#[derive(Serialize)]
+pub struct SessionView {
+	id: String,
+	device: String,
+	ip: String,
+	created: u64,
+	seen: u64,
+	expires: u64,
+	/// The one making this request
+	current: bool,
+}
+impl SessionView {
+	fn new(session: Session, current: &str) -> Self {
+		Self {
+			current: session.id == current,
+			id: session.id,
+			device: session.device,
+			ip: session.ip,
+			created: session.created,
+			seen: session.seen,
+			expires: session.expires,
+		}
+	}
+}
+
+#[derive(Deserialize)]
+struct RefreshClaims {
+	jti: String,
+	user: String,
+	site: Option<SiteId>,
+	secret: String,
+	exp: String,
+}
+
+/// What a refresh token that's still valid for `host` says
+fn refresh_claims(host: &str, token: &str) -> Result<RefreshClaims, DbError> {
+	let mut validation_rules = ClaimsValidationRules::new();
+	validation_rules.validate_issuer_with("talebox");
+	validation_rules.validate_audience_with(host);
+
+	let token = decrypt(token, &validation_rules, Some(REFRESH_ASSERTION)).ok_or(DbError::AuthError)?;
+	let claims = token.payload_claims().ok_or(DbError::AuthError)?;
+	serde_json::from_str(&claims.to_string().map_err(|_| DbError::AuthError)?).map_err(|_| DbError::AuthError)
+}
+
+/// Trades the `refresh` cookie for a new `auth` cookie, and the next `refresh` one.
+///
+/// A `refresh` cookie that was already traded revokes its session.
+pub async fn refresh(
+	TypedHeader(host): TypedHeader<headers::Host>,
+	cookies: Option<TypedHeader<headers::Cookie>>,
+	Extension(db): Extension<LockedAtomic<DBAuth>>,
+	user_agent: Option<TypedHeader<headers::UserAgent>>,
+	ip: ClientIp,
+) -> Response {
+	let mut db = db.write().unwrap();
+	let (host, _) = db.host_to_site_id(host.hostname());
+	let claims = match cookies
+		.as_ref()
+		.and_then(|c| c.get("refresh"))
+		.ok_or(DbError::AuthError)
+		.and_then(|token| refresh_claims(&host, token))
+	{
+		Ok(claims) => claims,
+		Err(err) => return ([(header::SET_COOKIE, refresh_clear(&host))], err).into_response(),
+	};
+
+	match db.session_refresh(&claims.user, claims.site, &claims.jti, &claims.secret, &ip.0.to_string()) {
+		Ok((login, secret)) => {
+			let mut cookies = vec![(header::SET_COOKIE, access_cookie(&host, login, &claims.jti))];
+			if let Some(secret) = secret {
+				let expires = OffsetDateTime::parse(&claims.exp, &Rfc3339)
+					.map(|e| e.unix_timestamp() as u64)
+					.unwrap_or_else(|_| get_secs());
+				cookies.push((
+					header::SET_COOKIE,
+					refresh_cookie(&host, &claims.user, claims.site, &claims.jti, &secret, expires),
+				));
+			}
+			AppendHeaders(cookies).into_response()
+		}
+		Err(err) => {
+			// Might have been revoked for reuse
+			revoked::write(&db.revoked);
+			event("auth_refresh", ip.0, &user_agent, &claims.user, claims.site, Outcome::Failed);
+			([(header::SET_COOKIE, refresh_clear(&host))], err).into_response()
+		}
+	}
+}
+
+pub async fn sessions_get(
+	TypedHeader(host): TypedHeader<headers::Host>,
+	Extension(db): Extension<LockedAtomic<DBAuth>>,
+	Extension(user_claims): Extension<UserClaims>,
+) -> Result<impl IntoResponse, DbError> {
+	let db = db.read().unwrap();
+	let site_id = user_site(&db, &host, &user_claims)?;
+	let mut sessions = db
+		.sessions_get(&user_claims.user, site_id)?
+		.into_iter()
+		.map(|session| SessionView::new(session, &user_claims.jti))
+		.collect::<Vec<_>>();
+	sessions.sort_by_key(|s| std::cmp::Reverse(s.seen));
+	Ok(Json(sessions))
+}
+
+/// Signs out every other session
+pub async fn sessions_delete(
+	TypedHeader(host): TypedHeader<headers::Host>,
+	Extension(db): Extension<LockedAtomic<DBAuth>>,
+	Extension(user_claims): Extension<UserClaims>,
+	user_agent: Option<TypedHeader<headers::UserAgent>>,
+	ip: ClientIp,
+) -> Result<impl IntoResponse, DbError> {
+	let mut db = db.write().unwrap();
+	let site_id = user_site(&db, &host, &user_claims)?;
+	let count = db.sessions_del_others(&user_claims.user, site_id, &user_claims.jti)?;
+	revoked::write(&db.revoked);
+
+	event("auth_sessions_del", ip.0, &user_agent, &user_claims.user, site_id, Outcome::Ok);
+	Ok(Json(count))
+}
+
+pub async fn session_delete(
+	TypedHeader(host): TypedHeader<headers::Host>,
+	Path(id): Path<String>,
+	Extension(db): Extension<LockedAtomic<DBAuth>>,
+	Extension(user_claims): Extension<UserClaims>,
+	user_agent: Option<TypedHeader<headers::UserAgent>>,
+	ip: ClientIp,
+) -> Result<impl IntoResponse, DbError> {
+	let mut db = db.write().unwrap();
+	let site_id = user_site(&db, &host, &user_claims)?;
+	db.session_del(&user_claims.user, site_id, &id)?;
+	revoked::write(&db.revoked);
+
+	event("auth_session_del", ip.0, &user_agent, &user_claims.user, site_id, Outcome::Ok);
+	Ok(())
+}
@@ -0,0 +1,74 @@
+use auth::{
+	validate::{revoked, seal},
+	UserClaims,
+};
+use axum::{
+	extract::{Extension, Path},
+	headers,
+	response::IntoResponse,
+	Json, TypedHeader,
+};
+use common::{
+	utils::{get_secs, DbError, LockedAtomic},
+	vreji::Outcome,
+};
+use log::info;
+use serde_json::json;
+
+use axum_client_ip::InsecureClientIp;
+type ClientIp = InsecureClientIp;
+
+use crate::{db::DBAuth, user::token::ApiTokenIn};
+
+use super::{event, login_claims, user_site};
+
+pub async fn tokens_get(
+	TypedHeader(host): TypedHeader<headers::Host>,
+	Extension(db): Extension<LockedAtomic<DBAuth>>,
+	Extension(user_claims): Extension<UserClaims>,
+) -> Result<impl IntoResponse, DbError> {
+	let db = db.read().unwrap();
+	let site_id = user_site(&db, &host, &user_claims)?;
+	Ok(Json(db.tokens_get(&user_claims.user, site_id)?))
+}
+
+/// Creates an API token for `Authorization: Bearer`, it's only returned this once
+pub async fn tokens_post(
+	TypedHeader(host): TypedHeader<headers::Host>,
+	Extension(db): Extension<LockedAtomic<DBAuth>>,
+	Extension(user_claims): Extension<UserClaims>,
+	user_agent: Option<TypedHeader<headers::UserAgent>>,
+	ip: ClientIp,
+	Json(body): Json<ApiTokenIn>,
+) -> Result<impl IntoResponse, DbError> {
+	let db = db.read().unwrap();
+	let site_id = user_site(&db, &host, &user_claims)?;
+	let (token, login) = db.token_new(&user_claims.user, site_id, body)?;
+
+	let (host, _) = db.host_to_site_id(host.hostname());
+	let mut claims = login_claims(&host, login, token.expires.saturating_sub(get_secs()));
+	claims.token_identifier(&token.id).unwrap();
+	claims.add_additional("scopes", token.scopes.clone()).unwrap();
+	let secret = seal(&claims);
+
+	info!("API token '{}' created for '{}'.", token.name, user_claims.user);
+	event("auth_token_new", ip.0, &user_agent, &user_claims.user, site_id, Outcome::Ok);
+	Ok(Json(json!({ "token": token, "secret": secret })))
+}
+
+pub async fn tokens_delete(
+	TypedHeader(host): TypedHeader<headers::Host>,
+	Path(id): Path<String>,
+	Extension(db): Extension<LockedAtomic<DBAuth>>,
+	Extension(user_claims): Extension<UserClaims>,
+	user_agent: Option<TypedHeader<headers::UserAgent>>,
+	ip: ClientIp,
+) -> Result<impl IntoResponse, DbError> {
+	let mut db = db.write().unwrap();
+	let site_id = user_site(&db, &host, &user_claims)?;
+	db.token_del(&user_claims.user, site_id, &id)?;
+	revoked::write(&db.revoked);
+
+	event("auth_token_del", ip.0, &user_agent, &user_claims.user, site_id, Outcome::Ok);
+	Ok(())
+}
@@ -0,0 +1,171 @@
+use auth::{
+	validate::{decrypt, encrypt},
+	UserClaims,
+};
+use axum::{extract::Extension, headers, response::IntoResponse, Json, TypedHeader};
+use common::{
+	utils::{get_secs, DbError, LockedAtomic},
+	vreji::Outcome,
+};
+use log::{error, info};
+use pasetors::claims::{Claims, ClaimsValidationRules};
+use serde::Deserialize;
+use serde_json::json;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use axum_client_ip::InsecureClientIp;
+type ClientIp = InsecureClientIp;
+
+use crate::db::{site::SiteId, DBAuth};
+
+use super::{device, event, login_cookies, user_site};
+
+/// Bound into pending tokens, so they never decrypt as an `auth` cookie
+const PENDING_ASSERTION: &[u8] = b"totp_pending";
+/// Secs a user has to type their code after the password
+const PENDING_AGE: u64 = 5 * 60;
+
+/// Token proving the password step of a login was passed
+pub fn pending_token(host: &str, user: &str, site: Option<SiteId>) -> String {
+	let rfc3339 = |secs: u64| {
+		OffsetDateTime::from_unix_timestamp(secs.try_into().unwrap())
+			.unwrap()
+			.format(&Rfc3339)
+			.unwrap()
+	};
+	let mut claims = Claims::new().unwrap();
+	claims.issuer("talebox").unwrap();
+	claims.audience(host).unwrap();
+	claims.add_additional("pending", user).unwrap();
+	claims.add_additional("site", serde_json::to_value(site).unwrap()).unwrap();
+
+	let iat = rfc3339(get_secs());
+	claims.not_before(&iat).unwrap();
+	claims.issued_at(&iat).unwrap();
+	claims.expiration(&rfc3339(get_secs() + PENDING_AGE)).unwrap();
+
+	encrypt(&claims, Some(PENDING_ASSERTION))
+}
+
+/// User and site of a pending token that's still valid for `host`
+fn pending_claims(host: &str, token: &str) -> Result<(String, Option<SiteId>), DbError> {
+	let mut validation_rules = ClaimsValidationRules::new();
+	validation_rules.validate_issuer_with("talebox");
+	validation_rules.validate_audience_with(host);
+
+	let token = decrypt(token, &validation_rules, Some(PENDING_ASSERTION)).ok_or(DbError::AuthError)?;
+	let claims = token.payload_claims().ok_or(DbError::AuthError)?;
+	let user = claims
+		.get_claim("pending")
+		.and_then(|u| u.as_str())
+		.ok_or(DbError::AuthError)?;
+	let site = claims
+		.get_claim("site")
+		.and_then(|s| serde_json::from_value(s.clone()).ok())
+		.ok_or(DbError::AuthError)?;
+	Ok((user.to_owned(), site))
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct LoginTotpBody {
+	/// From the first step of `/login`
+	token: String,
+	/// From the authenticator, or a recovery code
+	code: String,
+}
+
+/// Second step of a login, trades a pending token and a code for the `auth` cookie
+pub async fn login_totp(
+	TypedHeader(host): TypedHeader<headers::Host>,
+	Extension(db): Extension<LockedAtomic<DBAuth>>,
+	user_agent: Option<TypedHeader<headers::UserAgent>>,
+	ip: ClientIp,
+	Json(body): Json<LoginTotpBody>,
+) -> Result<impl IntoResponse, DbError> {
+	let mut db = db.write().unwrap();
+	let (host, _) = db.host_to_site_id(host.hostname());
+	let (user, site_id) = pending_claims(&host, &body.token)?;
+
+	// Codes are easier to guess than passwords, they count as failed logins too
+	if let Err(err) = db.login_allowed(&user, &ip.0.to_string(), site_id) {
+		event("auth_login_totp", ip.0, &user_agent, &user, site_id, Outcome::Locked);
+		return Err(err);
+	}
+	let login = db.login_totp(&user, &body.code, site_id);
+	if login.is_err() {
+		db.login_failed(&user, &ip.0.to_string(), site_id);
+	}
+
+	login
+		.and_then(|(login, recovery)| {
+			let cookies = login_cookies(&mut db, &host, login, &device(&user_agent), &ip.0.to_string())?;
+			db.login_succeeded(&user, site_id);
+			event("auth_login", ip.0, &user_agent, &user, site_id, Outcome::Ok);
+			Ok((cookies, Json(json!({ "recovery": recovery }))))
+		})
+		.inspect_err(|err| {
+			error!("Failed second factor for '{user}': {err:?}.");
+			event("auth_login_totp", ip.0, &user_agent, &user, site_id, Outcome::Failed);
+		})
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct CodeBody {
+	code: String,
+}
+
+pub async fn totp_get(
+	TypedHeader(host): TypedHeader<headers::Host>,
+	Extension(db): Extension<LockedAtomic<DBAuth>>,
+	Extension(user_claims): Extension<UserClaims>,
+) -> Result<impl IntoResponse, DbError> {
+	let db = db.read().unwrap();
+	let site_id = user_site(&db, &host, &user_claims)?;
+	Ok(Json(db.totp_status(&user_claims.user, site_id)?))
+}
+
+/// Starts enrollment, returns the secret and `otpauth://` uri to show as a QR code
+pub async fn totp_post(
+	TypedHeader(host): TypedHeader<headers::Host>,
+	Extension(db): Extension<LockedAtomic<DBAuth>>,
+	Extension(user_claims): Extension<UserClaims>,
+) -> Result<impl IntoResponse, DbError> {
+	let db = db.read().unwrap();
+	let site_id = user_site(&db, &host, &user_claims)?;
+	Ok(Json(db.totp_begin(&user_claims.user, site_id)?))
+}
+
+/// Confirms enrollment with a first code, returns the recovery codes
+pub async fn totp_confirm(
+	TypedHeader(host): TypedHeader<headers::Host>,
+	Extension(db): Extension<LockedAtomic<DBAuth>>,
+	Extension(user_claims): Extension<UserClaims>,
+	user_agent: Option<TypedHeader<headers::UserAgent>>,
+	ip: ClientIp,
+	Json(body): Json<CodeBody>,
+) -> Result<impl IntoResponse, DbError> {
+	let db = db.read().unwrap();
+	let site_id = user_site(&db, &host, &user_claims)?;
+	let recovery = db.totp_confirm(&user_claims.user, site_id, &body.code)?;
+	info!("Two-factor enabled for '{}'.", user_claims.user);
+	event("auth_totp_enable", ip.0, &user_agent, &user_claims.user, site_id, Outcome::Ok);
+	Ok(Json(recovery))
+}
+
+pub async fn totp_delete(
+	TypedHeader(host): TypedHeader<headers::Host>,
+	Extension(db): Extension<LockedAtomic<DBAuth>>,
+	Extension(user_claims): Extension<UserClaims>,
+	user_agent: Option<TypedHeader<headers::UserAgent>>,
+	ip: ClientIp,
+	Json(body): Json<CodeBody>,
+) -> Result<impl IntoResponse, DbError> {
+	let db = db.read().unwrap();
+	let site_id = user_site(&db, &host, &user_claims)?;
+	db.totp_disable(&user_claims.user, site_id, &body.code)?;
+	info!("Two-factor disabled for '{}'.", user_claims.user);
+	event("auth_totp_disable", ip.0, &user_agent, &user_claims.user, site_id, Outcome::Ok);
+	Ok(())
+}
@@ -26,9 +26,31 @@ pub struct UserClaims {
 	/// Media limit, in bytes
 	#[serde(skip_serializing_if = "is_zero")]
 	pub media_limit: u64,
+
+	/// Only API tokens have scopes, logins can do everything
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub scopes: Option<Vec<String>>,
+	/// Token id, so it can be revoked
+	#[serde(skip_serializing_if = "String::is_empty")]
+	pub jti: String,
 	
 	pub exp: String,
 }
+impl UserClaims {
+	/// Can this token do `scope`, like `chunk:read`, `{resource}:write` implies `{resource}:read`
+	pub fn has_scope(&self, scope: &str) -> bool {
+		let Some(scopes) = self.scopes.as_ref() else {
+			return true;
+		};
+		scopes.iter().any(|s| {
+			s == scope
+				|| scope
+					.strip_suffix(":read")
+					.map(|resource| *s == format!("{resource}:write"))
+					.unwrap_or(false)
+		})
+	}
+}
 impl From<&Claims> for UserClaims {
 	fn from(claims: &Claims) -> Self {
 		serde_json::from_str(claims.to_string().unwrap().as_str()).unwrap()
@@ -0,0 +1,225 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use common::utils::URL;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::{
+	env,
+	io::{Error, ErrorKind},
+	sync::{Arc, Mutex},
+	time::Duration,
+};
+use tokio::{
+	io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+	net::TcpStream,
+	time::timeout,
+};
+
+/// Longest a whole SMTP conversation can take, a stuck relay shouldn't hold up requests
+const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+lazy_static! {
+	/// SMTP relay mails go through, like `127.0.0.1:25`. Nothing gets sent if unset.
+	///
+	/// It's plain SMTP, no TLS, so keep it local and let your MTA relay it.
+	static ref SMTP_SOCKET: Option<String> = env::var("SMTP_SOCKET").ok();
+	/// For `AUTH PLAIN`, if the relay asks for it
+	static ref SMTP_USER: Option<String> = env::var("SMTP_USER").ok();
+	static ref SMTP_PASS: Option<String> = env::var("SMTP_PASS").ok();
+	/// Who mails come from
+	static ref MAIL_FROM: String = env::var("MAIL_FROM").unwrap_or_else(|_| format!("auth@{}", host(&URL)));
+}
+
+/// Host of an url, `https://a.b.com:4000/x` is `a.b.com`
+fn host(url: &str) -> &str {
+	let url = url.split_once("://").map(|(_, u)| u).unwrap_or(url);
+	url.split(['/', ':']).next().unwrap_or_default()
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mail {
+	pub to: String,
+	pub subject: String,
+	pub body: String,
+}
+
+/// What a site's mails say, `{user}`, `{site}` and `{link}` get replaced
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct MailTemplate {
+	pub subject: String,
+	pub body: String,
+}
+impl Default for MailTemplate {
+	fn default() -> Self {
+		Self {
+			subject: "Reset your {site} password".into(),
+			body: "\
+				Hi {user},\n\
+				\n\
+				Someone asked to reset your password on {site}.\n\
+				Follow this link within 30 minutes to choose a new one:\n\
+				\n\
+				{link}\n\
+				\n\
+				If it wasn't you, you can ignore this email.\n"
+				.into(),
+		}
+	}
+}
+impl MailTemplate {
+	pub fn render(&self, to: &str, vars: &[(&str, &str)]) -> Mail {
+		let fill = |text: &str| {
+			vars
+				.iter()
+				.fold(text.to_owned(), |text, (k, v)| text.replace(&format!("{{{k}}}"), v))
+		};
+		Mail {
+			to: to.into(),
+			// Headers end at a newline
+			subject: fill(&self.subject).replace(['\r', '\n'], " "),
+			body: fill(&self.body),
+		}
+	}
+}
+
+/// Where mails go
+#[derive(Clone)]
+pub enum Mailer {
+	/// Through `SMTP_SOCKET`
+	Smtp(String),
+	/// Kept in memory, for tests
+	#[allow(dead_code)]
+	Capture(Arc<Mutex<Vec<Mail>>>),
+	/// Nowhere, only logged that they weren't sent
+	None,
+}
+impl Mailer {
+	pub fn from_env() -> Self {
+		match SMTP_SOCKET.as_ref() {
+			Some(socket) => Self::Smtp(socket.to_owned()),
+			None => Self::None,
+		}
+	}
+
+	pub async fn send(&self, mail: Mail) -> std::io::Result<()> {
+		match self {
+			Mailer::Smtp(socket) => {
+				let send = async { smtp(TcpStream::connect(socket).await?, &mail).await };
+				timeout(SMTP_TIMEOUT, send)
+					.await
+					.map_err(|_| Error::new(ErrorKind::TimedOut, "SMTP server took too long"))?
+			}
+			Mailer::Capture(mails) => {
+				mails.lock().unwrap().push(mail);
+				Ok(())
+			}
+			Mailer::None => {
+				log::warn!("No SMTP_SOCKET set, mail to '{}' wasn't sent.", mail.to);
+				Ok(())
+			}
+		}
+	}
+}
+
+/// Reads a reply, multiline ones too, erroring unless its code starts with `expect`
+async fn reply<R: AsyncBufReadExt + Unpin>(reader: &mut R, expect: char) -> std::io::Result<()> {
+	loop {
+		let mut line = String::new();
+		if reader.read_line(&mut line).await? == 0 {
+			return Err(Error::new(ErrorKind::UnexpectedEof, "SMTP server hung up"));
+		}
+		if !line.starts_with(expect) {
+			return Err(Error::other(format!("SMTP: {}", line.trim_end())));
+		}
+		// `250-` continues, `250 ` ends
+		if line.as_bytes().get(3) != Some(&b'-') {
+			return Ok(());
+		}
+	}
+}
+
+/// One SMTP conversation sending `mail`
+async fn smtp<S: AsyncRead + AsyncWrite + Unpin>(stream: S, mail: &Mail) -> std::io::Result<()> {
+	let (reader, mut writer) = tokio::io::split(stream);
+	let mut reader = BufReader::new(reader);
+
+	reply(&mut reader, '2').await?;
+	writer.write_all(format!("EHLO {}\r\n", host(&URL)).as_bytes()).await?;
+	reply(&mut reader, '2').await?;
+	if let (Some(user), Some(pass)) = (SMTP_USER.as_ref(), SMTP_PASS.as_ref()) {
+		let plain = STANDARD.encode(format!("\0{user}\0{pass}"));
+		writer.write_all(format!("AUTH PLAIN {plain}\r\n").as_bytes()).await?;
+		reply(&mut reader, '2').await?;
+	}
+	writer.write_all(format!("MAIL FROM:<{}>\r\n", MAIL_FROM.as_str()).as_bytes()).await?;
+	reply(&mut reader, '2').await?;
+	writer.write_all(format!("RCPT TO:<{}>\r\n", mail.to).as_bytes()).await?;
+	reply(&mut reader, '2').await?;
+	writer.write_all(b"DATA\r\n").await?;
+	reply(&mut reader, '3').await?;
+
+	let mut data = format!(
+		"From: <{}>\r\nTo: <{}>\r\nSubject: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n",
+		MAIL_FROM.as_str(),
+		mail.to,
+		mail.subject
+	);
+	for line in mail.body.lines() {
+		// Dot stuffing, a lone `.` would end the data
+		if line.starts_with('.') {
+			data.push('.');
+		}
+		data.push_str(line);
+		data.push_str("\r\n");
+	}
+	data.push_str(".\r\n");
+	writer.write_all(data.as_bytes()).await?;
+	reply(&mut reader, '2').await?;
+	writer.write_all(b"QUIT\r\n").await?;
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn smtp_sends() {
+		let (client, server) = tokio::io::duplex(4096);
+		let relay = tokio::spawn(async move {
+			let (reader, mut writer) = tokio::io::split(server);
+			let mut reader = BufReader::new(reader);
+			let (mut seen, mut data) = (vec![], false);
+			writer.write_all(b"220 relay ready\r\n").await.unwrap();
+			loop {
+				let mut line = String::new();
+				if reader.read_line(&mut line).await.unwrap() == 0 {
+					return seen;
+				}
+				let reply: &[u8] = match line.trim_end() {
+					"." if data => b"250 Queued\r\n",
+					_ if data => b"",
+					l if l.starts_with("EHLO") => b"250-relay\r\n250 8BITMIME\r\n",
+					"DATA" => b"354 Go ahead\r\n",
+					"QUIT" => b"221 Bye\r\n",
+					_ => b"250 OK\r\n",
+				};
+				data = (data || reply.starts_with(b"354")) && !reply.starts_with(b"250");
+				seen.push(line);
+				// The client hangs up right after QUIT
+				writer.write_all(reply).await.ok();
+			}
+		});
+
+		let mail = Mail {
+			to: "nina@example.com".into(),
+			subject: "Hi".into(),
+			body: "First\n.hidden\nLast".into(),
+		};
+		smtp(client, &mail).await.unwrap();
+		let seen = relay.await.unwrap().concat();
+		assert!(seen.contains("RCPT TO:<nina@example.com>\r\n"));
+		assert!(seen.contains("Subject: Hi\r\n"));
+		assert!(seen.contains("First\r\n..hidden\r\nLast\r\n.\r\n"), "Dot stuffed");
+	}
+}
@@ -1,7 +1,7 @@
-use auth::validate::KPR;
+use auth::validate::{KP, KPR};
 use axum::{
 	error_handling::HandleErrorLayer,
-	routing::{get, patch, post, put},
+	routing::{delete, get, patch, post, put},
 	BoxError, Extension, Router,
 };
 
@@ -31,6 +31,7 @@ use tower_http::timeout::TimeoutLayer;
 
 mod db;
 mod ends;
+mod mail;
 mod user;
 
 #[tokio::main]
@@ -58,13 +59,15 @@ async fn main() {
 
 	{
 		// Check that keys exist
-		// lazy_static::initialize(&KP);
+		lazy_static::initialize(&KP);
 		lazy_static::initialize(&KPR);
 	}
 
 	// Read cache
 	let cache = Arc::new(RwLock::new(Cache::init()));
 	let db = Arc::new(RwLock::new(common::init::init::<db::DBAuth>().await));
+	// Other slepaus only see revocations through this file
+	auth::validate::revoked::write(&db.read().unwrap().revoked);
 
 	let (shutdown_tx, mut shutdown_rx) = watch::channel(());
 
@@ -121,6 +124,14 @@ async fn main() {
 					"/sites/:site_id/users/:id",
 					patch(ends::admin::put_user).delete(ends::admin::del_user),
 				)
+				.route(
+					"/sites/:site_id/clients",
+					get(ends::admin::get_clients).post(ends::admin::post_client),
+				)
+				.route("/sites/:site_id/clients/:id", delete(ends::admin::del_client))
+				.route("/sites/:site_id/lockouts", get(ends::admin::get_lockouts))
+				.route("/sites/:site_id/lockouts/:key", delete(ends::admin::del_lockout))
+				.route("/sites/:site_id/events", get(ends::admin::get_events))
 				// Only Admins ^
 				.layer(axum::middleware::from_fn(auth::validate::flow::only_admins)),
 		)
@@ -128,6 +139,8 @@ async fn main() {
 		.merge(
 			Router::new()
 				.route("/reset", post(crate::ends::reset))
+				.route("/reset/request", post(crate::ends::forgot::reset_request))
+				.route("/reset/confirm", post(crate::ends::forgot::reset_confirm))
 				.route("/register", post(crate::ends::register)), // .layer(security_limit(1, 10)),
 		)
 		.merge(
@@ -135,7 +148,39 @@ async fn main() {
 				.route("/user", get(crate::ends::user).patch(crate::ends::user_patch))
 				.route("/logout", get(crate::ends::logout)), // .layer(security_limit(1, 1)),
 		)
+		.merge(
+			Router::new()
+				.route(
+					"/totp",
+					get(crate::ends::totp::totp_get)
+						.post(crate::ends::totp::totp_post)
+						.delete(crate::ends::totp::totp_delete),
+				)
+				.route("/totp/confirm", post(crate::ends::totp::totp_confirm))
+				.route(
+					"/tokens",
+					get(crate::ends::token::tokens_get).post(crate::ends::token::tokens_post),
+				)
+				.route("/tokens/:id", delete(crate::ends::token::tokens_delete))
+				.route(
+					"/sessions",
+					get(crate::ends::session::sessions_get).delete(crate::ends::session::sessions_delete),
+				)
+				.route("/sessions/:id", delete(crate::ends::session::session_delete))
+				.route_layer(axum::middleware::from_fn(auth::validate::flow::no_api_tokens))
+				.route_layer(axum::middleware::from_fn(auth::validate::flow::auth_required)),
+		)
 		.route("/login", post(crate::ends::login))
+		.route("/login/totp", post(crate::ends::totp::login_totp))
+		.route("/refresh", post(crate::ends::session::refresh))
+		// OpenID Connect provider
+		.route("/.well-known/openid-configuration", get(crate::ends::oidc::discovery))
+		.route("/oidc/jwks", get(crate::ends::oidc::jwks))
+		// Key discovery, for slepaus verifying `v4.public` tokens
+		.route("/keys", get(crate::ends::oidc::jwks))
+		.route("/oidc/authorize", get(crate::ends::oidc::authorize))
+		.route("/oidc/token", post(crate::ends::oidc::token))
+		.route("/oidc/userinfo", get(crate::ends::oidc::userinfo))
 		
 		// The request limiter :)
 		.layer(
@@ -156,7 +201,11 @@ async fn main() {
 						}),
 				),
 		)
+		// Slepaus acting on behalf of users, unlimited as they all come from few IPs
+		.route("/service/token", post(crate::ends::service::service_token))
+		.layer(axum::middleware::from_fn_with_state("auth", auth::validate::flow::scoped))
 		.layer(axum::middleware::from_fn(auth::validate::authenticate))
+		.layer(axum::middleware::from_fn(auth::validate::refresh::refresh))
 		// Serves static assets
 		.fallback_service(static_routes())
 		.layer(TimeoutLayer::new(Duration::from_secs(30)))
@@ -166,6 +215,7 @@ async fn main() {
 				.concurrency_limit(100)
 				.layer(Extension(db.clone()))
 				.layer(Extension(cache.clone()))
+				.layer(Extension(mail::Mailer::from_env()))
 				.layer(Extension(shutdown_rx.clone())), // .layer(Extension(resource_tx.clone())),
 		);
 	// If we're local, then allow cors
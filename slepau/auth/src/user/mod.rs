@@ -5,7 +5,11 @@ use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
 mod blacklist;
+pub mod reset;
+pub mod session;
 mod src;
+pub mod token;
+pub mod totp;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct User {
@@ -13,12 +17,26 @@ pub struct User {
 	pass: String,
 	pub active: bool,
 	pub claims: BTreeMap<String, Value>,
+	/// Second factor, if the user started enrolling
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	totp: Option<totp::Totp>,
+	/// API tokens by id
+	#[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+	tokens: BTreeMap<String, token::ApiToken>,
+	/// Logins by id
+	#[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+	sessions: BTreeMap<String, session::Session>,
+	/// Password reset sent by email, if one was asked for
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	reset: Option<reset::PassReset>,
 }
 #[derive(Serialize, Clone, Debug)]
 pub struct UserView {
 	user: String,
 	active: bool,
 	claims: BTreeMap<String, String>,
+	/// Two-factor enabled
+	totp: bool,
 }
 #[allow(dead_code)]
 #[derive(Deserialize)]
@@ -38,6 +56,7 @@ impl From<&User> for UserView {
 				.iter()
 				.map(|(k, v)| (k.to_owned(), serde_json::to_string(&v).unwrap()))
 				.collect(),
+			totp: value.totp_enabled(),
 		}
 	}
 }
@@ -0,0 +1,50 @@
+use common::utils::{get_secs, DbError};
+use rand::distributions::{Alphanumeric, DistString};
+use serde::{Deserialize, Serialize};
+
+use super::{session::sha256, User};
+
+/// Secs a reset link works for
+const RESET_AGE: u64 = 30 * 60;
+
+/// A pending password reset, the token itself only goes out by email
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PassReset {
+	/// Hash of the token
+	hash: String,
+	/// In secs
+	expires: u64,
+}
+
+impl User {
+	/// Where reset links go, the `email` claim
+	pub fn email(&self) -> Option<String> {
+		self
+			.claims
+			.get("email")
+			.and_then(|e| e.as_str())
+			.filter(|e| e.contains('@') && !e.chars().any(|c| c.is_whitespace() || c.is_control() || "<>".contains(c)))
+			.map(|e| e.to_owned())
+	}
+
+	/// Starts a reset, returning its token. Replaces any previous one.
+	pub fn reset_begin(&mut self) -> String {
+		let token = Alphanumeric.sample_string(&mut rand::thread_rng(), 43);
+		self.reset = Some(PassReset {
+			hash: sha256(&token),
+			expires: get_secs() + RESET_AGE,
+		});
+		token
+	}
+
+	/// Sets a new password with a reset token, which only works once
+	pub fn reset_finish(&mut self, token: &str, pass: &str) -> Result<(), DbError> {
+		let reset = self.reset.as_ref().ok_or(DbError::AuthError)?;
+		if !self.active || reset.expires <= get_secs() || reset.hash != sha256(token) {
+			return Err(DbError::AuthError);
+		}
+		self.reset_pass(None, pass)?;
+		self.reset = None;
+		Ok(())
+	}
+}
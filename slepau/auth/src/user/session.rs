@@ -0,0 +1,137 @@
+use common::utils::{get_secs, DbError};
+use rand::distributions::{Alphanumeric, DistString};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::User;
+
+/// Most sessions kept per user, the least recently seen go first
+pub(crate) const MAX_SESSIONS: usize = 50;
+/// Secs a just rotated refresh token still gets access tokens, for requests racing each other
+const REUSE_GRACE: u64 = 30;
+
+pub(super) fn sha256(secret: &str) -> String {
+	format!("{:x}", Sha256::digest(secret.as_bytes()))
+}
+
+/// A login, its id is the `jti` of the token it handed out
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Session {
+	pub id: String,
+	/// User agent it logged in with
+	pub device: String,
+	pub ip: String,
+	/// In secs
+	pub created: u64,
+	/// Last time it talked to auth (in secs)
+	pub seen: u64,
+	/// In secs
+	pub expires: u64,
+	/// Hash of the current refresh token
+	#[serde(default)]
+	refresh: String,
+	/// Hash of the one it replaced
+	#[serde(default)]
+	previous: String,
+	/// When the refresh token was last rotated (in secs)
+	#[serde(default)]
+	rotated: u64,
+}
+
+/// What presenting a refresh token did
+#[derive(Debug, PartialEq)]
+pub enum Refresh {
+	/// Here's the next refresh token
+	Rotated(String),
+	/// The previous token, just rotated by a concurrent request
+	Grace,
+	/// An old token came back, so it leaked, the session is gone
+	Reused(Session),
+}
+
+impl Session {
+	pub fn new(device: &str, ip: &str, max_age: u64) -> Self {
+		Self {
+			id: common::proquint::Proquint::<u64>::default().to_quint(),
+			device: device.chars().take(256).collect(),
+			ip: ip.to_owned(),
+			created: get_secs(),
+			seen: get_secs(),
+			expires: get_secs() + max_age,
+			refresh: Default::default(),
+			previous: Default::default(),
+			rotated: Default::default(),
+		}
+	}
+
+	/// Replaces the refresh token, returning the new one. Only its hash is kept.
+	pub fn rotate(&mut self) -> String {
+		let secret = Alphanumeric.sample_string(&mut rand::thread_rng(), 43);
+		self.previous = std::mem::replace(&mut self.refresh, sha256(&secret));
+		self.rotated = get_secs();
+		secret
+	}
+}
+
+impl User {
+	pub fn sessions(&self) -> Vec<Session> {
+		self.sessions.values().cloned().collect()
+	}
+
+	/// Adds a session, returns the ones it pushed out past `MAX_SESSIONS`, still good until revoked
+	pub fn session_add(&mut self, session: Session) -> Vec<Session> {
+		let now = get_secs();
+		self.sessions.retain(|_, s| s.expires > now);
+		let mut evicted = vec![];
+		while self.sessions.len() >= MAX_SESSIONS {
+			let oldest = self.sessions.values().min_by_key(|s| s.seen).unwrap().id.clone();
+			evicted.extend(self.sessions.remove(&oldest));
+		}
+		self.sessions.insert(session.id.clone(), session);
+		evicted
+	}
+
+	pub fn session_seen(&mut self, id: &str, ip: &str) {
+		if let Some(session) = self.sessions.get_mut(id) {
+			session.seen = get_secs();
+			session.ip = ip.to_owned();
+		}
+	}
+
+	/// Trades a session's refresh token for the next one
+	pub fn session_refresh(&mut self, id: &str, secret: &str, ip: &str) -> Result<Refresh, DbError> {
+		let session = self.sessions.get_mut(id).ok_or(DbError::AuthError)?;
+		if session.expires <= get_secs() {
+			self.sessions.remove(id);
+			return Err(DbError::AuthError);
+		}
+		let hash = sha256(secret);
+		let refresh = if !session.refresh.is_empty() && hash == session.refresh {
+			Refresh::Rotated(session.rotate())
+		} else if !session.previous.is_empty() && hash == session.previous && session.rotated + REUSE_GRACE > get_secs() {
+			Refresh::Grace
+		} else {
+			return Ok(Refresh::Reused(self.sessions.remove(id).unwrap()));
+		};
+		session.seen = get_secs();
+		session.ip = ip.to_owned();
+		Ok(refresh)
+	}
+
+	pub fn session_remove(&mut self, id: &str) -> Option<Session> {
+		self.sessions.remove(id)
+	}
+
+	/// Ends every session but `keep`, returns the ones that ended
+	pub fn sessions_remove_others(&mut self, keep: &str) -> Vec<Session> {
+		let others = self.sessions.keys().filter(|id| *id != keep).cloned().collect::<Vec<_>>();
+		others.iter().filter_map(|id| self.sessions.remove(id)).collect()
+	}
+
+	/// Ends all sessions and drops all API tokens, returns their ids and expiration to revoke
+	pub fn sign_out(&mut self) -> Vec<(String, u64)> {
+		let sessions = std::mem::take(&mut self.sessions).into_values().map(|s| (s.id, s.expires));
+		let tokens = std::mem::take(&mut self.tokens).into_values().map(|t| (t.id, t.expires));
+		sessions.chain(tokens).collect()
+	}
+}
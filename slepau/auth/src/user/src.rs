@@ -5,34 +5,51 @@ use argon2::{
 
 use common::utils::{DbError, REGEX_PASSWORD, REGEX_PASSWORD_HUMAN, REGEX_USERNAME, REGEX_USERNAME_HUMAN};
 
+use lazy_static::lazy_static;
+
 use super::{blacklist::BLACKLIST, User};
 
+lazy_static! {
+	/// Checked against when there's no such user, so it takes as long as when there is
+	static ref NOBODY: String = User::hash_secret("nobody");
+}
+
 #[allow(dead_code)]
 impl User {
 	pub fn verify_login(&self, pass: &str) -> Result<(), DbError> {
-		if !self.active {
-			return Err(DbError::AuthError);
-		}
 		// PHC string -> PasswordHash.
 		let parsed_hash = PasswordHash::new(&self.pass).expect("Error parsing existing password field");
 
-		// Compare pass hash vs PasswordHash
-		Argon2::default()
-			.verify_password(pass.as_bytes(), &parsed_hash)
-			.map_err(|_| DbError::AuthError)
+		// Compare pass hash vs PasswordHash, even for inactive users so they take as long
+		let verified = Argon2::default().verify_password(pass.as_bytes(), &parsed_hash);
+		if !self.active {
+			return Err(DbError::AuthError);
+		}
+		verified.map_err(|_| DbError::AuthError)
+	}
+	/// Fails a login for a user that doesn't exist, after the same argon2 work as one that does
+	pub(crate) fn verify_nobody(pass: &str) -> Result<(), DbError> {
+		Self::verify_secret(&NOBODY, pass);
+		Err(DbError::AuthError)
 	}
 	fn hash(pass: &str) -> Result<String, DbError> {
 		if !REGEX_PASSWORD.is_match(pass) {
 			return Err(DbError::InvalidPassword(REGEX_PASSWORD_HUMAN.as_str()));
 		}
-
+		Ok(Self::hash_secret(pass))
+	}
+	/// Argon2 PHC string of anything we only need to compare against later
+	pub(crate) fn hash_secret(secret: &str) -> String {
 		let salt = SaltString::generate(&mut OsRng);
-		Ok(
-			Argon2::default()
-				.hash_password(pass.as_bytes(), &salt)
-				.unwrap()
-				.to_string(),
-		)
+		Argon2::default()
+			.hash_password(secret.as_bytes(), &salt)
+			.unwrap()
+			.to_string()
+	}
+	pub(crate) fn verify_secret(hash: &str, secret: &str) -> bool {
+		PasswordHash::new(hash)
+			.map(|hash| Argon2::default().verify_password(secret.as_bytes(), &hash).is_ok())
+			.unwrap_or(false)
 	}
 	pub fn new(user: &str, pass: &str) -> Result<Self, DbError> {
 		if !REGEX_USERNAME.is_match(user) {
@@ -47,6 +64,10 @@ impl User {
 			pass: Self::hash(pass)?,
 			active: true,
 			claims: Default::default(),
+			totp: None,
+			tokens: Default::default(),
+			sessions: Default::default(),
+			reset: None,
 		})
 	}
 
@@ -0,0 +1,89 @@
+use common::{
+	proquint::Proquint,
+	utils::{get_secs, DbError, SECS_IN_DAY},
+};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use super::User;
+
+lazy_static! {
+	/// `resource:action`, like `chunk:read` or `samn:command`
+	static ref REGEX_SCOPE: Regex = Regex::new(r"^[a-z]+:[a-z]+$").unwrap();
+}
+
+const MAX_TOKENS: usize = 20;
+const MAX_DAYS: u64 = 365;
+
+/// A named, scoped API token, the token itself is only shown when it's created
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ApiToken {
+	/// Also the token's `jti`
+	pub id: String,
+	pub name: String,
+	pub scopes: Vec<String>,
+	/// In secs
+	pub created: u64,
+	/// In secs
+	pub expires: u64,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct ApiTokenIn {
+	pub name: String,
+	pub scopes: Vec<String>,
+	/// Days until it expires
+	pub days: u64,
+}
+impl Default for ApiTokenIn {
+	fn default() -> Self {
+		Self {
+			name: Default::default(),
+			scopes: Default::default(),
+			days: 30,
+		}
+	}
+}
+
+impl User {
+	pub fn tokens(&self) -> Vec<ApiToken> {
+		self.tokens.values().cloned().collect()
+	}
+
+	pub fn token_add(&mut self, token: ApiTokenIn) -> Result<ApiToken, DbError> {
+		let name = token.name.trim();
+		if name.is_empty() || name.chars().count() > 64 {
+			return Err(DbError::Custom("Token name has to be 1-64 characters long.".into()));
+		}
+		if token.scopes.is_empty() || token.scopes.iter().any(|s| !REGEX_SCOPE.is_match(s)) {
+			return Err(DbError::Custom("Scopes look like 'chunk:read'.".into()));
+		}
+		if !(1..=MAX_DAYS).contains(&token.days) {
+			return Err(DbError::Custom(format!("Tokens last 1-{MAX_DAYS} days.")));
+		}
+		// Expired ones don't count
+		self.tokens.retain(|_, t| t.expires > get_secs());
+		if self.tokens.len() >= MAX_TOKENS {
+			return Err(DbError::Custom(format!("Can't have more than {MAX_TOKENS} tokens.")));
+		}
+
+		let mut scopes = token.scopes;
+		scopes.sort();
+		scopes.dedup();
+		let token = ApiToken {
+			id: Proquint::<u64>::default().to_quint(),
+			name: name.to_owned(),
+			scopes,
+			created: get_secs(),
+			expires: get_secs() + token.days * SECS_IN_DAY,
+		};
+		self.tokens.insert(token.id.clone(), token.clone());
+		Ok(token)
+	}
+
+	pub fn token_remove(&mut self, id: &str) -> Result<ApiToken, DbError> {
+		self.tokens.remove(id).ok_or(DbError::NotFound)
+	}
+}
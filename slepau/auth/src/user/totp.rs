@@ -0,0 +1,252 @@
+use common::utils::{get_secs, DbError};
+use hmac::{Hmac, Mac};
+use rand::{distributions::Alphanumeric, Rng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+
+use super::{session::sha256, User};
+
+/// Secs a code is valid for
+const STEP: u64 = 30;
+const DIGITS: u32 = 6;
+/// Steps before/after now that are still accepted, for clocks that drift
+const SKEW: u64 = 1;
+/// Bytes of shared secret, 160 bits like RFC 4226 recommends
+const SECRET_SIZE: usize = 20;
+const RECOVERY_CODES: usize = 10;
+
+const BASE32: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+fn base32_encode(data: &[u8]) -> String {
+	let mut out = String::new();
+	for chunk in data.chunks(5) {
+		let mut buf = [0u8; 5];
+		buf[..chunk.len()].copy_from_slice(chunk);
+		let bits = buf.iter().fold(0u64, |a, b| (a << 8) | *b as u64);
+		let chars = (chunk.len() * 8 + 4) / 5;
+		for i in 0..chars {
+			out.push(BASE32[((bits >> (35 - i * 5)) & 31) as usize] as char);
+		}
+	}
+	out
+}
+
+fn base32_decode(value: &str) -> Option<Vec<u8>> {
+	let (mut bits, mut len, mut out) = (0u64, 0, vec![]);
+	for c in value.trim_end_matches('=').bytes() {
+		let v = BASE32.iter().position(|b| *b == c.to_ascii_uppercase())? as u64;
+		bits = (bits << 5) | v;
+		len += 5;
+		if len >= 8 {
+			len -= 8;
+			out.push((bits >> len) as u8);
+		}
+	}
+	Some(out)
+}
+
+/// RFC 4226 HOTP of `secret` at `counter`
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+	let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("Hmac takes any key size");
+	mac.update(&counter.to_be_bytes());
+	let hash = mac.finalize().into_bytes();
+	let offset = (hash[hash.len() - 1] & 0xf) as usize;
+	let code = u32::from_be_bytes(hash[offset..offset + 4].try_into().unwrap()) & 0x7fff_ffff;
+	code % 10u32.pow(DIGITS)
+}
+
+/// Code of a base32 `secret` for a time in secs
+pub(crate) fn code(secret: &str, secs: u64) -> String {
+	let secret = base32_decode(secret).unwrap_or_default();
+	format!("{:0width$}", hotp(&secret, secs / STEP), width = DIGITS as usize)
+}
+
+/// Minimal escaping for the parts of an uri, like `otpauth://` ones
+pub(crate) fn uri_escape(value: &str) -> String {
+	value
+		.bytes()
+		.map(|b| match b {
+			b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' => (b as char).to_string(),
+			b => format!("%{b:02X}"),
+		})
+		.collect()
+}
+
+/**
+ * RFC 6238 TOTP second factor.
+ *
+ * Secret is stored as is, authenticators need the same one to generate codes.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct Totp {
+	/// Base32
+	secret: String,
+	/// Set once the user proves their authenticator has the secret
+	enabled: bool,
+	/// Sha256 of unused recovery codes, they're random enough not to need a slow hash
+	recovery: Vec<String>,
+	/// Last step a code was used for, so a code can't be used twice
+	last_step: u64,
+}
+
+/// What an authenticator needs to be set up
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct TotpSetup {
+	pub secret: String,
+	/// `otpauth://` uri, for a QR code
+	pub uri: String,
+}
+
+impl Totp {
+	fn new() -> Self {
+		let mut secret = [0u8; SECRET_SIZE];
+		rand::thread_rng().fill_bytes(&mut secret);
+		Self {
+			secret: base32_encode(&secret),
+			..Default::default()
+		}
+	}
+
+	fn setup(&self, issuer: &str, user: &str) -> TotpSetup {
+		TotpSetup {
+			secret: self.secret.clone(),
+			uri: format!(
+				"otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={DIGITS}&period={STEP}",
+				uri_escape(issuer),
+				uri_escape(user),
+				self.secret,
+				uri_escape(issuer)
+			),
+		}
+	}
+
+	/// Checks a code around `secs`, each code works once
+	fn verify_at(&mut self, given: &str, secs: u64) -> bool {
+		let given = given.trim().replace(' ', "");
+		if given.len() != DIGITS as usize {
+			return false;
+		}
+		let now = secs / STEP;
+		let step = (now.saturating_sub(SKEW)..=now + SKEW)
+			.find(|step| *step > self.last_step && code(&self.secret, step * STEP) == given);
+		if let Some(step) = step {
+			self.last_step = step;
+		}
+		step.is_some()
+	}
+
+	/// Uses up a matching recovery code
+	fn recover(&mut self, code: &str) -> bool {
+		let code = code.trim().to_lowercase();
+		let hash = sha256(&code);
+		if let Some(i) = self.recovery.iter().position(|h| *h == hash) {
+			self.recovery.remove(i);
+			return true;
+		}
+		false
+	}
+}
+
+impl User {
+	pub fn totp_enabled(&self) -> bool {
+		self.totp.as_ref().map(|t| t.enabled).unwrap_or(false)
+	}
+
+	pub fn totp_recovery_left(&self) -> usize {
+		self.totp.as_ref().map(|t| t.recovery.len()).unwrap_or_default()
+	}
+
+	/// Starts enrollment with a fresh secret, replacing any unconfirmed one
+	pub fn totp_begin(&mut self, issuer: &str) -> Result<TotpSetup, DbError> {
+		if self.totp_enabled() {
+			return Err(DbError::Custom("Two-factor is already enabled.".into()));
+		}
+		let totp = Totp::new();
+		let setup = totp.setup(issuer, &self.user);
+		self.totp = Some(totp);
+		Ok(setup)
+	}
+
+	/// Finishes enrollment with a first code, returns recovery codes, they're only shown this once
+	pub fn totp_confirm(&mut self, code: &str) -> Result<Vec<String>, DbError> {
+		let totp = self
+			.totp
+			.as_mut()
+			.filter(|t| !t.enabled)
+			.ok_or(DbError::Custom("Start two-factor enrollment first.".into()))?;
+		if !totp.verify_at(code, get_secs()) {
+			return Err(DbError::AuthError);
+		}
+
+		let codes = (0..RECOVERY_CODES)
+			.map(|_| {
+				let code = rand::thread_rng()
+					.sample_iter(&Alphanumeric)
+					.take(10)
+					.map(|c| (c as char).to_ascii_lowercase())
+					.collect::<String>();
+				format!("{}-{}", &code[..5], &code[5..])
+			})
+			.collect::<Vec<_>>();
+		totp.recovery = codes.iter().map(|c| sha256(c)).collect();
+		totp.enabled = true;
+		Ok(codes)
+	}
+
+	/// Second factor check, a code from the authenticator or a recovery code
+	pub fn verify_totp(&mut self, code: &str) -> Result<(), DbError> {
+		if !self.active {
+			return Err(DbError::AuthError);
+		}
+		let totp = self.totp.as_mut().filter(|t| t.enabled).ok_or(DbError::AuthError)?;
+		let digits = code.trim().replace(' ', "");
+		let valid = if digits.len() == DIGITS as usize && digits.bytes().all(|b| b.is_ascii_digit()) {
+			totp.verify_at(code, get_secs())
+		} else {
+			totp.recover(code)
+		};
+		if valid {
+			Ok(())
+		} else {
+			Err(DbError::AuthError)
+		}
+	}
+
+	/// Turns two-factor off, needs a valid code
+	pub fn totp_disable(&mut self, code: &str) -> Result<(), DbError> {
+		self.verify_totp(code)?;
+		self.totp = None;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{base32_decode, base32_encode, code, hotp, sha256, Totp};
+
+	#[test]
+	fn rfc() {
+		// RFC 4226 appendix D
+		let secret = b"12345678901234567890";
+		assert_eq!(base32_encode(secret), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+		assert_eq!(base32_decode(&base32_encode(secret)).unwrap(), secret);
+		assert_eq!(hotp(secret, 0), 755224);
+		assert_eq!(hotp(secret, 9), 520489);
+
+		// RFC 6238 appendix B, last 6 digits
+		let mut totp = Totp {
+			secret: base32_encode(secret),
+			..Default::default()
+		};
+		assert_eq!(code(&totp.secret, 59), "287082");
+		assert_eq!(code(&totp.secret, 1111111109), "081804");
+		assert!(totp.verify_at("081804", 1111111109));
+		assert!(!totp.verify_at("081804", 1111111109), "Codes only work once");
+
+		totp.recovery = vec![sha256("abcde-12345")];
+		assert!(!totp.recover("abcde-54321"));
+		assert!(totp.recover(" ABCDE-12345"));
+		assert!(!totp.recover("abcde-12345"), "Recovery codes only work once too");
+	}
+}
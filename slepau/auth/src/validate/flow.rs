@@ -1,4 +1,5 @@
 use axum::{
+	extract::State,
 	http::Request,
 	middleware::Next,
 	response::{IntoResponse, Response},
@@ -54,3 +55,60 @@ pub async fn public_only_get<B>(req: Request<B>, next: Next<B>) -> Result<Respon
 		Ok(next.run(req).await)
 	}
 }
+
+/// API tokens need `{resource}:read` for GET/HEAD/OPTIONS and `{resource}:write` for the rest, logins go through.
+///
+/// `.layer(from_fn_with_state("chunk", flow::scoped))`
+pub async fn scoped<B>(
+	State(resource): State<&'static str>,
+	req: Request<B>,
+	next: Next<B>,
+) -> Result<Response, impl IntoResponse> {
+	let access = if matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+		"read"
+	} else {
+		"write"
+	};
+	if !req
+		.extensions()
+		.get::<UserClaims>()
+		.map(|claims| claims.has_scope(&format!("{resource}:{access}")))
+		.unwrap_or(false)
+	{
+		Err(DbError::AuthError)
+	} else {
+		Ok(next.run(req).await)
+	}
+}
+
+/// API tokens need `scope`, like `samn:command`, logins go through
+pub async fn only_scope<B>(
+	State(scope): State<&'static str>,
+	req: Request<B>,
+	next: Next<B>,
+) -> Result<Response, impl IntoResponse> {
+	if !req
+		.extensions()
+		.get::<UserClaims>()
+		.map(|claims| claims.has_scope(scope))
+		.unwrap_or(false)
+	{
+		Err(DbError::AuthError)
+	} else {
+		Ok(next.run(req).await)
+	}
+}
+
+/// Only logins, API tokens can't manage credentials
+pub async fn no_api_tokens<B>(req: Request<B>, next: Next<B>) -> Result<Response, impl IntoResponse> {
+	if req
+		.extensions()
+		.get::<UserClaims>()
+		.map(|claims| claims.scopes.is_some())
+		.unwrap_or(false)
+	{
+		Err(DbError::AuthError)
+	} else {
+		Ok(next.run(req).await)
+	}
+}
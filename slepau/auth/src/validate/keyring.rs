@@ -0,0 +1,146 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use common::utils::{get_secs, K_KEYRING, K_KEYRING_PRIVATE, K_PRIVATE, K_PUBLIC};
+use lazy_static::lazy_static;
+use log::error;
+use pasetors::{
+	keys::{AsymmetricPublicKey, SymmetricKey},
+	paserk::{FormatAsPaserk, Id},
+	version4::V4,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::RwLock, time::SystemTime};
+
+/// Secs between checks of the key files for changes
+const CHECK_SECS: u64 = 5;
+
+/// A key that doesn't issue tokens anymore
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OldKey {
+	/// When it was rotated out (in secs)
+	pub rotated: u64,
+	/// The key, in base64
+	pub key: String,
+}
+
+/// Old keys saved at `path`, `K_KEYRING` for public ones and `K_KEYRING_PRIVATE` for local ones.
+///
+/// Kept apart so slepaus that only verify `v4.public` tokens never get keys that could issue tokens.
+pub fn read(path: &str) -> Vec<OldKey> {
+	std::fs::read(path)
+		.ok()
+		.and_then(|b| serde_json::from_slice(&b).ok())
+		.unwrap_or_default()
+}
+
+/// Saves old keys at `path`, only `gen_key` calls this
+pub fn write(path: &str, keys: &[OldKey]) -> std::io::Result<()> {
+	let tmp = format!("{path}.tmp");
+	std::fs::write(&tmp, serde_json::to_vec_pretty(keys).unwrap())?;
+	std::fs::rename(&tmp, path)
+}
+
+fn paserk(id: Id) -> String {
+	let mut paserk = String::new();
+	id.fmt(&mut paserk).unwrap();
+	paserk
+}
+/// PASERK id of a local key, like `k4.lid.…`
+pub fn local_id(key: &SymmetricKey<V4>) -> String {
+	paserk(Id::from(key))
+}
+/// PASERK id of a public key, like `k4.pid.…`
+pub fn public_id(key: &AsymmetricPublicKey<V4>) -> String {
+	paserk(Id::from(key))
+}
+
+#[derive(Default)]
+struct Ring {
+	/// When the files were last checked (in secs)
+	checked: u64,
+	modified: [Option<SystemTime>; 4],
+	/// Key bytes by id, the current ones and the rotated out ones
+	locals: HashMap<String, Vec<u8>>,
+	publics: HashMap<String, Vec<u8>>,
+}
+
+lazy_static! {
+	static ref RING: RwLock<Ring> = Default::default();
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+	std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Bytes of the old keys at `path`
+fn old_keys(path: &str) -> impl Iterator<Item = Vec<u8>> + '_ {
+	read(path).into_iter().filter_map(move |k| {
+		URL_SAFE_NO_PAD
+			.decode(k.key)
+			.inspect_err(|err| error!("Bad key in '{path}': {err}"))
+			.ok()
+	})
+}
+
+/// Reloads the keys if any of the files changed, at most every few secs
+fn refresh() {
+	let now = get_secs();
+	if RING.read().unwrap().checked + CHECK_SECS > now {
+		return;
+	}
+	let mut ring = RING.write().unwrap();
+	ring.checked = now;
+
+	let files = [
+		modified(K_KEYRING.as_str()),
+		modified(K_KEYRING_PRIVATE.as_str()),
+		modified(K_PRIVATE.as_str()),
+		modified(K_PUBLIC.as_str()),
+	];
+	if files == ring.modified {
+		return;
+	}
+	ring.modified = files;
+
+	let locals = std::fs::read(K_PRIVATE.as_str())
+		.ok()
+		.into_iter()
+		.chain(old_keys(K_KEYRING_PRIVATE.as_str()));
+	ring.locals = locals
+		.filter_map(|b| Some((local_id(&SymmetricKey::<V4>::from(b.as_slice()).ok()?), b)))
+		.collect();
+	let publics = std::fs::read(K_PUBLIC.as_str())
+		.ok()
+		.into_iter()
+		.chain(old_keys(K_KEYRING.as_str()));
+	ring.publics = publics
+		.filter_map(|b| Some((public_id(&AsymmetricPublicKey::<V4>::from(b.as_slice()).ok()?), b)))
+		.collect();
+}
+
+/// Local key with this id
+pub fn local_key(kid: &str) -> Option<SymmetricKey<V4>> {
+	refresh();
+	let ring = RING.read().unwrap();
+	ring.locals.get(kid).and_then(|b| SymmetricKey::from(b.as_slice()).ok())
+}
+
+/// Every local key, for tokens from before key ids
+pub fn local_keys() -> Vec<SymmetricKey<V4>> {
+	refresh();
+	let ring = RING.read().unwrap();
+	ring.locals.values().filter_map(|b| SymmetricKey::from(b.as_slice()).ok()).collect()
+}
+
+/// Public key with this id
+pub fn public_key(kid: &str) -> Option<AsymmetricPublicKey<V4>> {
+	refresh();
+	let ring = RING.read().unwrap();
+	ring.publics.get(kid).and_then(|b| AsymmetricPublicKey::from(b.as_slice()).ok())
+}
+
+/// Every public key, what auth publishes
+pub fn public_keys() -> Vec<AsymmetricPublicKey<V4>> {
+	refresh();
+	let ring = RING.read().unwrap();
+	ring.publics.values().filter_map(|b| AsymmetricPublicKey::from(b.as_slice()).ok()).collect()
+}
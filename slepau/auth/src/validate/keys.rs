@@ -0,0 +1,123 @@
+use axum::http::{header, Request};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use common::utils::{get_secs, AUTH_URL};
+use hyper::{Body, Client};
+use lazy_static::lazy_static;
+use log::error;
+use pasetors::{keys::AsymmetricPublicKey, version4::V4};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{collections::HashMap, sync::RwLock, time::Duration};
+
+use super::keyring::{self, public_id};
+
+/// Secs between asking auth for keys we don't know
+const FETCH_SECS: u64 = 60;
+
+/// Keys fetched from auth, by id
+#[derive(Default)]
+struct Keys {
+	/// When auth was last asked (in secs)
+	fetched: u64,
+	by_id: HashMap<String, AsymmetricPublicKey<V4>>,
+}
+
+lazy_static! {
+	static ref KEYS: RwLock<Keys> = Default::default();
+}
+
+/// A public key as a JWK, what `/keys` and `/oidc/jwks` publish
+pub fn jwk(key: &AsymmetricPublicKey<V4>) -> Value {
+	json!({
+		"kty": "OKP",
+		"crv": "Ed25519",
+		"x": URL_SAFE_NO_PAD.encode(key.as_bytes()),
+		"use": "sig",
+		"alg": "EdDSA",
+		"kid": public_id(key),
+	})
+}
+
+#[derive(Deserialize)]
+struct Jwk {
+	x: String,
+	kid: String,
+}
+#[derive(Deserialize)]
+struct Jwks {
+	keys: Vec<Jwk>,
+}
+
+/// Asks auth for the keys it signs with
+async fn fetch() -> Option<HashMap<String, AsymmetricPublicKey<V4>>> {
+	let req = Request::get(format!("{}/keys", AUTH_URL.as_str()))
+		.header(header::ACCEPT, "application/json")
+		.body(Body::empty())
+		.ok()?;
+	let res = match tokio::time::timeout(Duration::from_secs(5), Client::new().request(req)).await {
+		Ok(Ok(res)) => res,
+		_ => {
+			error!("Couldn't get keys from '{}'.", AUTH_URL.as_str());
+			return None;
+		}
+	};
+	let body = hyper::body::to_bytes(res.into_body()).await.ok()?;
+	parse(&body)
+}
+
+/// Keys in a JWKS body, skipping any whose id doesn't match
+fn parse(body: &[u8]) -> Option<HashMap<String, AsymmetricPublicKey<V4>>> {
+	let jwks = serde_json::from_slice::<Jwks>(body).ok()?;
+	Some(
+		jwks
+			.keys
+			.into_iter()
+			.filter_map(|k| {
+				let key = AsymmetricPublicKey::<V4>::from(URL_SAFE_NO_PAD.decode(k.x).ok()?.as_slice()).ok()?;
+				// Don't take the id's word for it
+				(public_id(&key) == k.kid).then_some((k.kid, key))
+			})
+			.collect(),
+	)
+}
+
+/// Public key with this id, from our key files or asking auth if it's one we haven't seen, like after a rotation
+pub async fn public_key(kid: &str) -> Option<AsymmetricPublicKey<V4>> {
+	if let Some(key) = keyring::public_key(kid) {
+		return Some(key);
+	}
+	if let Some(key) = KEYS.read().unwrap().by_id.get(kid) {
+		return Some(key.clone());
+	}
+	{
+		let mut keys = KEYS.write().unwrap();
+		if keys.fetched + FETCH_SECS > get_secs() {
+			return None;
+		}
+		keys.fetched = get_secs();
+	}
+	let fetched = fetch().await?;
+	let mut keys = KEYS.write().unwrap();
+	keys.by_id.extend(fetched);
+	keys.by_id.get(kid).cloned()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use pasetors::keys::{AsymmetricKeyPair, Generate};
+
+	#[test]
+	fn jwks() {
+		let kp = AsymmetricKeyPair::<V4>::generate().unwrap();
+		let other = AsymmetricKeyPair::<V4>::generate().unwrap();
+		let mut forged = jwk(&other.public);
+		forged["kid"] = public_id(&kp.public).into();
+
+		let body = json!({ "keys": [jwk(&kp.public), forged] }).to_string();
+		let keys = parse(body.as_bytes()).unwrap();
+		assert_eq!(keys.len(), 1);
+		assert!(public_id(&kp.public).starts_with("k4.pid."));
+		assert_eq!(keys[&public_id(&kp.public)].as_bytes(), kp.public.as_bytes());
+	}
+}
@@ -1,6 +1,6 @@
 use axum::{
 	extract::TypedHeader,
-	headers::{Cookie, Host},
+	headers::{authorization::Bearer, Authorization, Cookie, Host},
 	http::Request,
 	middleware::Next,
 	response::Response, RequestPartsExt,
@@ -9,23 +9,37 @@ use common::utils::{hostname_normalize, K_PRIVATE, K_PUBLIC, K_SECRET};
 use hyper::StatusCode;
 use lazy_static::lazy_static;
 use pasetors::{
-	claims::ClaimsValidationRules,
+	claims::{Claims, ClaimsValidationRules},
+	footer::Footer,
 	keys::{AsymmetricKeyPair, AsymmetricPublicKey, AsymmetricSecretKey, SymmetricKey},
 	local,
-	token::UntrustedToken,
+	paserk::Id,
+	public,
+	token::{TrustedToken, UntrustedToken},
 	version4::V4,
-	Local,
+	Local, Public,
 };
 
-
 lazy_static! {
 	pub static ref KPR: SymmetricKey::<V4> = private_key();
 	pub static ref KP: AsymmetricKeyPair::<V4> = public_key();
+	/// Issue `v4.public` tokens signed with `KP`, so slepaus can verify them with only `K_PUBLIC`
+	pub static ref PUBLIC_TOKENS: bool = std::env::var("AUTH_PUBLIC_TOKENS").unwrap_or_default().parse::<bool>().unwrap_or(false);
+	/// Slepaus that only verify `v4.public` tokens can go without `K_PRIVATE`
+	static ref HAS_PRIVATE: bool = std::fs::read(K_PRIVATE.as_str())
+		.ok()
+		.and_then(|b| SymmetricKey::<V4>::from(b.as_slice()).ok())
+		.is_some();
 }
 
 use crate::UserClaims;
 
 pub mod flow;
+pub mod keyring;
+pub mod keys;
+pub mod refresh;
+pub mod revoked;
+pub mod service;
 
 fn private_key() -> SymmetricKey<V4> {
 	let kp;
@@ -76,6 +90,74 @@ fn public_key() -> AsymmetricKeyPair<V4> {
 	kp
 }
 
+/// Seals login claims into a token, signed with `KP` if `PUBLIC_TOKENS`, otherwise encrypted with `KPR`
+pub fn seal(claims: &Claims) -> String {
+	if *PUBLIC_TOKENS {
+		let mut footer = Footer::new();
+		footer.key_id(&Id::from(&KP.public));
+		public::sign(&KP.secret, claims, Some(&footer), None).unwrap()
+	} else {
+		encrypt(claims, None)
+	}
+}
+
+/// `v4.local` token encrypted with `KPR`, its id in the footer so it outlives a rotation
+pub fn encrypt(claims: &Claims, implicit_assert: Option<&[u8]>) -> String {
+	let mut footer = Footer::new();
+	footer.key_id(&Id::from(&*KPR));
+	local::encrypt(&KPR, claims, Some(&footer), implicit_assert).unwrap()
+}
+
+/// Key id a token's footer names
+fn footer_kid(footer: &[u8]) -> Option<String> {
+	let footer = serde_json::from_slice::<serde_json::Value>(footer).ok()?;
+	footer.get("kid")?.as_str().map(|kid| kid.to_owned())
+}
+
+/// Decrypts a `v4.local` token with the key its footer names, or any key for tokens from before key ids
+pub fn decrypt(
+	token: &str,
+	validation_rules: &ClaimsValidationRules,
+	implicit_assert: Option<&[u8]>,
+) -> Option<TrustedToken> {
+	let token = UntrustedToken::<Local, V4>::try_from(token).ok()?;
+	match footer_kid(token.untrusted_footer()) {
+		Some(kid) => local::decrypt(&keyring::local_key(&kid)?, &token, validation_rules, None, implicit_assert).ok(),
+		None => keyring::local_keys()
+			.iter()
+			.find_map(|key| local::decrypt(key, &token, validation_rules, None, implicit_assert).ok()),
+	}
+}
+
+/// Checks for keys to verify tokens with, call it when starting
+pub fn init_keys() {
+	if *HAS_PRIVATE {
+		lazy_static::initialize(&KPR);
+	} else {
+		println!(
+			"No key at K_PRIVATE:'{}', only `v4.public` tokens will be accepted.",
+			K_PRIVATE.as_str()
+		);
+	}
+}
+
+/// A token that's valid for `host` and wasn't revoked, either `v4.public` or `v4.local`
+pub(crate) async fn verify(host: &str, token: &str) -> Option<TrustedToken> {
+	let mut validation_rules = ClaimsValidationRules::new();
+	validation_rules.validate_issuer_with("talebox");
+	validation_rules.validate_audience_with(host);
+
+	let token = if token.starts_with("v4.public.") {
+		let token = UntrustedToken::<Public, V4>::try_from(token).ok()?;
+		let key = keys::public_key(&footer_kid(token.untrusted_footer())?).await?;
+		public::verify(&key, &token, &validation_rules, None, None).ok()?
+	} else {
+		decrypt(token, &validation_rules, None)?
+	};
+	let claims = UserClaims::from(token.payload_claims()?);
+	(!revoked::is_revoked(&claims.jti)).then_some(token)
+}
+
 /// Function used to authenticate.
 pub async fn authenticate<B>(req: Request<B>, next: Next<B>) -> Result<Response, StatusCode> {
 	let mut user_claims = UserClaims {
@@ -86,32 +168,21 @@ pub async fn authenticate<B>(req: Request<B>, next: Next<B>) -> Result<Response,
 	let (mut parts, body) = req.into_parts();
 	let TypedHeader(host): TypedHeader<Host> = parts.extract().await.expect("A Host header");
 	let cookies = parts.extract::<TypedHeader<Cookie>>().await;
+	let bearer = parts.extract::<TypedHeader<Authorization<Bearer>>>().await;
 	let mut req = Request::from_parts(parts, body);
 
 	let host = host.hostname();
 	let host = hostname_normalize(host);
 
-	if let Some(auth_cookie) = cookies
-		.ok()
-		.and_then(|cookies| cookies.get("auth").map(|v| v.to_owned()))
-	{
-		let mut validation_rules = ClaimsValidationRules::new();
-		validation_rules.validate_issuer_with("talebox");
-		validation_rules.validate_audience_with(host);
-
-		if let Ok(token) = UntrustedToken::<
-			// Public
-			Local,
-			V4,
-		>::try_from(&auth_cookie)
-		{
-			if let Ok(token) =
-				// public::verify(&KP.public, &token, &validation_rules, None, None)
-				local::decrypt(&KPR, &token, &validation_rules, None, None)
-			{
-				user_claims = UserClaims::from(&token.payload_claims().unwrap().clone());
-				req.extensions_mut().insert(token);
-			}
+	// API tokens come as `Authorization: Bearer`, logins as the `auth` cookie
+	if let Some(auth_token) = bearer.ok().map(|b| b.token().to_owned()).or_else(|| {
+		cookies
+			.ok()
+			.and_then(|cookies| cookies.get("auth").map(|v| v.to_owned()))
+	}) {
+		if let Some(token) = verify(host, &auth_token).await {
+			user_claims = UserClaims::from(&token.payload_claims().unwrap().clone());
+			req.extensions_mut().insert(token);
 		}
 	}
 
@@ -0,0 +1,110 @@
+use axum::{
+	http::{header, HeaderMap, HeaderValue, Request},
+	middleware::Next,
+	response::Response,
+	RequestPartsExt,
+};
+use axum_client_ip::InsecureClientIp;
+use common::utils::{hostname_normalize, AUTH_URL};
+use hyper::{Body, Client};
+use log::error;
+use std::{collections::BTreeMap, net::IpAddr, time::Duration};
+
+use super::verify;
+
+/// Secs to wait on auth before going on without a new cookie
+const TIMEOUT_SECS: u64 = 5;
+
+/// Cookies a request carries, by name
+fn jar(headers: &HeaderMap) -> BTreeMap<String, String> {
+	headers
+		.get_all(header::COOKIE)
+		.iter()
+		.filter_map(|c| c.to_str().ok())
+		.flat_map(|c| c.split(';'))
+		.filter_map(|c| c.trim().split_once('='))
+		.map(|(k, v)| (k.to_owned(), v.to_owned()))
+		.collect()
+}
+
+/// Trades a `refresh` cookie for new cookies at auth, returns the `Set-Cookie`s it answered with
+async fn fetch(
+	host: &HeaderValue,
+	refresh: &str,
+	ip: Option<IpAddr>,
+	user_agent: Option<&HeaderValue>,
+) -> Option<Vec<HeaderValue>> {
+	let mut req = Request::post(format!("{}/refresh", AUTH_URL.as_str()))
+		.header(header::HOST, host)
+		.header(header::COOKIE, format!("refresh={refresh}"));
+	// So the session shows the user's ip and device, not ours
+	if let Some(ip) = ip {
+		req = req.header("X-Forwarded-For", ip.to_string());
+	}
+	if let Some(user_agent) = user_agent {
+		req = req.header(header::USER_AGENT, user_agent);
+	}
+	let req = req.body(Body::empty()).ok()?;
+
+	match tokio::time::timeout(Duration::from_secs(TIMEOUT_SECS), Client::new().request(req)).await {
+		Ok(Ok(res)) => Some(res.headers().get_all(header::SET_COOKIE).iter().cloned().collect()),
+		Ok(Err(err)) => {
+			error!("Couldn't refresh at '{}': {err}", AUTH_URL.as_str());
+			None
+		}
+		Err(_) => {
+			error!("Refreshing at '{}' timed out.", AUTH_URL.as_str());
+			None
+		}
+	}
+}
+
+/// When the `auth` cookie is missing or expired but there's a `refresh` one, gets new ones from auth
+/// and swaps them into the request, so `authenticate` sees fresh claims. The browser gets them too.
+///
+/// Goes outside of `authenticate`.
+pub async fn refresh<B>(req: Request<B>, next: Next<B>) -> Response {
+	let (mut parts, body) = req.into_parts();
+	let ip = parts.extract::<InsecureClientIp>().await.ok().map(|ip| ip.0);
+	let mut req = Request::from_parts(parts, body);
+
+	let headers = req.headers();
+	let mut cookies = jar(headers);
+	let (Some(host), Some(refresh)) = (headers.get(header::HOST).cloned(), cookies.get("refresh").cloned()) else {
+		return next.run(req).await;
+	};
+	let hostname = host.to_str().unwrap_or_default().split(':').next().unwrap_or_default();
+	let auth_valid = match cookies.get("auth") {
+		Some(auth) => verify(hostname_normalize(hostname), auth).await.is_some(),
+		None => false,
+	};
+	// API tokens don't refresh, and `/refresh` does it itself
+	if auth_valid || headers.contains_key(header::AUTHORIZATION) || req.uri().path() == "/refresh" {
+		return next.run(req).await;
+	}
+
+	let Some(set_cookies) = fetch(&host, &refresh, ip, headers.get(header::USER_AGENT)).await else {
+		return next.run(req).await;
+	};
+	// As if the browser had sent them
+	set_cookies
+		.iter()
+		.filter_map(|c| c.to_str().ok()?.split(';').next()?.split_once('='))
+		.for_each(|(k, v)| {
+			if v.is_empty() {
+				cookies.remove(k);
+			} else {
+				cookies.insert(k.to_owned(), v.to_owned());
+			}
+		});
+	let cookies = cookies.iter().map(|(k, v)| format!("{k}={v}")).collect::<Vec<_>>().join("; ");
+	if let Ok(cookies) = HeaderValue::from_str(&cookies) {
+		req.headers_mut().insert(header::COOKIE, cookies);
+	}
+
+	let mut res = next.run(req).await;
+	set_cookies.into_iter().for_each(|c| {
+		res.headers_mut().append(header::SET_COOKIE, c);
+	});
+	res
+}
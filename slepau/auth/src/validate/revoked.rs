@@ -0,0 +1,63 @@
+use common::utils::{get_secs, K_REVOKED};
+use lazy_static::lazy_static;
+use log::error;
+use std::{
+	collections::{BTreeMap, HashSet},
+	sync::RwLock,
+	time::SystemTime,
+};
+
+/// Secs between checks of the file for changes
+const CHECK_SECS: u64 = 5;
+
+#[derive(Default)]
+struct Revoked {
+	/// When the file was last checked (in secs)
+	checked: u64,
+	modified: Option<SystemTime>,
+	ids: HashSet<String>,
+}
+
+lazy_static! {
+	static ref REVOKED: RwLock<Revoked> = Default::default();
+}
+
+/// Reloads the list if the file changed, at most every few secs
+fn refresh() {
+	let now = get_secs();
+	if REVOKED.read().unwrap().checked + CHECK_SECS > now {
+		return;
+	}
+	let mut revoked = REVOKED.write().unwrap();
+	revoked.checked = now;
+
+	let modified = std::fs::metadata(K_REVOKED.as_str()).and_then(|m| m.modified()).ok();
+	if modified == revoked.modified {
+		return;
+	}
+	revoked.modified = modified;
+	revoked.ids = std::fs::read(K_REVOKED.as_str())
+		.ok()
+		.and_then(|b| serde_json::from_slice::<BTreeMap<String, u64>>(&b).ok())
+		.map(|ids| ids.into_keys().collect())
+		.unwrap_or_default();
+}
+
+/// Was the token with this id revoked, tokens without an id can't be
+pub fn is_revoked(jti: &str) -> bool {
+	if jti.is_empty() {
+		return false;
+	}
+	refresh();
+	REVOKED.read().unwrap().ids.contains(jti)
+}
+
+/// Publishes revoked token ids with their expiration (in secs), only auth calls this
+pub fn write(ids: &BTreeMap<String, u64>) {
+	let tmp = format!("{}.tmp", K_REVOKED.as_str());
+	let result = std::fs::write(&tmp, serde_json::to_vec(ids).unwrap())
+		.and_then(|_| std::fs::rename(&tmp, K_REVOKED.as_str()));
+	if let Err(err) = result {
+		error!("Couldn't write revoked tokens to '{}': {err}", K_REVOKED.as_str());
+	}
+}
@@ -0,0 +1,42 @@
+use axum::http::{header, Request};
+use common::utils::{AUTH_URL, SERVICE_SECRET};
+use hyper::{Body, Client};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+/// Secs a service token lasts at most
+pub const SERVICE_MAX_SECS: u64 = 300;
+
+/// All a service token can do, media asking chunk which media is referenced and chunk uploading mail attachments
+pub const SERVICE_SCOPES: [&str; 2] = ["chunk:read", "media:write"];
+
+/// Secs to wait on auth for a token
+const TIMEOUT_SECS: u64 = 10;
+
+/// Short lived token for slepaus acting on behalf of `user`, like chunk uploading mail attachments to media.
+///
+/// Auth makes it from the user's claims on `host`'s site, like a login, so limits such as `media_limit` hold.
+/// It only has `scope`, one of [SERVICE_SCOPES]. Needs `SERVICE_SECRET`, the same auth has.
+pub async fn service_token(user: &str, host: &str, scope: &str, secs: u64) -> Result<String, String> {
+	let secret = SERVICE_SECRET.as_ref().ok_or("No SERVICE_SECRET set")?;
+	let req = Request::post(format!("{}/service/token", AUTH_URL.as_str()))
+		.header(header::HOST, host)
+		.header(header::AUTHORIZATION, format!("Bearer {secret}"))
+		.header(header::CONTENT_TYPE, "application/json")
+		.body(Body::from(json!({ "user": user, "secs": secs, "scopes": [scope] }).to_string()))
+		.map_err(|err| err.to_string())?;
+
+	let res = tokio::time::timeout(Duration::from_secs(TIMEOUT_SECS), Client::new().request(req))
+		.await
+		.map_err(|_| format!("Asking '{}' for a token timed out", AUTH_URL.as_str()))?
+		.map_err(|err| err.to_string())?;
+	let status = res.status();
+	let body = hyper::body::to_bytes(res.into_body()).await.map_err(|err| err.to_string())?;
+	if !status.is_success() {
+		return Err(format!("Auth answered {status} {}", String::from_utf8_lossy(&body)));
+	}
+	serde_json::from_slice::<Value>(&body)
+		.ok()
+		.and_then(|v| v["token"].as_str().map(String::from))
+		.ok_or_else(|| "No token in auth's response".into())
+}
@@ -0,0 +1,253 @@
+use common::{
+	sonnerie::db,
+	utils::{get_secs, DbError},
+	vreji::RecordValues,
+};
+use serde::{Deserialize, Serialize};
+use sonnerie::Wildcard;
+use std::collections::HashMap;
+
+use crate::db::{chunk::ChunkId, user_access::Access, DB};
+
+/// Records that change who can reach a chunk, listed one by one.
+///
+/// `chunk_access` is logged wherever access changed, edits and shares alike.
+const ACCESS_ACTIONS: [&str; 5] = [
+	"chunk_access",
+	"chunk_transfer",
+	"chunk_transfer_accept",
+	"chunk_encrypt",
+	"chunk_unencrypt",
+];
+
+/// Longest period, in secs, a year
+const PERIOD_MAX: u64 = 365 * 86400;
+/// Most periods an audit goes back
+const LIMIT_MAX: usize = 366;
+
+/// `/chunks/:id/audit?period=3600&limit=24&user=john`
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct AuditQuery {
+	/// In secs
+	pub period: u64,
+	/// In # of periods
+	pub limit: usize,
+	pub user: Option<String>,
+	/// Sonnerie wildcard over record keys, only chunk ones (`chunk_%`)
+	pub key: String,
+}
+impl AuditQuery {
+	/// Within bounds, `period` and `limit` come straight from the query string
+	pub fn clamped(self) -> Self {
+		Self {
+			period: self.period.clamp(1, PERIOD_MAX),
+			limit: self.limit.clamp(1, LIMIT_MAX),
+			..self
+		}
+	}
+}
+impl Default for AuditQuery {
+	fn default() -> Self {
+		Self {
+			period: 86400, // a day
+			limit: 30,     // 30 periods (days, ~1 month)
+			user: None,
+			key: "chunk_%".into(),
+		}
+	}
+}
+
+/// What a user did to a chunk, counted per period, most recent period first
+#[derive(Serialize, Debug, PartialEq)]
+pub struct AuditRow {
+	pub user: String,
+	pub action: String,
+	pub count: u64,
+	/// In secs
+	pub last: u64,
+	pub periods: Vec<u64>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct AuditEvent {
+	/// In secs
+	pub time: u64,
+	pub user: String,
+	pub action: String,
+}
+
+/**
+ * Who read, edited or shared a chunk.
+ *
+ * Ips aren't part of it, only supers get to see those in vreji.
+ */
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Audit {
+	pub period: u64,
+	pub limit: usize,
+	/// Most recently active first
+	pub rows: Vec<AuditRow>,
+	/// Access changes, most recent first
+	pub access: Vec<AuditEvent>,
+}
+
+/// Folds records into an audit of chunk `id` up to `now` (in secs)
+pub fn aggregate(
+	records: impl Iterator<Item = RecordValues>,
+	id: ChunkId,
+	now: u64,
+	query: &AuditQuery,
+) -> Audit {
+	let query = query.clone().clamped();
+	let id = id.to_quint();
+	let period = query.period;
+	let mut rows = HashMap::<(String, String), AuditRow>::new();
+	let mut access = vec![];
+
+	for r in records.filter(|r| r.id.as_deref() == Some(id.as_str())) {
+		let Some(user) = r.user else { continue };
+		if query.user.as_ref().map(|u| *u != user).unwrap_or(false) {
+			continue;
+		}
+		let time = r.time / 1_000_000_000; // Secs
+		let index = (now.saturating_sub(time) / period) as usize;
+		if index >= query.limit {
+			continue;
+		}
+
+		if ACCESS_ACTIONS.contains(&r.key.as_str()) {
+			access.push(AuditEvent {
+				time,
+				user: user.clone(),
+				action: r.key.clone(),
+			});
+		}
+		let row = rows.entry((user.clone(), r.key.clone())).or_insert_with(|| AuditRow {
+			user,
+			action: r.key,
+			count: 0,
+			last: 0,
+			periods: vec![0; query.limit],
+		});
+		row.count += 1;
+		row.last = row.last.max(time);
+		row.periods[index] += 1;
+	}
+
+	let mut rows = rows.into_values().collect::<Vec<_>>();
+	rows.sort_by(|a, b| b.last.cmp(&a.last).then_with(|| a.action.cmp(&b.action)));
+	access.sort_by_key(|e| std::cmp::Reverse(e.time));
+
+	Audit {
+		period,
+		limit: query.limit,
+		rows,
+		access,
+	}
+}
+
+/// Audit of a chunk up to now, only reads records within `query`'s periods.
+///
+/// Reads the whole log, so check with [DB::audit_allowed] and drop the DB lock first.
+pub fn audit(id: ChunkId, query: &AuditQuery) -> Audit {
+	let query = query.clone().clamped();
+	let now = get_secs();
+	let since = now.saturating_sub(query.period * query.limit as u64) * 1_000_000_000;
+
+	let db = db();
+	let records = db
+		.get_filter(&Wildcard::new(&query.key))
+		.into_iter()
+		.filter(|r| r.timestamp_nanos() >= since);
+	aggregate(records.map(|r| RecordValues::from(&r)), id, now, &query)
+}
+
+impl DB {
+	/// Can user see a chunk's audit, only its Owner/Admins can
+	pub fn audit_allowed(&self, id: ChunkId, user: &str, query: &AuditQuery) -> Result<(), DbError> {
+		// public assertion
+		if user == "public" {
+			return Err(DbError::AuthError);
+		}
+		let chunk = self.get_chunk(id, user).ok_or(DbError::NotFound)?;
+		if !chunk.read().unwrap().has_access(&(user, Access::Admin).into()) {
+			return Err(DbError::AuthError);
+		}
+		// Other slepaus log under ids too, those aren't the chunk's to show
+		if !query.key.starts_with("chunk_") {
+			return Err(DbError::AuthError);
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use common::vreji::RecordValues;
+
+	use common::utils::DbError;
+
+	use super::{aggregate, AuditQuery};
+	use crate::db::{chunk::ChunkId, dbchunk::DBChunk, DB};
+
+	#[test]
+	fn only_chunk_records() {
+		let mut db = DB::default();
+		let chunk: DBChunk = "# Notes\n".into();
+		let id = chunk.chunk().id;
+		db.set_chunk(chunk, "john").unwrap();
+		// Refused before anything gets read
+		for key in ["%", "auth_%", "media_%"] {
+			let query = AuditQuery {
+				key: key.into(),
+				..Default::default()
+			};
+			assert_eq!(db.audit_allowed(id, "john", &query), Err(DbError::AuthError));
+		}
+		assert_eq!(db.audit_allowed(id, "john", &AuditQuery::default()), Ok(()));
+		assert!(db.audit_allowed(id, "nina", &AuditQuery::default()).is_err());
+	}
+
+	#[test]
+	fn bounded_query() {
+		let query = AuditQuery {
+			period: 0,
+			limit: 1_000_000_000_000,
+			..Default::default()
+		};
+		let audit = aggregate(vec![].into_iter(), ChunkId::from(7), 86400, &query);
+		assert_eq!((audit.period, audit.limit), (1, super::LIMIT_MAX));
+	}
+
+	#[test]
+	fn aggregate_by_user() {
+		let id = ChunkId::from(7);
+		let other = ChunkId::from(8);
+		let now = 10 * 86400;
+		let record = |key: &str, days_ago: u64, user: &str, id: ChunkId| RecordValues {
+			key: key.into(),
+			time: (now - days_ago * 86400) * 1_000_000_000,
+			ip: "127.0.0.1".into(),
+			user: Some(user.into()),
+			id: Some(id.to_quint()),
+		};
+		let records = vec![
+			record("chunk_get_id", 0, "nina", id),
+			record("chunk_get_id", 2, "nina", id),
+			record("chunk_edit", 1, "john", id),
+			record("chunk_share", 3, "john", id),
+			record("chunk_access", 3, "john", id),
+			record("chunk_get_id", 0, "nina", other),
+			record("chunk_get_id", 40, "nina", id),
+		];
+
+		let audit = aggregate(records.into_iter(), id, now, &AuditQuery::default());
+		assert_eq!(audit.rows.len(), 4);
+		assert_eq!(audit.rows[0].user, "nina");
+		assert_eq!(audit.rows[0].count, 2);
+		assert_eq!(audit.rows[0].periods[..3], [1, 0, 1]);
+		assert_eq!(audit.access.len(), 1);
+		assert_eq!(audit.access[0].action, "chunk_access");
+	}
+}
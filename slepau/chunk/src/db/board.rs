@@ -0,0 +1,209 @@
+use common::utils::LockedAtomic;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::cmp::Ordering;
+
+use super::{
+	chunk::ChunkId,
+	dbchunk::DBChunk,
+	schema::PropType,
+	user_access::UserAccess,
+	view::{ChunkView, ViewType},
+	DB,
+};
+
+/// `views/board/<root>?by=status`
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct BoardQuery {
+	/// Prop cards are grouped by
+	pub by: String,
+}
+impl Default for BoardQuery {
+	fn default() -> Self {
+		Self { by: "status".into() }
+	}
+}
+
+/// `views/table/<root>?columns=estimate,status&sort=estimate&desc=true`
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct TableQuery {
+	/// Comma separated props
+	pub columns: String,
+	pub sort: Option<String>,
+	pub desc: bool,
+}
+
+/// A card moved to another column, sent on `chunks/<id>/prop`
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct PropIn {
+	pub key: String,
+	/// Empty removes the prop
+	pub value: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct BoardColumn {
+	/// None holds the cards without the prop
+	pub value: Option<String>,
+	pub cards: Vec<ChunkView>,
+}
+#[derive(Serialize, Debug)]
+pub struct Board {
+	pub by: String,
+	pub columns: Vec<BoardColumn>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Table {
+	pub columns: Vec<String>,
+	pub rows: Vec<ChunkView>,
+}
+
+/// Numbers by value, then strings, missing values last
+fn compare(a: &Value, b: &Value) -> Ordering {
+	match (a, b) {
+		(Value::Number(a), Value::Number(b)) => a
+			.as_f64()
+			.partial_cmp(&b.as_f64())
+			.unwrap_or(Ordering::Equal),
+		(Value::Null, Value::Null) => Ordering::Equal,
+		(Value::Null, _) => Ordering::Greater,
+		(_, Value::Null) => Ordering::Less,
+		(Value::Number(_), _) => Ordering::Less,
+		(_, Value::Number(_)) => Ordering::Greater,
+		(a, b) => a.to_string().cmp(&b.to_string()),
+	}
+}
+
+/// Prop as a column/group name, strings without their quotes
+fn prop_string(chunk: &DBChunk, key: &str) -> Option<String> {
+	match chunk.get_prop::<Value>(key)? {
+		Value::String(v) if v.trim().is_empty() => None,
+		Value::String(v) => Some(v.trim().to_owned()),
+		v => Some(v.to_string()),
+	}
+}
+
+impl DB {
+	/// Children of root the user can see, or their top level chunks
+	fn board_cards(&self, root: Option<ChunkId>, user: &str) -> Vec<LockedAtomic<DBChunk>> {
+		// public assertion
+		if user == "public" {
+			return vec![];
+		}
+		let ua = UserAccess::from(user);
+		match root {
+			Some(id) => self
+				.get_chunk(id, user)
+				.map(|c| c.read().unwrap().children(Some(&ua)))
+				.unwrap_or_default(),
+			None => self.roots(&ua),
+		}
+	}
+
+	/// Children of root grouped by a prop.
+	///
+	/// Columns follow the enum of the schema over root's children if there's one,
+	/// otherwise they're sorted, cards without the prop go last.
+	pub fn board(&self, root: Option<ChunkId>, user: &str, query: BoardQuery) -> Board {
+		let mut cards = self.board_cards(root, user);
+		cards.sort_by_key(|c| std::cmp::Reverse(c.read().unwrap().chunk().modified));
+
+		// Cards can have other parents with their own schemas, the board's is root's
+		let schema = root
+			.and_then(|id| self.get_chunk(id, user))
+			.and_then(|r| self.schema_above(vec![r]));
+		let mut values = schema
+			.and_then(|s| {
+				s.fields.iter().find(|f| f.name == query.by).and_then(|f| match &f._type {
+					PropType::Enum(options) => Some(options.clone()),
+					_ => None,
+				})
+			})
+			.unwrap_or_default();
+		let mut others = cards
+			.iter()
+			.filter_map(|c| prop_string(&c.read().unwrap(), &query.by))
+			.filter(|v| !values.contains(v))
+			.collect::<Vec<_>>();
+		others.sort();
+		others.dedup();
+		values.extend(others);
+
+		let mut columns = values
+			.into_iter()
+			.map(|value| BoardColumn {
+				value: Some(value),
+				cards: vec![],
+			})
+			.collect::<Vec<_>>();
+		let mut none = BoardColumn {
+			value: None,
+			cards: vec![],
+		};
+		for card in cards {
+			let value = prop_string(&card.read().unwrap(), &query.by);
+			let view = ChunkView::from((&card, user, ViewType::Board));
+			match columns.iter_mut().find(|c| c.value == value) {
+				Some(column) => column.cards.push(view),
+				None => none.cards.push(view),
+			}
+		}
+		if !none.cards.is_empty() {
+			columns.push(none);
+		}
+
+		Board { by: query.by, columns }
+	}
+
+	/// Children of root with the chosen props as columns, sorted by one of them
+	pub fn table(&self, root: Option<ChunkId>, user: &str, query: TableQuery) -> Table {
+		let columns = query
+			.columns
+			.split(',')
+			.map(str::trim)
+			.filter(|c| !c.is_empty())
+			.map(String::from)
+			.collect::<Vec<_>>();
+
+		let mut rows = self
+			.board_cards(root, user)
+			.into_iter()
+			.map(|c| {
+				let key = {
+					let chunk = c.read().unwrap();
+					(
+						columns
+							.iter()
+							.map(|key| chunk.get_prop::<Value>(key).unwrap_or(Value::Null))
+							.collect::<Vec<_>>(),
+						chunk.get_prop::<String>("title").map(|t| t.trim().to_lowercase()),
+					)
+				};
+				(key, c)
+			})
+			.collect::<Vec<_>>();
+
+		match query.sort.and_then(|s| columns.iter().position(|c| *c == s)) {
+			Some(i) => rows.sort_by(|((a, _), _), ((b, _), _)| {
+				let order = compare(&a[i], &b[i]);
+				// Missing values stay last either way
+				if query.desc && !a[i].is_null() && !b[i].is_null() {
+					order.reverse()
+				} else {
+					order
+				}
+			}),
+			None => rows.sort_by(|((_, a), _), ((_, b), _)| a.cmp(b)),
+		}
+		let rows = rows
+			.iter()
+			.map(|(_, c)| ChunkView::from((c, user, ViewType::Table)))
+			.collect();
+
+		Table { columns, rows }
+	}
+}
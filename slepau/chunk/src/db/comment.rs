@@ -0,0 +1,268 @@
+use common::{
+	proquint::Proquint,
+	utils::{get_secs, DbError},
+};
+use diff::Result::{Both, Left, Right};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+use super::{chunk::ChunkId, crypt::ENCRYPTED_PROP, user_access::Access, DB};
+
+pub type ThreadId = Proquint<u32>;
+
+/// A text range in char offsets, `quote` is what the range held last time it was anchored.
+///
+/// Quotes are plaintext, so detached threads and encrypted chunks have none.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct Anchor {
+	pub start: usize,
+	pub end: usize,
+	#[serde(default)]
+	pub quote: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Comment {
+	pub user: String,
+	pub text: String,
+	pub created: u64,
+}
+
+/**
+ * A discussion anchored to a piece of a chunk's text.
+ * Stored beside chunks, never inside their value.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Thread {
+	pub id: ThreadId,
+	pub chunk: ChunkId,
+	pub anchor: Anchor,
+	/// The anchored text is gone, the thread stays but doesn't point anywhere
+	#[serde(default)]
+	pub detached: bool,
+	pub comments: Vec<Comment>,
+	#[serde(default)]
+	pub resolved: bool,
+	pub created: u64,
+}
+impl Thread {
+	/// Everyone that has commented
+	pub fn participants(&self) -> HashSet<String> {
+		self.comments.iter().map(|c| c.user.clone()).collect()
+	}
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct ThreadIn {
+	pub start: usize,
+	pub end: usize,
+	pub text: String,
+}
+
+fn chars_slice(value: &str, start: usize, end: usize) -> String {
+	value.chars().skip(start).take(end.saturating_sub(start)).collect()
+}
+
+/// Maps char offsets in `old` to char offsets in `new`, line by line, and if their line was kept.
+///
+/// Offsets inside removed lines collapse to where those lines used to be.
+fn offset_map(old: &str, new: &str) -> impl Fn(usize) -> (usize, bool) {
+	// (old start, new start, length, kept)
+	let mut segments = vec![];
+	let (mut o, mut n) = (0, 0);
+	for d in diff::lines(old, new) {
+		match d {
+			Both(l, _) => {
+				let len = l.chars().count() + 1;
+				segments.push((o, n, len, true));
+				o += len;
+				n += len;
+			}
+			Left(l) => {
+				let len = l.chars().count() + 1;
+				segments.push((o, n, len, false));
+				o += len;
+			}
+			Right(r) => {
+				n += r.chars().count() + 1;
+			}
+		}
+	}
+	let new_len = new.chars().count();
+	move |p| {
+		let (p, kept) = segments
+			.iter()
+			.find(|(os, _, len, _)| p < os + len)
+			.map(|(os, ns, _, kept)| if *kept { (ns + (p - os), true) } else { (*ns, false) })
+			.unwrap_or((n, true));
+		(p.min(new_len), kept)
+	}
+}
+
+impl Anchor {
+	/// Moves the anchor along with an edit from `old` to `new`
+	///
+	/// Returns false if the anchored text couldn't be found anymore,
+	/// or for a zero-width anchor, if its line is gone.
+	pub fn reanchor(&mut self, old: &str, new: &str) -> bool {
+		let map = offset_map(old, new);
+		let ((start, kept), (end, _)) = (map(self.start), map(self.end));
+		if start < end || (self.start == self.end && kept) {
+			self.start = start;
+			self.end = end;
+			self.quote = chars_slice(new, start, end);
+			return true;
+		}
+		// Whole lines holding the anchor changed, look for the text itself
+		if !self.quote.is_empty() {
+			if let Some(byte) = new.find(&self.quote) {
+				self.start = new[..byte].chars().count();
+				self.end = self.start + self.quote.chars().count();
+				return true;
+			}
+		}
+		self.start = start;
+		self.end = start;
+		self.quote.clear();
+		false
+	}
+}
+
+impl DB {
+	/// Can user see (and so comment on) this chunk?
+	fn comment_access(&self, id: ChunkId, user: &str) -> Result<(), DbError> {
+		// public assertion
+		if user == "public" {
+			return Err(DbError::AuthError);
+		}
+		let chunk = self.chunks.get(&id).ok_or(DbError::NotFound)?;
+		if chunk.read().unwrap().has_access(&user.into()) {
+			Ok(())
+		} else {
+			Err(DbError::NotFound)
+		}
+	}
+
+	/// Threads on a chunk, in order of creation
+	pub fn get_threads(&self, id: ChunkId, user: &str) -> Result<Vec<Thread>, DbError> {
+		self.comment_access(id, user)?;
+		let mut threads = self
+			.threads
+			.values()
+			.filter(|t| t.chunk == id)
+			.cloned()
+			.collect::<Vec<_>>();
+		threads.sort_by_key(|t| t.created);
+		Ok(threads)
+	}
+
+	pub fn thread_new(&mut self, id: ChunkId, user: &str, thread: ThreadIn) -> Result<Thread, DbError> {
+		self.comment_access(id, user)?;
+		if thread.text.trim().is_empty() {
+			return Err(DbError::Custom("Comment can't be empty.".into()));
+		}
+		let value = self.chunks[&id].read().unwrap().chunk().value.clone();
+		let encrypted = self.is_encrypted(id);
+		if thread.start > thread.end || thread.end > value.chars().count() {
+			return Err(DbError::Custom("Anchor is out of the chunk's bounds.".into()));
+		}
+
+		let mut thread_id = ThreadId::default();
+		while self.threads.contains_key(&thread_id) {
+			thread_id = ThreadId::default();
+		}
+		let created = get_secs();
+		let thread = Thread {
+			id: thread_id,
+			chunk: id,
+			anchor: Anchor {
+				start: thread.start,
+				end: thread.end,
+				quote: match encrypted {
+					true => String::new(),
+					false => chars_slice(&value, thread.start, thread.end),
+				},
+			},
+			detached: false,
+			comments: vec![Comment {
+				user: user.to_owned(),
+				text: thread.text,
+				created,
+			}],
+			resolved: false,
+			created,
+		};
+		self.threads.insert(thread_id, thread.clone());
+		Ok(thread)
+	}
+
+	fn thread_mut(&mut self, id: ChunkId, thread: ThreadId, user: &str) -> Result<&mut Thread, DbError> {
+		self.comment_access(id, user)?;
+		self
+			.threads
+			.get_mut(&thread)
+			.filter(|t| t.chunk == id)
+			.ok_or(DbError::NotFound)
+	}
+
+	pub fn thread_reply(
+		&mut self,
+		id: ChunkId,
+		thread: ThreadId,
+		user: &str,
+		text: String,
+	) -> Result<Thread, DbError> {
+		if text.trim().is_empty() {
+			return Err(DbError::Custom("Comment can't be empty.".into()));
+		}
+		let thread = self.thread_mut(id, thread, user)?;
+		thread.comments.push(Comment {
+			user: user.to_owned(),
+			text,
+			created: get_secs(),
+		});
+		Ok(thread.clone())
+	}
+
+	/// Resolves or reopens a thread, anyone with access can
+	pub fn thread_resolve(
+		&mut self,
+		id: ChunkId,
+		thread: ThreadId,
+		user: &str,
+		resolved: bool,
+	) -> Result<Thread, DbError> {
+		let thread = self.thread_mut(id, thread, user)?;
+		thread.resolved = resolved;
+		Ok(thread.clone())
+	}
+
+	/// Thread starter or chunk Admins can delete a thread
+	pub fn thread_del(&mut self, id: ChunkId, thread: ThreadId, user: &str) -> Result<Thread, DbError> {
+		let is_admin = self
+			.chunks
+			.get(&id)
+			.map(|c| c.read().unwrap().has_access(&(user, Access::Admin).into()))
+			.unwrap_or_default();
+		let starter = self.thread_mut(id, thread, user)?.comments.first().map(|c| c.user.clone());
+		if !is_admin && starter.as_deref() != Some(user) {
+			return Err(DbError::AuthError);
+		}
+		Ok(self.threads.remove(&thread).unwrap())
+	}
+
+	/// Moves every thread on chunk `id` along with an edit
+	pub(super) fn reanchor_threads(&mut self, id: ChunkId, old: &str, new: &str) {
+		let encrypted = self.chunks.get(&id).map_or(false, |c| {
+			c.read().unwrap().get_prop::<String>(ENCRYPTED_PROP).is_some()
+		});
+		for thread in self.threads.values_mut().filter(|t| t.chunk == id && !t.detached) {
+			thread.detached = !thread.anchor.reanchor(old, new);
+			// Would keep a copy of what got encrypted
+			if encrypted {
+				thread.anchor.quote.clear();
+			}
+		}
+	}
+}
@@ -0,0 +1,400 @@
+use base64::{engine::general_purpose::STANDARD as B64, Engine};
+use common::utils::{DbError, LockedAtomic, REGEX_ACCESS};
+use orion::{aead, hazardous::ecc::x25519, kdf};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use super::{chunk::ChunkId, dbchunk::DBChunk, media::media_refs, user_access::Access, DB};
+
+/// Prop holding the encrypted body of a chunk
+pub const ENCRYPTED_PROP: &str = "encrypted";
+
+/// Argon2i cost to derive a key from a user's secret
+#[derive(Clone, Copy, Debug)]
+pub struct Kdf {
+	pub iterations: u32,
+	/// In KiB
+	pub memory: u32,
+}
+impl Default for Kdf {
+	fn default() -> Self {
+		Self {
+			iterations: 3,
+			memory: 1 << 16,
+		}
+	}
+}
+
+/**
+ * A user's X25519 keypair, the private key is sealed with their secret.
+ * The server never keeps the secret, only uses it for the request it came with.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UserKey {
+	pub user: String,
+	/// Base64
+	pub public: String,
+	private: String,
+	salt: String,
+}
+impl UserKey {
+	/// A new keypair sealed with `secret`, slow on purpose
+	pub fn generate(user: &str, secret: &str, kdf: Kdf) -> Result<Self, DbError> {
+		if secret.chars().count() < 8 {
+			return Err(DbError::Custom("Secret has to be at least 8 characters long.".into()));
+		}
+		let salt = kdf::Salt::default();
+		let private = x25519::PrivateKey::generate();
+		let public = x25519::PublicKey::try_from(&private).map_err(corrupted)?;
+		let sealed = aead::seal(&derive(secret, salt.as_ref(), kdf)?, private.unprotected_as_bytes())
+			.map_err(corrupted)?;
+
+		Ok(Self {
+			user: user.to_owned(),
+			public: B64.encode(public.to_bytes()),
+			private: B64.encode(sealed),
+			salt: B64.encode(salt.as_ref()),
+		})
+	}
+
+	/// Opens the private key, a wrong secret is an AuthError. Slow on purpose.
+	pub fn unlock(&self, secret: &str, kdf: Kdf) -> Result<Unlocked, DbError> {
+		let private = aead::open(&derive(secret, &decode(&self.salt)?, kdf)?, &decode(&self.private)?)
+			.map_err(|_| DbError::AuthError)?;
+		Ok(Unlocked {
+			user: self.user.clone(),
+			private: x25519::PrivateKey::from_slice(&private).map_err(corrupted)?,
+		})
+	}
+}
+
+/// A user's private key, opened with their secret for the request it came with
+pub struct Unlocked {
+	user: String,
+	private: x25519::PrivateKey,
+}
+
+fn blocking<E: ToString>(err: E) -> DbError {
+	DbError::Custom(err.to_string())
+}
+
+/// Creates user's keypair, returns the public key.
+///
+/// Derived off the async workers, the DB is only locked to check and store it.
+pub async fn key_new(db: &LockedAtomic<DB>, user: &str, secret: String) -> Result<String, DbError> {
+	let kdf = {
+		let db = db.read().unwrap();
+		db.key_check(user)?;
+		db.kdf
+	};
+	let user_ = user.to_owned();
+	let key = tokio::task::spawn_blocking(move || UserKey::generate(&user_, &secret, kdf))
+		.await
+		.map_err(blocking)??;
+	db.write().unwrap().key_add(key)
+}
+
+/// Opens user's private key, derived off the async workers without holding the DB lock
+pub async fn unlock(db: &LockedAtomic<DB>, user: &str, secret: String) -> Result<Unlocked, DbError> {
+	let (key, kdf) = {
+		let db = db.read().unwrap();
+		(db.keys.get(user).cloned().ok_or(DbError::NotFound)?, db.kdf)
+	};
+	tokio::task::spawn_blocking(move || key.unlock(&secret, kdf))
+		.await
+		.map_err(blocking)?
+}
+
+/// Content key of an encrypted chunk, wrapped for every user with access
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ChunkKeys {
+	pub id: ChunkId,
+	pub keys: HashMap<String, String>,
+	/// Media referenced by the plaintext, so encrypted chunks still count as using it
+	#[serde(default)]
+	pub media: BTreeSet<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Secret {
+	pub secret: String,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct EncryptedIn {
+	pub value: String,
+	pub secret: String,
+}
+
+fn corrupted<E>(_: E) -> DbError {
+	DbError::Custom("Key material is corrupted.".into())
+}
+
+fn decode(v: &str) -> Result<Vec<u8>, DbError> {
+	B64.decode(v.trim()).map_err(corrupted)
+}
+
+fn derive(secret: &str, salt: &[u8], cost: Kdf) -> Result<aead::SecretKey, DbError> {
+	let password = kdf::Password::from_slice(secret.as_bytes()).map_err(|_| DbError::AuthError)?;
+	let salt = kdf::Salt::from_slice(salt).map_err(corrupted)?;
+	let key = kdf::derive_key(&password, &salt, cost.iterations, cost.memory, 32).map_err(corrupted)?;
+	aead::SecretKey::from_slice(key.unprotected_as_bytes()).map_err(corrupted)
+}
+
+/// Key that wraps a content key, from an X25519 agreement between both public keys
+fn wrapping_key(
+	shared: &x25519::SharedKey,
+	ephemeral: &x25519::PublicKey,
+	recipient: &x25519::PublicKey,
+) -> Result<aead::SecretKey, DbError> {
+	let mut input = shared.unprotected_as_bytes().to_vec();
+	input.extend(ephemeral.to_bytes());
+	input.extend(recipient.to_bytes());
+	let digest = orion::hash::digest(&input).map_err(corrupted)?;
+	aead::SecretKey::from_slice(digest.as_ref()).map_err(corrupted)
+}
+
+/// Wraps `key` for `public`, `<ephemeral public key><sealed key>` in base64
+fn wrap(key: &aead::SecretKey, public: &str) -> Result<String, DbError> {
+	let recipient = x25519::PublicKey::from_slice(&decode(public)?).map_err(corrupted)?;
+	let ephemeral = x25519::PrivateKey::generate();
+	let ephemeral_public = x25519::PublicKey::try_from(&ephemeral).map_err(corrupted)?;
+	let shared = x25519::key_agreement(&ephemeral, &recipient).map_err(corrupted)?;
+
+	let mut wrapped = ephemeral_public.to_bytes().to_vec();
+	wrapped.extend(
+		aead::seal(&wrapping_key(&shared, &ephemeral_public, &recipient)?, key.unprotected_as_bytes())
+			.map_err(corrupted)?,
+	);
+	Ok(B64.encode(wrapped))
+}
+
+fn unwrap(wrapped: &str, private: &x25519::PrivateKey) -> Result<aead::SecretKey, DbError> {
+	let wrapped = decode(wrapped)?;
+	if wrapped.len() < x25519::PUBLIC_KEY_SIZE {
+		return Err(corrupted(()));
+	}
+	let (ephemeral, sealed) = wrapped.split_at(x25519::PUBLIC_KEY_SIZE);
+	let ephemeral = x25519::PublicKey::from_slice(ephemeral).map_err(corrupted)?;
+	let recipient = x25519::PublicKey::try_from(private).map_err(corrupted)?;
+	let shared = x25519::key_agreement(private, &ephemeral).map_err(corrupted)?;
+
+	let key = aead::open(&wrapping_key(&shared, &ephemeral, &recipient)?, sealed).map_err(corrupted)?;
+	aead::SecretKey::from_slice(&key).map_err(corrupted)
+}
+
+/// Splits a value into what stays queryable (title and share lines) and the body to encrypt
+fn split(value: &str) -> (String, String) {
+	let (mut header, mut body) = (vec![], vec![]);
+	for (i, line) in value.split('\n').enumerate() {
+		if (i == 0 && line.starts_with('#')) || REGEX_ACCESS.is_match(line) {
+			header.push(line);
+		} else {
+			body.push(line);
+		}
+	}
+	(header.join("\n"), body.join("\n"))
+}
+
+impl DB {
+	pub fn key_public(&self, user: &str) -> Option<String> {
+		self.keys.get(user).map(|k| k.public.clone())
+	}
+
+	/// Can user get a keypair
+	fn key_check(&self, user: &str) -> Result<(), DbError> {
+		// public assertion
+		if user == "public" {
+			return Err(DbError::AuthError);
+		}
+		if self.keys.contains_key(user) {
+			return Err(DbError::Custom("User already has a key.".into()));
+		}
+		Ok(())
+	}
+
+	/// Stores a keypair from [UserKey::generate], returns the public key
+	pub fn key_add(&mut self, key: UserKey) -> Result<String, DbError> {
+		// Checked again, another request could have added one meanwhile
+		self.key_check(&key.user)?;
+		let public = key.public.clone();
+		self.keys.insert(key.user.clone(), key);
+		Ok(public)
+	}
+
+	/// Encrypts the body of `value` with a fresh content key wrapped for every user
+	fn seal(&self, value: &str, users: &HashSet<String>) -> Result<(String, HashMap<String, String>), DbError> {
+		if users.contains("public") {
+			return Err(DbError::Custom("Public chunks can't be encrypted.".into()));
+		}
+		let key = aead::SecretKey::default();
+		let keys = users
+			.iter()
+			.map(|u| {
+				let public = self
+					.key_public(u)
+					.ok_or_else(|| DbError::Custom(format!("{u} doesn't have an encryption key.")))?;
+				Ok((u.clone(), wrap(&key, &public)?))
+			})
+			.collect::<Result<HashMap<_, _>, DbError>>()?;
+
+		let (header, body) = split(value);
+		let body = aead::seal(&key, body.as_bytes()).map_err(corrupted)?;
+		Ok((format!("{header}\n{ENCRYPTED_PROP}: {}", B64.encode(body)), keys))
+	}
+
+	pub fn is_encrypted(&self, id: ChunkId) -> bool {
+		self.encrypted.contains_key(&id)
+	}
+
+	/// Encrypts a chunk for everyone with access, only Admins can.
+	///
+	/// Doesn't need a secret, just everyone's public key.
+	pub fn encrypt(&mut self, id: ChunkId, user: &str) -> Result<(), DbError> {
+		if self.is_encrypted(id) {
+			return Err(DbError::Custom("Chunk is already encrypted.".into()));
+		}
+		let chunk = self.get_chunk(id, user).ok_or(DbError::NotFound)?;
+		let (value, owner, users) = {
+			let chunk = chunk.read().unwrap();
+			if !chunk.has_access(&(user, Access::Admin).into()) {
+				return Err(DbError::AuthError);
+			}
+			(chunk.chunk().value.clone(), chunk.chunk().owner.clone(), chunk.access_users())
+		};
+
+		let media = media_refs(&value);
+		let (value, keys) = self.seal(&value, &users)?;
+		self.set_chunk(DBChunk::from((id, value.as_str(), owner.as_str())), user)?;
+		self.encrypted.insert(id, ChunkKeys { id, keys, media });
+		self.index_media(id);
+		Ok(())
+	}
+
+	/// Decrypted value of an encrypted chunk, share lines end up under the title
+	pub fn decrypt(&self, id: ChunkId, unlocked: &Unlocked) -> Result<String, DbError> {
+		let user = unlocked.user.as_str();
+		let chunk = self.get_chunk(id, user).ok_or(DbError::NotFound)?;
+		let wrapped = self
+			.encrypted
+			.get(&id)
+			.and_then(|k| k.keys.get(user))
+			.ok_or(DbError::NotFound)?;
+		let key = unwrap(wrapped, &unlocked.private)?;
+
+		let chunk = chunk.read().unwrap();
+		let (header, _) = split(&chunk.chunk().value);
+		let body = chunk.get_prop::<String>(ENCRYPTED_PROP).ok_or(DbError::NotFound)?;
+		let body = aead::open(&key, &decode(&body)?).map_err(corrupted)?;
+		Ok(format!("{header}\n{}", String::from_utf8(body).map_err(corrupted)?))
+	}
+
+	/// Replaces an encrypted chunk's value with `value`, re-encrypting it for everyone with access.
+	///
+	/// Returns the list of users for which access changed
+	pub fn set_encrypted(&mut self, id: ChunkId, unlocked: &Unlocked, value: &str) -> Result<HashSet<String>, DbError> {
+		let user = unlocked.user.as_str();
+		// Proves user holds a key to it
+		self.decrypt(id, unlocked)?;
+		let owner = self.chunks[&id].read().unwrap().chunk().owner.clone();
+		let users = DBChunk::from((id, value, owner.as_str())).access_users();
+		let media = media_refs(value);
+		// A fresh key, so users that lost access can't read what comes next
+		let (value, keys) = self.seal(value, &users)?;
+
+		let keys_old = self.encrypted.remove(&id);
+		match self.set_chunk(DBChunk::from((id, value.as_str(), owner.as_str())), user) {
+			Ok(changed) => {
+				self.encrypted.insert(id, ChunkKeys { id, keys, media });
+				self.index_media(id);
+				Ok(changed)
+			}
+			Err(err) => {
+				if let Some(keys_old) = keys_old {
+					self.encrypted.insert(id, keys_old);
+				}
+				Err(err)
+			}
+		}
+	}
+
+	/// Stores an encrypted chunk in plaintext again, only Admins can
+	pub fn unencrypt(&mut self, id: ChunkId, unlocked: &Unlocked) -> Result<(), DbError> {
+		let user = unlocked.user.as_str();
+		let value = self.decrypt(id, unlocked)?;
+		let owner = {
+			let chunk = self.chunks[&id].read().unwrap();
+			if !chunk.has_access(&(user, Access::Admin).into()) {
+				return Err(DbError::AuthError);
+			}
+			chunk.chunk().owner.clone()
+		};
+
+		let keys_old = self.encrypted.remove(&id);
+		if let Err(err) = self.set_chunk(DBChunk::from((id, value.as_str(), owner.as_str())), user) {
+			if let Some(keys_old) = keys_old {
+				self.encrypted.insert(id, keys_old);
+			}
+			return Err(err);
+		}
+		Ok(())
+	}
+
+	/// Plain updates to an encrypted chunk can't touch the ciphertext or share it with anyone new
+	pub(super) fn check_encrypted(&self, old: &DBChunk, new: &DBChunk) -> Result<(), DbError> {
+		let Some(keys) = self.encrypted.get(&old.chunk().id) else {
+			return Ok(());
+		};
+		if old.get_prop::<String>(ENCRYPTED_PROP) != new.get_prop::<String>(ENCRYPTED_PROP)
+			|| new.access_users().iter().any(|u| !keys.keys.contains_key(u))
+		{
+			return Err(DbError::Custom("Chunk is encrypted, it has to be edited with a secret.".into()));
+		}
+		Ok(())
+	}
+
+	/// Forgets the wrapped keys of users that lost access
+	pub(super) fn prune_encrypted(&mut self, id: ChunkId, users: &HashSet<String>) {
+		if let Some(keys) = self.encrypted.get_mut(&id) {
+			keys.keys.retain(|u, _| users.contains(u));
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use common::utils::DbError;
+	use std::sync::{Arc, RwLock};
+
+	use super::{key_new, split, unlock, Kdf, DB};
+
+	#[tokio::test]
+	async fn keys_off_the_lock() {
+		let db = Arc::new(RwLock::new(DB {
+			kdf: Kdf {
+				memory: 1 << 8,
+				..Default::default()
+			},
+			..Default::default()
+		}));
+		assert!(key_new(&db, "public", "correct horse".into()).await.is_err());
+		assert!(key_new(&db, "john", "short".into()).await.is_err());
+		let public = key_new(&db, "john", "correct horse".into()).await.unwrap();
+		assert_eq!(db.read().unwrap().key_public("john"), Some(public));
+		assert!(key_new(&db, "john", "correct horse".into()).await.is_err());
+
+		assert!(unlock(&db, "john", "correct horse".into()).await.is_ok());
+		assert!(matches!(unlock(&db, "john", "wrong secret".into()).await, Err(DbError::AuthError)));
+		assert!(matches!(unlock(&db, "nina", "correct horse".into()).await, Err(DbError::NotFound)));
+	}
+
+	#[test]
+	fn split_header() {
+		assert_eq!(
+			split("# Passwords\nwifi: hunter2\nshare: john r\nbank: 1234"),
+			("# Passwords\nshare: john r".into(), "wifi: hunter2\nbank: 1234".into())
+		);
+	}
+}
@@ -10,7 +10,8 @@ use std::{
 	sync::{Arc, RwLock, Weak},
 };
 
-use super::chunk::Chunk;
+use super::chunk::{Chunk, ChunkId};
+use super::schema::{Schema, SchemaIssue, RESERVED, WARNINGS_PROP};
 use super::user_access::{Access, UserAccess};
 
 struct DynamicProperty {
@@ -42,6 +43,9 @@ pub struct DBChunk {
 	/// Dynamic prop values defined by  (User + Key) -> Value
 	props_per_user: HashMap<(String, String), Value>,
 
+	/// Schema that types this chunk's props, if any applies
+	schema: Option<Arc<Schema>>,
+
 	/// parents, whoever modifies these refs, has to make sure there are no circular references
 	pub parents: Vec<Weak<RwLock<DBChunk>>>,
 
@@ -102,8 +106,39 @@ impl DBChunk {
 		self.chunk.owner = owner;
 	}
 
-	/// Fills props with extracted static values
-	fn extract(&mut self) {
+	/// A copy of this chunk owned by `owner`, sharing stays the same
+	/// except for the new owner's own entries which aren't needed anymore.
+	///
+	/// If `keep_admin` the previous owner stays on as an Admin.
+	pub fn transfer_to(&self, owner: &str, keep_admin: bool) -> Self {
+		let mut access = self.get_prop::<HashSet<UserAccess>>("access").unwrap_or_default();
+		access.retain(|ua| ua.user != owner);
+		if keep_admin {
+			access.extend(UserAccess::from((self.chunk.owner.as_str(), Access::Admin)).implied());
+		}
+
+		let mut other = Self::from(Chunk {
+			owner: owner.to_owned(),
+			created: self.chunk.created,
+			..Chunk::from((self.chunk.id, self.chunk.value.as_str()))
+		});
+		other.r#override("access", json!(access));
+		other.set_schema(self.schema.clone());
+		other.children = self.children.clone();
+		other
+	}
+
+	pub fn schema(&self) -> Option<&Schema> {
+		self.schema.as_deref()
+	}
+	/// Re-extracts props typed by `schema`, returns what doesn't match it
+	pub fn set_schema(&mut self, schema: Option<Arc<Schema>>) -> Vec<SchemaIssue> {
+		self.schema = schema;
+		self.extract()
+	}
+
+	/// Fills props with extracted static values, typed if there's a schema
+	fn extract(&mut self) -> Vec<SchemaIssue> {
 		// Clear previous
 		self.props.clear();
 
@@ -146,6 +181,17 @@ impl DBChunk {
 		if !access.is_empty() {
 			self.props.insert("access".to_string(), json!(access));
 		}
+
+		// Type props, issues of a lenient schema are kept as warnings
+		let Some(schema) = self.schema.clone() else {
+			return vec![];
+		};
+		let issues = schema.apply(&mut self.props);
+		if !schema.strict && !issues.is_empty() {
+			let warnings = issues.iter().map(|i| i.to_string()).collect::<Vec<_>>();
+			self.props.insert(WARNINGS_PROP.into(), json!(warnings));
+		}
+		issues
 	}
 	/// Attempts to override value prop. Will always fail if it's already linked.
 	pub fn r#override(&mut self, key: &str, value: Value) -> bool {
@@ -168,6 +214,9 @@ impl DBChunk {
 					.collect::<Vec<_>>()
 					.join(", ");
 				if !uas.is_empty() {
+					if !v.is_empty() && !v.ends_with('\n') {
+						v.push('\n');
+					}
 					v.push_str(format!("share: {uas}",).as_str());
 				}
 
@@ -310,6 +359,13 @@ impl DBChunk {
 			false
 		}
 	}
+	/// Is this chunk marked with `template: true`?
+	pub fn is_template(&self) -> bool {
+		self
+			.get_prop::<String>("template")
+			.map(|v| v.trim() == "true")
+			.unwrap_or(false)
+	}
 	pub fn is_public(&self) -> bool {
 		self
 			.get_prop::<HashSet<UserAccess>>("access")
@@ -433,17 +489,78 @@ impl DBChunk {
 
 	/// Links child and removes any dangling pointers for a self healing vector
 	pub fn link_child(&mut self, child: &LockedAtomic<DBChunk>) {
-		self.children.push(Arc::downgrade(child));
+		let child = Arc::downgrade(child);
+		if !self.children.iter().any(|v| v.ptr_eq(&child)) {
+			self.children.push(child);
+		}
 		self.children.retain(|v| v.upgrade().is_some());
 	}
 
-	/// Links child and removes any dangling pointers for a self healing vector
+	/// Links parent and removes any dangling pointers for a self healing vector
 	pub fn link_parent(&mut self, parent: &LockedAtomic<DBChunk>) {
-		self.parents.push(Arc::downgrade(parent));
+		let parent = Arc::downgrade(parent);
+		if !self.parents.iter().any(|v| v.ptr_eq(&parent)) {
+			self.parents.push(parent);
+		}
 		self.parents.retain(|v| v.upgrade().is_some());
 	}
 }
 
+/// Rewrites the parents on the title line of `value`.
+///
+/// Returns None if there's no title to attach the parents to.
+pub fn set_parents(value: &str, parents: &[ChunkId]) -> Option<String> {
+	if let Some(captures) = REGEX_TITLE.captures(value) {
+		let all = captures.get(0).unwrap();
+		let mut title = format!("# {}", captures.get(1).unwrap().as_str().trim());
+		if !parents.is_empty() {
+			title.push_str(" -> ");
+			title.push_str(&parents.iter().map(|p| p.to_quint()).collect::<Vec<_>>().join(", "));
+		}
+		title.push('\n');
+		Some(format!("{}{}{}", &value[..all.start()], title, &value[all.end()..]))
+	} else if parents.is_empty() {
+		Some(value.to_string())
+	} else {
+		None
+	}
+}
+
+/// Rewrites the `key: value` line of `value`, appends it if there's none and removes it if `prop` is empty.
+///
+/// Returns None for keys the system sets itself.
+pub fn set_prop_line(value: &str, key: &str, prop: &str) -> Option<String> {
+	let valid = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_';
+	if key.is_empty() || !key.chars().all(valid) || RESERVED.contains(&key) || prop.contains('\n') {
+		return None;
+	}
+	let prop = prop.trim();
+	let line = format!("{key}: {prop}");
+
+	let mut found = false;
+	let mut lines = vec![];
+	for l in value.split('\n') {
+		match REGEX_PROPERTY.captures(l) {
+			// Only the first is kept, the last one would win otherwise
+			Some(c) if &c[1] == key => {
+				if !found && !prop.is_empty() {
+					lines.push(line.as_str());
+				}
+				found = true;
+			}
+			_ => lines.push(l),
+		}
+	}
+	if !found && !prop.is_empty() {
+		// Before the trailing newline if there's one
+		match lines.last() {
+			Some(&"") => lines.insert(lines.len() - 1, line.as_str()),
+			_ => lines.push(line.as_str()),
+		}
+	}
+	Some(lines.join("\n"))
+}
+
 pub fn extract_access(value: &str, access: &mut HashSet<UserAccess>) {
 	for capture in REGEX_ACCESS.captures_iter(value) {
 		if let Some(m) = capture.get(1) {
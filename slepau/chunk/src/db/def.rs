@@ -1,4 +1,4 @@
-use common::utils::{diff_calc, DbError, LockedAtomic, LockedWeak};
+use common::utils::{diff_calc, DbError, LockedAtomic, LockedWeak, REGEX_ACCESS};
 /**
  * A DB without a reference (normalized title) implementation and actual dynamic memory pointers instead of repetitive lookups.
  * Should be orders of magnitud simpler and faster.
@@ -13,8 +13,14 @@ use std::{
 
 use super::{
 	chunk::{Chunk, ChunkId},
+	comment::Thread,
+	crypt::{ChunkKeys, UserKey},
 	dbchunk::DBChunk,
+	schema::Schema,
+	transfer::Transfer,
+	webhook::Webhook,
 	user_access::{Access, UserAccess},
+	view::{ChunkPlacement, ChunkVec, SortType},
 	DBMap, GraphView, DB,
 };
 
@@ -25,6 +31,11 @@ use super::{
 #[serde(default)]
 pub struct DBData {
 	pub chunks: Vec<Chunk>,
+	pub transfers: Vec<Transfer>,
+	pub webhooks: Vec<Webhook>,
+	pub threads: Vec<Thread>,
+	pub keys: Vec<UserKey>,
+	pub encrypted: Vec<ChunkKeys>,
 }
 
 // impl From<DBData> for DB {
@@ -98,27 +109,7 @@ impl DB {
 				Value::Null,
 				if iter > 0 {
 					Some(
-						children_fn(
-							self
-								.chunks
-								.values()
-								.filter_map(|v| {
-									let mut g = false;
-									{
-										if let Ok(chunk) = v.read() {
-											if chunk.has_access(ua) && chunk.parents(Some(ua)).is_empty() {
-												g = true;
-											}
-										}
-									}
-									if g {
-										Some(v.to_owned())
-									} else {
-										None
-									}
-								})
-								.collect(),
-						)
+						children_fn(self.roots(ua))
 						.into_iter()
 						.map(|root| self.subtree(Some(&root), ua, children_fn, view_fn, iter - 1))
 						.collect(),
@@ -129,6 +120,28 @@ impl DB {
 			)
 		}
 	}
+	/// Top level chunks for a user, the ones without any parents they can see
+	pub fn roots(&self, ua: &UserAccess) -> Vec<LockedAtomic<DBChunk>> {
+		self
+			.chunks
+			.values()
+			.filter_map(|v| {
+				let mut g = false;
+				{
+					if let Ok(chunk) = v.read() {
+						if chunk.has_access(ua) && chunk.parents(Some(ua)).is_empty() {
+							g = true;
+						}
+					}
+				}
+				if g {
+					Some(v.to_owned())
+				} else {
+					None
+				}
+			})
+			.collect()
+	}
 	/// Get all chunks for a particular user
 	pub fn get_chunks(&self, user: &str) -> Vec<LockedAtomic<DBChunk>> {
 		// public assertion
@@ -149,6 +162,47 @@ impl DB {
 			})
 			.collect()
 	}
+	/// Chunks shared with `public`, most recently modified first
+	pub fn public_chunks(&self) -> Vec<LockedAtomic<DBChunk>> {
+		let mut chunks = self
+			.chunks
+			.values()
+			.filter(|v| v.read().map(|c| c.is_public()).unwrap_or(false))
+			.cloned()
+			.collect::<Vec<_>>();
+		chunks.sort_by_key(|c| std::cmp::Reverse(c.read().unwrap().chunk().modified));
+		chunks
+	}
+	/// Chunks matching `term` for a particular user, most recently modified first
+	///
+	/// `term` is a case insensitive regex, if it isn't a valid one it's matched as plain text.
+	pub fn search(&self, user: &str, term: &str) -> Result<ChunkVec, DbError> {
+		if term.is_empty() {
+			return Err(DbError::Custom("Search term has to be at least 1 character.".into()));
+		}
+
+		let regex = regex::Regex::new(format!("(?im){}", term).as_str());
+		let mut chunks = ChunkVec::from(
+			self
+				.get_chunks(user)
+				.into_iter()
+				.filter(|chunk| {
+					let chunk = chunk.read().unwrap();
+					if let Ok(regex) = regex.as_ref() {
+						regex.is_match(&chunk.chunk().value)
+					} else {
+						chunk.chunk().value.contains(term)
+					}
+				})
+				.collect::<Vec<_>>(),
+		);
+		chunks.sort(SortType::Modified);
+		Ok(chunks)
+	}
+	/// Where a chunk currently shows up, empty if it doesn't exist
+	pub fn placement(&self, id: ChunkId) -> ChunkPlacement {
+		ChunkPlacement::from(self.chunks.get(&id))
+	}
 	/// Only used for when _supers want access
 	pub fn get_chunk_(&self, id: ChunkId) -> Option<LockedAtomic<DBChunk>> {
 		self.chunks.get(&id).cloned()
@@ -206,12 +260,19 @@ impl DB {
 			// Perform the update
 			if let Some(chunk_to_replace) = chunk_to_replace {
 				let owner = chunk_to_replace.chunk().owner.clone();
-				self.set_chunk(chunk_to_replace, owner.as_str()).unwrap();
+				self.set_chunk(chunk_to_replace, owner.as_str())?;
 
 				changed.insert(user.into());
 			}
 		}
 
+		// Chunks left without a parent might be left without a schema too
+		let orphans = to_remove
+			.iter()
+			.flat_map(|id| self.chunks.get(id).unwrap().read().unwrap().children(None))
+			.filter(|c| !to_remove.contains(&c.read().unwrap().chunk().id))
+			.collect::<Vec<_>>();
+
 		// Delete all them chunks which have to be deleted
 		to_remove.iter().for_each(|id| {
 			{
@@ -220,6 +281,10 @@ impl DB {
 			}
 			self.chunks.remove(id);
 		});
+		to_remove.iter().for_each(|id| self.index_media(*id));
+		self.reapply_schemas(orphans);
+		self.threads.retain(|_, t| !to_remove.contains(&t.chunk));
+		self.encrypted.retain(|id, _| !to_remove.contains(id));
 
 		Ok(changed)
 	}
@@ -233,6 +298,11 @@ impl DB {
 		}
 
 		let diff_users;
+		let mut value_old = None;
+		// Whether what's under it has to be re-typed
+		let mut restructured = false;
+		// Only share lines changed, a strict schema the chunk already broke shouldn't stop that
+		let mut access_only = false;
 		// let diff_props;
 		if let Some(chunk_old) = self.chunks.get(&chunk.chunk().id).cloned() {
 			// Updating
@@ -242,9 +312,17 @@ impl DB {
 			if !chunk_old.try_clone_to(&mut chunk, user) {
 				return Err(DbError::AuthError);
 			}
+			self.check_encrypted(&chunk_old, &chunk)?;
 
 			// Find diff, link and insert
 			diff_users = chunk_old.access_diff(Some(&chunk));
+			if chunk_old.chunk().value != chunk.chunk().value {
+				value_old = Some(chunk_old.chunk().value.clone());
+			}
+			restructured = Schema::from_chunk(&chunk_old) != Schema::from_chunk(&chunk)
+				|| chunk_old.get_prop::<Vec<ChunkId>>("parents") != chunk.get_prop("parents");
+			let unshared = |value: &str| REGEX_ACCESS.replace_all(value, "").trim_end().to_owned();
+			access_only = unshared(&chunk_old.chunk().value) == unshared(&chunk.chunk().value);
 			// diff_props = chunk_old.props_diff(Some(&chunk));
 		} else {
 			// Creating
@@ -256,6 +334,28 @@ impl DB {
 			// diff_props = chunk.props_diff(None);
 		}
 
+		match self.apply_schema(&mut chunk) {
+			Err(err) if !access_only => return Err(err),
+			_ => {}
+		}
+
+		let id = chunk.chunk().id;
+		let value = value_old.as_ref().map(|_| chunk.chunk().value.clone());
+		let users = chunk.access_users();
+		self.insert_chunk(chunk)?;
+		if restructured {
+			let children = self.chunks.get(&id).map(|c| c.read().unwrap().children(None));
+			self.reapply_schemas(children.unwrap_or_default());
+		}
+		self.prune_encrypted(id, &users);
+		if let (Some(old), Some(new)) = (value_old, value) {
+			self.reanchor_threads(id, &old, &new);
+		}
+
+		Ok(diff_users)
+	}
+	/// Links and inserts an already validated chunk, replacing any chunk with the same id
+	pub(super) fn insert_chunk(&mut self, chunk: DBChunk) -> Result<(), DbError> {
 		let id = chunk.chunk().id;
 		let chunk = Arc::new(RwLock::new(chunk));
 		self.link_chunk(&chunk, None)?;
@@ -265,8 +365,9 @@ impl DB {
 		}
 
 		self.chunks.insert(id, chunk);
+		self.index_media(id);
 
-		Ok(diff_users)
+		Ok(())
 	}
 	
 	/// Chunk update called by socket, adds `diff` information to returned Result
@@ -303,7 +404,7 @@ impl DB {
 	///
 	/// * `chunk` - The chunk that's currently being linked
 	/// * `child` - If None, `chunk` is the original, Some if its a recursive iteration and we're checking for circulars.
-	fn link_chunk(
+	pub(super) fn link_chunk(
 		&mut self,
 		chunk: &LockedAtomic<DBChunk>,
 		child: Option<&LockedAtomic<DBChunk>>,
@@ -313,7 +414,7 @@ impl DB {
 			// If child was Some, means this is a recursive iteration
 			if Arc::ptr_eq(chunk, child) {
 				// println!("Circular reference detected!");
-				return Err(DbError::InvalidChunk("Circular reference not allowed!"));
+				return Err(DbError::InvalidChunk("Circular reference not allowed!".into()));
 			}
 		}
 
@@ -325,7 +426,7 @@ impl DB {
 				if let Some(parent_ids) = chunk_lock.get_prop::<Vec<ChunkId>>("parents") {
 					if parent_ids.contains(&chunk_lock.chunk().id) {
 						// error!("Circular reference detected!; Links to itself");
-						return Err(DbError::InvalidChunk("Links to itself not allowed!"));
+						return Err(DbError::InvalidChunk("Links to itself not allowed!".into()));
 					}
 
 					let parent_weaks = parent_ids
@@ -396,8 +497,21 @@ impl From<DBData> for DB {
 			})
 			.collect();
 
-		let mut db = Self { chunks };
-		db.link_all().unwrap();
+		let mut db = Self {
+			chunks,
+			transfers: data.transfers.into_iter().map(|t| (t.id, t)).collect(),
+			webhooks: data.webhooks.into_iter().map(|w| (w.id, w)).collect(),
+			deliveries: Default::default(),
+			threads: data.threads.into_iter().map(|t| (t.id, t)).collect(),
+			keys: data.keys.into_iter().map(|k| (k.user.clone(), k)).collect(),
+			encrypted: data.encrypted.into_iter().map(|k| (k.id, k)).collect(),
+			media: Default::default(),
+			kdf: Default::default(),
+		};
+		db.link_loaded();
+		let ids = db.chunks.keys().cloned().collect::<Vec<_>>();
+		ids.into_iter().for_each(|id| db.index_media(id));
+		db.apply_schemas();
 		db
 	}
 }
@@ -408,6 +522,11 @@ impl From<&DB> for DBData {
 	fn from(db: &DB) -> Self {
 		DBData {
 			chunks: db.chunks.values().map(|v| v.read().unwrap().chunk().clone()).collect(),
+			transfers: db.transfers.values().cloned().collect(),
+			webhooks: db.webhooks.values().cloned().collect(),
+			threads: db.threads.values().cloned().collect(),
+			keys: db.keys.values().cloned().collect(),
+			encrypted: db.encrypted.values().cloned().collect(),
 		}
 	}
 }
@@ -0,0 +1,174 @@
+use common::utils::LockedAtomic;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::{HashSet, VecDeque};
+
+use super::{chunk::ChunkId, dbchunk::DBChunk, user_access::UserAccess, DB};
+
+/// Bounds for a graph query, `views/graph/<root>?depth=N&limit=M`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(default)]
+pub struct GraphQuery {
+	/// Levels below root, root itself is depth 0
+	pub depth: usize,
+	/// Max amount of nodes
+	pub limit: usize,
+}
+impl Default for GraphQuery {
+	fn default() -> Self {
+		Self { depth: 2, limit: 500 }
+	}
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct GraphNode {
+	pub id: ChunkId,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub title: Option<String>,
+	pub props: Value,
+}
+impl From<&DBChunk> for GraphNode {
+	fn from(chunk: &DBChunk) -> Self {
+		Self {
+			id: chunk.chunk().id,
+			title: chunk.get_prop("title"),
+			props: Value::Object(Map::from_iter(chunk.props())),
+		}
+	}
+}
+
+/// Always goes parent -> child
+#[derive(Serialize, Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct GraphEdge {
+	pub from: ChunkId,
+	pub to: ChunkId,
+}
+
+/**
+ * A flat graph, unlike GraphView it can hold chunks with many parents only once.
+ * Edges only connect nodes that are part of the graph.
+ */
+#[derive(Serialize, Debug, Default)]
+pub struct Graph {
+	pub nodes: Vec<GraphNode>,
+	pub edges: Vec<GraphEdge>,
+	/// Limit was hit before the whole depth was walked
+	pub truncated: bool,
+}
+
+impl Graph {
+	/// Builds a graph out of nodes, keeping only the edges between them
+	fn from_nodes(chunks: Vec<LockedAtomic<DBChunk>>, ua: &UserAccess) -> Self {
+		let ids = chunks
+			.iter()
+			.map(|c| c.read().unwrap().chunk().id)
+			.collect::<HashSet<_>>();
+		let mut graph = Self::default();
+		for chunk in chunks {
+			let chunk = chunk.read().unwrap();
+			let from = chunk.chunk().id;
+			graph.edges.extend(chunk.children(Some(ua)).into_iter().filter_map(|child| {
+				let to = child.read().unwrap().chunk().id;
+				ids.contains(&to).then_some(GraphEdge { from, to })
+			}));
+			graph.nodes.push(GraphNode::from(&*chunk));
+		}
+		graph
+	}
+
+	pub fn to_dot(&self) -> String {
+		let escape = |v: &str| v.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+		let mut dot = String::from("digraph chunks {\n");
+		for node in self.nodes.iter() {
+			dot.push_str(&format!(
+				"\t\"{}\" [label=\"{}\"];\n",
+				node.id,
+				escape(node.title.as_deref().unwrap_or_default())
+			));
+		}
+		for edge in self.edges.iter() {
+			dot.push_str(&format!("\t\"{}\" -> \"{}\";\n", edge.from, edge.to));
+		}
+		dot.push_str("}\n");
+		dot
+	}
+
+	pub fn to_graphml(&self) -> String {
+		let escape = |v: &str| {
+			v.replace('&', "&amp;")
+				.replace('<', "&lt;")
+				.replace('>', "&gt;")
+				.replace('"', "&quot;")
+		};
+		let mut xml = String::from(concat!(
+			"<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+			"<graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">\n",
+			"\t<key id=\"title\" for=\"node\" attr.name=\"title\" attr.type=\"string\"/>\n",
+			"\t<key id=\"props\" for=\"node\" attr.name=\"props\" attr.type=\"string\"/>\n",
+			"\t<graph id=\"chunks\" edgedefault=\"directed\">\n"
+		));
+		for node in self.nodes.iter() {
+			xml.push_str(&format!("\t\t<node id=\"{}\">\n", node.id));
+			if let Some(title) = node.title.as_ref() {
+				xml.push_str(&format!("\t\t\t<data key=\"title\">{}</data>\n", escape(title)));
+			}
+			xml.push_str(&format!(
+				"\t\t\t<data key=\"props\">{}</data>\n\t\t</node>\n",
+				escape(&node.props.to_string())
+			));
+		}
+		for edge in self.edges.iter() {
+			xml.push_str(&format!(
+				"\t\t<edge source=\"{}\" target=\"{}\"/>\n",
+				edge.from, edge.to
+			));
+		}
+		xml.push_str("\t</graph>\n</graphml>\n");
+		xml
+	}
+}
+
+impl DB {
+	/// Breadth first walk from `root` (or from the user's top level chunks if None),
+	/// every chunk is visited once so circular links can't loop forever.
+	pub fn graph(&self, root: Option<ChunkId>, user: &str, query: GraphQuery) -> Graph {
+		// public assertion
+		if user == "public" {
+			return Graph::default();
+		}
+		let ua = UserAccess::from(user);
+
+		let mut queue: VecDeque<_> = match root {
+			Some(id) => self.get_chunk(id, user).into_iter().map(|c| (c, 0)).collect(),
+			None => self.roots(&ua).into_iter().map(|c| (c, 0)).collect(),
+		};
+		let mut visited = HashSet::<ChunkId>::default();
+		let mut nodes = vec![];
+		let mut truncated = false;
+
+		while let Some((chunk, depth)) = queue.pop_front() {
+			if !visited.insert(chunk.read().unwrap().chunk().id) {
+				continue;
+			}
+			if nodes.len() >= query.limit {
+				truncated = true;
+				break;
+			}
+			if depth < query.depth {
+				let children = chunk.read().unwrap().children(Some(&ua));
+				queue.extend(children.into_iter().map(|c| (c, depth + 1)));
+			}
+			nodes.push(chunk);
+		}
+
+		Graph {
+			truncated,
+			..Graph::from_nodes(nodes, &ua)
+		}
+	}
+
+	/// Every chunk the user can see, with all links between them
+	pub fn graph_all(&self, user: &str) -> Graph {
+		Graph::from_nodes(self.get_chunks(user), &user.into())
+	}
+}
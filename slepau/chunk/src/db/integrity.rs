@@ -0,0 +1,279 @@
+use common::utils::{LockedAtomic, SECS_IN_HOUR};
+use log::{error, info, warn};
+use serde::Serialize;
+use std::{
+	collections::{BTreeMap, HashMap, HashSet},
+	fmt::Display,
+	sync::{RwLock, Weak},
+	time::Duration,
+};
+use tokio::sync::watch;
+
+use super::{
+	chunk::ChunkId,
+	dbchunk::{set_parents, DBChunk},
+	DB,
+};
+
+/// Something wrong with the chunk graph
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum Issue {
+	/// `parents` names a chunk that doesn't exist
+	DanglingParent { chunk: ChunkId, parent: ChunkId },
+	SelfReference { chunk: ChunkId },
+	/// Each chunk is a parent of the one before it, the first is a parent of the last
+	Cycle { chunks: Vec<ChunkId> },
+	/// Parent/child pointers to chunks that were dropped
+	OrphanedPointer { chunk: ChunkId },
+	/// Pointers don't match `parents`, or the chunk never got linked
+	StaleLink { chunk: ChunkId },
+}
+impl Display for Issue {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Issue::DanglingParent { chunk, parent } => {
+				write!(f, "{chunk} has parent {parent} that doesn't exist")
+			}
+			Issue::SelfReference { chunk } => write!(f, "{chunk} is its own parent"),
+			Issue::Cycle { chunks } => write!(
+				f,
+				"cycle {}",
+				chunks.iter().map(|c| c.to_string()).collect::<Vec<_>>().join(" -> ")
+			),
+			Issue::OrphanedPointer { chunk } => write!(f, "{chunk} points to dropped chunks"),
+			Issue::StaleLink { chunk } => write!(f, "{chunk} pointers don't match its parents"),
+		}
+	}
+}
+
+/// Depth first over parent edges from `root`, every back edge closes a cycle.
+///
+/// Keeps its own stack, parent chains can be longer than the thread's.
+fn find_cycles(
+	root: ChunkId,
+	graph: &BTreeMap<ChunkId, Vec<ChunkId>>,
+	done: &mut HashSet<ChunkId>,
+	cycles: &mut Vec<Vec<ChunkId>>,
+) {
+	// Path from root, with how many parents of each were looked at
+	let mut stack = vec![(root, 0)];
+	// Where each id on the path is
+	let mut on_stack = HashMap::from([(root, 0)]);
+	while let Some((id, next)) = stack.last_mut() {
+		let id = *id;
+		let parent = graph[&id].get(*next).copied();
+		*next += 1;
+		match parent {
+			None => {
+				stack.pop();
+				on_stack.remove(&id);
+				done.insert(id);
+			}
+			Some(parent) if parent == id || !graph.contains_key(&parent) => {}
+			Some(parent) => {
+				if let Some(i) = on_stack.get(&parent) {
+					cycles.push(stack[*i..].iter().map(|(s, _)| *s).collect());
+				} else if !done.contains(&parent) {
+					on_stack.insert(parent, stack.len());
+					stack.push((parent, 0));
+				}
+			}
+		}
+	}
+}
+
+/// Ids behind a list of pointers, None if any of them was dropped
+fn pointer_ids(pointers: &[Weak<RwLock<DBChunk>>]) -> Option<HashSet<ChunkId>> {
+	pointers
+		.iter()
+		.map(|p| p.upgrade().map(|p| p.read().unwrap().chunk().id))
+		.collect()
+}
+
+impl DB {
+	/// Parent ids as written in every chunk's title
+	fn parent_graph(&self) -> BTreeMap<ChunkId, Vec<ChunkId>> {
+		self
+			.chunks
+			.iter()
+			.map(|(id, c)| (*id, c.read().unwrap().get_prop::<Vec<ChunkId>>("parents").unwrap_or_default()))
+			.collect()
+	}
+
+	/// Dangling parents, self references and cycles, only looks at ids so it's safe before linking
+	pub fn graph_issues(&self) -> Vec<Issue> {
+		let graph = self.parent_graph();
+		let mut issues = vec![];
+		for (id, parents) in graph.iter() {
+			for parent in parents {
+				if parent == id {
+					issues.push(Issue::SelfReference { chunk: *id });
+				} else if !graph.contains_key(parent) {
+					issues.push(Issue::DanglingParent {
+						chunk: *id,
+						parent: *parent,
+					});
+				}
+			}
+		}
+
+		let (mut done, mut cycles) = (HashSet::new(), vec![]);
+		for id in graph.keys() {
+			if !done.contains(id) {
+				find_cycles(*id, &graph, &mut done, &mut cycles);
+			}
+		}
+		issues.extend(cycles.into_iter().map(|chunks| Issue::Cycle { chunks }));
+		issues
+	}
+
+	/// Orphaned pointers and stale links
+	pub fn link_issues(&self) -> Vec<Issue> {
+		let graph = self.parent_graph();
+		let mut issues = vec![];
+		for (id, chunk) in self.chunks.iter() {
+			let chunk = chunk.read().unwrap();
+			let (parents, children) = (pointer_ids(&chunk.parents), pointer_ids(&chunk.children));
+			let (Some(parents), Some(children)) = (parents, children) else {
+				issues.push(Issue::OrphanedPointer { chunk: *id });
+				continue;
+			};
+			let expected = graph[id]
+				.iter()
+				.filter(|p| *p != id && self.chunks.contains_key(p))
+				.cloned()
+				.collect::<HashSet<_>>();
+			let children_ok = children
+				.iter()
+				.all(|c| graph.get(c).map(|p| p.contains(id)).unwrap_or(false));
+			if !chunk.linked || parents != expected || !children_ok {
+				issues.push(Issue::StaleLink { chunk: *id });
+			}
+		}
+		issues
+	}
+
+	/// Everything wrong with the graph, nothing is changed
+	pub fn check(&self) -> Vec<Issue> {
+		let mut issues = self.graph_issues();
+		issues.extend(self.link_issues());
+		issues
+	}
+
+	/// Drops the parent ids behind `issues` from the chunks' titles
+	fn fix_graph(&mut self, issues: &[Issue]) {
+		let mut remove = HashMap::<ChunkId, HashSet<ChunkId>>::new();
+		for issue in issues {
+			let (chunk, parent) = match issue {
+				Issue::DanglingParent { chunk, parent } => (*chunk, *parent),
+				Issue::SelfReference { chunk } => (*chunk, *chunk),
+				// The last chunk is the one closing the cycle
+				Issue::Cycle { chunks } => (*chunks.last().unwrap(), chunks[0]),
+				_ => continue,
+			};
+			remove.entry(chunk).or_default().insert(parent);
+		}
+
+		for (id, remove) in remove {
+			let mut chunk = self.chunks[&id].write().unwrap();
+			let parents = chunk
+				.get_prop::<Vec<ChunkId>>("parents")
+				.unwrap_or_default()
+				.into_iter()
+				.filter(|p| !remove.contains(p))
+				.collect::<Vec<_>>();
+			if let Some(value) = set_parents(&chunk.chunk().value, &parents) {
+				let mut inner = chunk.chunk().clone();
+				inner.value = value;
+				*chunk = DBChunk::from(inner);
+			}
+		}
+	}
+
+	/// Drops every pointer and links the whole graph again, a chunk that can't be linked doesn't
+	/// keep the rest from it
+	fn relink(&mut self) {
+		for chunk in self.chunks.values() {
+			let mut chunk = chunk.write().unwrap();
+			chunk.parents.clear();
+			chunk.children.clear();
+			chunk.linked = false;
+		}
+		let chunks = self.chunks.values().cloned().collect::<Vec<_>>();
+		for chunk in chunks {
+			if let Err(err) = self.link_chunk(&chunk, None) {
+				error!("Couldn't link {}: {err:?}", chunk.read().unwrap().chunk().id);
+			}
+		}
+	}
+
+	/// Fixes and relinks until the graph has no issues left, or fixing stops getting rid of them.
+	///
+	/// Returns every issue it fixed.
+	fn heal(&mut self, mut issues: Vec<Issue>) -> Vec<Issue> {
+		let mut fixed = vec![];
+		loop {
+			self.fix_graph(&issues);
+			self.relink();
+			let before = issues.len();
+			fixed.append(&mut issues);
+
+			let left = self.graph_issues();
+			if left.is_empty() {
+				break;
+			}
+			if left.len() >= before {
+				for issue in left {
+					error!("Couldn't repair {issue}");
+				}
+				break;
+			}
+			issues = left;
+		}
+		fixed
+	}
+
+	/// Fixes the graph and returns what was wrong with it.
+	///
+	/// Bad parent ids are removed from titles, every pointer is rebuilt.
+	pub fn repair(&mut self) -> Vec<Issue> {
+		let issues = self.check();
+		if issues.is_empty() {
+			return issues;
+		}
+		let issues = self.heal(issues);
+		self.apply_schemas();
+		issues
+	}
+
+	/// Links a freshly loaded DB, repairing the graph instead of failing
+	pub(super) fn link_loaded(&mut self) {
+		let issues = self.graph_issues();
+		for issue in issues.iter() {
+			warn!("Repairing {issue}");
+		}
+		self.heal(issues);
+	}
+}
+
+/// Checks the graph every few hours, only reports, `POST /integrity` repairs
+pub async fn integrity_service(db: LockedAtomic<DB>, mut shutdown_rx: watch::Receiver<()>) {
+	loop {
+		let issues = db.read().unwrap().check();
+		// Edits and deletes leave those behind all the time, they heal on their own
+		let (orphaned, issues): (Vec<_>, Vec<_>) =
+			issues.into_iter().partition(|i| matches!(i, Issue::OrphanedPointer { .. }));
+		info!("Chunk graph has {} issues, {} orphaned pointers.", issues.len(), orphaned.len());
+		for issue in issues {
+			warn!("Chunk graph: {issue}");
+		}
+		tokio::select! {
+			_ = tokio::time::sleep(Duration::from_secs(SECS_IN_HOUR * 6)) => {}
+			_ = shutdown_rx.changed() => {
+				break;
+			}
+		}
+	}
+	info!("Integrity service shut down.");
+}
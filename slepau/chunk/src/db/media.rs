@@ -0,0 +1,66 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::{chunk::ChunkId, DB};
+
+lazy_static! {
+	static ref REGEX_MEDIA: Regex =
+		Regex::new(concat!(r"\((?:image|video|media)/(", env!("REGEX_PROQUINT"), r")\)")).unwrap();
+}
+
+/// Media ids embedded in a chunk's value
+pub fn media_refs(value: &str) -> BTreeSet<String> {
+	REGEX_MEDIA.captures_iter(value).map(|c| c[1].to_owned()).collect()
+}
+
+impl DB {
+	/// Refreshes the media referenced by chunk `id`, call it whenever its value or keys change.
+	///
+	/// Encrypted chunks keep theirs in `ChunkKeys`, the ciphertext says nothing.
+	pub(super) fn index_media(&mut self, id: ChunkId) {
+		let refs = match (self.chunks.get(&id), self.encrypted.get(&id)) {
+			(None, _) => Default::default(),
+			(Some(_), Some(keys)) => keys.media.clone(),
+			(Some(chunk), None) => media_refs(&chunk.read().unwrap().chunk().value),
+		};
+		if refs.is_empty() {
+			self.media.remove(&id);
+		} else {
+			self.media.insert(id, refs);
+		}
+	}
+
+	/// Media referenced by chunks `owner` owns, with how many chunks reference each
+	pub fn media_by_owner(&self, owner: &str) -> BTreeMap<String, usize> {
+		let mut counts = BTreeMap::<String, usize>::new();
+		self
+			.media
+			.iter()
+			.filter(|(id, _)| {
+				self
+					.chunks
+					.get(id)
+					.map(|c| c.read().unwrap().chunk().owner == owner)
+					.unwrap_or(false)
+			})
+			.flat_map(|(_, refs)| refs.iter())
+			.for_each(|r| *counts.entry(r.clone()).or_default() += 1);
+		counts
+	}
+
+	/// How many chunks reference each of `ids`, ids nobody references are left out.
+	///
+	/// Only answers for ids the caller already knows, so it can't be used to list anyone's media.
+	pub fn media_referenced(&self, ids: &[String]) -> BTreeMap<String, usize> {
+		let ids = ids.iter().collect::<BTreeSet<_>>();
+		let mut counts = BTreeMap::<String, usize>::new();
+		self
+			.media
+			.values()
+			.flat_map(|refs| refs.iter())
+			.filter(|r| ids.contains(r))
+			.for_each(|r| *counts.entry(r.clone()).or_default() += 1);
+		counts
+	}
+}
@@ -2,7 +2,7 @@ use common::utils::LockedAtomic;
 use serde::Serialize;
 use serde_json::Value;
 /** Designing a new Data Structure that would allow for all queries/insertions/serializations to efficiently happen */
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, VecDeque};
 
 pub type DBMap<K, V> = BTreeMap<K, V>;
 
@@ -28,14 +28,39 @@ pub struct GraphView(
 #[derive(Default)]
 pub struct DB {
 	chunks: DBMap<ChunkId, LockedAtomic<dbchunk::DBChunk>>,
+	/// Pending ownership transfers by root chunk
+	transfers: DBMap<ChunkId, transfer::Transfer>,
+	webhooks: DBMap<webhook::WebhookId, webhook::Webhook>,
+	/// Delivery log, only kept in memory
+	deliveries: DBMap<webhook::WebhookId, VecDeque<webhook::Delivery>>,
+	/// Comment threads, kept beside the chunks they're on
+	threads: DBMap<comment::ThreadId, comment::Thread>,
+	/// Public keys and sealed private keys by user
+	keys: DBMap<String, crypt::UserKey>,
+	/// Wrapped content keys of encrypted chunks
+	encrypted: DBMap<ChunkId, crypt::ChunkKeys>,
+	/// Cost of deriving users' keys, tests lower it
+	kdf: crypt::Kdf,
+	/// Media ids referenced by every chunk, derived from values so never stored
+	media: DBMap<ChunkId, std::collections::BTreeSet<String>>,
 	// by_owner: DBMap<String, Vec<LockedWeak<dbchunk::DBChunk>>>,
 }
 
+pub mod board;
 pub mod chunk;
+pub mod comment;
+pub mod crypt;
 pub mod dbchunk;
 mod def;
+pub mod graph;
+pub mod integrity;
+pub mod media;
+pub mod schema;
+pub mod template;
+pub mod transfer;
 pub mod user_access;
 pub mod view;
+pub mod webhook;
 
 #[cfg(test)]
 mod tests;
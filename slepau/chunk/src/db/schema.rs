@@ -0,0 +1,285 @@
+use chrono::NaiveDate;
+use common::utils::{DbError, LockedAtomic, REGEX_USERNAME};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::{
+	collections::{HashMap, HashSet, VecDeque},
+	fmt::Display,
+	sync::Arc,
+};
+
+use super::{chunk::ChunkId, crypt::ENCRYPTED_PROP, dbchunk::DBChunk, DB};
+
+lazy_static! {
+	/// `field <name>: <type>[ required][ = <default>]`
+	static ref REGEX_FIELD: Regex = Regex::new(
+		r"(?m)^field +([a-z0-9_]+): *(text|number|date|user|chunk|enum\(([^)\n]*)\))( +required)?(?: *= *(.+?))? *$"
+	)
+	.unwrap();
+}
+
+/// Props the system sets or reads itself, schemas can't type them
+pub const RESERVED: [&str; 9] = [
+	"title",
+	"ref",
+	"parents",
+	"access",
+	"share",
+	"template",
+	"schema",
+	"encrypted",
+	WARNINGS_PROP,
+];
+
+/// Where issues end up when a schema isn't strict
+pub const WARNINGS_PROP: &str = "schema_warnings";
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase", tag = "type", content = "options")]
+pub enum PropType {
+	Text,
+	Number,
+	/// Stored as `%Y-%m-%d`, `%Y/%m/%d` is read too
+	Date,
+	Enum(Vec<String>),
+	User,
+	Chunk,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Field {
+	pub name: String,
+	#[serde(flatten)]
+	pub _type: PropType,
+	pub required: bool,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub default: Option<String>,
+}
+
+/**
+ * Declared by a chunk with `schema: strict` or `schema: warn`,
+ * applies to every chunk under it, the nearest schema wins.
+ *
+ * ```md
+ * # Tasks
+ * schema: strict
+ * field estimate: number required
+ * field status: enum(todo, doing, done) = todo
+ * field due: date
+ * ```
+ */
+#[derive(Serialize, Debug, Clone, PartialEq, Default)]
+pub struct Schema {
+	pub id: ChunkId,
+	pub fields: Vec<Field>,
+	/// Issues keep chunks from being saved, otherwise they're saved with warnings
+	pub strict: bool,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SchemaIssue {
+	pub prop: String,
+	pub message: String,
+}
+impl Display for SchemaIssue {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{}: {}", self.prop, self.message)
+	}
+}
+
+impl PropType {
+	fn parse(&self, raw: &str) -> Result<Value, String> {
+		match self {
+			PropType::Text => Ok(json!(raw)),
+			PropType::Number => raw
+				.parse::<i64>()
+				.map(|n| json!(n))
+				.or_else(|_| raw.parse::<f64>().map(|n| json!(n)))
+				.map_err(|_| "has to be a number".into()),
+			PropType::Date => NaiveDate::parse_from_str(raw, "%Y-%m-%d")
+				.or_else(|_| NaiveDate::parse_from_str(raw, "%Y/%m/%d"))
+				.map(|d| json!(d.format("%Y-%m-%d").to_string()))
+				.map_err(|_| "has to be a date, like 2023-09-30".into()),
+			PropType::Enum(options) => {
+				if options.iter().any(|o| o == raw) {
+					Ok(json!(raw))
+				} else {
+					Err(format!("has to be one of {}", options.join(", ")))
+				}
+			}
+			PropType::User => {
+				if REGEX_USERNAME.is_match(raw) {
+					Ok(json!(raw))
+				} else {
+					Err("has to be a username".into())
+				}
+			}
+			PropType::Chunk => ChunkId::from_quint(raw)
+				.map(|id| json!(id))
+				.map_err(|_| "has to be a chunk id".into()),
+		}
+	}
+}
+
+impl Schema {
+	pub fn from_chunk(chunk: &DBChunk) -> Option<Self> {
+		let strict = match chunk.get_prop::<String>("schema")?.trim() {
+			"strict" => true,
+			"warn" => false,
+			_ => return None,
+		};
+		let fields = REGEX_FIELD
+			.captures_iter(&chunk.chunk().value)
+			.filter(|c| !RESERVED.contains(&&c[1]))
+			.map(|c| Field {
+				name: c[1].to_owned(),
+				_type: match &c[2] {
+					"number" => PropType::Number,
+					"date" => PropType::Date,
+					"user" => PropType::User,
+					"chunk" => PropType::Chunk,
+					"text" => PropType::Text,
+					_ => PropType::Enum(
+						c.get(3)
+							.map(|o| o.as_str())
+							.unwrap_or_default()
+							.split(',')
+							.map(str::trim)
+							.filter(|o| !o.is_empty())
+							.map(String::from)
+							.collect(),
+					),
+				},
+				required: c.get(4).is_some(),
+				default: c.get(5).map(|d| d.as_str().to_owned()),
+			})
+			.collect();
+		Some(Self {
+			id: chunk.chunk().id,
+			fields,
+			strict,
+		})
+	}
+
+	/// Replaces string props with typed ones and fills in defaults
+	pub fn apply(&self, props: &mut HashMap<String, Value>) -> Vec<SchemaIssue> {
+		let mut issues = vec![];
+		for field in self.fields.iter() {
+			let raw = props
+				.get(&field.name)
+				.and_then(|v| v.as_str())
+				.map(str::trim)
+				.filter(|v| !v.is_empty())
+				.or(field.default.as_deref());
+			let message = match raw.map(|raw| field._type.parse(raw)) {
+				Some(Ok(value)) => {
+					props.insert(field.name.clone(), value);
+					continue;
+				}
+				Some(Err(message)) => message,
+				None if field.required => "is required".into(),
+				None => continue,
+			};
+			issues.push(SchemaIssue {
+				prop: field.name.clone(),
+				message,
+			});
+		}
+		issues
+	}
+}
+
+impl DB {
+	/// Nearest schema above `parents`, breadth first
+	pub(super) fn schema_above(&self, parents: Vec<LockedAtomic<DBChunk>>) -> Option<Arc<Schema>> {
+		let mut seen = HashSet::<ChunkId>::default();
+		let mut queue = VecDeque::from(parents);
+		while let Some(chunk) = queue.pop_front() {
+			let chunk = chunk.read().unwrap();
+			if !seen.insert(chunk.chunk().id) {
+				continue;
+			}
+			if let Some(schema) = Schema::from_chunk(&chunk) {
+				return Some(Arc::new(schema));
+			}
+			queue.extend(chunk.parents(None));
+		}
+		None
+	}
+
+	/// Types chunk's props with the schema above it, issues are errors if it's strict.
+	///
+	/// Chunk can't be locked by the caller, nor linked yet.
+	pub(super) fn apply_schema(&self, chunk: &mut DBChunk) -> Result<(), DbError> {
+		// Encrypted props can't be read, so they can't be checked
+		if chunk.get_prop::<String>(ENCRYPTED_PROP).is_some() {
+			return Ok(());
+		}
+		let parents = chunk
+			.get_prop::<Vec<ChunkId>>("parents")
+			.unwrap_or_default()
+			.into_iter()
+			.filter(|id| *id != chunk.chunk().id)
+			.filter_map(|id| self.chunks.get(&id).cloned())
+			.collect();
+		let schema = self.schema_above(parents);
+		let strict = schema.as_ref().map(|s| s.strict).unwrap_or_default();
+
+		let issues = chunk.set_schema(schema);
+		if strict && !issues.is_empty() {
+			return Err(DbError::InvalidChunk(
+				issues.iter().map(|i| i.to_string()).collect::<Vec<_>>().join("\n"),
+			));
+		}
+		Ok(())
+	}
+
+	/// Re-types `roots` and everything under them, after a schema or the tree above them changed.
+	///
+	/// They were valid when saved, so issues only show up as warnings of lenient schemas.
+	pub(super) fn reapply_schemas(&self, roots: Vec<LockedAtomic<DBChunk>>) {
+		let mut seen = HashSet::<ChunkId>::default();
+		let mut queue = VecDeque::from(roots);
+		while let Some(chunk) = queue.pop_front() {
+			let (id, parents, children, encrypted) = {
+				let chunk = chunk.read().unwrap();
+				(
+					chunk.chunk().id,
+					chunk.parents(None),
+					chunk.children(None),
+					chunk.get_prop::<String>(ENCRYPTED_PROP).is_some(),
+				)
+			};
+			if !seen.insert(id) {
+				continue;
+			}
+			if !encrypted {
+				let schema = self.schema_above(parents);
+				chunk.write().unwrap().set_schema(schema);
+			}
+			queue.extend(children);
+		}
+	}
+
+	/// Types every chunk, they're loaded without knowing their schemas
+	pub(super) fn apply_schemas(&self) {
+		for chunk in self.chunks.values() {
+			let parents = chunk.read().unwrap().parents(None);
+			let schema = self.schema_above(parents);
+			if chunk.read().unwrap().get_prop::<String>(ENCRYPTED_PROP).is_none() {
+				chunk.write().unwrap().set_schema(schema);
+			}
+		}
+	}
+
+	/// Schemas user can see
+	pub fn get_schemas(&self, user: &str) -> Vec<Schema> {
+		self
+			.get_chunks(user)
+			.into_iter()
+			.filter_map(|c| Schema::from_chunk(&c.read().unwrap()))
+			.collect()
+	}
+}
@@ -0,0 +1,188 @@
+use chrono::Utc;
+use common::utils::{DbError, LockedAtomic};
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use super::{
+	chunk::ChunkId,
+	dbchunk::{set_parents, DBChunk},
+	user_access::UserAccess,
+	DB,
+};
+
+lazy_static! {
+	static ref REGEX_VARIABLE: Regex = Regex::new(r"\{\{ *([a-z0-9_]+) *\}\}").unwrap();
+	static ref REGEX_TEMPLATE: Regex = Regex::new(r"(?m)^template: .*(?:\n|$)").unwrap();
+}
+
+/// What gets sent to instantiate a template
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct TemplateInstance {
+	/// Replaces `{{title}}`, defaults to the template's title
+	pub title: Option<String>,
+	/// Parents the new chunk is attached to
+	pub parents: Vec<ChunkId>,
+	/// Also clone all of the template's children with fresh ids
+	pub subtree: bool,
+	/// Any other `{{key}}` replacements
+	pub vars: HashMap<String, String>,
+}
+
+/// Replaces every known `{{key}}` in `value`, unknown ones are left as is
+pub fn substitute(value: &str, vars: &HashMap<String, String>) -> String {
+	REGEX_VARIABLE
+		.replace_all(value, |c: &Captures| {
+			vars.get(&c[1]).cloned().unwrap_or_else(|| c[0].to_owned())
+		})
+		.to_string()
+}
+
+/// Collects the subtree of `root` that `ua` can see,
+/// every chunk comes after all of its parents that are part of the subtree.
+fn subtree_ordered(root: &LockedAtomic<DBChunk>, ua: &UserAccess) -> Vec<LockedAtomic<DBChunk>> {
+	let root_id = root.read().unwrap().chunk().id;
+
+	// Gather every node once
+	let mut nodes = vec![];
+	let mut seen = HashSet::<ChunkId>::default();
+	let mut queue = VecDeque::from([root.clone()]);
+	while let Some(chunk) = queue.pop_front() {
+		if !seen.insert(chunk.read().unwrap().chunk().id) {
+			continue;
+		}
+		queue.extend(chunk.read().unwrap().children(Some(ua)));
+		nodes.push(chunk);
+	}
+
+	// Order them, parents first
+	let mut ordered = vec![];
+	let mut placed = HashSet::<ChunkId>::default();
+	while !nodes.is_empty() {
+		let before = nodes.len();
+		nodes.retain(|chunk| {
+			let c = chunk.read().unwrap();
+			let id = c.chunk().id;
+			let ready = id == root_id
+				|| c.parents(None).iter().all(|p| {
+					let p = p.read().unwrap().chunk().id;
+					placed.contains(&p) || !seen.contains(&p)
+				});
+			if ready {
+				placed.insert(id);
+				ordered.push(chunk.clone());
+			}
+			!ready
+		});
+		if nodes.len() == before {
+			// Can only happen with a circular reference, which linking doesn't allow
+			break;
+		}
+	}
+	ordered
+}
+
+impl DB {
+	/// Get all templates a user has access to
+	pub fn get_templates(&self, user: &str) -> Vec<LockedAtomic<DBChunk>> {
+		self
+			.get_chunks(user)
+			.into_iter()
+			.filter(|c| c.read().unwrap().is_template())
+			.collect()
+	}
+
+	/// Creates a new chunk from template `id`.
+	///
+	/// Returns the id of the new chunk and the list of users for which access changed.
+	pub fn instantiate(
+		&mut self,
+		id: ChunkId,
+		user: &str,
+		instance: TemplateInstance,
+	) -> Result<(ChunkId, HashSet<String>), DbError> {
+		// public assertion
+		if user == "public" {
+			return Err(DbError::AuthError);
+		}
+
+		let template = self.get_chunk(id, user).ok_or(DbError::NotFound)?;
+		if !template.read().unwrap().is_template() {
+			return Err(DbError::InvalidChunk("Chunk isn't a template.".into()));
+		}
+		if instance.parents.iter().any(|p| self.get_chunk(*p, user).is_none()) {
+			return Err(DbError::NotFound);
+		}
+
+		let mut vars = instance.vars;
+		// Dashes aren't allowed in titles, so no ISO dates here
+		vars.insert("date".into(), Utc::now().format("%Y/%m/%d").to_string());
+		vars.insert("user".into(), user.into());
+		vars.insert(
+			"title".into(),
+			instance
+				.title
+				.or_else(|| template.read().unwrap().get_prop::<String>("title"))
+				.unwrap_or_default(),
+		);
+
+		let chunks = if instance.subtree {
+			subtree_ordered(&template, &user.into())
+		} else {
+			vec![template]
+		};
+		if chunks.iter().any(|c| self.is_encrypted(c.read().unwrap().chunk().id)) {
+			return Err(DbError::InvalidChunk("Encrypted chunks can't be instantiated.".into()));
+		}
+
+		// Fresh ids for everything we're about to clone
+		let mut ids = HashMap::<ChunkId, ChunkId>::default();
+		for chunk in chunks.iter() {
+			let mut new_id = ChunkId::default();
+			while self.chunks.contains_key(&new_id) || ids.values().any(|v| v == &new_id) {
+				new_id = ChunkId::default();
+			}
+			ids.insert(chunk.read().unwrap().chunk().id, new_id);
+		}
+
+		let (mut users, mut inserted) = (HashSet::default(), HashSet::default());
+		for chunk in chunks {
+			let (old_id, value, parents) = {
+				let chunk = chunk.read().unwrap();
+				(
+					chunk.chunk().id,
+					chunk.chunk().value.clone(),
+					chunk.get_prop::<Vec<ChunkId>>("parents").unwrap_or_default(),
+				)
+			};
+			// Root goes where it was asked to, the rest follow their cloned parents
+			let parents = if old_id == id {
+				instance.parents.clone()
+			} else {
+				parents.iter().filter_map(|p| ids.get(p).copied()).collect()
+			};
+
+			let value = substitute(&REGEX_TEMPLATE.replace_all(&value, ""), &vars);
+			let result = set_parents(&value, &parents)
+				.ok_or(DbError::InvalidChunk("Template needs a title to have parents.".into()))
+				.and_then(|value| self.set_chunk(DBChunk::from((ids[&old_id], value.as_str(), user)), user));
+			match result {
+				Ok(changed) => {
+					users.extend(changed);
+					inserted.insert(ids[&old_id]);
+				}
+				Err(err) => {
+					// All or nothing, clones that made it in go again
+					if !inserted.is_empty() {
+						self.del_chunk(inserted, user).ok();
+					}
+					return Err(err);
+				}
+			}
+		}
+
+		Ok((ids[&id], users))
+	}
+}
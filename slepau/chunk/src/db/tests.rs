@@ -1,14 +1,26 @@
 use std::collections::HashSet;
 
 
+use common::socket::ResourceMessage;
 use serde_json::{json, Value};
 
 use crate::db::{
 	chunk::Chunk,
-	view::{ChunkId, ChunkView, ViewType},
+	view::{ChunkId, ChunkVec, ChunkView, Cursor, CursorQuery, ViewType},
 };
 
-use super::{dbchunk::DBChunk, GraphView, DB};
+use super::{
+	board::{BoardQuery, TableQuery},
+	comment::ThreadIn,
+	crypt::{ChunkKeys, Kdf, Unlocked, UserKey},
+	dbchunk::{set_prop_line, DBChunk},
+	graph::{GraphEdge, GraphQuery},
+	template::TemplateInstance,
+	transfer::{BulkShare, TransferIn},
+	user_access::Access,
+	webhook::{WebhookEvent, WebhookFilter, WebhookIn},
+	GraphView, DB,
+};
 
 #[test]
 fn delete() {
@@ -430,3 +442,818 @@ fn linking() {
 // 		}
 // 	});
 // }
+#[test]
+fn template() {
+	let mut db = DB::default();
+
+	let c_tpl: DBChunk = "# Meeting {{title}}\ntemplate: true\nBy {{user}} on {{date}}, {{place}}".into();
+	let id_tpl = c_tpl.chunk().id;
+	assert!(db.set_chunk(c_tpl, "john").is_ok());
+	let c_agenda: DBChunk = (None, format!("# Agenda -> {id_tpl}\n- {{{{title}}}}").as_str(), "john").into();
+	let id_agenda = c_agenda.chunk().id;
+	assert!(db.set_chunk(c_agenda, "john").is_ok());
+	let c_notes: DBChunk = "# Notes\n".into();
+	let id_notes = c_notes.chunk().id;
+	assert!(db.set_chunk(c_notes, "john").is_ok());
+
+	// Regular chunks can't be instantiated
+	assert!(db.instantiate(id_notes, "john", Default::default()).is_err());
+	// Others can't see john's template
+	assert!(db.instantiate(id_tpl, "nina", Default::default()).is_err());
+	assert_eq!(db.get_templates("john").len(), 1);
+
+	let (id_new, _) = db
+		.instantiate(
+			id_tpl,
+			"john",
+			TemplateInstance {
+				title: Some("Weekly".into()),
+				parents: vec![id_notes],
+				subtree: true,
+				vars: [("place".to_string(), "Office".to_string())].into(),
+			},
+		)
+		.unwrap();
+
+	let new = db.get_chunk(id_new, "john").unwrap();
+	let new = new.read().unwrap();
+	assert!(!new.is_template());
+	assert_eq!(new.get_prop::<Vec<crate::db::chunk::ChunkId>>("parents"), Some(vec![id_notes]));
+	assert!(new.chunk().value.starts_with(&format!("# Meeting Weekly -> {id_notes}\nBy john on ")));
+	assert!(new.chunk().value.ends_with(", Office"));
+
+	// Subtree was cloned with new ids
+	let children = new.children(None);
+	assert_eq!(children.len(), 1);
+	let child = children[0].read().unwrap();
+	assert_ne!(child.chunk().id, id_agenda);
+	assert_eq!(child.chunk().value, format!("# Agenda -> {id_new}\n- Weekly"));
+	// Template stays untouched
+	assert_eq!(db.get_chunk(id_tpl, "john").unwrap().read().unwrap().children(None).len(), 1);
+
+	// A clone failing takes back the ones before it, the Step here breaks the schema
+	let c_plan: DBChunk = "# Plan\ntemplate: true\nplace: Office".into();
+	let id_plan = c_plan.chunk().id;
+	assert!(db.set_chunk(c_plan, "john").is_ok());
+	assert!(db.set_chunk((None, format!("# Step -> {id_plan}\n").as_str(), "john").into(), "john").is_ok());
+	let c_tasks: DBChunk = "# Tasks\nschema: strict\nfield place: text required".into();
+	let id_tasks = c_tasks.chunk().id;
+	assert!(db.set_chunk(c_tasks, "john").is_ok());
+	let count = db.chunks.len();
+	let instance = TemplateInstance {
+		parents: vec![id_tasks],
+		subtree: true,
+		..Default::default()
+	};
+	assert_eq!(
+		db.instantiate(id_plan, "john", instance),
+		Err(common::utils::DbError::InvalidChunk("place: is required".into()))
+	);
+	assert_eq!(db.chunks.len(), count);
+	assert!(db.get_chunk(id_tasks, "john").unwrap().read().unwrap().children(None).is_empty());
+}
+#[test]
+fn paginate() {
+	let mut db = DB::default();
+
+	let ids = (0..5)
+		.map(|i| {
+			let chunk: DBChunk = format!("# Note {i}\n").as_str().into();
+			let id = chunk.chunk().id;
+			assert!(db.set_chunk(chunk, "john").is_ok());
+			id
+		})
+		.collect::<Vec<_>>();
+	let chunks = ChunkVec::from(ids.iter().map(|id| db.get_chunk(*id, "john").unwrap()).collect::<Vec<_>>());
+	let page = |cursor, limit| {
+		chunks
+			.paginate(&CursorQuery { cursor, limit })
+			.0
+			.iter()
+			.map(|v| v.read().unwrap().chunk().id)
+			.collect::<Vec<_>>()
+	};
+
+	assert_eq!(page(None, 2), ids[0..2]);
+	assert_eq!(page(Some(Cursor::After(ids[1])), 2), ids[2..4]);
+	assert_eq!(page(Some(Cursor::After(ids[3])), 2), ids[4..5]);
+	assert_eq!(page(Some(Cursor::Before(ids[3])), 2), ids[1..3]);
+	assert_eq!(page(Some(Cursor::Before(ids[1])), 2), ids[0..1]);
+	assert!(page(Some(Cursor::After(Default::default())), 2).is_empty());
+
+	assert_eq!(db.search("john", "note [34]").unwrap().0.len(), 2);
+	assert!(db.search("nina", "note").unwrap().0.is_empty());
+	assert!(db.search("john", "").is_err());
+}
+#[test]
+fn deltas() {
+	let mut db = DB::default();
+
+	let c_notes: DBChunk = "# Notes\n".into();
+	let id_notes = c_notes.chunk().id;
+	assert!(db.set_chunk(c_notes, "john").is_ok());
+	let c_child: DBChunk = (None, format!("# Child -> {id_notes}\n").as_str(), "john").into();
+	let id_child = c_child.chunk().id;
+
+	let resources = |messages: Vec<ResourceMessage>| {
+		let mut r = messages
+			.into_iter()
+			.map(|m| {
+				let mut users = m.users.unwrap().into_iter().collect::<Vec<_>>();
+				users.sort();
+				(m.message.resource, users, serde_json::from_str::<Value>(&m.message.value.unwrap()).unwrap().to_string())
+			})
+			.collect::<Vec<_>>();
+		r.sort();
+		r
+	};
+	let delta = |t: &str, id| json!({"type": t, "id": id}).to_string();
+
+	// Creating
+	let before = db.placement(id_child);
+	assert!(db.set_chunk(c_child, "john").is_ok());
+	assert_eq!(
+		resources(before.deltas(id_child, &db.placement(id_child))),
+		vec![
+			("views/notes/delta".to_string(), vec!["john".to_string()], delta("inserted", id_child)),
+			(format!("views/well/{id_notes}/delta"), vec!["john".to_string()], delta("inserted", id_child)),
+		]
+	);
+
+	// Sharing with nina and moving out of notes
+	let before = db.placement(id_child);
+	assert!(db
+		.set_chunk((id_child, "# Child\nshare: nina r").into(), "john")
+		.is_ok());
+	assert_eq!(
+		resources(before.deltas(id_child, &db.placement(id_child))),
+		vec![
+			("views/notes/delta".to_string(), vec!["john".to_string()], delta("moved", id_child)),
+			("views/notes/delta".to_string(), vec!["nina".to_string()], delta("inserted", id_child)),
+			(format!("views/well/{id_notes}/delta"), vec!["john".to_string()], delta("removed", id_child)),
+		]
+	);
+}
+#[test]
+fn graph() {
+	let mut db = DB::default();
+
+	// a -> b -> d, a -> c -> d
+	let c_a: DBChunk = "# A\n".into();
+	let id_a = c_a.chunk().id;
+	assert!(db.set_chunk(c_a, "john").is_ok());
+	let c_b: DBChunk = (None, format!("# B -> {id_a}\n").as_str(), "john").into();
+	let id_b = c_b.chunk().id;
+	assert!(db.set_chunk(c_b, "john").is_ok());
+	let c_c: DBChunk = (None, format!("# C -> {id_a}\n").as_str(), "john").into();
+	let id_c = c_c.chunk().id;
+	assert!(db.set_chunk(c_c, "john").is_ok());
+	let c_d: DBChunk = (None, format!("# D \"quoted\" -> {id_b}, {id_c}\n").as_str(), "john").into();
+	let id_d = c_d.chunk().id;
+	assert!(db.set_chunk(c_d, "john").is_ok());
+
+	let graph = db.graph(Some(id_a), "john", GraphQuery { depth: 1, limit: 10 });
+	assert_eq!(graph.nodes.len(), 3);
+	assert_eq!(graph.edges.len(), 2);
+	assert!(!graph.truncated);
+
+	// D is reachable twice but only shows up once
+	let graph = db.graph(Some(id_a), "john", GraphQuery { depth: 5, limit: 10 });
+	assert_eq!(graph.nodes.len(), 4);
+	assert_eq!(graph.edges.len(), 4);
+	assert!(graph.edges.contains(&GraphEdge { from: id_c, to: id_d }));
+
+	let graph = db.graph(None, "john", GraphQuery { depth: 5, limit: 2 });
+	assert_eq!(graph.nodes.len(), 2);
+	assert!(graph.truncated);
+
+	assert!(db.graph(Some(id_a), "nina", Default::default()).nodes.is_empty());
+
+	let graph = db.graph_all("john");
+	assert_eq!(graph.nodes.len(), 4);
+	let dot = graph.to_dot();
+	assert!(dot.contains(&format!("\"{id_b}\" -> \"{id_d}\";")));
+	assert!(dot.contains("[label=\"D \\\"quoted\\\""));
+	let graphml = graph.to_graphml();
+	assert!(graphml.contains(&format!("<edge source=\"{id_a}\" target=\"{id_c}\"/>")));
+	assert!(graphml.contains("D &quot;quoted&quot;"));
+}
+#[test]
+fn transfer() {
+	let mut db = DB::default();
+
+	let c_notes: DBChunk = "# Notes\nschema: warn\nfield estimate: number\nshare: poca r, nina w".into();
+	let id_notes = c_notes.chunk().id;
+	assert!(db.set_chunk(c_notes, "john").is_ok());
+	let c_child: DBChunk = (None, format!("# Child -> {id_notes}\nestimate: 3").as_str(), "john").into();
+	let id_child = c_child.chunk().id;
+	assert!(db.set_chunk(c_child, "john").is_ok());
+	let c_other: DBChunk = (None, format!("# Other -> {id_child}\nshare: john a").as_str(), "poca").into();
+	let id_other = c_other.chunk().id;
+	assert!(db.set_chunk(c_other, "poca").is_ok());
+
+	let to_nina = || TransferIn {
+		to: "nina".into(),
+		subtree: true,
+		keep_admin: true,
+	};
+	// Only owners can transfer
+	assert!(db.transfer_request(id_notes, "nina", to_nina()).is_err());
+	assert!(db.transfer_request(id_notes, "john", to_nina()).is_ok());
+	assert_eq!(db.get_transfers("nina").len(), 1);
+	// Only the recipient can accept
+	assert!(db.transfer_accept(id_notes, "poca").is_err());
+	assert_eq!(
+		db.transfer_accept(id_notes, "nina"),
+		Ok(HashSet::from(["john".into(), "nina".into()]))
+	);
+	assert!(db.get_transfers("nina").is_empty());
+
+	let owner = |id| db.get_chunk_(id).unwrap().read().unwrap().chunk().owner.clone();
+	assert_eq!(owner(id_notes), "nina");
+	assert_eq!(owner(id_child), "nina");
+	// Not john's to give
+	assert_eq!(owner(id_other), "poca");
+	// Still typed by the schema above
+	let estimate = db.get_chunk_(id_child).unwrap().read().unwrap().get_prop::<Value>("estimate");
+	assert_eq!(estimate, Some(json!(3)));
+
+	let notes = db.get_chunk(id_notes, "john").unwrap();
+	let notes = notes.read().unwrap();
+	assert!(notes.has_access(&("john", Access::Admin).into()));
+	assert!(notes.has_access(&"poca".into()));
+	assert_eq!(notes.children(None).len(), 1);
+	assert!(notes.chunk().value.contains("\nshare: "));
+
+	// Failing leaves the transfer pending, encrypted chunks need nina's key first
+	let c_secret: DBChunk = "# Secret\n".into();
+	let id_secret = c_secret.chunk().id;
+	assert!(db.set_chunk(c_secret, "john").is_ok());
+	let keys = ChunkKeys {
+		id: id_secret,
+		keys: [("john".to_string(), "wrapped".to_string())].into(),
+		..Default::default()
+	};
+	db.encrypted.insert(id_secret, keys);
+	assert!(db.transfer_request(id_secret, "john", to_nina()).is_ok());
+	assert!(db.transfer_accept(id_secret, "nina").is_err());
+	assert_eq!(db.get_transfers("nina").len(), 1);
+}
+#[test]
+fn share_bulk() {
+	let mut db = DB::default();
+
+	let ids = (0..3)
+		.map(|i| {
+			let chunk: DBChunk = format!("# Note {i}\nshare: poca a").as_str().into();
+			let id = chunk.chunk().id;
+			assert!(db.set_chunk(chunk, "john").is_ok());
+			id
+		})
+		.collect::<HashSet<_>>();
+
+	assert_eq!(
+		db.share_bulk(
+			BulkShare {
+				ids: ids.clone(),
+				add: HashSet::from([("nina", Access::Write).into()]),
+				remove: HashSet::from([("poca", Access::Write).into()]),
+			},
+			"john",
+		),
+		Ok(HashSet::from(["nina".into(), "poca".into()]))
+	);
+	for id in ids.iter() {
+		let chunk = db.get_chunk(*id, "john").unwrap();
+		let chunk = chunk.read().unwrap();
+		assert!(chunk.has_access(&("nina", Access::Write).into()));
+		assert!(chunk.has_access(&("nina", Access::Read).into()));
+		assert!(!chunk.has_access(&("poca", Access::Write).into()));
+		assert!(chunk.has_access(&("poca", Access::Read).into()));
+	}
+
+	// Nina isn't an admin of these
+	assert!(db
+		.share_bulk(
+			BulkShare {
+				ids,
+				remove: HashSet::from(["poca".into()]),
+				..Default::default()
+			},
+			"nina",
+		)
+		.is_err());
+
+	// All or nothing, isa has no key to the encrypted one
+	let c_secret: DBChunk = "# Secret\n".into();
+	let id_secret = c_secret.chunk().id;
+	assert!(db.set_chunk(c_secret, "john").is_ok());
+	let keys = ChunkKeys {
+		id: id_secret,
+		keys: [("john".to_string(), "wrapped".to_string())].into(),
+		..Default::default()
+	};
+	db.encrypted.insert(id_secret, keys);
+	let share = BulkShare {
+		ids: ids.iter().copied().chain([id_secret]).collect(),
+		add: HashSet::from([("isa", Access::Read).into()]),
+		..Default::default()
+	};
+	assert!(db.share_bulk(share, "john").is_err());
+	assert!(ids.iter().all(|id| db.get_chunk(*id, "isa").is_none()));
+}
+#[test]
+fn webhooks() {
+	let mut db = DB::default();
+
+	let c_notes: DBChunk = "# Notes\nshare: nina r".into();
+	let id_notes = c_notes.chunk().id;
+	assert!(db.set_chunk(c_notes, "john").is_ok());
+	let c_child: DBChunk = (None, format!("# Child -> {id_notes}\nstatus: done").as_str(), "john").into();
+	let id_child = c_child.chunk().id;
+	assert!(db.set_chunk(c_child, "john").is_ok());
+
+	let hook = |url: &str, filter| WebhookIn {
+		url: url.into(),
+		events: HashSet::from([WebhookEvent::Edited]),
+		filter,
+		active: true,
+	};
+	assert!(db.webhook_new("john", hook("ftp://nope", Default::default())).is_err());
+	for url in [
+		"http://localhost:4002/x",
+		"http://127.0.0.1/",
+		"http://[::1]:80/",
+		"http://169.254.169.254/latest",
+	] {
+		assert!(db.webhook_new("john", hook(url, Default::default())).is_err(), "{url}");
+	}
+	let w_subtree = db
+		.webhook_new(
+			"john",
+			hook(
+				"https://a",
+				WebhookFilter {
+					subtree: Some(id_notes),
+					..Default::default()
+				},
+			),
+		)
+		.unwrap();
+	let w_prop = db
+		.webhook_new(
+			"john",
+			hook(
+				"https://b",
+				WebhookFilter {
+					prop: Some("status".into()),
+					value: Some("todo".into()),
+					..Default::default()
+				},
+			),
+		)
+		.unwrap();
+	// Nina can't see the child
+	db.webhook_new("nina", hook("https://c", Default::default())).unwrap();
+
+	let jobs = |db: &DB, id| {
+		let mut urls = db
+			.webhook_jobs(id, WebhookEvent::Edited, "john", None)
+			.into_iter()
+			.map(|j| j.url)
+			.collect::<Vec<_>>();
+		urls.sort();
+		urls
+	};
+	assert_eq!(jobs(&db, id_child), vec!["https://a"]);
+	assert_eq!(jobs(&db, id_notes), vec!["https://a", "https://c"]);
+	assert!(db.webhook_jobs(id_child, WebhookEvent::Deleted, "john", None).is_empty());
+
+	db.webhook_mod(
+		w_prop.id,
+		"john",
+		hook(
+			"https://b",
+			WebhookFilter {
+				prop: Some("status".into()),
+				value: Some("done".into()),
+				..Default::default()
+			},
+		),
+	)
+	.unwrap();
+	assert_eq!(jobs(&db, id_child), vec!["https://a", "https://b"]);
+
+	// Only the owner manages a webhook
+	assert!(db.webhook_del(w_subtree.id, "nina").is_err());
+	assert!(db.webhook_del(w_subtree.id, "john").is_ok());
+	assert_eq!(db.get_webhooks("john").len(), 1);
+}
+
+#[test]
+fn comments() {
+	let mut db = DB::default();
+
+	let value = "# Spec\nshare: poca r\nThe quick fox\nJumps high\n";
+	let c_spec: DBChunk = value.into();
+	let id_spec = c_spec.chunk().id;
+	assert!(db.set_chunk(c_spec, "john").is_ok());
+
+	let start = value.find("quick").unwrap();
+	let quick = || ThreadIn {
+		start,
+		end: start + 5,
+		text: "Which fox?".into(),
+	};
+	// Read access is enough, no access or public isn't
+	assert!(db.thread_new(id_spec, "nina", quick()).is_err());
+	assert!(db.thread_new(id_spec, "public", quick()).is_err());
+	assert!(db
+		.thread_new(id_spec, "poca", ThreadIn { end: 1000, ..quick() })
+		.is_err());
+	let thread = db.thread_new(id_spec, "poca", quick()).unwrap();
+	assert_eq!(thread.anchor.quote, "quick");
+	let thread = db.thread_reply(id_spec, thread.id, "john", "The brown one".into()).unwrap();
+	assert_eq!(thread.participants(), HashSet::from(["john".into(), "poca".into()]));
+	assert!(db.thread_resolve(id_spec, thread.id, "poca", true).unwrap().resolved);
+	assert!(!db.thread_resolve(id_spec, thread.id, "john", false).unwrap().resolved);
+
+	let anchor = |db: &DB| db.get_threads(id_spec, "john").unwrap()[0].clone();
+	// Lines added before move the anchor along
+	let value = "# Spec\nshare: poca r\nIntro\nThe quick fox\nJumps high\n";
+	assert!(db.set_chunk((id_spec, value).into(), "john").is_ok());
+	let start = value.find("quick").unwrap();
+	assert_eq!((anchor(&db).anchor.start, anchor(&db).anchor.end), (start, start + 5));
+	// The anchored line changed, but the text is still there
+	let value = "# Spec\nshare: poca r\nIntro\nA quick fox\nJumps high\n";
+	assert!(db.set_chunk((id_spec, value).into(), "john").is_ok());
+	let start = value.find("quick").unwrap();
+	assert_eq!((anchor(&db).anchor.start, anchor(&db).anchor.end), (start, start + 5));
+	assert!(!anchor(&db).detached);
+	// The text is gone
+	let value = "# Spec\nshare: poca r\nIntro\nA slow fox\nJumps high\n";
+	assert!(db.set_chunk((id_spec, value).into(), "john").is_ok());
+	assert!(anchor(&db).detached);
+	assert!(anchor(&db).anchor.quote.is_empty());
+
+	// Only the starter or Admins delete threads
+	let thread = anchor(&db);
+	assert!(db.thread_del(id_spec, thread.id, "nina").is_err());
+	let other = db
+		.thread_new(id_spec, "john", ThreadIn { start: 0, end: 6, text: "Title?".into() })
+		.unwrap();
+	assert_eq!(db.thread_del(id_spec, other.id, "poca"), Err(common::utils::DbError::AuthError));
+	assert!(db.thread_del(id_spec, thread.id, "john").is_ok());
+
+	// Zero-width anchors move by position, until their line is gone
+	let high = value.find("high").unwrap();
+	let caret = db
+		.thread_new(id_spec, "poca", ThreadIn { start: high, end: high, text: "Here?".into() })
+		.unwrap();
+	let caret = |db: &DB| db.get_threads(id_spec, "john").unwrap().into_iter().find(|t| t.id == caret.id).unwrap();
+	let value = "# Spec\nshare: poca r\nIntro\nMore\nA slow fox\nJumps high\n";
+	assert!(db.set_chunk((id_spec, value).into(), "john").is_ok());
+	let high = value.find("high").unwrap();
+	assert_eq!((caret(&db).anchor.start, caret(&db).anchor.end), (high, high));
+	assert!(!caret(&db).detached);
+	let value = "# Spec\nshare: poca r\nIntro\nMore\nA slow fox\n";
+	assert!(db.set_chunk((id_spec, value).into(), "john").is_ok());
+	assert!(caret(&db).detached);
+
+	// Threads go along with their chunk
+	assert!(db.del_chunk([id_spec].into(), "john").is_ok());
+	assert!(db.threads.is_empty());
+}
+
+/// Keys at full cost make tests crawl
+fn cheap_keys(db: &mut DB) {
+	db.kdf = Kdf {
+		memory: 1 << 8,
+		..Default::default()
+	};
+}
+fn key_new(db: &mut DB, user: &str, secret: &str) -> Result<String, common::utils::DbError> {
+	db.key_add(UserKey::generate(user, secret, db.kdf)?)
+}
+fn unlock(db: &DB, user: &str, secret: &str) -> Unlocked {
+	db.keys[user].unlock(secret, db.kdf).unwrap()
+}
+
+#[test]
+fn encryption() {
+	let mut db = DB::default();
+	cheap_keys(&mut db);
+
+	let value = "# Vault\nshare: poca r\nwifi: hunter2";
+	let c_vault: DBChunk = value.into();
+	let id_vault = c_vault.chunk().id;
+	assert!(db.set_chunk(c_vault, "john").is_ok());
+	let start = value.find("hunter2").unwrap();
+	let on_wifi = || ThreadIn {
+		start,
+		end: start + 7,
+		text: "Too short".into(),
+	};
+	assert!(db.thread_new(id_vault, "poca", on_wifi()).is_ok());
+
+	// Everyone with access needs a key first
+	assert!(key_new(&mut db, "john", "short").is_err());
+	assert!(key_new(&mut db, "john", "correct horse").is_ok());
+	assert!(key_new(&mut db, "john", "correct horse").is_err());
+	assert!(db.encrypt(id_vault, "john").is_err());
+	assert!(key_new(&mut db, "poca", "battery staple").is_ok());
+	// Only Admins can encrypt
+	assert_eq!(db.encrypt(id_vault, "poca"), Err(common::utils::DbError::AuthError));
+	assert!(db.encrypt(id_vault, "john").is_ok());
+	assert!(db.is_encrypted(id_vault));
+
+	{
+		let vault = db.get_chunk(id_vault, "john").unwrap();
+		let vault = vault.read().unwrap();
+		assert!(!vault.chunk().value.contains("hunter2"));
+		assert_eq!(vault.get_prop::<String>("title"), Some("Vault".into()));
+		assert!(vault.has_access(&"poca".into()));
+	}
+	assert!(!serde_json::to_string(&db).unwrap().contains("hunter2"));
+
+	assert_eq!(db.decrypt(id_vault, &unlock(&db, "poca", "battery staple")), Ok(value.into()));
+	assert_eq!(
+		db.keys["poca"].unlock("wrong secret", db.kdf).err(),
+		Some(common::utils::DbError::AuthError)
+	);
+	// Ciphertext can't be edited without a secret
+	assert!(db.set_chunk((id_vault, value).into(), "john").is_err());
+
+	let value = "# Vault\nshare: nina r\nwifi: hunter3";
+	let john = unlock(&db, "john", "correct horse");
+	assert!(db.set_encrypted(id_vault, &john, value).is_err());
+	assert!(key_new(&mut db, "nina", "open sesame").is_ok());
+	assert_eq!(
+		db.set_encrypted(id_vault, &john, value),
+		Ok(HashSet::from(["nina".into(), "poca".into()]))
+	);
+	let nina = unlock(&db, "nina", "open sesame");
+	assert_eq!(db.decrypt(id_vault, &nina), Ok(value.into()));
+	assert!(db.decrypt(id_vault, &unlock(&db, "poca", "battery staple")).is_err());
+	assert!(db.thread_new(id_vault, "nina", on_wifi()).is_ok());
+	let json = serde_json::to_string(&db).unwrap();
+	assert!(!json.contains("hunter2") && !json.contains("hunter3"));
+
+	assert!(db.unencrypt(id_vault, &nina).is_err());
+	assert!(db.unencrypt(id_vault, &john).is_ok());
+	assert!(!db.is_encrypted(id_vault));
+	assert_eq!(db.get_chunk(id_vault, "john").unwrap().read().unwrap().chunk().value, value);
+}
+
+#[test]
+fn schema() {
+	let mut db = DB::default();
+
+	let c_tasks: DBChunk = "# Tasks\nschema: strict\nfield estimate: number required\nfield status: enum(todo, doing, done) = todo\nfield due: date".into();
+	let id_tasks = c_tasks.chunk().id;
+	assert!(db.set_chunk(c_tasks, "john").is_ok());
+	assert_eq!(db.get_schemas("john")[0].fields.len(), 3);
+
+	let c_fix: DBChunk = (None, format!("# Fix -> {id_tasks}\nestimate: three").as_str(), "john").into();
+	assert_eq!(
+		db.set_chunk(c_fix, "john"),
+		Err(common::utils::DbError::InvalidChunk("estimate: has to be a number".into()))
+	);
+	let c_fix: DBChunk = (None, format!("# Fix -> {id_tasks}\nestimate: 3\ndue: 2023/09/30").as_str(), "john").into();
+	let id_fix = c_fix.chunk().id;
+	assert!(db.set_chunk(c_fix, "john").is_ok());
+	{
+		let fix = db.get_chunk(id_fix, "john").unwrap();
+		let fix = fix.read().unwrap();
+		assert_eq!(fix.get_prop::<Value>("estimate"), Some(json!(3)));
+		assert_eq!(fix.get_prop::<Value>("status"), Some(json!("todo")));
+		assert_eq!(fix.get_prop::<Value>("due"), Some(json!("2023-09-30")));
+	}
+	// Applies to the whole subtree
+	let c_sub: DBChunk = (None, format!("# Sub -> {id_fix}\nstatus: doing").as_str(), "john").into();
+	assert_eq!(
+		db.set_chunk(c_sub, "john"),
+		Err(common::utils::DbError::InvalidChunk("estimate: is required".into()))
+	);
+
+	// Lenient schemas save with warnings
+	let c_ideas: DBChunk = "# Ideas\nschema: warn\nfield size: enum(s, m, l)".into();
+	let id_ideas = c_ideas.chunk().id;
+	assert!(db.set_chunk(c_ideas, "john").is_ok());
+	let c_idea: DBChunk = (None, format!("# Idea -> {id_ideas}\nsize: xl").as_str(), "john").into();
+	let id_idea = c_idea.chunk().id;
+	assert!(db.set_chunk(c_idea, "john").is_ok());
+	assert_eq!(
+		db.get_chunk(id_idea, "john").unwrap().read().unwrap().get_prop::<Value>("schema_warnings"),
+		Some(json!(["size: has to be one of s, m, l"]))
+	);
+
+	// Types survive a reload
+	let mut db: DB = serde_json::from_str(&serde_json::to_string(&db).unwrap()).unwrap();
+	assert_eq!(
+		db.get_chunk(id_fix, "john").unwrap().read().unwrap().get_prop::<Value>("estimate"),
+		Some(json!(3))
+	);
+
+	// Changing a schema re-types what's under it
+	let c_tasks: DBChunk = (id_tasks, "# Tasks\nschema: warn\nfield estimate: text").into();
+	db.set_chunk(c_tasks, "john").unwrap();
+	let prop = |db: &DB, id, prop: &str| {
+		db.get_chunk(id, "john").unwrap().read().unwrap().get_prop::<Value>(prop)
+	};
+	assert_eq!(prop(&db, id_fix, "estimate"), Some(json!("3")));
+
+	// So does moving a chunk under another schema, and out from under it
+	let c_sub: DBChunk = (None, format!("# Sub -> {id_fix}\nsize: xl").as_str(), "john").into();
+	let id_sub = c_sub.chunk().id;
+	db.set_chunk(c_sub, "john").unwrap();
+	assert_eq!(prop(&db, id_sub, "schema_warnings"), None);
+	let c_fix: DBChunk = (id_fix, format!("# Fix -> {id_ideas}\nestimate: 3").as_str()).into();
+	db.set_chunk(c_fix, "john").unwrap();
+	assert_eq!(prop(&db, id_sub, "schema_warnings"), Some(json!(["size: has to be one of s, m, l"])));
+	db.del_chunk(HashSet::from([id_ideas]), "john").unwrap();
+	assert_eq!(prop(&db, id_sub, "schema_warnings"), None);
+
+	// Leaving a chunk that breaks a strict schema made later still works
+	let c_rules: DBChunk = "# Rules\nschema: warn\nfield size: number".into();
+	let id_rules = c_rules.chunk().id;
+	db.set_chunk(c_rules, "john").unwrap();
+	let c_rule: DBChunk = (None, format!("# Rule -> {id_rules}\nsize: xl\nshare: nina r").as_str(), "john").into();
+	let id_rule = c_rule.chunk().id;
+	db.set_chunk(c_rule, "john").unwrap();
+	db.set_chunk((id_rules, "# Rules\nschema: strict\nfield size: number").into(), "john").unwrap();
+	assert!(db.del_chunk(HashSet::from([id_rule]), "nina").is_ok());
+	assert!(db.get_chunk(id_rule, "nina").is_none());
+	// Anything but share lines is still checked
+	let rule = format!("# Rule -> {id_rules}\nsize: xxl");
+	assert!(db.set_chunk((id_rule, rule.as_str()).into(), "john").is_err());
+}
+
+#[test]
+fn board() {
+	let mut db = DB::default();
+
+	let c_tasks: DBChunk = "# Tasks\nschema: warn\nfield status: enum(todo, doing, done)\nfield estimate: number\nshare: nina r".into();
+	let id_tasks = c_tasks.chunk().id;
+	assert!(db.set_chunk(c_tasks, "john").is_ok());
+	let mut add = |value: String| {
+		let c: DBChunk = (None, value.as_str(), "john").into();
+		let id = c.chunk().id;
+		assert!(db.set_chunk(c, "john").is_ok());
+		id
+	};
+	let id_a = add(format!("# A -> {id_tasks}\nstatus: doing\nestimate: 10\nshare: nina r"));
+	let id_b = add(format!("# B -> {id_tasks}\nstatus: todo\nestimate: 2\nshare: nina r"));
+	let id_c = add(format!("# C -> {id_tasks}\nshare: nina r"));
+
+	let board = db.board(Some(id_tasks), "john", BoardQuery::default());
+	// Columns follow the schema, cards without status go last
+	let columns = board
+		.columns
+		.iter()
+		.map(|c| (c.value.clone(), c.cards.iter().map(|v| v.id).collect::<Vec<_>>()))
+		.collect::<Vec<_>>();
+	assert_eq!(
+		columns,
+		vec![
+			(Some("todo".into()), vec![id_b]),
+			(Some("doing".into()), vec![id_a]),
+			(Some("done".into()), vec![]),
+			(None, vec![id_c]),
+		]
+	);
+
+	let table = db.table(
+		Some(id_tasks),
+		"john",
+		TableQuery {
+			columns: "estimate, status".into(),
+			sort: Some("estimate".into()),
+			desc: false,
+		},
+	);
+	assert_eq!(table.columns, vec!["estimate".to_string(), "status".to_string()]);
+	// Numerically, missing last
+	assert_eq!(table.rows.iter().map(|r| r.id).collect::<Vec<_>>(), vec![id_b, id_a, id_c]);
+
+	// Moving a card rewrites its line, with the usual permission checks
+	let value = db.get_chunk_(id_b).unwrap().read().unwrap().chunk().value.clone();
+	let moved = set_prop_line(&value, "status", "done").unwrap();
+	assert_eq!(moved, format!("# B -> {id_tasks}\nstatus: done\nestimate: 2\nshare: nina r"));
+	assert!(set_prop_line(&value, "access", "john a").is_none());
+	assert!(db.set_chunk((id_b, moved.as_str()).into(), "nina").is_err());
+	assert!(db.set_chunk((id_b, moved.as_str()).into(), "john").is_ok());
+	assert_eq!(
+		set_prop_line("# New\n", "status", "todo").unwrap(),
+		"# New\nstatus: todo\n"
+	);
+
+	// A card typed by another parent's schema doesn't change the board's columns
+	let c_bugs: DBChunk = "# Bugs\nschema: warn\nfield status: enum(new, fixed)".into();
+	let id_bugs = c_bugs.chunk().id;
+	assert!(db.set_chunk(c_bugs, "john").is_ok());
+	let c_d: DBChunk = (None, format!("# D -> {id_bugs}, {id_tasks}\nstatus: new").as_str(), "john").into();
+	assert!(db.set_chunk(c_d, "john").is_ok());
+	let board = db.board(Some(id_tasks), "john", BoardQuery::default());
+	assert_eq!(
+		board.columns.iter().map(|c| c.value.as_deref()).collect::<Vec<_>>(),
+		vec![Some("todo"), Some("doing"), Some("done"), Some("new"), None]
+	);
+}
+
+#[test]
+fn integrity() {
+	use super::{def::DBData, integrity::Issue};
+
+	let [a, b, c, d, e, f] = [1, 2, 3, 4, 5, 6].map(ChunkId::from);
+	let missing = ChunkId::from(99);
+	let chunk = |id: ChunkId, value: String| Chunk {
+		owner: "john".into(),
+		..Chunk::from((id, value.as_str()))
+	};
+	let data = DBData {
+		chunks: vec![
+			chunk(a, "# A\n".into()),
+			chunk(b, format!("# B -> {a}\n")),
+			chunk(c, format!("# C -> {c}, {a}\n")),
+			chunk(d, format!("# D -> {missing}\n")),
+			chunk(e, format!("# E -> {f}\n")),
+			chunk(f, format!("# F -> {e}\n")),
+		],
+		..Default::default()
+	};
+
+	let db = DB::from(data);
+	assert!(db.check().is_empty());
+	let value = |id| db.get_chunk_(id).unwrap().read().unwrap().chunk().value.clone();
+	assert_eq!(value(b), format!("# B -> {a}\n"));
+	assert_eq!(value(c), format!("# C -> {a}\n"));
+	assert_eq!(value(d), "# D\n");
+	// One of the edges in the cycle is gone
+	assert!(value(e) == "# E\n" || value(f) == "# F\n");
+	let parents = db.get_chunk_(c).unwrap().read().unwrap().parents(None);
+	assert_eq!(parents.len(), 1);
+	assert_eq!(db.get_chunk_(a).unwrap().read().unwrap().children(None).len(), 2);
+
+	// Pointers gone stale are found and rebuilt
+	let mut db = db;
+	db.get_chunk_(b).unwrap().write().unwrap().parents.clear();
+	assert_eq!(db.check(), vec![Issue::StaleLink { chunk: b }]);
+	assert_eq!(db.repair(), vec![Issue::StaleLink { chunk: b }]);
+	assert!(db.check().is_empty());
+
+	// Parent chains longer than the stack is deep, closing into one big cycle
+	let mut db = DB::default();
+	let n = 100_000;
+	for i in 1..=n {
+		let parent = ChunkId::from(if i == 1 { n } else { i - 1 });
+		let chunk = DBChunk::from((ChunkId::from(i), format!("# C{i} -> {parent}\n").as_str(), "john"));
+		db.chunks.insert(ChunkId::from(i), std::sync::Arc::new(std::sync::RwLock::new(chunk)));
+	}
+	let issues = db.graph_issues();
+	assert!(matches!(&issues[..], [Issue::Cycle { chunks }] if chunks.len() == n as usize));
+}
+
+#[test]
+fn media_refs() {
+	let mut db = DB::default();
+	cheap_keys(&mut db);
+
+	let c_trip: DBChunk = "# Trip\n(image/lusab_babad)\n(video/gutih_tugad) and (media/lusab_babad)".into();
+	let id_trip = c_trip.chunk().id;
+	let c_notes: DBChunk = "# Notes\nshare: poca r\n(image/lusab_babad)".into();
+	let id_notes = c_notes.chunk().id;
+	assert!(db.set_chunk(c_trip, "john").is_ok());
+	assert!(db.set_chunk(c_notes, "john").is_ok());
+
+	let refs = db.media_by_owner("john");
+	assert_eq!(refs.get("lusab_babad"), Some(&2));
+	assert_eq!(refs.get("gutih_tugad"), Some(&1));
+	assert!(db.media_by_owner("poca").is_empty());
+	// Only answers for ids asked about
+	let referenced = db.media_referenced(&["gutih_tugad".into(), "dasab_lusab".into()]);
+	assert_eq!(referenced.len(), 1);
+	assert_eq!(referenced.get("gutih_tugad"), Some(&1));
+
+	// Edits and deletes drop references
+	assert!(db.set_chunk(DBChunk::from((id_trip, "# Trip\nNo pictures", "john")), "john").is_ok());
+	assert!(db.media_referenced(&["gutih_tugad".into()]).is_empty());
+	assert!(db.del_chunk([id_trip].into(), "john").is_ok());
+	assert_eq!(db.media_by_owner("john").get("lusab_babad"), Some(&1));
+
+	// Encrypted chunks still count
+	assert!(key_new(&mut db, "john", "correct horse").is_ok());
+	assert!(key_new(&mut db, "poca", "battery staple").is_ok());
+	assert!(db.encrypt(id_notes, "john").is_ok());
+	assert_eq!(db.media_by_owner("john").get("lusab_babad"), Some(&1));
+	let john = unlock(&db, "john", "correct horse");
+	assert!(db
+		.set_encrypted(id_notes, &john, "# Notes\nshare: poca r\n(video/gutih_tugad)")
+		.is_ok());
+	assert!(db.media_referenced(&["lusab_babad".into()]).is_empty());
+	assert_eq!(db.media_by_owner("john").get("gutih_tugad"), Some(&1));
+
+	// Rebuilt on load
+	let db = DB::from(super::def::DBData::from(&db));
+	assert_eq!(db.media_by_owner("john").get("gutih_tugad"), Some(&1));
+}
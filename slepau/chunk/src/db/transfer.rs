@@ -0,0 +1,217 @@
+use common::utils::{get_secs, DbError, LockedAtomic};
+use log::error;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{HashSet, VecDeque};
+
+use super::{
+	chunk::ChunkId,
+	dbchunk::DBChunk,
+	user_access::{Access, UserAccess},
+	DB,
+};
+
+/// A pending ownership transfer, the recipient has to accept it
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Transfer {
+	/// Root chunk being transferred
+	pub id: ChunkId,
+	pub from: String,
+	pub to: String,
+	/// Also transfer every chunk under root that `from` owns
+	pub subtree: bool,
+	/// `from` stays on as an Admin
+	pub keep_admin: bool,
+	pub created: u64,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct TransferIn {
+	pub to: String,
+	pub subtree: bool,
+	pub keep_admin: bool,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct BulkShare {
+	pub ids: HashSet<ChunkId>,
+	/// Added along with every access they imply
+	pub add: HashSet<UserAccess>,
+	/// Removed along with every access above them
+	pub remove: HashSet<UserAccess>,
+}
+
+impl DB {
+	/// Pending transfers sent or received by user
+	pub fn get_transfers(&self, user: &str) -> Vec<Transfer> {
+		self
+			.transfers
+			.values()
+			.filter(|t| t.from == user || t.to == user)
+			.cloned()
+			.collect()
+	}
+
+	/// Offers chunk `id` (and optionally its subtree) to another user, only owners can do this
+	pub fn transfer_request(
+		&mut self,
+		id: ChunkId,
+		user: &str,
+		transfer: TransferIn,
+	) -> Result<Transfer, DbError> {
+		// public assertion
+		if user == "public" || transfer.to == "public" {
+			return Err(DbError::AuthError);
+		}
+		if transfer.to == user || transfer.to.is_empty() {
+			return Err(DbError::InvalidUsername("Can't transfer to yourself."));
+		}
+		let chunk = self.get_chunk(id, user).ok_or(DbError::NotFound)?;
+		if chunk.read().unwrap().chunk().owner != user {
+			return Err(DbError::AuthError);
+		}
+
+		let transfer = Transfer {
+			id,
+			from: user.to_owned(),
+			to: transfer.to,
+			subtree: transfer.subtree,
+			keep_admin: transfer.keep_admin,
+			created: get_secs(),
+		};
+		self.transfers.insert(id, transfer.clone());
+		Ok(transfer)
+	}
+
+	/// Recipient declines or sender cancels
+	pub fn transfer_cancel(&mut self, id: ChunkId, user: &str) -> Result<Transfer, DbError> {
+		match self.transfers.get(&id) {
+			Some(t) if t.from == user || t.to == user => Ok(self.transfers.remove(&id).unwrap()),
+			Some(_) => Err(DbError::AuthError),
+			None => Err(DbError::NotFound),
+		}
+	}
+
+	/// Recipient accepts, returns the list of users for which access changed.
+	///
+	/// The transfer stays pending until it went through, so it can be retried.
+	pub fn transfer_accept(&mut self, id: ChunkId, user: &str) -> Result<HashSet<String>, DbError> {
+		let transfer = match self.transfers.get(&id) {
+			Some(t) if t.to == user => t.clone(),
+			Some(_) => return Err(DbError::AuthError),
+			None => return Err(DbError::NotFound),
+		};
+		let root = self.get_chunk_(id).filter(|r| r.read().unwrap().chunk().owner == transfer.from);
+		let Some(root) = root else {
+			// Won't ever go through
+			error!("Transfer of {id} is stale, {} isn't the owner anymore.", transfer.from);
+			self.transfers.remove(&id);
+			return Err(DbError::AuthError);
+		};
+
+		// Only what `from` owns gets transferred, never someone else's chunks
+		let mut chunks = vec![];
+		let mut seen = HashSet::<ChunkId>::default();
+		let mut queue = VecDeque::from([root]);
+		while let Some(chunk) = queue.pop_front() {
+			let lock = chunk.read().unwrap();
+			if !seen.insert(lock.chunk().id) || lock.chunk().owner != transfer.from {
+				continue;
+			}
+			if transfer.subtree {
+				queue.extend(lock.children(None));
+			}
+			drop(lock);
+			chunks.push(chunk);
+		}
+
+		// Encrypted chunks have to be shared with the recipient beforehand
+		for chunk in chunks.iter() {
+			let id = chunk.read().unwrap().chunk().id;
+			if let Some(keys) = self.encrypted.get(&id) {
+				if !keys.keys.contains_key(&transfer.to) {
+					return Err(DbError::Custom(format!("{id} is encrypted and not shared with {}.", transfer.to)));
+				}
+			}
+		}
+
+		// Typed like set_chunk would, all of them before inserting any
+		let mut changed = HashSet::default();
+		let mut others = vec![];
+		for chunk in chunks {
+			let mut other = {
+				let chunk = chunk.read().unwrap();
+				let other = chunk.transfer_to(&transfer.to, transfer.keep_admin);
+				changed.extend(chunk.access_diff(Some(&other)));
+				other
+			};
+			self.apply_schema(&mut other)?;
+			others.push(other);
+		}
+		for other in others {
+			let (id, users) = (other.chunk().id, other.access_users());
+			self.insert_chunk(other)?;
+			self.prune_encrypted(id, &users);
+		}
+		self.transfers.remove(&id);
+
+		Ok(changed)
+	}
+
+	/// Adds and removes access on many chunks at once, user has to be an Admin of all of them.
+	///
+	/// Returns the list of users for which access changed.
+	pub fn share_bulk(&mut self, share: BulkShare, user: &str) -> Result<HashSet<String>, DbError> {
+		// public assertion
+		if user == "public" {
+			return Err(DbError::AuthError);
+		}
+		if share.add.iter().any(|ua| ua.access == Access::Owner) {
+			return Err(DbError::InvalidChunk("Ownership can only be transferred.".into()));
+		}
+
+		// Check everything before changing anything
+		let chunks = share
+			.ids
+			.iter()
+			.map(|id| {
+				let chunk = self.get_chunk(*id, user).ok_or(DbError::NotFound)?;
+				if !chunk.read().unwrap().has_access(&(user, Access::Admin).into()) {
+					return Err(DbError::AuthError);
+				}
+				Ok(chunk)
+			})
+			.collect::<Result<Vec<LockedAtomic<DBChunk>>, DbError>>()?;
+
+		// Checked like set_chunk would, all of them before setting any
+		let mut others = vec![];
+		for chunk in chunks {
+			let chunk = chunk.read().unwrap();
+			let mut other = DBChunk::from((chunk.chunk().id, chunk.chunk().value.as_str(), chunk.chunk().owner.as_str()));
+			let mut access = other.get_prop::<HashSet<UserAccess>>("access").unwrap_or_default();
+			access.retain(|ua| {
+				!share
+					.remove
+					.iter()
+					.any(|r| r.user == ua.user && r.access <= ua.access)
+			});
+			access.extend(share.add.iter().flat_map(|ua| ua.implied()));
+			other.r#override("access", json!(access));
+
+			if !chunk.try_clone_to(&mut other, user) {
+				return Err(DbError::AuthError);
+			}
+			self.check_encrypted(&chunk, &other)?;
+			others.push(other);
+		}
+
+		let mut changed = HashSet::default();
+		for other in others {
+			changed.extend(self.set_chunk(other, user)?);
+		}
+
+		Ok(changed)
+	}
+}
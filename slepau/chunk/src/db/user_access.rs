@@ -28,3 +28,13 @@ impl From<&str> for UserAccess {
 		Self::from((user.to_string(), Access::default()))
 	}
 }
+impl UserAccess {
+	/// Every access this one implies, Admin implies Write which implies Read
+	pub fn implied(&self) -> Vec<UserAccess> {
+		[Access::Read, Access::Write, Access::Admin]
+			.into_iter()
+			.filter(|a| a <= &self.access)
+			.map(|a| (self.user.clone(), a).into())
+			.collect()
+	}
+}
@@ -1,7 +1,10 @@
-use std::sync::RwLockWriteGuard;
+use std::{
+	collections::{HashMap, HashSet},
+	sync::RwLockWriteGuard,
+};
 
-use common::utils::LockedAtomic;
-use serde::Serialize;
+use common::{socket::ResourceMessage, utils::LockedAtomic};
+use serde::{Deserialize, Serialize};
 use serde_json::{Map, Value};
 
 use super::{
@@ -46,6 +49,10 @@ pub enum ViewType {
 	Notes,
 	Well,
 	Graph,
+	/// Card grouped in a column
+	Board,
+	/// Row of chosen props
+	Table,
 	// Search,
 }
 impl From<(LockedAtomic<DBChunk>, &str, ViewType)> for ChunkView {
@@ -144,6 +151,29 @@ impl From<(&LockedAtomic<DBChunk>, &str, ViewType)> for ChunkView {
 					// 	.and_then(|a| if a == Access::Owner { None } else { Some(a) }),
 					..Default::default()
 				},
+				ViewType::Board => Self {
+					id: db_chunk.chunk().id,
+					props: Some(Value::Object(Map::from_iter(db_chunk.props()))),
+					value: Some(value_short(&db_chunk)),
+					modified: Some(db_chunk.chunk().modified),
+					children: Some(db_chunk.children(Some(&user.into())).len()),
+					access: db_chunk
+						.highest_access(user)
+						.and_then(|a| if a == Access::Owner { None } else { Some(a) }),
+					..Default::default()
+				},
+				ViewType::Table => Self {
+					id: db_chunk.chunk().id,
+					props: Some(Value::Object(Map::from_iter(db_chunk.props()))),
+					props_dynamic: Some(Value::Object(Map::from_iter(db_chunk.props_dynamic(&user.into())))),
+					owner: Some(db_chunk.chunk().owner.clone()),
+					modified: Some(db_chunk.chunk().modified),
+					created: Some(db_chunk.chunk().created),
+					access: db_chunk
+						.highest_access(user)
+						.and_then(|a| if a == Access::Owner { None } else { Some(a) }),
+					..Default::default()
+				},
 				// ViewType::Search => Self {
 				// 	id: db_chunk.chunk().id,
 				// 	value: Some(value_short(&db_chunk)),
@@ -208,6 +238,23 @@ impl ChunkVec {
 		})
 	}
 }
+impl ChunkVec {
+	/// Takes a page out of an already sorted ChunkVec, the cursor itself isn't included
+	///
+	/// An unknown cursor gives an empty page, clients should start over.
+	pub fn paginate(&self, query: &CursorQuery) -> ChunkVec {
+		let position = |id: chunk::ChunkId| self.0.iter().position(|v| v.read().unwrap().chunk().id == id);
+		Self(match query.cursor {
+			Some(Cursor::After(id)) => position(id)
+				.map(|i| self.0.iter().skip(i + 1).take(query.limit).cloned().collect())
+				.unwrap_or_default(),
+			Some(Cursor::Before(id)) => position(id)
+				.map(|i| self.0[i.saturating_sub(query.limit)..i].to_vec())
+				.unwrap_or_default(),
+			None => self.0.iter().take(query.limit).cloned().collect(),
+		})
+	}
+}
 impl From<Vec<LockedAtomic<DBChunk>>> for ChunkVec {
 	fn from(v: Vec<LockedAtomic<DBChunk>>) -> Self {
 		Self(v)
@@ -218,3 +265,114 @@ impl<T: From<LockedAtomic<DBChunk>>> From<ChunkVec> for Vec<T> {
 		val.0.into_iter().map(|v| v.into()).collect()
 	}
 }
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Cursor {
+	Before(chunk::ChunkId),
+	After(chunk::ChunkId),
+}
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct CursorQuery {
+	pub cursor: Option<Cursor>,
+	pub limit: usize,
+}
+impl Default for CursorQuery {
+	fn default() -> Self {
+		Self {
+			cursor: None,
+			limit: 50,
+		}
+	}
+}
+
+/// Search over the socket, paged like any other view
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct SearchQuery {
+	pub term: String,
+	#[serde(flatten)]
+	pub query: CursorQuery,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq, Hash, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum DeltaType {
+	/// New in the view, goes on top
+	Inserted,
+	/// Was modified, goes on top
+	Moved,
+	Removed,
+}
+/// An incremental change to a sorted view, so clients don't have to refetch it
+#[derive(Serialize, Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct ViewDelta {
+	#[serde(rename = "type")]
+	pub _type: DeltaType,
+	pub id: chunk::ChunkId,
+}
+
+/**
+ * Where a chunk shows up, who sees it and under which parents.
+ * Taken before and after a change to find out which views have to be updated.
+ */
+#[derive(Default, Debug, Clone)]
+pub struct ChunkPlacement {
+	pub users: HashSet<String>,
+	pub parents: HashSet<chunk::ChunkId>,
+}
+impl From<&DBChunk> for ChunkPlacement {
+	fn from(chunk: &DBChunk) -> Self {
+		Self {
+			users: chunk.access_users(),
+			parents: chunk
+				.get_prop::<Vec<chunk::ChunkId>>("parents")
+				.unwrap_or_default()
+				.into_iter()
+				.collect(),
+		}
+	}
+}
+impl From<Option<&LockedAtomic<DBChunk>>> for ChunkPlacement {
+	fn from(chunk: Option<&LockedAtomic<DBChunk>>) -> Self {
+		chunk.map(|c| Self::from(&*c.read().unwrap())).unwrap_or_default()
+	}
+}
+impl ChunkPlacement {
+	/// Messages for `views/notes/delta` and `views/well/<parent>/delta`
+	/// that take every affected user from `self` to `after`.
+	pub fn deltas(&self, id: chunk::ChunkId, after: &ChunkPlacement) -> Vec<ResourceMessage> {
+		let mut grouped = HashMap::<(String, ViewDelta), HashSet<String>>::default();
+		let mut add = |resource: String, was: bool, is: bool, user: &String| {
+			let _type = match (was, is) {
+				(false, true) => DeltaType::Inserted,
+				(true, true) => DeltaType::Moved,
+				(true, false) => DeltaType::Removed,
+				(false, false) => return,
+			};
+			grouped
+				.entry((resource, ViewDelta { _type, id }))
+				.or_default()
+				.insert(user.clone());
+		};
+
+		for user in self.users.union(&after.users) {
+			let (was, is) = (self.users.contains(user), after.users.contains(user));
+			add("views/notes/delta".into(), was, is, user);
+			for parent in self.parents.union(&after.parents) {
+				add(
+					format!("views/well/{parent}/delta"),
+					was && self.parents.contains(parent),
+					is && after.parents.contains(parent),
+					user,
+				);
+			}
+		}
+
+		grouped
+			.into_iter()
+			.map(|((resource, delta), users)| ResourceMessage::from((resource.as_str(), users, &delta)))
+			.collect()
+	}
+}
@@ -0,0 +1,299 @@
+use common::{
+	proquint::Proquint,
+	utils::{gen_64, get_secs, DbError, LockedAtomic},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+	collections::{HashSet, VecDeque},
+	net::IpAddr,
+};
+
+use super::{chunk::ChunkId, dbchunk::DBChunk, DB};
+
+pub type WebhookId = Proquint<u32>;
+
+/// How many deliveries are kept per webhook
+const DELIVERIES_MAX: usize = 50;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum WebhookEvent {
+	Created,
+	Edited,
+	Shared,
+	Deleted,
+}
+
+/// Every Some has to match for a chunk to trigger the webhook
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct WebhookFilter {
+	/// Chunk itself or any chunk under it
+	pub subtree: Option<ChunkId>,
+	/// Chunk has prop `key`
+	pub prop: Option<String>,
+	/// Chunk has prop `key: value`, needs `prop`
+	pub value: Option<String>,
+	pub owner: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Webhook {
+	pub id: WebhookId,
+	pub user: String,
+	pub url: String,
+	/// Key for the HMAC-SHA256 signature of every payload
+	pub secret: String,
+	/// Empty means all events
+	pub events: HashSet<WebhookEvent>,
+	pub filter: WebhookFilter,
+	pub active: bool,
+	pub created: u64,
+}
+
+/// What users send to create/modify a webhook
+#[derive(Deserialize, Debug)]
+pub struct WebhookIn {
+	pub url: String,
+	#[serde(default)]
+	pub events: HashSet<WebhookEvent>,
+	#[serde(default)]
+	pub filter: WebhookFilter,
+	#[serde(default = "active_default")]
+	pub active: bool,
+}
+fn active_default() -> bool {
+	true
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Delivery {
+	pub event: WebhookEvent,
+	pub chunk: ChunkId,
+	pub time: u64,
+	pub attempts: u32,
+	/// HTTP status of the last attempt
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub status: Option<u16>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub error: Option<String>,
+	pub delivered: bool,
+}
+
+/// A payload waiting to be delivered
+#[derive(Debug, Clone)]
+pub struct WebhookJob {
+	pub webhook: WebhookId,
+	pub url: String,
+	pub secret: String,
+	pub event: WebhookEvent,
+	pub chunk: ChunkId,
+	pub payload: Value,
+}
+
+impl WebhookFilter {
+	fn matches(&self, chunk: &LockedAtomic<DBChunk>) -> bool {
+		{
+			let chunk = chunk.read().unwrap();
+			if let Some(owner) = self.owner.as_ref() {
+				if &chunk.chunk().owner != owner {
+					return false;
+				}
+			}
+			if let Some(prop) = self.prop.as_ref() {
+				match (chunk.get_prop::<Value>(prop), self.value.as_ref()) {
+					(None, _) => return false,
+					(Some(v), Some(value)) if v.as_str().map(str::trim) != Some(value.as_str()) => {
+						return false
+					}
+					_ => {}
+				}
+			}
+		}
+		if let Some(root) = self.subtree {
+			// Walk up through every parent, once
+			let mut seen = HashSet::<ChunkId>::default();
+			let mut queue = VecDeque::from([chunk.clone()]);
+			while let Some(c) = queue.pop_front() {
+				let c = c.read().unwrap();
+				if c.chunk().id == root {
+					return true;
+				}
+				if seen.insert(c.chunk().id) {
+					queue.extend(c.parents(None));
+				}
+			}
+			return false;
+		}
+		true
+	}
+}
+
+impl DB {
+	pub fn get_webhooks(&self, user: &str) -> Vec<Webhook> {
+		self.webhooks.values().filter(|w| w.user == user).cloned().collect()
+	}
+
+	pub fn webhook_new(&mut self, user: &str, webhook: WebhookIn) -> Result<Webhook, DbError> {
+		// public assertion
+		if user == "public" {
+			return Err(DbError::AuthError);
+		}
+		validate_url(&webhook.url)?;
+
+		let mut id = WebhookId::default();
+		while self.webhooks.contains_key(&id) {
+			id = WebhookId::default();
+		}
+		let webhook = Webhook {
+			id,
+			user: user.to_owned(),
+			url: webhook.url,
+			secret: format!("{:016x}{:016x}", gen_64(), gen_64()),
+			events: webhook.events,
+			filter: webhook.filter,
+			active: webhook.active,
+			created: get_secs(),
+		};
+		self.webhooks.insert(id, webhook.clone());
+		Ok(webhook)
+	}
+
+	pub fn webhook_mod(&mut self, id: WebhookId, user: &str, webhook: WebhookIn) -> Result<Webhook, DbError> {
+		validate_url(&webhook.url)?;
+		let current = self
+			.webhooks
+			.get_mut(&id)
+			.filter(|w| w.user == user)
+			.ok_or(DbError::NotFound)?;
+		current.url = webhook.url;
+		current.events = webhook.events;
+		current.filter = webhook.filter;
+		current.active = webhook.active;
+		Ok(current.clone())
+	}
+
+	pub fn webhook_del(&mut self, id: WebhookId, user: &str) -> Result<(), DbError> {
+		if self.webhooks.get(&id).filter(|w| w.user == user).is_none() {
+			return Err(DbError::NotFound);
+		}
+		self.webhooks.remove(&id);
+		self.deliveries.remove(&id);
+		Ok(())
+	}
+
+	/// Delivery log for a webhook, newest first
+	pub fn webhook_deliveries(&self, id: WebhookId, user: &str) -> Result<Vec<Delivery>, DbError> {
+		if self.webhooks.get(&id).filter(|w| w.user == user).is_none() {
+			return Err(DbError::NotFound);
+		}
+		Ok(
+			self
+				.deliveries
+				.get(&id)
+				.map(|d| d.iter().rev().cloned().collect())
+				.unwrap_or_default(),
+		)
+	}
+
+	pub fn webhook_delivered(&mut self, id: WebhookId, delivery: Delivery) {
+		if !self.webhooks.contains_key(&id) {
+			return;
+		}
+		let deliveries = self.deliveries.entry(id).or_default();
+		deliveries.push_back(delivery);
+		while deliveries.len() > DELIVERIES_MAX {
+			deliveries.pop_front();
+		}
+	}
+
+	/// Jobs for every active webhook interested in `event` on chunk `id`.
+	///
+	/// Webhooks only see chunks their user has access to.
+	/// For deletions this has to be called before the chunk is gone.
+	pub fn webhook_jobs(
+		&self,
+		id: ChunkId,
+		event: WebhookEvent,
+		user: &str,
+		diff: Option<&Vec<String>>,
+	) -> Vec<WebhookJob> {
+		let Some(chunk) = self.chunks.get(&id) else {
+			return vec![];
+		};
+		self
+			.webhooks
+			.values()
+			.filter(|w| {
+				w.active
+					&& (w.events.is_empty() || w.events.contains(&event))
+					&& chunk.read().unwrap().has_access(&w.user.as_str().into())
+					&& w.filter.matches(chunk)
+			})
+			.map(|w| WebhookJob {
+				webhook: w.id,
+				url: w.url.clone(),
+				secret: w.secret.clone(),
+				event,
+				chunk: id,
+				payload: json!({
+					"webhook": w.id,
+					"event": event,
+					"user": user,
+					"time": get_secs(),
+					"chunk": chunk.read().unwrap().chunk(),
+					"diff": diff,
+				}),
+			})
+			.collect()
+	}
+}
+
+/// Whether an address is out on the internet, webhooks can't reach into our own network
+pub fn is_public(ip: IpAddr) -> bool {
+	match ip.to_canonical() {
+		IpAddr::V4(ip) => {
+			let [a, b, ..] = ip.octets();
+			!(ip.is_loopback()
+				|| ip.is_private()
+				|| ip.is_link_local()
+				|| ip.is_unspecified()
+				|| ip.is_broadcast()
+				|| ip.is_documentation()
+				// Carrier-grade NAT, 100.64.0.0/10
+				|| (a == 100 && b & 0xc0 == 64))
+		}
+		IpAddr::V6(ip) => {
+			let first = ip.segments()[0];
+			!(ip.is_loopback()
+				|| ip.is_unspecified()
+				// Unique local, fc00::/7
+				|| first & 0xfe00 == 0xfc00
+				// Link local, fe80::/10
+				|| first & 0xffc0 == 0xfe80)
+		}
+	}
+}
+
+/// An http(s) url not pointing at us, hosts that resolve to private addresses are refused on delivery
+fn validate_url(url: &str) -> Result<(), DbError> {
+	let invalid = |msg: &str| Err(DbError::Custom(msg.into()));
+	let Ok(url) = reqwest::Url::parse(url) else {
+		return invalid("Webhook url isn't valid.");
+	};
+	if !matches!(url.scheme(), "http" | "https") {
+		return invalid("Webhook url has to be http(s).");
+	}
+	let Some(host) = url.host_str() else {
+		return invalid("Webhook url needs a host.");
+	};
+	let internal = match host.trim_matches(['[', ']']).parse::<IpAddr>() {
+		Ok(ip) => !is_public(ip),
+		Err(_) => host == "localhost" || host.ends_with(".localhost"),
+	};
+	if internal {
+		return invalid("Webhook url can't point at a private address.");
+	}
+	Ok(())
+}
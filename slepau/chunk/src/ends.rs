@@ -1,13 +1,14 @@
 use auth::UserClaims;
 use axum::{
-	extract::{Extension, Path},
+	extract::{Extension, Path, Query},
+	http::header,
 	response::IntoResponse,
 	Json, TypedHeader,
 };
 use common::{
 	socket::{ResourceMessage, ResourceSender},
-	utils::{DbError, LockedAtomic},
-	vreji::log_ip_user_id,
+	utils::{diff_calc, DbError, LockedAtomic},
+	vreji::{log_ip_user, log_ip_user_id},
 };
 use headers::ContentType;
 
@@ -19,13 +20,22 @@ use serde::Deserialize;
 use std::collections::HashSet;
 
 use crate::{
+	audit::{self, AuditQuery},
 	db::{
 		chunk::ChunkId,
+		crypt::{self, EncryptedIn, Secret},
 		dbchunk::DBChunk,
-		view::{ChunkView, ViewType},
+		graph::GraphQuery,
+		template::TemplateInstance,
+		transfer::{BulkShare, TransferIn},
+		user_access::Access,
+		view::{ChunkPlacement, ChunkView, ViewType},
+		webhook::{WebhookEvent, WebhookId, WebhookIn},
 		DB,
 	},
 	format::value_to_html,
+	seo::{robots, sitemap, PageMeta},
+	webhook::{dispatch, WebhookSender},
 };
 
 // pub async fn chunks_get(
@@ -64,10 +74,13 @@ pub async fn chunks_get_id(
 		Err(DbError::NotFound)
 	}
 }
-fn make_page(title: &str, body: &str, edit: Option<&str>) -> String {
+fn make_page(title: &str, body: &str, edit: Option<&str>, meta: Option<&PageMeta>) -> String {
 	let page = include_str!(env!("CHUNK_PAGE_PATH"));
 	let mut page = page.replace("PAGE_TITLE", title);
 	page = page.replace("PAGE_BODY", body);
+	if let Some(meta) = meta {
+		page = page.replacen("</head>", &format!("\t{}\n</head>", meta.to_html()), 1);
+	}
 	if let Some(edit) = edit {
 		page = page.replace(
 			"class=\"edit-button\"",
@@ -91,12 +104,14 @@ pub async fn page_get_id(
 	} {
 		let mut title: String = "Page".into();
 		let html;
+		let meta;
 		{
 			let lock = chunk.read().unwrap();
 			if let Some(v) = lock.get_prop::<String>("title") {
 				title = v
 			};
 			html = value_to_html(&lock.chunk().value);
+			meta = PageMeta::from(&*lock);
 		}
 		let id_str = id.to_quint();
 		let page = make_page(
@@ -107,6 +122,7 @@ pub async fn page_get_id(
 			} else {
 				None
 			},
+			Some(&meta),
 		);
 		log_ip_user_id("chunk_get_page", ip.0, &user_claims.user, id.inner().into());
 		Ok((StatusCode::OK, TypedHeader(ContentType::html()), page))
@@ -125,36 +141,36 @@ pub async fn page_get_id(
 				user_claims.user
 			),
 			None,
+			Some(&PageMeta {
+				title: "Not found".into(),
+				..Default::default()
+			}),
 		);
 		Ok((StatusCode::NOT_FOUND, TypedHeader(ContentType::html()), page))
 	}
 }
 
-async fn search_(db: LockedAtomic<DB>, user_claims: UserClaims, term: String) -> Result<impl IntoResponse, DbError> {
-	let db = db.read().unwrap();
+/// Every public page, for search engines
+pub async fn sitemap_get(Extension(db): Extension<LockedAtomic<DB>>) -> impl IntoResponse {
+	let chunks = db.read().unwrap().public_chunks();
+	([(header::CONTENT_TYPE, "application/xml")], sitemap(&chunks))
+}
 
-	if term.is_empty() {
-		return Err(DbError::Custom("Search term has to be at least 1 character.".into()));
-	}
+/// Lets crawlers into public pages only
+pub async fn robots_get(Extension(db): Extension<LockedAtomic<DB>>) -> impl IntoResponse {
+	let chunks = db.read().unwrap().public_chunks();
+	([(header::CONTENT_TYPE, "text/plain")], robots(&chunks))
+}
 
-	let regex = regex::Regex::new(format!("(?im){}", term).as_str());
+async fn search_(db: LockedAtomic<DB>, user_claims: UserClaims, term: String) -> Result<impl IntoResponse, DbError> {
+	let chunks = db.read().unwrap().search(&user_claims.user, &term)?;
 
 	Ok(Json(
-		db.get_chunks(&user_claims.user)
+		chunks
+			.0
 			.into_iter()
-			.filter_map(|chunk| {
-				let contains = if let Ok(regex) = regex.as_ref() {
-					regex.is_match(&chunk.read().unwrap().chunk().value)
-				} else {
-					chunk.read().unwrap().chunk().value.contains(&term)
-				};
-				if contains {
-					Some(ChunkView::from((chunk, user_claims.user.as_str(), ViewType::Well)))
-				} else {
-					None
-				}
-			})
 			.take(10)
+			.map(|chunk| ChunkView::from((chunk, user_claims.user.as_str(), ViewType::Well)))
 			.collect::<Vec<_>>(),
 	))
 }
@@ -184,19 +200,52 @@ pub async fn chunks_put(
 	Extension(db): Extension<LockedAtomic<DB>>,
 	Extension(user_claims): Extension<UserClaims>,
 	Extension(tx_r): Extension<ResourceSender>,
+	Extension(tx_w): Extension<WebhookSender>,
 	ip: ClientIp,
 	Json(body): Json<ChunkIn>,
 ) -> Result<impl IntoResponse, DbError> {
 	let db_chunk = DBChunk::from((body.id, body.value.as_str(), user_claims.user.as_str()));
 	let users = db_chunk.access_users();
 	let id = db_chunk.chunk().id;
+	let before = db.read().unwrap().placement(id);
+	let last_value = db
+		.read()
+		.unwrap()
+		.get_chunk_(id)
+		.map(|c| c.read().unwrap().chunk().value.clone());
 	let users_to_notify = db.write().unwrap().set_chunk(db_chunk, &user_claims.user)?;
+	// A new chunk always notifies its owner, only others mean it was shared
+	let access_changed = match &last_value {
+		Some(_) => !users_to_notify.is_empty(),
+		None => users_to_notify.iter().any(|u| *u != user_claims.user),
+	};
+
+	{
+		let db = db.read().unwrap();
+		if let Some(last_value) = last_value {
+			let diff = diff_calc(&last_value, &body.value);
+			dispatch(&db, &tx_w, id, WebhookEvent::Edited, &user_claims.user, Some(&diff));
+			if !users_to_notify.is_empty() {
+				dispatch(&db, &tx_w, id, WebhookEvent::Shared, &user_claims.user, None);
+			}
+		} else {
+			dispatch(&db, &tx_w, id, WebhookEvent::Created, &user_claims.user, None);
+		}
+	}
+
+	// Notifies users of how their views changed
+	for m in before.deltas(id, &db.read().unwrap().placement(id)) {
+		tx_r.send(m).unwrap();
+	}
 
 	// Notifies users for which access has changed
 	// They should request an update of their active view that uses chunks
 	// upon this request
 	tx_r.send(ResourceMessage::from(("chunks", users_to_notify))).unwrap();
 	log_ip_user_id("chunk_put", ip.0, &user_claims.user, id.inner().into());
+	if access_changed {
+		log_ip_user_id("chunk_access", ip.0, &user_claims.user, id.inner().into());
+	}
 	// Notifies users which already have access, of the note's new content
 	//
 	// Only do so if modifying a chunk, because a new one won't have an id.
@@ -224,16 +273,422 @@ pub async fn chunks_del(
 	Extension(db): Extension<LockedAtomic<DB>>,
 	Extension(user_claims): Extension<UserClaims>,
 	Extension(tx_r): Extension<ResourceSender>,
+	Extension(tx_w): Extension<WebhookSender>,
 	ip: ClientIp,
 	Json(input): Json<HashSet<ChunkId>>,
 ) -> Result<impl IntoResponse, DbError> {
-	let users_to_notify = db.write().unwrap().del_chunk(input.to_owned(), &user_claims.user)?;
+	let before = input
+		.iter()
+		.map(|id| (*id, db.read().unwrap().placement(*id)))
+		.collect::<Vec<_>>();
+	// Admins delete, everyone else only leaves the chunk
+	let (deleted, left): (Vec<ChunkId>, Vec<ChunkId>) = input.iter().copied().partition(|id| {
+		db.read()
+			.unwrap()
+			.get_chunk_(*id)
+			.map(|c| c.read().unwrap().has_access(&(user_claims.user.as_str(), Access::Admin).into()))
+			.unwrap_or_default()
+	});
+	// Payloads have to be built while deleted chunks still exist
+	let jobs = deleted
+		.iter()
+		.flat_map(|id| {
+			db.read()
+				.unwrap()
+				.webhook_jobs(*id, WebhookEvent::Deleted, &user_claims.user, None)
+		})
+		.collect::<Vec<_>>();
+	let users_to_notify = db.write().unwrap().del_chunk(input, &user_claims.user)?;
 
-	input.into_iter().for_each(|id| {
-		log_ip_user_id("chunk_del", ip.0, &user_claims.user, id.inner().into());
+	jobs.into_iter().for_each(|job| {
+		tx_w.send(job).ok();
 	});
+	for id in left {
+		dispatch(&db.read().unwrap(), &tx_w, id, WebhookEvent::Shared, &user_claims.user, None);
+	}
+
+	for (id, before) in before {
+		for m in before.deltas(id, &db.read().unwrap().placement(id)) {
+			tx_r.send(m).unwrap();
+		}
+		log_ip_user_id("chunk_del", ip.0, &user_claims.user, id.inner().into());
+	}
+
+	tx_r.send(ResourceMessage::from(("chunks", users_to_notify))).unwrap();
+
+	Ok(())
+}
+
+/// Creates a new chunk (or subtree) from a template, returns the new chunk's id
+pub async fn chunks_instantiate(
+	Path(id): Path<ChunkId>,
+	Extension(db): Extension<LockedAtomic<DB>>,
+	Extension(user_claims): Extension<UserClaims>,
+	Extension(tx_r): Extension<ResourceSender>,
+	Extension(tx_w): Extension<WebhookSender>,
+	ip: ClientIp,
+	Json(instance): Json<TemplateInstance>,
+) -> Result<impl IntoResponse, DbError> {
+	let (new_id, users_to_notify) =
+		db.write().unwrap().instantiate(id, &user_claims.user, instance)?;
+	dispatch(&db.read().unwrap(), &tx_w, new_id, WebhookEvent::Created, &user_claims.user, None);
+
+	log_ip_user_id("chunk_instantiate", ip.0, &user_claims.user, new_id.inner().into());
+	tx_r.send(ResourceMessage::from(("chunks", users_to_notify))).unwrap();
+	for m in ChunkPlacement::default().deltas(new_id, &db.read().unwrap().placement(new_id)) {
+		tx_r.send(m).unwrap();
+	}
+
+	Ok(Json(new_id))
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct GraphExportQuery {
+	/// Exports everything the user can see if None
+	root: Option<ChunkId>,
+	depth: Option<usize>,
+	limit: Option<usize>,
+}
+
+/// Exports the user's chunk graph as `dot`, `graphml` or `json`
+pub async fn graph_export(
+	Path(format): Path<String>,
+	Query(query): Query<GraphExportQuery>,
+	Extension(db): Extension<LockedAtomic<DB>>,
+	Extension(user_claims): Extension<UserClaims>,
+	ip: ClientIp,
+) -> Result<impl IntoResponse, DbError> {
+	let graph = {
+		let db = db.read().unwrap();
+		if query.root.is_some() || query.depth.is_some() || query.limit.is_some() {
+			let default = GraphQuery::default();
+			db.graph(
+				query.root,
+				&user_claims.user,
+				GraphQuery {
+					depth: query.depth.unwrap_or(default.depth),
+					limit: query.limit.unwrap_or(default.limit),
+				},
+			)
+		} else {
+			db.graph_all(&user_claims.user)
+		}
+	};
+
+	let (content_type, body) = match format.as_str() {
+		"dot" => ("text/vnd.graphviz", graph.to_dot()),
+		"graphml" => ("application/graphml+xml", graph.to_graphml()),
+		"json" => ("application/json", serde_json::to_string(&graph).unwrap()),
+		_ => return Err(DbError::Custom(format!("Format '{format}' isn't supported."))),
+	};
+
+	log_ip_user("chunk_graph_export", ip.0, &user_claims.user);
+	Ok(([(header::CONTENT_TYPE, content_type)], body))
+}
+
+pub async fn transfers_get(
+	Extension(db): Extension<LockedAtomic<DB>>,
+	Extension(user_claims): Extension<UserClaims>,
+) -> Result<impl IntoResponse, DbError> {
+	Ok(Json(db.read().unwrap().get_transfers(&user_claims.user)))
+}
+
+/// Offers ownership of a chunk (or subtree) to another user
+pub async fn chunks_transfer(
+	Path(id): Path<ChunkId>,
+	Extension(db): Extension<LockedAtomic<DB>>,
+	Extension(user_claims): Extension<UserClaims>,
+	Extension(tx_r): Extension<ResourceSender>,
+	ip: ClientIp,
+	Json(body): Json<TransferIn>,
+) -> Result<impl IntoResponse, DbError> {
+	let transfer = db.write().unwrap().transfer_request(id, &user_claims.user, body)?;
+
+	log_ip_user_id("chunk_transfer", ip.0, &user_claims.user, id.inner().into());
+	tx_r
+		.send(ResourceMessage::from(("transfers", HashSet::from([transfer.to.clone()]))))
+		.unwrap();
+
+	Ok(Json(transfer))
+}
+
+pub async fn transfers_accept(
+	Path(id): Path<ChunkId>,
+	Extension(db): Extension<LockedAtomic<DB>>,
+	Extension(user_claims): Extension<UserClaims>,
+	Extension(tx_r): Extension<ResourceSender>,
+	Extension(tx_w): Extension<WebhookSender>,
+	ip: ClientIp,
+) -> Result<impl IntoResponse, DbError> {
+	let users_to_notify = db.write().unwrap().transfer_accept(id, &user_claims.user)?;
+	dispatch(&db.read().unwrap(), &tx_w, id, WebhookEvent::Shared, &user_claims.user, None);
+
+	log_ip_user_id("chunk_transfer_accept", ip.0, &user_claims.user, id.inner().into());
+	tx_r.send(ResourceMessage::from(("chunks", users_to_notify.clone()))).unwrap();
+	tx_r.send(ResourceMessage::from(("transfers", users_to_notify))).unwrap();
+
+	Ok(())
+}
+
+/// Declines (recipient) or cancels (sender) a transfer
+pub async fn transfers_del(
+	Path(id): Path<ChunkId>,
+	Extension(db): Extension<LockedAtomic<DB>>,
+	Extension(user_claims): Extension<UserClaims>,
+	Extension(tx_r): Extension<ResourceSender>,
+	ip: ClientIp,
+) -> Result<impl IntoResponse, DbError> {
+	let transfer = db.write().unwrap().transfer_cancel(id, &user_claims.user)?;
+
+	log_ip_user_id("chunk_transfer_cancel", ip.0, &user_claims.user, id.inner().into());
+	tx_r
+		.send(ResourceMessage::from(("transfers", HashSet::from([transfer.from, transfer.to]))))
+		.unwrap();
+
+	Ok(())
+}
+
+/// Who read, edited or shared a chunk, for its Owner/Admins
+pub async fn chunks_audit(
+	Path(id): Path<ChunkId>,
+	Query(query): Query<AuditQuery>,
+	Extension(db): Extension<LockedAtomic<DB>>,
+	Extension(user_claims): Extension<UserClaims>,
+	ip: ClientIp,
+) -> Result<impl IntoResponse, DbError> {
+	db.read().unwrap().audit_allowed(id, &user_claims.user, &query)?;
+	let audit = audit::audit(id, &query);
+	log_ip_user_id("chunk_audit", ip.0, &user_claims.user, id.inner().into());
+	Ok(Json(audit))
+}
+
+/// Media referenced by the user's chunks, with how many chunks reference each
+pub async fn media_refs_get(
+	Extension(db): Extension<LockedAtomic<DB>>,
+	Extension(user_claims): Extension<UserClaims>,
+) -> Result<impl IntoResponse, DbError> {
+	Ok(Json(db.read().unwrap().media_by_owner(&user_claims.user)))
+}
+
+/// Which of the given media ids are still referenced by any chunk, used by media to find unused files
+pub async fn media_refs_post(
+	Extension(db): Extension<LockedAtomic<DB>>,
+	Json(ids): Json<Vec<String>>,
+) -> Result<impl IntoResponse, DbError> {
+	Ok(Json(db.read().unwrap().media_referenced(&ids)))
+}
+
+/// Problems with the chunk graph, supers only
+pub async fn integrity_get(
+	Extension(db): Extension<LockedAtomic<DB>>,
+	Extension(user_claims): Extension<UserClaims>,
+) -> Result<impl IntoResponse, DbError> {
+	if !user_claims._super {
+		return Err(DbError::AuthError);
+	}
+	Ok(Json(db.read().unwrap().check()))
+}
+
+/// Repairs the chunk graph, returns what was repaired, supers only
+pub async fn integrity_post(
+	Extension(db): Extension<LockedAtomic<DB>>,
+	Extension(user_claims): Extension<UserClaims>,
+	ip: ClientIp,
+) -> Result<impl IntoResponse, DbError> {
+	if !user_claims._super {
+		return Err(DbError::AuthError);
+	}
+	let issues = db.write().unwrap().repair();
+	log_ip_user("chunk_integrity_repair", ip.0, &user_claims.user);
+	Ok(Json(issues))
+}
 
+/// Adds/removes access on a selection of chunks
+pub async fn chunks_share(
+	Extension(db): Extension<LockedAtomic<DB>>,
+	Extension(user_claims): Extension<UserClaims>,
+	Extension(tx_r): Extension<ResourceSender>,
+	Extension(tx_w): Extension<WebhookSender>,
+	ip: ClientIp,
+	Json(body): Json<BulkShare>,
+) -> Result<impl IntoResponse, DbError> {
+	let ids = body.ids.clone();
+	let users_to_notify = db.write().unwrap().share_bulk(body, &user_claims.user)?;
+
+	ids.into_iter().for_each(|id| {
+		dispatch(&db.read().unwrap(), &tx_w, id, WebhookEvent::Shared, &user_claims.user, None);
+		log_ip_user_id("chunk_share", ip.0, &user_claims.user, id.inner().into());
+		if !users_to_notify.is_empty() {
+			log_ip_user_id("chunk_access", ip.0, &user_claims.user, id.inner().into());
+		}
+	});
 	tx_r.send(ResourceMessage::from(("chunks", users_to_notify))).unwrap();
 
 	Ok(())
 }
+
+pub async fn webhooks_get(
+	Extension(db): Extension<LockedAtomic<DB>>,
+	Extension(user_claims): Extension<UserClaims>,
+) -> Result<impl IntoResponse, DbError> {
+	Ok(Json(db.read().unwrap().get_webhooks(&user_claims.user)))
+}
+
+pub async fn webhooks_post(
+	Extension(db): Extension<LockedAtomic<DB>>,
+	Extension(user_claims): Extension<UserClaims>,
+	ip: ClientIp,
+	Json(body): Json<WebhookIn>,
+) -> Result<impl IntoResponse, DbError> {
+	let webhook = db.write().unwrap().webhook_new(&user_claims.user, body)?;
+	log_ip_user_id("chunk_webhook_new", ip.0, &user_claims.user, webhook.id.inner().into());
+	Ok(Json(webhook))
+}
+
+pub async fn webhooks_put(
+	Path(id): Path<WebhookId>,
+	Extension(db): Extension<LockedAtomic<DB>>,
+	Extension(user_claims): Extension<UserClaims>,
+	ip: ClientIp,
+	Json(body): Json<WebhookIn>,
+) -> Result<impl IntoResponse, DbError> {
+	let webhook = db.write().unwrap().webhook_mod(id, &user_claims.user, body)?;
+	log_ip_user_id("chunk_webhook_mod", ip.0, &user_claims.user, id.inner().into());
+	Ok(Json(webhook))
+}
+
+pub async fn webhooks_del(
+	Path(id): Path<WebhookId>,
+	Extension(db): Extension<LockedAtomic<DB>>,
+	Extension(user_claims): Extension<UserClaims>,
+	ip: ClientIp,
+) -> Result<impl IntoResponse, DbError> {
+	db.write().unwrap().webhook_del(id, &user_claims.user)?;
+	log_ip_user_id("chunk_webhook_del", ip.0, &user_claims.user, id.inner().into());
+	Ok(())
+}
+
+pub async fn webhooks_deliveries(
+	Path(id): Path<WebhookId>,
+	Extension(db): Extension<LockedAtomic<DB>>,
+	Extension(user_claims): Extension<UserClaims>,
+) -> Result<impl IntoResponse, DbError> {
+	Ok(Json(db.read().unwrap().webhook_deliveries(id, &user_claims.user)?))
+}
+
+/// Creates the user's encryption keypair, returns the public key
+pub async fn keys_post(
+	Extension(db): Extension<LockedAtomic<DB>>,
+	Extension(user_claims): Extension<UserClaims>,
+	ip: ClientIp,
+	Json(body): Json<Secret>,
+) -> Result<impl IntoResponse, DbError> {
+	let public = crypt::key_new(&db, &user_claims.user, body.secret).await?;
+
+	log_ip_user("key_new", ip.0, &user_claims.user);
+	Ok(Json(public))
+}
+
+/// Sends the new view of an encrypted/decrypted chunk to everyone with access
+fn send_chunk(db: &DB, tx_r: &ResourceSender, id: ChunkId, user: &str) {
+	if let Some(chunk) = db.get_chunk(id, user) {
+		let users = chunk.read().unwrap().access_users();
+		let view = ChunkView::from((chunk, user));
+		tx_r
+			.send(ResourceMessage::from((format!("chunks/{id}").as_str(), users, &view)))
+			.unwrap();
+	}
+}
+
+pub async fn chunks_encrypt(
+	Path(id): Path<ChunkId>,
+	Extension(db): Extension<LockedAtomic<DB>>,
+	Extension(user_claims): Extension<UserClaims>,
+	Extension(tx_r): Extension<ResourceSender>,
+	Extension(tx_w): Extension<WebhookSender>,
+	ip: ClientIp,
+) -> Result<impl IntoResponse, DbError> {
+	db.write().unwrap().encrypt(id, &user_claims.user)?;
+
+	let db = db.read().unwrap();
+	dispatch(&db, &tx_w, id, WebhookEvent::Edited, &user_claims.user, None);
+	send_chunk(&db, &tx_r, id, &user_claims.user);
+	log_ip_user_id("chunk_encrypt", ip.0, &user_claims.user, id.inner().into());
+	Ok(())
+}
+
+/// Turns encryption off for a chunk
+pub async fn chunks_unencrypt(
+	Path(id): Path<ChunkId>,
+	Extension(db): Extension<LockedAtomic<DB>>,
+	Extension(user_claims): Extension<UserClaims>,
+	Extension(tx_r): Extension<ResourceSender>,
+	Extension(tx_w): Extension<WebhookSender>,
+	ip: ClientIp,
+	Json(body): Json<Secret>,
+) -> Result<impl IntoResponse, DbError> {
+	let unlocked = crypt::unlock(&db, &user_claims.user, body.secret).await?;
+	db.write().unwrap().unencrypt(id, &unlocked)?;
+
+	let db = db.read().unwrap();
+	dispatch(&db, &tx_w, id, WebhookEvent::Edited, &user_claims.user, None);
+	send_chunk(&db, &tx_r, id, &user_claims.user);
+	log_ip_user_id("chunk_unencrypt", ip.0, &user_claims.user, id.inner().into());
+	Ok(())
+}
+
+/// Plaintext value of an encrypted chunk, never stored nor sent to anyone else
+pub async fn chunks_decrypt(
+	Path(id): Path<ChunkId>,
+	Extension(db): Extension<LockedAtomic<DB>>,
+	Extension(user_claims): Extension<UserClaims>,
+	ip: ClientIp,
+	Json(body): Json<Secret>,
+) -> Result<impl IntoResponse, DbError> {
+	let result = match crypt::unlock(&db, &user_claims.user, body.secret).await {
+		Ok(unlocked) => db.read().unwrap().decrypt(id, &unlocked),
+		Err(err) => Err(err),
+	};
+	match result {
+		Ok(value) => {
+			log_ip_user_id("chunk_decrypt", ip.0, &user_claims.user, id.inner().into());
+			Ok(Json(value))
+		}
+		Err(err) => {
+			log_ip_user_id("chunk_decrypt_error", ip.0, &user_claims.user, id.inner().into());
+			Err(err)
+		}
+	}
+}
+
+/// Edits an encrypted chunk, diffs aren't sent to keep plaintext off the wire
+pub async fn chunks_put_encrypted(
+	Path(id): Path<ChunkId>,
+	Extension(db): Extension<LockedAtomic<DB>>,
+	Extension(user_claims): Extension<UserClaims>,
+	Extension(tx_r): Extension<ResourceSender>,
+	Extension(tx_w): Extension<WebhookSender>,
+	ip: ClientIp,
+	Json(body): Json<EncryptedIn>,
+) -> Result<impl IntoResponse, DbError> {
+	let unlocked = crypt::unlock(&db, &user_claims.user, body.secret).await?;
+	let before = db.read().unwrap().placement(id);
+	let users_to_notify = db.write().unwrap().set_encrypted(id, &unlocked, &body.value)?;
+
+	let db = db.read().unwrap();
+	dispatch(&db, &tx_w, id, WebhookEvent::Edited, &user_claims.user, None);
+	if !users_to_notify.is_empty() {
+		dispatch(&db, &tx_w, id, WebhookEvent::Shared, &user_claims.user, None);
+	}
+	for m in before.deltas(id, &db.placement(id)) {
+		tx_r.send(m).unwrap();
+	}
+	tx_r.send(ResourceMessage::from(("chunks", users_to_notify))).unwrap();
+	send_chunk(&db, &tx_r, id, &user_claims.user);
+	log_ip_user_id("chunk_put_encrypted", ip.0, &user_claims.user, id.inner().into());
+	if !users_to_notify.is_empty() {
+		log_ip_user_id("chunk_access", ip.0, &user_claims.user, id.inner().into());
+	}
+	Ok(())
+}
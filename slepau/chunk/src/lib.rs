@@ -1,5 +1,10 @@
 #![feature(test)]
+pub mod audit;
 pub mod db;
 pub mod ends;
 mod format;
+pub mod mail;
+pub mod presence;
+mod seo;
 pub mod socket;
+pub mod webhook;
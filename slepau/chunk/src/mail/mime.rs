@@ -0,0 +1,399 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+
+lazy_static! {
+	/// RFC 2047 `=?charset?B|Q?text?=`
+	static ref REGEX_WORD: Regex = Regex::new(r"=\?([^?]+)\?([bBqQ])\?([^?]*)\?=").unwrap();
+	/// Whitespace between two encoded words isn't part of the text
+	static ref REGEX_WORD_GAP: Regex = Regex::new(r"(=\?[^?]+\?[bBqQ]\?[^?]*\?=)\s+(=\?)").unwrap();
+	static ref REGEX_HTML_DROP: Regex =
+		Regex::new(r"(?is)<(head|style|script|title)[^>]*>.*?</(head|style|script|title)>|<!--.*?-->").unwrap();
+	static ref REGEX_HTML_HEADING: Regex = Regex::new(r"(?i)<h([1-6])[^>]*>").unwrap();
+	static ref REGEX_HTML_LINK: Regex =
+		Regex::new(r#"(?is)<a\s[^>]*href\s*=\s*["']([^"']*)["'][^>]*>(.*?)</a>"#).unwrap();
+	static ref REGEX_HTML_BLOCK: Regex = Regex::new(r"(?i)</(p|div|tr|table|h[1-6]|ul|ol|blockquote)>").unwrap();
+	static ref REGEX_HTML_BR: Regex = Regex::new(r"(?i)<br\s*/?>").unwrap();
+	static ref REGEX_HTML_LI: Regex = Regex::new(r"(?i)<li[^>]*>").unwrap();
+	static ref REGEX_HTML_STRONG: Regex = Regex::new(r"(?i)</?(strong|b)(\s[^>]*)?>").unwrap();
+	static ref REGEX_HTML_EM: Regex = Regex::new(r"(?i)</?(em|i)(\s[^>]*)?>").unwrap();
+	static ref REGEX_HTML_TAG: Regex = Regex::new(r"(?s)<[^>]*>").unwrap();
+	static ref REGEX_ENTITY: Regex = Regex::new(r"&(#x?[0-9a-fA-F]+|[a-z]+);").unwrap();
+	static ref REGEX_BLANK: Regex = Regex::new(r"\n{3,}").unwrap();
+}
+
+/// A part of the message that isn't its text
+#[derive(Debug, Default, PartialEq)]
+pub struct Attachment {
+	pub content_type: String,
+	pub filename: String,
+	pub data: Vec<u8>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Message {
+	pub subject: String,
+	pub from: String,
+	pub text: Option<String>,
+	pub html: Option<String>,
+	pub attachments: Vec<Attachment>,
+}
+impl Message {
+	/// Text of the message as markdown, the html version if there's no plain one
+	pub fn markdown(&self) -> String {
+		self
+			.text
+			.as_ref()
+			.map(|t| t.replace("\r\n", "\n").trim().to_owned())
+			.or_else(|| self.html.as_deref().map(html_to_markdown))
+			.unwrap_or_default()
+	}
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+	haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Headers and body, split at the first empty line
+fn split_head(raw: &[u8]) -> (&[u8], &[u8]) {
+	if let Some(body) = raw.strip_prefix(b"\r\n").or_else(|| raw.strip_prefix(b"\n")) {
+		return (&[], body);
+	}
+	match (find(raw, b"\r\n\r\n"), find(raw, b"\n\n")) {
+		(Some(crlf), Some(lf)) if lf < crlf => (&raw[..lf], &raw[lf + 2..]),
+		(Some(crlf), _) => (&raw[..crlf], &raw[crlf + 4..]),
+		(None, Some(lf)) => (&raw[..lf], &raw[lf + 2..]),
+		(None, None) => (raw, &[]),
+	}
+}
+
+/// Unfolded headers with lowercase names
+fn headers(head: &[u8]) -> Vec<(String, String)> {
+	let mut headers: Vec<(String, String)> = vec![];
+	for line in String::from_utf8_lossy(head).lines() {
+		if line.starts_with([' ', '\t']) {
+			if let Some((_, value)) = headers.last_mut() {
+				value.push(' ');
+				value.push_str(line.trim());
+			}
+		} else if let Some((name, value)) = line.split_once(':') {
+			headers.push((name.trim().to_lowercase(), value.trim().to_owned()));
+		}
+	}
+	headers
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+	headers.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+}
+
+/// `name=value` parameter of a header like `Content-Type`, RFC 2231 `name*=utf-8''value` too
+fn param(value: &str, name: &str) -> Option<String> {
+	value.split(';').skip(1).find_map(|p| {
+		let (key, v) = p.split_once('=')?;
+		let key = key.trim().to_lowercase();
+		let v = v.trim().trim_matches('"');
+		if key == name {
+			Some(v.to_owned())
+		} else if key == format!("{name}*") {
+			let v = v.split_once("''").map(|(_, v)| v).unwrap_or(v);
+			Some(String::from_utf8_lossy(&percent_decode(v)).into_owned())
+		} else {
+			None
+		}
+	})
+}
+
+fn hex(bytes: &[u8]) -> Option<u8> {
+	u8::from_str_radix(std::str::from_utf8(bytes).ok()?, 16).ok()
+}
+
+fn percent_decode(v: &str) -> Vec<u8> {
+	let bytes = v.as_bytes();
+	let mut out = vec![];
+	let mut i = 0;
+	while i < bytes.len() {
+		match (bytes[i], bytes.get(i + 1..i + 3).and_then(hex)) {
+			(b'%', Some(b)) => {
+				out.push(b);
+				i += 3;
+			}
+			(b, _) => {
+				out.push(b);
+				i += 1;
+			}
+		}
+	}
+	out
+}
+
+pub fn decode_quoted_printable(v: &[u8]) -> Vec<u8> {
+	let mut out = vec![];
+	let mut i = 0;
+	while i < v.len() {
+		if v[i] != b'=' {
+			out.push(v[i]);
+			i += 1;
+			continue;
+		}
+		// Soft line breaks
+		if v[i + 1..].starts_with(b"\r\n") {
+			i += 3;
+		} else if v[i + 1..].starts_with(b"\n") {
+			i += 2;
+		} else if let Some(b) = v.get(i + 1..i + 3).and_then(hex) {
+			out.push(b);
+			i += 3;
+		} else {
+			out.push(b'=');
+			i += 1;
+		}
+	}
+	out
+}
+
+fn decode_body(body: &[u8], encoding: &str) -> Vec<u8> {
+	match encoding.trim().to_lowercase().as_str() {
+		"base64" => {
+			let clean = body.iter().filter(|b| !b.is_ascii_whitespace()).cloned().collect::<Vec<_>>();
+			STANDARD.decode(clean).unwrap_or_default()
+		}
+		"quoted-printable" => decode_quoted_printable(body),
+		_ => body.to_vec(),
+	}
+}
+
+/// Bytes in `charset` as a string, anything that isn't latin 1 is read as utf 8
+fn charset_string(bytes: &[u8], charset: &str) -> String {
+	match charset.trim().to_lowercase().as_str() {
+		"iso-8859-1" | "latin1" | "windows-1252" | "us-ascii" => bytes.iter().map(|b| *b as char).collect(),
+		_ => String::from_utf8_lossy(bytes).into_owned(),
+	}
+}
+
+/// Decodes RFC 2047 encoded words in a header
+pub fn decode_words(value: &str) -> String {
+	let mut value = value.to_owned();
+	while REGEX_WORD_GAP.is_match(&value) {
+		value = REGEX_WORD_GAP.replace_all(&value, "$1$2").into_owned();
+	}
+	REGEX_WORD
+		.replace_all(&value, |c: &Captures| {
+			let bytes = if c[2].eq_ignore_ascii_case("b") {
+				STANDARD.decode(&c[3]).unwrap_or_default()
+			} else {
+				decode_quoted_printable(c[3].replace('_', " ").as_bytes())
+			};
+			charset_string(&bytes, &c[1])
+		})
+		.into_owned()
+}
+
+/// Parts of a multipart body, between `--boundary` lines
+fn multipart<'a>(body: &'a [u8], boundary: &str) -> Vec<&'a [u8]> {
+	let delimiter = format!("--{boundary}");
+	let mut parts = vec![];
+	let (mut start, mut offset) = (None, 0);
+	for line in body.split_inclusive(|b| *b == b'\n') {
+		let trimmed = line.strip_suffix(b"\n").unwrap_or(line);
+		let trimmed = trimmed.strip_suffix(b"\r").unwrap_or(trimmed);
+		if trimmed.starts_with(delimiter.as_bytes()) {
+			if let Some(start) = start {
+				// The line break before a delimiter belongs to it
+				let end = offset - if body[..offset].ends_with(b"\r\n") { 2 } else { 1 };
+				parts.push(&body[start..end.max(start)]);
+			}
+			if trimmed[delimiter.len()..].starts_with(b"--") {
+				return parts;
+			}
+			start = Some(offset + line.len());
+		}
+		offset += line.len();
+	}
+	if let Some(start) = start {
+		parts.push(&body[start.min(body.len())..]);
+	}
+	parts
+}
+
+/// Multiparts nested deeper than this are kept as opaque attachments
+const MAX_DEPTH: usize = 16;
+
+fn walk(raw: &[u8], message: &mut Message, depth: usize) {
+	let (head, body) = split_head(raw);
+	let headers = headers(head);
+	let content_type = header(&headers, "content-type").unwrap_or("text/plain");
+	let mime = content_type.split(';').next().unwrap_or_default().trim().to_lowercase();
+
+	if mime.starts_with("multipart/") && depth < MAX_DEPTH {
+		if let Some(boundary) = param(content_type, "boundary") {
+			for part in multipart(body, &boundary) {
+				walk(part, message, depth + 1);
+			}
+		}
+		return;
+	}
+
+	let data = decode_body(body, header(&headers, "content-transfer-encoding").unwrap_or_default());
+	let disposition = header(&headers, "content-disposition").unwrap_or_default();
+	let filename = param(disposition, "filename")
+		.or_else(|| param(content_type, "name"))
+		.map(|f| decode_words(&f));
+	let charset = param(content_type, "charset").unwrap_or_default();
+	let attachment = disposition.to_lowercase().starts_with("attachment") || filename.is_some();
+
+	match mime.as_str() {
+		"text/plain" if !attachment && message.text.is_none() => {
+			message.text = Some(charset_string(&data, &charset));
+		}
+		"text/html" if !attachment && message.html.is_none() => {
+			message.html = Some(charset_string(&data, &charset));
+		}
+		_ if !data.is_empty() => message.attachments.push(Attachment {
+			filename: filename.unwrap_or_else(|| match mime.as_str() {
+				"text/calendar" => "invite.ics".into(),
+				_ => "attachment".into(),
+			}),
+			content_type: mime,
+			data,
+		}),
+		_ => {}
+	}
+}
+
+/// Parses a raw RFC 5322 message
+pub fn parse(raw: &[u8]) -> Message {
+	let headers = headers(split_head(raw).0);
+	let mut message = Message {
+		subject: decode_words(header(&headers, "subject").unwrap_or_default()),
+		from: decode_words(header(&headers, "from").unwrap_or_default()),
+		..Default::default()
+	};
+	walk(raw, &mut message, 0);
+	message
+}
+
+fn entity(c: &Captures) -> String {
+	let e = &c[1];
+	let code = if let Some(hex) = e.strip_prefix("#x").or_else(|| e.strip_prefix("#X")) {
+		u32::from_str_radix(hex, 16).ok()
+	} else if let Some(dec) = e.strip_prefix('#') {
+		dec.parse().ok()
+	} else {
+		None
+	};
+	if let Some(c) = code.and_then(char::from_u32) {
+		return c.to_string();
+	}
+	match e {
+		"nbsp" => " ",
+		"amp" => "&",
+		"lt" => "<",
+		"gt" => ">",
+		"quot" => "\"",
+		"apos" => "'",
+		_ => return c[0].to_owned(),
+	}
+	.into()
+}
+
+/// Rough html to markdown, keeps paragraphs, headings, lists, links and emphasis
+pub fn html_to_markdown(html: &str) -> String {
+	let md = REGEX_HTML_DROP.replace_all(html, "");
+	let md = md.replace(['\r', '\n'], " ");
+	// A `#` heading would read as the chunk's title, so they start at `##`
+	let md = REGEX_HTML_HEADING.replace_all(&md, |c: &Captures| {
+		format!("\n\n{} ", "#".repeat((c[1].parse::<usize>().unwrap_or(1) + 1).min(6)))
+	});
+	let md = REGEX_HTML_LINK.replace_all(&md, |c: &Captures| {
+		let text = REGEX_HTML_TAG.replace_all(&c[2], "");
+		format!("[{}]({})", text.trim(), &c[1])
+	});
+	let md = REGEX_HTML_BLOCK.replace_all(&md, "\n\n");
+	let md = REGEX_HTML_BR.replace_all(&md, "\n");
+	let md = REGEX_HTML_LI.replace_all(&md, "\n- ");
+	let md = REGEX_HTML_STRONG.replace_all(&md, "**");
+	let md = REGEX_HTML_EM.replace_all(&md, "_");
+	let md = REGEX_HTML_TAG.replace_all(&md, "");
+	let md = REGEX_ENTITY.replace_all(&md, entity);
+	let md = md
+		.lines()
+		.map(|l| l.split_whitespace().collect::<Vec<_>>().join(" "))
+		.collect::<Vec<_>>()
+		.join("\n");
+	REGEX_BLANK.replace_all(&md, "\n\n").trim().to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{decode_words, html_to_markdown, parse, Attachment};
+
+	#[test]
+	fn multipart() {
+		let raw = b"From: =?utf-8?Q?Nin=C3=A1?= <nina@example.com>\r\n\
+Subject: =?utf-8?B?UmVjZWlwdA==?= for\r\n you\r\n\
+Content-Type: multipart/mixed; boundary=\"out\"\r\n\
+\r\n\
+preamble\r\n\
+--out\r\n\
+Content-Type: multipart/alternative; boundary=in\r\n\
+\r\n\
+--in\r\n\
+Content-Type: text/plain; charset=utf-8\r\n\
+Content-Transfer-Encoding: quoted-printable\r\n\
+\r\n\
+Total: 3=E2=82=AC, thanks=\r\n for shopping\r\n\
+--in\r\n\
+Content-Type: text/html\r\n\
+\r\n\
+<p>Total</p>\r\n\
+--in--\r\n\
+--out\r\n\
+Content-Type: image/png; name=\"r.png\"\r\n\
+Content-Disposition: attachment; filename=\"r.png\"\r\n\
+Content-Transfer-Encoding: base64\r\n\
+\r\n\
+iVBO\r\nRw==\r\n\
+--out--\r\n";
+		let message = parse(raw);
+		assert_eq!(message.from, "Niná <nina@example.com>");
+		assert_eq!(message.subject, "Receipt for you");
+		assert_eq!(message.markdown(), "Total: 3€, thanks for shopping");
+		assert_eq!(message.html.as_deref(), Some("<p>Total</p>"));
+		assert_eq!(
+			message.attachments,
+			vec![Attachment {
+				content_type: "image/png".into(),
+				filename: "r.png".into(),
+				data: vec![0x89, 0x50, 0x4e, 0x47],
+			}]
+		);
+		assert_eq!(decode_words("=?utf-8?Q?a?= =?utf-8?Q?b?= c"), "ab c");
+	}
+
+	#[test]
+	fn nested() {
+		let mut raw = b"Content-Type: text/plain\r\n\r\nDeep down".to_vec();
+		for i in 0..100 {
+			let mut outer = format!("Content-Type: multipart/mixed; boundary=b{i}\r\n\r\n--b{i}\r\n").into_bytes();
+			outer.extend(raw);
+			outer.extend(format!("\r\n--b{i}--\r\n").as_bytes());
+			raw = outer;
+		}
+		let message = parse(&raw);
+		assert_eq!(message.text, None);
+		assert_eq!(message.attachments.len(), 1);
+		assert_eq!(message.attachments[0].content_type, "multipart/mixed");
+	}
+
+	#[test]
+	fn html() {
+		assert_eq!(
+			html_to_markdown(
+				"<html><head><style>p{}</style></head><body><h1>Hi</h1><p>See <a href=\"https://x.org\">\
+				<b>this</b></a> &amp; that</p><ul><li>one</li><li><i>two</i></li></ul></body></html>"
+			),
+			"## Hi\n\nSee [this](https://x.org) & that\n\n- one\n- _two_"
+		);
+	}
+}
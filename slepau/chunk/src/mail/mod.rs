@@ -0,0 +1,549 @@
+use auth::validate::service::service_token;
+use common::{
+	socket::{ResourceMessage, ResourceSender},
+	utils::{standardize, DbError, LockedAtomic, REGEX_ACCESS, REGEX_PROPERTY, REGEX_USERNAME, URL},
+	vreji::log_ip_user_id,
+};
+use lazy_static::lazy_static;
+use log::{error, info};
+use serde_json::Value;
+use std::{
+	env,
+	net::{IpAddr, SocketAddr},
+	time::Duration,
+};
+use tokio::{
+	io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+	net::TcpListener,
+	sync::watch,
+};
+
+use crate::{
+	db::{
+		chunk::ChunkId,
+		dbchunk::{set_parents, DBChunk},
+		user_access::Access,
+		webhook::WebhookEvent,
+		DB,
+	},
+	webhook::{dispatch, WebhookSender},
+};
+
+use self::mime::{Attachment, Message};
+
+pub mod mime;
+
+lazy_static! {
+	/// Where the LMTP/SMTP listener binds, there's no listener if unset.
+	///
+	/// There's no auth besides knowing the recipient, so only `MAIL_ALLOW` can talk to it.
+	pub static ref MAIL_SOCKET: Option<SocketAddr> =
+		env::var("MAIL_SOCKET").ok().and_then(|v| v.parse().ok());
+	/// IPs that can hand over mail, comma separated, like your MTA's. Only loopback ones if unset.
+	static ref MAIL_ALLOW: Vec<IpAddr> = env::var("MAIL_ALLOW")
+		.unwrap_or_default()
+		.split(',')
+		.filter_map(|ip| ip.trim().parse().ok())
+		.collect();
+	/// Where attachments are uploaded to, media's upload path
+	static ref MEDIA_UPLOAD_URL: String = env::var("MEDIA_UPLOAD_URL")
+		.unwrap_or_else(|_| format!("{}/media/media", URL.trim_end_matches('/')));
+}
+
+/// Biggest message accepted, in bytes
+const MAX_SIZE: usize = 25 * 1024 * 1024;
+
+/// Host of an url, `https://a.b.com:4000/x` is `a.b.com`
+fn host(url: &str) -> &str {
+	let url = url.split_once("://").map(|(_, u)| u).unwrap_or(url);
+	url.split(['/', ':']).next().unwrap_or_default()
+}
+
+/// Whether `ip` can hand over mail
+fn allowed(ip: IpAddr) -> bool {
+	let ip = ip.to_canonical();
+	if MAIL_ALLOW.is_empty() {
+		ip.is_loopback()
+	} else {
+		MAIL_ALLOW.iter().any(|a| a.to_canonical() == ip)
+	}
+}
+
+/// Who a mail is for and the chunk it goes under
+#[derive(Debug, PartialEq)]
+pub struct Target {
+	pub user: String,
+	pub parent: ChunkId,
+}
+
+/// Maps `<user>+<parent>@host` to a user and a parent they can write to.
+///
+/// The parent is a chunk id or a chunk's ref (its standardized title).
+pub fn target(db: &DB, address: &str) -> Result<Target, &'static str> {
+	let address = address.trim().trim_start_matches('<');
+	let address = address.split('>').next().unwrap_or_default();
+	let (local, _) = address.rsplit_once('@').ok_or("Not an address.")?;
+	let (user, parent) = local
+		.split_once('+')
+		.ok_or("Mail needs a parent, send it to <user>+<parent>@host.")?;
+	if user == "public" || !REGEX_USERNAME.is_match(user) {
+		return Err("No such user.");
+	}
+
+	let can_write = |c: &LockedAtomic<DBChunk>| c.read().unwrap().has_access(&(user, Access::Write).into());
+	let chunk = match ChunkId::from_quint(parent) {
+		Ok(id) => db.get_chunk(id, user).filter(can_write),
+		Err(_) => {
+			let parent = standardize(parent);
+			db.get_chunks(user).into_iter().filter(can_write).find(|c| {
+				c.read().unwrap().get_prop::<String>("ref").as_deref() == Some(parent.as_str())
+			})
+		}
+	};
+	chunk
+		.map(|c| Target {
+			user: user.to_owned(),
+			parent: c.read().unwrap().chunk().id,
+		})
+		.ok_or("No such parent, or it can't be written to.")
+}
+
+/// Escapes the colons of lines that would read as props, so a mail can't `share:` its chunk or
+/// make it a `schema:` or `template:`. Markdown still shows them the same.
+fn inert(text: &str) -> String {
+	text
+		.lines()
+		.map(|l| {
+			if REGEX_PROPERTY.is_match(l) || REGEX_ACCESS.is_match(l) {
+				l.replace(':', "\\:")
+			} else {
+				l.to_owned()
+			}
+		})
+		.collect::<Vec<_>>()
+		.join("\n")
+}
+
+/// The chunk a message turns into, `embeds` are its uploaded attachments
+pub fn compose(message: &Message, parent: ChunkId, embeds: &[String]) -> String {
+	// Titles can't hold `-`, `=` nor `>`
+	let clean = |v: &str| {
+		v.replace(['-', '=', '>', '<', '\n', '\r'], " ")
+			.split_whitespace()
+			.collect::<Vec<_>>()
+			.join(" ")
+	};
+	let title = [clean(&message.subject), clean(&message.from)]
+		.into_iter()
+		.find(|t| !t.is_empty())
+		.unwrap_or_else(|| "Mail".into());
+
+	let mut value = format!("# {title}\n");
+	if !message.from.trim().is_empty() {
+		value.push_str(&format!("from: {}\n", message.from.replace(['\n', '\r'], " ").trim()));
+	}
+	value.push('\n');
+	// Only the `from` above is a prop, whatever the sender wrote is text
+	let mut body = message.markdown();
+	if !embeds.is_empty() {
+		body.push_str("\n\n");
+		body.push_str(&embeds.join("\n"));
+	}
+	value.push_str(&inert(&body));
+	value.push('\n');
+	set_parents(&value, &[parent]).unwrap_or(value)
+}
+
+/// Stores an attachment with media on behalf of `user`, returns its embed
+async fn upload(client: &reqwest::Client, user: &str, attachment: &Attachment) -> Result<String, String> {
+	let token = service_token(user, host(&MEDIA_UPLOAD_URL), "media:write", 300).await?;
+	let response = client
+		.post(MEDIA_UPLOAD_URL.as_str())
+		.header(reqwest::header::COOKIE, format!("auth={token}"))
+		.header(reqwest::header::CONTENT_TYPE, attachment.content_type.as_str())
+		.body(attachment.data.clone())
+		.send()
+		.await
+		.map_err(|err| err.to_string())?;
+	let status = response.status();
+	let body = response.bytes().await.map_err(|err| err.to_string())?;
+	if !status.is_success() {
+		return Err(format!("{status} {}", String::from_utf8_lossy(&body)));
+	}
+	let id = serde_json::from_slice::<Value>(&body)
+		.ok()
+		.and_then(|media| media["id"].as_str().map(String::from))
+		.ok_or("No id in media's response")?;
+
+	Ok(match attachment.content_type.split('/').next() {
+		Some("image") => format!("(image/{id})"),
+		Some("video") => format!("(video/{id})"),
+		_ => format!("{} (media/{id})", attachment.filename),
+	})
+}
+
+/// Turns a message into a chunk under the target's parent
+async fn deliver(
+	db: &LockedAtomic<DB>,
+	tx_r: &ResourceSender,
+	tx_w: &WebhookSender,
+	client: &reqwest::Client,
+	ip: IpAddr,
+	target: &Target,
+	message: &Message,
+) -> Result<ChunkId, DbError> {
+	let mut embeds = vec![];
+	for attachment in message.attachments.iter() {
+		match upload(client, &target.user, attachment).await {
+			Ok(embed) => embeds.push(embed),
+			Err(err) => {
+				error!("Couldn't store {} for {}: {err}", attachment.filename, target.user);
+				embeds.push(format!("{} couldn't be stored.", attachment.filename));
+			}
+		}
+	}
+
+	let chunk = DBChunk::from(compose(message, target.parent, &embeds).as_str());
+	let id = chunk.chunk().id;
+	let before = db.read().unwrap().placement(id);
+	let users_to_notify = db.write().unwrap().set_chunk(chunk, &target.user)?;
+
+	dispatch(&db.read().unwrap(), tx_w, id, WebhookEvent::Created, &target.user, None);
+	for m in before.deltas(id, &db.read().unwrap().placement(id)) {
+		tx_r.send(m).ok();
+	}
+	tx_r.send(ResourceMessage::from(("chunks", users_to_notify))).ok();
+	log_ip_user_id("chunk_mail", ip, &target.user, id.inner().into());
+
+	Ok(id)
+}
+
+/// RFC 5321 line length, the CRLF included
+const MAX_LINE: usize = 1000;
+
+/// Next line of at most `MAX_LINE` bytes, None once the session is over.
+///
+/// A longer one ends it with a 500, there's no telling where the next line starts.
+async fn next_line<R: AsyncBufRead + Unpin, W: AsyncWrite + Unpin>(
+	reader: &mut R,
+	writer: &mut W,
+) -> std::io::Result<Option<Vec<u8>>> {
+	let mut line = vec![];
+	if (&mut *reader).take(MAX_LINE as u64).read_until(b'\n', &mut line).await? == 0 {
+		return Ok(None);
+	}
+	if line.len() >= MAX_LINE && !line.ends_with(b"\n") {
+		writer.write_all(b"500 Line too long\r\n").await?;
+		return Ok(None);
+	}
+	Ok(Some(line))
+}
+
+/// One LMTP/SMTP conversation, `LHLO` makes it LMTP with a reply per recipient after `DATA`
+pub async fn session<S: AsyncRead + AsyncWrite + Unpin>(
+	stream: S,
+	db: LockedAtomic<DB>,
+	tx_r: ResourceSender,
+	tx_w: WebhookSender,
+	client: reqwest::Client,
+	ip: IpAddr,
+) -> std::io::Result<()> {
+	let (reader, mut writer) = tokio::io::split(stream);
+	let mut reader = BufReader::new(reader);
+	let hostname = host(&URL);
+
+	writer.write_all(format!("220 {hostname} chunk ready\r\n").as_bytes()).await?;
+	let (mut lmtp, mut from, mut targets) = (false, None::<String>, vec![]);
+	loop {
+		let Some(line) = next_line(&mut reader, &mut writer).await? else {
+			return Ok(());
+		};
+		let line = String::from_utf8_lossy(&line);
+		let line = line.trim_end();
+		let (verb, rest) = line.split_once(' ').unwrap_or((line, ""));
+		let reply = match verb.to_uppercase().as_str() {
+			verb @ ("LHLO" | "EHLO") => {
+				lmtp = verb == "LHLO";
+				format!("250-{hostname}\r\n250-8BITMIME\r\n250 SIZE {MAX_SIZE}")
+			}
+			"HELO" => {
+				lmtp = false;
+				format!("250 {hostname}")
+			}
+			"MAIL" => match rest.split_once(':') {
+				Some((key, address)) if key.eq_ignore_ascii_case("from") => {
+					from = Some(address.trim().to_owned());
+					targets.clear();
+					"250 OK".into()
+				}
+				_ => "501 Syntax: MAIL FROM:<address>".into(),
+			},
+			"RCPT" => match rest.split_once(':') {
+				_ if from.is_none() => "503 MAIL first".into(),
+				Some((key, address)) if key.eq_ignore_ascii_case("to") => {
+					// The lock can't be held across the awaits below
+					let found = target(&db.read().unwrap(), address);
+					match found {
+						Ok(target) => {
+							targets.push(target);
+							"250 OK".into()
+						}
+						Err(err) => format!("550 {err}"),
+					}
+				}
+				_ => "501 Syntax: RCPT TO:<address>".into(),
+			},
+			"DATA" if targets.is_empty() => "503 No valid recipients".into(),
+			"DATA" => {
+				writer.write_all(b"354 End data with <CR><LF>.<CR><LF>\r\n").await?;
+				let (mut data, mut too_big) = (vec![], false);
+				loop {
+					let Some(line) = next_line(&mut reader, &mut writer).await? else {
+						return Ok(());
+					};
+					if line == b".\r\n" || line == b".\n" {
+						break;
+					}
+					let line = line.strip_prefix(b".").unwrap_or(&line);
+					too_big |= data.len() + line.len() > MAX_SIZE;
+					if !too_big {
+						data.extend_from_slice(line);
+					}
+				}
+
+				let mut replies = vec![];
+				if too_big {
+					replies.push(format!("552 Message is bigger than {MAX_SIZE} bytes"));
+				} else {
+					let message = mime::parse(&data);
+					for target in targets.iter() {
+						replies.push(match deliver(&db, &tx_r, &tx_w, &client, ip, target, &message).await {
+							Ok(id) => format!("250 Delivered to {id}"),
+							Err(err) => {
+								error!("Couldn't deliver mail to {}: {err:?}", target.user);
+								format!("554 {err:?}")
+							}
+						});
+					}
+				}
+				// SMTP gets one reply for everyone, LMTP one per recipient
+				if !lmtp {
+					replies = vec![replies
+						.iter()
+						.find(|r| !r.starts_with("250"))
+						.or(replies.first())
+						.cloned()
+						.unwrap_or_default()];
+				}
+				from = None;
+				targets.clear();
+				replies.join("\r\n")
+			}
+			"RSET" => {
+				from = None;
+				targets.clear();
+				"250 OK".into()
+			}
+			"NOOP" => "250 OK".into(),
+			"VRFY" => "252 Send some mail, I'll try my best".into(),
+			"QUIT" => {
+				writer.write_all(format!("221 {hostname} Bye\r\n").as_bytes()).await?;
+				return Ok(());
+			}
+			_ => "500 Unknown command".into(),
+		};
+		writer.write_all(format!("{reply}\r\n").as_bytes()).await?;
+	}
+}
+
+/// Listens on `MAIL_SOCKET` for mail to turn into chunks, does nothing if it isn't set
+pub async fn mail_service(
+	db: LockedAtomic<DB>,
+	tx_r: ResourceSender,
+	tx_w: WebhookSender,
+	mut shutdown_rx: watch::Receiver<()>,
+) {
+	let Some(socket) = *MAIL_SOCKET else {
+		return;
+	};
+	let listener = match TcpListener::bind(socket).await {
+		Ok(listener) => listener,
+		Err(err) => {
+			error!("Couldn't listen for mail on '{socket}': {err:?}");
+			return;
+		}
+	};
+	let client = reqwest::Client::builder()
+		.timeout(Duration::from_secs(30))
+		.build()
+		.unwrap();
+	info!("Listening for mail on '{socket}'.");
+
+	loop {
+		tokio::select! {
+			accepted = listener.accept() => {
+				match accepted {
+					Ok((mut stream, address)) if !allowed(address.ip()) => {
+						error!("Refused mail from {address}, it isn't in MAIL_ALLOW.");
+						tokio::spawn(async move {
+							stream.write_all(b"554 Not allowed to relay mail here\r\n").await.ok();
+						});
+					}
+					Ok((stream, address)) => {
+						let (db, tx_r, tx_w, client) = (db.clone(), tx_r.clone(), tx_w.clone(), client.clone());
+						tokio::spawn(async move {
+							if let Err(err) = session(stream, db, tx_r, tx_w, client, address.ip()).await {
+								error!("Mail session with {address} failed: {err:?}");
+							}
+						});
+					}
+					Err(err) => error!("Couldn't accept mail connection: {err:?}"),
+				}
+			}
+			_ = shutdown_rx.changed() => {
+				break;
+			}
+		}
+	}
+	info!("Mail service shut down.");
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{allowed, compose, mime::parse, session, target, Target};
+	use crate::db::{chunk::ChunkId, dbchunk::DBChunk, DB};
+	use std::sync::{Arc, RwLock};
+	use tokio::{
+		io::{AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+		sync::{broadcast, mpsc},
+	};
+
+	#[test]
+	fn recipients() {
+		let mut db = DB::default();
+		let groceries: DBChunk = "# Groceries List\nshare: john r\n".into();
+		let id = groceries.chunk().id;
+		db.set_chunk(groceries, "nina").unwrap();
+
+		let nina = Ok(Target {
+			user: "nina".into(),
+			parent: id,
+		});
+		assert_eq!(target(&db, "<nina+groceries_list@localhost>"), nina);
+		assert_eq!(target(&db, &format!("<nina+{id}@localhost> SIZE=20")), nina);
+		// Read access isn't enough, and the parent has to be there
+		assert!(target(&db, "<john+groceries_list@localhost>").is_err());
+		assert!(target(&db, "<nina@localhost>").is_err());
+		assert!(target(&db, "<nina+nothing@localhost>").is_err());
+
+		let message = parse(b"From: Shop <shop@x.com>\r\nSubject: Order -> shipped\r\n\r\nIt's on its way.\r\n");
+		let value = compose(&message, id, &["(image/lusab_babad)".into()]);
+		assert_eq!(
+			value,
+			format!("# Order shipped -> {id}\nfrom: Shop <shop@x.com>\n\nIt's on its way.\n\n(image/lusab_babad)\n")
+		);
+		let chunk: DBChunk = value.as_str().into();
+		assert_eq!(chunk.get_prop::<String>("title").as_deref(), Some("Order shipped"));
+		assert!(db.set_chunk(chunk, "nina").is_ok());
+
+		// What the sender wrote never turns into props
+		let message = parse(b"Subject: Hi\r\n\r\nshare: eve a\r\ntemplate: true\r\nSee you soon.\r\n");
+		let value = compose(&message, id, &["share: eve a.pdf (media/lusab_babad)".into()]);
+		assert!(value.contains("\nshare\\: eve a\ntemplate\\: true\nSee you soon.\n"));
+		assert!(value.contains("\nshare\\: eve a.pdf (media/lusab_babad)\n"));
+		let chunk: DBChunk = value.as_str().into();
+		assert_eq!(chunk.get_prop::<String>("template"), None);
+		assert!(!chunk.has_access(&"eve".into()));
+	}
+
+	/// Reads a reply, all lines of a multiline one
+	async fn reply<R: AsyncBufReadExt + Unpin>(reader: &mut R) -> String {
+		let mut reply = String::new();
+		loop {
+			let mut line = String::new();
+			reader.read_line(&mut line).await.unwrap();
+			reply.push_str(&line);
+			if line.as_bytes().get(3) != Some(&b'-') {
+				return reply;
+			}
+		}
+	}
+
+	async fn say<R: AsyncBufReadExt + Unpin, W: AsyncWrite + Unpin>(reader: &mut R, writer: &mut W, line: &str) -> String {
+		writer.write_all(format!("{line}\r\n").as_bytes()).await.unwrap();
+		reply(reader).await
+	}
+
+	#[tokio::test]
+	async fn smtp_session() {
+		// Deliveries get logged
+		let log = std::env::temp_dir().join(format!("chunk_mail_{}", std::process::id()));
+		std::fs::create_dir_all(&log).unwrap();
+		std::env::set_var("DB_PATH_LOG", &log);
+		common::sonnerie::init();
+
+		let mut db = DB::default();
+		let groceries: DBChunk = "# Groceries List\n".into();
+		let parent = groceries.chunk().id;
+		db.set_chunk(groceries, "nina").unwrap();
+		let db = Arc::new(RwLock::new(db));
+
+		let (client, server) = tokio::io::duplex(4096);
+		let (tx_r, tx_w) = (broadcast::channel(16).0, mpsc::unbounded_channel().0);
+		let ip = "127.0.0.1".parse().unwrap();
+		let session = tokio::spawn(session(server, db.clone(), tx_r, tx_w, reqwest::Client::new(), ip));
+		let (reader, mut writer) = tokio::io::split(client);
+		let mut reader = BufReader::new(reader);
+		let (r, w) = (&mut reader, &mut writer);
+
+		assert!(reply(r).await.starts_with("220 "));
+		assert!(say(r, w, "EHLO mx.example.com").await.ends_with("250 SIZE 26214400\r\n"));
+		assert!(say(r, w, "RCPT TO:<nina+groceries_list@localhost>").await.starts_with("503"));
+		assert!(say(r, w, "MAIL FROM:<eve@example.com>").await.starts_with("250"));
+		assert!(say(r, w, "RCPT TO:<nobody+groceries_list@localhost>").await.starts_with("550"));
+		assert!(say(r, w, "DATA").await.starts_with("503"), "No valid recipients yet");
+		assert!(say(r, w, "RCPT TO:<nina+groceries_list@localhost>").await.starts_with("250"));
+		assert!(say(r, w, "DATA").await.starts_with("354"));
+		let data = "From: Eve <eve@example.com>\r\nSubject: Hello\r\n\r\nHi Nina,\r\nshare: eve a\r\n..dot\r\n.";
+		let delivered = say(r, w, data).await;
+		let id = delivered.trim_end().strip_prefix("250 Delivered to ").expect("Delivered");
+		assert!(say(r, w, "QUIT").await.starts_with("221"));
+		session.await.unwrap().unwrap();
+
+		let id = ChunkId::from_quint(id).unwrap();
+		let db = db.read().unwrap();
+		assert!(db.get_chunk(id, "eve").is_none(), "The mail can't share itself");
+		let chunk = db.get_chunk(id, "nina").unwrap();
+		let chunk = chunk.read().unwrap();
+		assert_eq!(chunk.get_prop::<String>("title").as_deref(), Some("Hello"));
+		assert!(chunk.chunk().value.contains("Hi Nina,\nshare\\: eve a\n.dot\n"), "Escaped and unstuffed");
+		assert!(chunk.parents(None).iter().any(|p| p.read().unwrap().chunk().id == parent));
+	}
+
+	#[tokio::test]
+	async fn long_line() {
+		let db = Arc::new(RwLock::new(DB::default()));
+		let (client, server) = tokio::io::duplex(4096);
+		let (tx_r, tx_w) = (broadcast::channel(16).0, mpsc::unbounded_channel().0);
+		let ip = "127.0.0.1".parse().unwrap();
+		let session = tokio::spawn(session(server, db, tx_r, tx_w, reqwest::Client::new(), ip));
+		let (reader, mut writer) = tokio::io::split(client);
+		let mut reader = BufReader::new(reader);
+		let (r, w) = (&mut reader, &mut writer);
+
+		assert!(reply(r).await.starts_with("220 "));
+		assert!(say(r, w, &format!("HELO {}", "x".repeat(993))).await.starts_with("250"), "1000 bytes is fine");
+		assert!(say(r, w, &format!("HELO {}", "x".repeat(2000))).await.starts_with("500"));
+		session.await.unwrap().unwrap();
+		assert_eq!(reply(r).await, "", "Closed");
+	}
+
+	#[test]
+	fn allow_list() {
+		// Without MAIL_ALLOW only local MTAs get through
+		assert!(allowed("127.0.0.1".parse().unwrap()));
+		assert!(allowed("::ffff:127.0.0.1".parse().unwrap()));
+		assert!(!allowed("10.0.0.2".parse().unwrap()));
+		assert!(!allowed("203.0.113.9".parse().unwrap()));
+	}
+}
@@ -1,5 +1,4 @@
-use auth::validate::KPR;
-use axum::{error_handling::HandleErrorLayer, middleware::from_fn, routing::{get, put, post}, BoxError, Extension, Router};
+use axum::{error_handling::HandleErrorLayer, middleware::{from_fn, from_fn_with_state}, routing::{delete, get, put, post}, BoxError, Extension, Router};
 
 use common::{
 	init::{backup::backup_service, save_db},
@@ -21,14 +20,17 @@ use std::{
 use tokio::{
 	join,
 	signal::unix::{signal, SignalKind},
-	sync::{broadcast, watch},
+	sync::{broadcast, mpsc, watch},
 };
 use tower_http::timeout::TimeoutLayer;
 
 use chunk::{
 	db,
 	ends::{self},
+	mail,
+	presence::Presence,
 	socket::{self},
+	webhook,
 };
 
 #[tokio::main]
@@ -56,7 +58,7 @@ async fn main() {
 
 	{
 		// Check that keys exist
-		lazy_static::initialize(&KPR);
+		auth::validate::init_keys();
 	}
 
 	// Read cache
@@ -65,6 +67,8 @@ async fn main() {
 
 	let (shutdown_tx, mut shutdown_rx) = watch::channel(());
 	let (resource_tx, _resource_rx) = broadcast::channel::<ResourceMessage>(16);
+	let (webhook_tx, webhook_rx) = mpsc::unbounded_channel();
+	let presence = Arc::new(RwLock::new(Presence::default()));
 
 	let governor_conf = Box::new(
 		GovernorConfigBuilder::default()
@@ -81,17 +85,53 @@ async fn main() {
 			"/chunks",
 			put(ends::chunks_put).delete(ends::chunks_del),
 		)
+		.route("/chunks/share", post(ends::chunks_share))
+		.route("/chunks/:id/instantiate", post(ends::chunks_instantiate))
+		.route("/chunks/:id/transfer", post(ends::chunks_transfer))
+		.route("/chunks/:id/audit", get(ends::chunks_audit))
+		.route(
+			"/chunks/:id/encrypt",
+			post(ends::chunks_encrypt).delete(ends::chunks_unencrypt),
+		)
+		.route("/chunks/:id/decrypt", post(ends::chunks_decrypt))
+		.route("/chunks/:id/encrypted", put(ends::chunks_put_encrypted))
+		.route("/keys", post(ends::keys_post))
+		.route("/transfers", get(ends::transfers_get))
+		.route("/webhooks", get(ends::webhooks_get).post(ends::webhooks_post))
+		.route("/webhooks/:id", put(ends::webhooks_put).delete(ends::webhooks_del))
+		.route("/webhooks/:id/deliveries", get(ends::webhooks_deliveries))
+		.route("/transfers/:id", delete(ends::transfers_del))
+		.route("/transfers/:id/accept", post(ends::transfers_accept))
+		.route("/graph/:format", get(ends::graph_export))
+		.route("/integrity", get(ends::integrity_get).post(ends::integrity_post))
 		.route("/search/:term", get(ends::search_get))
 		.route("/search", post(ends::search_post))
 		// ONLY if NOT public ^
 		.route_layer(from_fn(auth::validate::flow::auth_required))
 		.route("/chunks/:id", get(ends::chunks_get_id))
-		.route("/stream", get(socket::websocket_handler))
+		.route(
+			"/stream",
+			// Edits come through the socket too
+			get(socket::websocket_handler)
+				.layer(from_fn_with_state("chunk:write", auth::validate::flow::only_scope)),
+		)
 		// // ONLY GET if public ^
 		// .route_layer(from_fn(auth::validate::flow::public_only_get))
 		.route("/page/:id", get(ends::page_get_id))
+		.route("/sitemap.xml", get(ends::sitemap_get))
+		.route("/robots.txt", get(ends::robots_get))
 		// .nest_service("/preview", index_service(WEB_DIST.as_str(), Some("preview.html")))
+		.layer(from_fn_with_state("chunk", auth::validate::flow::scoped))
+		// Asking which media is referenced only reads, POST or not, media's service tokens can
+		.route(
+			"/refs/media",
+			get(ends::media_refs_get)
+				.post(ends::media_refs_post)
+				.layer(from_fn_with_state("chunk:read", auth::validate::flow::only_scope))
+				.layer(from_fn(auth::validate::flow::auth_required)),
+		)
 		.layer(axum::middleware::from_fn(auth::validate::authenticate))
+		.layer(axum::middleware::from_fn(auth::validate::refresh::refresh))
 		// The request limiter :)
 		.layer(
 			ServiceBuilder::new()
@@ -109,11 +149,24 @@ async fn main() {
 				.layer(Extension(db.clone()))
 				.layer(Extension(cache.clone()))
 				.layer(Extension(shutdown_rx.clone()))
-				.layer(Extension(resource_tx.clone())),
+				.layer(Extension(resource_tx.clone()))
+				.layer(Extension(webhook_tx.clone()))
+				.layer(Extension(presence)),
 		);
 
 	// Backup service
 	let backup = tokio::spawn(backup_service(cache.clone(), db.clone(), shutdown_rx.clone()));
+	// Webhook service
+	let webhooks = tokio::spawn(webhook::webhook_service(db.clone(), webhook_rx, shutdown_rx.clone()));
+	// Graph integrity service
+	let integrity = tokio::spawn(db::integrity::integrity_service(db.clone(), shutdown_rx.clone()));
+	// Mail service, only listens if MAIL_SOCKET is set
+	let mail = tokio::spawn(mail::mail_service(
+		db.clone(),
+		resource_tx.clone(),
+		webhook_tx.clone(),
+		shutdown_rx.clone(),
+	));
 
 	info!("Listening on '{}'.", SOCKET.to_string());
 	info!("Public url is on '{}'.", URL.as_str());
@@ -147,7 +200,8 @@ async fn main() {
 	shutdown_tx.send(()).unwrap();
 
 	info!("Waiting for everyone to shutdown.");
-	let (_server_r, _backup_r) = join!(server, backup);
+	let (_server_r, _backup_r, _webhooks_r, _integrity_r, _mail_r) =
+		join!(server, backup, webhooks, integrity, mail);
 
 	info!("Everyone's shut down!");
 
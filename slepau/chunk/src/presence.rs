@@ -0,0 +1,124 @@
+use std::{
+	collections::{HashMap, HashSet},
+	sync::atomic::{AtomicUsize, Ordering},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::db::chunk::ChunkId;
+
+static SOCKET_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Unique id for every socket opened, so many tabs of the same user can be told apart
+pub fn socket_id() -> usize {
+	SOCKET_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum PresenceType {
+	Join,
+	Leave,
+}
+/// Sent on `chunks/<id>/presence` to everyone with access
+#[derive(Serialize, Debug)]
+pub struct PresenceEvent {
+	pub user: String,
+	#[serde(rename = "type")]
+	pub _type: PresenceType,
+}
+
+/// Cursor/selection in char offsets, `start == end` if nothing is selected
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct Cursor {
+	/// Filled in by the server
+	pub user: String,
+	pub start: usize,
+	pub end: usize,
+}
+
+/**
+ * Which sockets have which chunks open, only kept in memory.
+ */
+#[derive(Default, Debug)]
+pub struct Presence {
+	/// Chunk -> Socket -> User
+	open: HashMap<ChunkId, HashMap<usize, String>>,
+}
+
+impl Presence {
+	/// Returns true if user wasn't already there through another socket
+	pub fn join(&mut self, id: ChunkId, socket: usize, user: &str) -> bool {
+		let sockets = self.open.entry(id).or_default();
+		let new = !sockets.values().any(|u| u == user);
+		sockets.insert(socket, user.to_owned());
+		new
+	}
+	/// Returns the user if this was their last socket on the chunk
+	pub fn leave(&mut self, id: ChunkId, socket: usize) -> Option<String> {
+		let sockets = self.open.get_mut(&id)?;
+		let user = sockets.remove(&socket)?;
+		let gone = !sockets.values().any(|u| u == &user);
+		if sockets.is_empty() {
+			self.open.remove(&id);
+		}
+		gone.then_some(user)
+	}
+	/// Leaves every chunk the socket had open, for when it closes
+	pub fn leave_all(&mut self, socket: usize) -> Vec<(ChunkId, String)> {
+		let ids = self
+			.open
+			.iter()
+			.filter(|(_, sockets)| sockets.contains_key(&socket))
+			.map(|(id, _)| *id)
+			.collect::<Vec<_>>();
+		ids
+			.into_iter()
+			.filter_map(|id| self.leave(id, socket).map(|user| (id, user)))
+			.collect()
+	}
+	pub fn is_open(&self, id: ChunkId, socket: usize) -> bool {
+		self.open.get(&id).map(|s| s.contains_key(&socket)).unwrap_or_default()
+	}
+	/// Users that have the chunk open
+	pub fn users(&self, id: ChunkId) -> HashSet<String> {
+		self
+			.open
+			.get(&id)
+			.map(|s| s.values().cloned().collect())
+			.unwrap_or_default()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashSet;
+
+	use super::Presence;
+	use crate::db::chunk::ChunkId;
+
+	#[test]
+	fn presence() {
+		let mut presence = Presence::default();
+		let (a, b) = (ChunkId::default(), ChunkId::default());
+
+		assert!(presence.join(a, 0, "john"));
+		// Second tab
+		assert!(!presence.join(a, 1, "john"));
+		assert!(presence.join(a, 2, "nina"));
+		assert!(presence.join(b, 2, "nina"));
+		assert_eq!(presence.users(a), HashSet::from(["john".into(), "nina".into()]));
+
+		assert_eq!(presence.leave(a, 0), None);
+		assert_eq!(presence.leave(a, 1), Some("john".into()));
+		assert_eq!(presence.leave(a, 1), None);
+
+		let mut left = presence.leave_all(2);
+		left.sort();
+		let mut expected = vec![(a, "nina".to_string()), (b, "nina".to_string())];
+		expected.sort();
+		assert_eq!(left, expected);
+		assert!(presence.users(a).is_empty());
+	}
+}
@@ -0,0 +1,218 @@
+use chrono::{TimeZone, Utc};
+use common::utils::{LockedAtomic, REGEX_ACCESS, REGEX_PROPERTY, REGEX_TITLE, URL};
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::db::{chunk::ChunkId, dbchunk::DBChunk};
+
+lazy_static! {
+	static ref REGEX_IMAGE: Regex =
+		Regex::new(concat!(r"\(image/(", env!("REGEX_PROQUINT"), r")\)")).unwrap();
+	/// Embedded media, not text for a description
+	static ref REGEX_EMBED: Regex =
+		Regex::new(concat!(r"\((?:image|video|media|chunks?)/", env!("REGEX_PROQUINT"), r"\)")).unwrap();
+	static ref REGEX_LINK: Regex = Regex::new(r"!?\[([^\]]*)\]\([^)]*\)").unwrap();
+	static ref REGEX_MARKDOWN: Regex = Regex::new(r"[*_`~#>]|\[[ xX]\]").unwrap();
+}
+
+/// Description length, OpenGraph previews cut around here anyway
+const DESCRIPTION_MAX: usize = 200;
+
+/// Where `/media` lives, `scheme://host` of `URL`
+fn origin() -> String {
+	URL.splitn(4, '/').take(3).collect::<Vec<_>>().join("/")
+}
+
+pub fn page_url(id: ChunkId) -> String {
+	format!("{}/page/{}", URL.trim_end_matches('/'), id.to_quint())
+}
+
+fn escape(value: &str) -> String {
+	value
+		.replace('&', "&amp;")
+		.replace('<', "&lt;")
+		.replace('>', "&gt;")
+		.replace('"', "&quot;")
+		.replace('\'', "&#39;")
+}
+
+/// First paragraph of text as plain text, skipping the title, props and embeds
+fn first_paragraph(value: &str) -> String {
+	let value = REGEX_TITLE.replace(value, "");
+	let paragraph = value
+		.lines()
+		.map(str::trim)
+		.skip_while(|l| {
+			l.is_empty()
+				|| l.starts_with('#')
+				|| REGEX_ACCESS.is_match(l)
+				|| REGEX_PROPERTY.is_match(l)
+				|| REGEX_EMBED.replace_all(l, "").trim().is_empty()
+		})
+		.take_while(|l| !l.is_empty())
+		.collect::<Vec<_>>()
+		.join(" ");
+
+	let text = REGEX_EMBED.replace_all(&paragraph, "");
+	let text = REGEX_LINK.replace_all(&text, "$1");
+	let text = REGEX_MARKDOWN.replace_all(&text, "");
+	let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+	if text.chars().count() > DESCRIPTION_MAX {
+		format!(
+			"{}…",
+			text
+				.chars()
+				.take(DESCRIPTION_MAX - 1)
+				.collect::<String>()
+				.trim_end()
+		)
+	} else {
+		text
+	}
+}
+
+/**
+ * What link previews and search engines get to know about a page.
+ */
+#[derive(Debug, Default, PartialEq)]
+pub struct PageMeta {
+	pub title: String,
+	pub description: String,
+	/// Absolute url of the first embedded image
+	pub image: Option<String>,
+	/// Only public pages are canonical, the rest aren't indexed
+	pub canonical: Option<String>,
+}
+impl From<&DBChunk> for PageMeta {
+	fn from(chunk: &DBChunk) -> Self {
+		let value = &chunk.chunk().value;
+		Self {
+			title: chunk
+				.get_prop::<String>("title")
+				.map(|t| t.trim().to_owned())
+				.unwrap_or_else(|| "Page".into()),
+			description: first_paragraph(value),
+			image: REGEX_IMAGE
+				.captures(value)
+				.map(|c| format!("{}/media/media/{}?max=1200x", origin(), &c[1])),
+			canonical: chunk.is_public().then(|| page_url(chunk.chunk().id)),
+		}
+	}
+}
+impl PageMeta {
+	/// Tags that go in `<head>`
+	pub fn to_html(&self) -> String {
+		let mut tags = vec![];
+		let mut meta = |attribute: &str, key: &str, value: &str| {
+			tags.push(format!(
+				r#"<meta {attribute}="{key}" content="{}" />"#,
+				escape(value)
+			));
+		};
+		meta("property", "og:type", "article");
+		meta("property", "og:title", &self.title);
+		meta("name", "twitter:title", &self.title);
+		if !self.description.is_empty() {
+			meta("name", "description", &self.description);
+			meta("property", "og:description", &self.description);
+			meta("name", "twitter:description", &self.description);
+		}
+		match self.image.as_ref() {
+			Some(image) => {
+				meta("property", "og:image", image);
+				meta("name", "twitter:image", image);
+				meta("name", "twitter:card", "summary_large_image");
+			}
+			None => meta("name", "twitter:card", "summary"),
+		}
+		match self.canonical.as_ref() {
+			Some(canonical) => {
+				meta("property", "og:url", canonical);
+				tags.push(format!(
+					r#"<link rel="canonical" href="{}" />"#,
+					escape(canonical)
+				));
+			}
+			None => meta("name", "robots", "noindex"),
+		}
+		tags.join("\n\t")
+	}
+}
+
+/// `/sitemap.xml` of every public page, most recently modified first
+pub fn sitemap(chunks: &[LockedAtomic<DBChunk>]) -> String {
+	let urls = chunks
+		.iter()
+		.map(|c| {
+			let chunk = c.read().unwrap();
+			let modified = Utc
+				.timestamp_opt(chunk.chunk().modified as i64, 0)
+				.single()
+				.map(|d| format!("<lastmod>{}</lastmod>", d.format("%Y-%m-%d")))
+				.unwrap_or_default();
+			format!(
+				"\t<url><loc>{}</loc>{modified}</url>\n",
+				escape(&page_url(chunk.chunk().id))
+			)
+		})
+		.collect::<String>();
+	format!(
+		"<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n{urls}</urlset>\n"
+	)
+}
+
+/// `/robots.txt`, only public pages can be crawled
+pub fn robots(chunks: &[LockedAtomic<DBChunk>]) -> String {
+	let path = URL
+		.splitn(4, '/')
+		.nth(3)
+		.map(|p| format!("/{}", p.trim_end_matches('/')))
+		.unwrap_or_default();
+	let allow = chunks
+		.iter()
+		.map(|c| {
+			format!(
+				"Allow: {path}/page/{}\n",
+				c.read().unwrap().chunk().id.to_quint()
+			)
+		})
+		.collect::<String>();
+	format!(
+		"User-agent: *\n{allow}Disallow: /\n\nSitemap: {}/sitemap.xml\n",
+		URL.trim_end_matches('/')
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{first_paragraph, PageMeta};
+	use crate::db::dbchunk::DBChunk;
+
+	#[test]
+	fn meta() {
+		let chunk = DBChunk::from((
+			None,
+			"# Trip\nshare: public r\n\n(image/lusab_babad)\n\nWe went to the **lake** and [swam](https://lake.org).\nIt was cold.\n\nMore later.",
+			"john",
+		));
+		let meta = PageMeta::from(&chunk);
+		assert_eq!(meta.title, "Trip");
+		assert_eq!(
+			meta.description,
+			"We went to the lake and swam. It was cold."
+		);
+		assert!(meta
+			.image
+			.unwrap()
+			.ends_with("/media/media/lusab_babad?max=1200x"));
+		assert!(meta.canonical.is_some());
+
+		let long = format!("# Long\n{}", "word ".repeat(100));
+		assert_eq!(first_paragraph(&long).chars().count(), 200);
+		assert!(
+			PageMeta::from(&DBChunk::from((None, "# Private\n", "john")))
+				.canonical
+				.is_none()
+		);
+	}
+}
@@ -1,9 +1,10 @@
 use std::{
-	collections::VecDeque,
+	cell::Cell,
+	collections::{HashMap, HashSet, VecDeque},
 	io::BufWriter,
 	net::SocketAddr,
 	sync::RwLock,
-	time::Duration,
+	time::{Duration, Instant},
 };
 
 use axum::{
@@ -18,7 +19,7 @@ use axum::{
 use brotli::enc::BrotliEncoderParams;
 use common::{
 	socket::{MessageType, ResourceMessage, ResourceSender, SocketMessage},
-	utils::LockedAtomic,
+	utils::{DbError, LockedAtomic},
 	vreji::log_ip_user_id,
 };
 
@@ -33,54 +34,88 @@ type ClientIp = InsecureClientIp;
 use auth::UserClaims;
 
 use crate::db::{
+	board::{BoardQuery, PropIn, TableQuery},
 	chunk::ChunkId,
-	dbchunk::DBChunk,
-	view::{ChunkValue, ChunkVec, ChunkView, SortType, ViewType},
+	comment::{Thread, ThreadId, ThreadIn},
+	dbchunk::{set_prop_line, DBChunk},
+	graph::GraphQuery,
+	template::TemplateInstance,
+	view::{ChunkPlacement, ChunkValue, ChunkVec, ChunkView, CursorQuery, SearchQuery, SortType, ViewType},
+	webhook::WebhookEvent,
 	DB,
 };
+use crate::{
+	presence::{socket_id, Cursor as PresenceCursor, Presence, PresenceEvent, PresenceType},
+	webhook::{dispatch, WebhookSender},
+};
+
+/// Cursors are relayed at most this often per chunk
+const CURSOR_THROTTLE: Duration = Duration::from_millis(100);
 
 pub async fn websocket_handler(
 	ws: WebSocketUpgrade,
 	Extension(_user): Extension<UserClaims>,
 	Extension(db): Extension<LockedAtomic<DB>>,
 	Extension(tx_r): Extension<ResourceSender>,
+	Extension(tx_w): Extension<WebhookSender>,
+	Extension(presence): Extension<LockedAtomic<Presence>>,
 	Extension(shutdown_rx): Extension<watch::Receiver<()>>,
 	ip: ClientIp,
 	ConnectInfo(address): ConnectInfo<SocketAddr>,
 ) -> Response {
 	info!("Opening Websocket with {} on {}.", &_user.user, address);
-	ws.on_upgrade(move |socket| handle_socket(ip, socket, _user, db, tx_r, shutdown_rx, address))
+	ws.on_upgrade(move |socket| {
+		handle_socket(ip, socket, _user, db, tx_r, tx_w, presence, shutdown_rx, address)
+	})
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn handle_socket(
 	ip: ClientIp,
 	socket: WebSocket,
 	user_claims: UserClaims,
 	db: LockedAtomic<DB>,
 	tx_resource: ResourceSender,
+	tx_webhook: WebhookSender,
+	presence: LockedAtomic<Presence>,
 	mut shutdown_rx: watch::Receiver<()>,
 	address: SocketAddr,
 ) {
 	let user = &user_claims.user;
+	let socket_id = socket_id();
 	// Create a new receiver for our Broadcast
 	let mut rx_resource = tx_resource.subscribe();
 
 	let (mut tx_socket, mut rx_socket) = socket.split();
 
-	let get_notes = || {
-		let mut chunks: ChunkVec = db.write().unwrap().get_chunks(user).into();
+	// Pages the view if a query was sent, otherwise gives all of it
+	let maybe_paginate = |query: Option<CursorQuery>, chunks: ChunkVec, view_type: ViewType| {
+		let view = |chunks: ChunkVec| {
+			chunks
+				.0
+				.into_iter()
+				.map(|v| ChunkView::from((v, user.as_str(), view_type)))
+				.collect::<Vec<_>>()
+		};
+		if let Some(query) = query {
+			let total = chunks.0.len();
+			let data = view(chunks.paginate(&query));
+			json!({"query": query, "data": data, "total": total})
+		} else {
+			json!(view(chunks))
+		}
+	};
+
+	let get_notes = |query: Option<CursorQuery>| {
+		let mut chunks: ChunkVec = db.read().unwrap().get_chunks(user).into();
 		chunks.sort(SortType::Modified);
-		let chunks = chunks.0;
-		// maybe_paginate((query, chunks, &|v| ChunkView::from((v, user.as_str(), ViewType::Notes))))
-		let chunks = chunks
-			.into_iter()
-			.map(|v| ChunkView::from((v, user.as_str(), ViewType::Notes)))
-			.collect::<Vec<_>>();
-		json!(chunks)
+		maybe_paginate(query, chunks, ViewType::Notes)
 	};
 
 	// [[parent,parent], [child,child]]
-	let get_subtree = |root: Option<ChunkId>, view_type: ViewType| {
+	// If paged, only the children get paged
+	let get_subtree = |root: Option<ChunkId>, view_type: ViewType, query: Option<CursorQuery>| {
+		let total = Cell::new(0);
 		let root = root.and_then(|id| db.try_read().unwrap().get_chunk(id, user));
 		let subtree = db.try_read().unwrap().subtree(
 			root.as_ref(),
@@ -88,12 +123,20 @@ async fn handle_socket(
 			&|v| {
 				let mut vec = ChunkVec::from(v);
 				vec.sort(SortType::ModifiedDynamic(user.as_str().into()));
+				if let Some(query) = query.as_ref() {
+					total.set(vec.0.len());
+					vec = vec.paginate(query);
+				}
 				vec.into()
 			},
 			&|v| json!(ChunkView::from((v, user.as_str(), view_type))),
 			1,
 		);
-		json!(subtree)
+		if let Some(query) = query {
+			json!({"query": query, "data": subtree, "total": total.get()})
+		} else {
+			json!(subtree)
+		}
 	};
 
 	// Keep last resource id so when we're sending
@@ -101,6 +144,62 @@ async fn handle_socket(
 	// the message on the instance that sent it
 	// (if it was incremented by that instance beforehand)
 	let resource_id_last = RwLock::new(0);
+	// Last time a cursor was relayed per chunk, to throttle them
+	let cursor_last = RwLock::new(HashMap::<ChunkId, Instant>::default());
+	// Latest cursor per chunk that came in while throttled, relayed once its window is over
+	let cursor_pending = RwLock::new(HashMap::<ChunkId, PresenceCursor>::default());
+	// Sends our cursor to the chunk's viewers, if we and they still have access
+	let relay_cursor = |id: ChunkId, cursor: &PresenceCursor| {
+		let chunk = db.read().unwrap().get_chunk(id, user);
+		let Some(chunk) = chunk else {
+			return false;
+		};
+		let chunk = chunk.read().unwrap();
+		if !chunk.has_access(&user.as_str().into()) {
+			return false;
+		}
+		let users = presence
+			.read()
+			.unwrap()
+			.users(id)
+			.into_iter()
+			.filter(|u| chunk.has_access(&u.as_str().into()))
+			.collect::<HashSet<_>>();
+		let m = ResourceMessage::from((format!("chunks/{id}/cursor").as_str(), users, cursor));
+		*resource_id_last.write().unwrap() = m.id;
+		tx_resource.send(m).unwrap();
+		cursor_last.write().unwrap().insert(id, Instant::now());
+		true
+	};
+	// When the earliest pending cursor is due
+	let cursor_due = || {
+		let cursor_last = cursor_last.read().unwrap();
+		cursor_pending
+			.read()
+			.unwrap()
+			.keys()
+			.filter_map(|id| cursor_last.get(id))
+			.min()
+			.map(|t| *t + CURSOR_THROTTLE)
+	};
+	let flush_cursors = || {
+		let due = {
+			let cursor_last = cursor_last.read().unwrap();
+			let mut pending = cursor_pending.write().unwrap();
+			let ids = pending
+				.keys()
+				.filter(|id| cursor_last.get(id).map(|t| t.elapsed() >= CURSOR_THROTTLE).unwrap_or(true))
+				.copied()
+				.collect::<Vec<_>>();
+			ids.into_iter().filter_map(|id| pending.remove(&id).map(|c| (id, c))).collect::<Vec<_>>()
+		};
+		for (id, cursor) in due {
+			// We might have left since
+			if presence.read().unwrap().is_open(id, socket_id) {
+				relay_cursor(id, &cursor);
+			}
+		}
+	};
 	// Keep a list of explicitely acccessed chunks
 	// So we don't give away all our public chunks to everyone
 	// let access_list = Mutex::new(HashSet::default());
@@ -150,55 +249,219 @@ async fn handle_socket(
 			brotli::BrotliCompress(&mut r, &mut w, &BrotliEncoderParams::default()).unwrap();
 			Some(Message::Binary(w))
 		};
-		let mut res = m.resource.split('/').collect::<VecDeque<_>>();
+		// Resources can carry a query, `views/graph/<root>?depth=2`
+		let (resource, resource_query) = m.resource.split_once('?').unwrap_or((m.resource.as_str(), ""));
+		let mut res = resource.split('/').collect::<VecDeque<_>>();
 		let mut piece = res.pop_front();
 
+		// User wants to change a value
+		let update_value = |id: ChunkId, value: &str| {
+			let db_chunk: DBChunk = (id, value).into();
+			let before = db.read().unwrap().placement(id);
+			let result = db.write().unwrap().update_chunk(db_chunk, user);
+			match result {
+				Ok((users_to_notify, diff, db_chunk)) => {
+					let access_changed = !users_to_notify.is_empty();
+					let after = ChunkPlacement::from(&*db_chunk.read().unwrap());
+					let users = db_chunk.read().unwrap().access_users();
+					let m = ResourceMessage::from((format!("chunks/{}/value/diff", id).as_str(), users.clone(), &diff));
+					{
+						// Update our resource_id_last so we don't send the same data back when sending a signal to tx_resource
+						let mut resource_id_last = resource_id_last.write().unwrap();
+						*resource_id_last = m.id;
+					}
+					tx_resource.send(m).unwrap();
+					tx_resource
+						.send(ResourceMessage::from((
+							format!("chunks/{}", id).as_str(),
+							users.clone(),
+							&ChunkView::from((db_chunk, user.as_str(), ViewType::Edit)),
+						)))
+						.unwrap();
+
+					{
+						let db = db.read().unwrap();
+						dispatch(&db, &tx_webhook, id, WebhookEvent::Edited, user, Some(&diff));
+						if !users_to_notify.is_empty() {
+							dispatch(&db, &tx_webhook, id, WebhookEvent::Shared, user, None);
+						}
+					}
+					if !users_to_notify.is_empty() {
+						tx_resource
+							.send(ResourceMessage::from(("chunks", users_to_notify)))
+							.unwrap();
+					}
+					for m in before.deltas(id, &after) {
+						tx_resource.send(m).unwrap();
+					}
+					// Threads were re-anchored along with the edit
+					if let Ok(threads) = db.read().unwrap().get_threads(id, user) {
+						if !threads.is_empty() {
+							let m = ResourceMessage::from((format!("chunks/{id}/comments").as_str(), users, &threads));
+							tx_resource.send(m).unwrap();
+						}
+					}
+
+					log_ip_user_id("chunk_edit", ip.0, &user_claims.user, id.inner().into());
+					if access_changed {
+						log_ip_user_id("chunk_access", ip.0, &user_claims.user, id.inner().into());
+					}
+					reply(MessageType::Ok.into())
+				}
+				Err(err) => {
+					log_ip_user_id("chunk_edit_error", ip.0, &user_claims.user, id.inner().into());
+					reply((MessageType::Error, &format!("{err:?}")).into())
+				}
+			}
+		};
+
 		if piece == Some("chunks") {
 			if let Some(id) = res.pop_front().map(|id| ChunkId::from_quint(id).expect("a ChunkId.")) {
 				piece = res.pop_front();
 
 				if piece == Some("value") {
 					if let Some(value) = m.value {
-						// User wants to change a value
-						let db_chunk: DBChunk = (id, value.as_str()).into();
-						match db.write().unwrap().update_chunk(db_chunk, user) {
-							Ok((users_to_notify, diff, db_chunk)) => {
-								let users = db_chunk.read().unwrap().access_users();
-								let m = ResourceMessage::from((format!("chunks/{}/value/diff", id).as_str(), users.clone(), &diff));
-								{
-									// Update our resource_id_last so we don't send the same data back when sending a signal to tx_resource
-									let mut resource_id_last = resource_id_last.write().unwrap();
-									*resource_id_last = m.id;
-								}
-								tx_resource.send(m).unwrap();
-								tx_resource
-									.send(ResourceMessage::from((
-										format!("chunks/{}", id).as_str(),
-										users,
-										&ChunkView::from((db_chunk, user.as_str(), ViewType::Edit)),
-									)))
-									.unwrap();
-
-								if !users_to_notify.is_empty() {
-									tx_resource
-										.send(ResourceMessage::from(("chunks", users_to_notify)))
-										.unwrap();
-								}
-
-								log_ip_user_id("chunk_edit", ip.0, &user_claims.user, id.inner().into());
-								return reply(MessageType::Ok.into());
-							}
-							Err(err) => {
-								log_ip_user_id("chunk_edit_error", ip.0, &user_claims.user, id.inner().into());
-								return reply((MessageType::Error, &format!("{err:?}")).into());
-							}
-						}
+						return update_value(id, &value);
 					} else {
 						// Request for "chunks/<id>/value"
 						if let Some(v) = db.read().unwrap().get_chunk(id, user) {
 							return reply((&ChunkValue::from(v)).into());
 						}
 					}
+				} else if piece == Some("prop") {
+					// Moving a card on a board rewrites the `key: value` line
+					let prop = m
+						.value
+						.as_ref()
+						.and_then(|v| serde_json::from_str::<PropIn>(v).ok())
+						.unwrap_or_default();
+					let value = db
+						.read()
+						.unwrap()
+						.get_chunk(id, user)
+						.and_then(|c| set_prop_line(&c.read().unwrap().chunk().value, &prop.key, &prop.value));
+					return match value {
+						Some(value) => update_value(id, &value),
+						None => reply((MessageType::Error, &"InvalidProp".to_string()).into()),
+					};
+				} else if piece == Some("instantiate") {
+					// User wants a new chunk out of a template
+					let instance = m
+						.value
+						.as_ref()
+						.and_then(|v| serde_json::from_str::<TemplateInstance>(v).ok())
+						.unwrap_or_default();
+					let result = db.write().unwrap().instantiate(id, user, instance);
+					match result {
+						Ok((new_id, users_to_notify)) => {
+							tx_resource
+								.send(ResourceMessage::from(("chunks", users_to_notify)))
+								.unwrap();
+							for m in ChunkPlacement::default().deltas(new_id, &db.read().unwrap().placement(new_id)) {
+								tx_resource.send(m).unwrap();
+							}
+							dispatch(&db.read().unwrap(), &tx_webhook, new_id, WebhookEvent::Created, user, None);
+							log_ip_user_id("chunk_instantiate", ip.0, &user_claims.user, new_id.inner().into());
+							return reply((&new_id).into());
+						}
+						Err(err) => {
+							return reply((MessageType::Error, &format!("{err:?}")).into());
+						}
+					}
+				} else if piece == Some("presence") && user != "public" {
+					// Who else has this chunk open, "join"/"leave" to change our own presence.
+					// Only for users with access, not everyone that can see a public chunk.
+					let chunk = db
+						.read()
+						.unwrap()
+						.get_chunk(id, user)
+						.filter(|c| c.read().unwrap().has_access(&user.as_str().into()));
+					if let Some(chunk) = chunk {
+						let event = match res.pop_front() {
+							Some("join") => presence.write().unwrap().join(id, socket_id, user).then_some(PresenceType::Join),
+							Some("leave") => presence.write().unwrap().leave(id, socket_id).map(|_| PresenceType::Leave),
+							_ => None,
+						};
+						if let Some(_type) = event {
+							let m = ResourceMessage::from((
+								format!("chunks/{id}/presence").as_str(),
+								chunk.read().unwrap().access_users(),
+								&PresenceEvent { user: user.clone(), _type },
+							));
+							*resource_id_last.write().unwrap() = m.id;
+							tx_resource.send(m).unwrap();
+						}
+						return reply((&presence.read().unwrap().users(id)).into());
+					}
+				} else if piece == Some("comments") {
+					// "comments", "comments/new" or "comments/<thread>/<reply|resolve|reopen|delete>"
+					let result: Result<Option<Thread>, _> = match (res.pop_front(), res.pop_front()) {
+						(None, _) => db.read().unwrap().get_threads(id, user).map(|_| None),
+						(Some("new"), None) => {
+							let thread = m
+								.value
+								.as_ref()
+								.and_then(|v| serde_json::from_str::<ThreadIn>(v).ok())
+								.unwrap_or_default();
+							db.write().unwrap().thread_new(id, user, thread).map(Some)
+						}
+						(Some(thread), action) => match (ThreadId::from_quint(thread), action) {
+							(Ok(thread), Some("reply")) => db
+								.write()
+								.unwrap()
+								.thread_reply(id, thread, user, m.value.clone().unwrap_or_default())
+								.map(Some),
+							(Ok(thread), Some("resolve")) => {
+								db.write().unwrap().thread_resolve(id, thread, user, true).map(Some)
+							}
+							(Ok(thread), Some("reopen")) => {
+								db.write().unwrap().thread_resolve(id, thread, user, false).map(Some)
+							}
+							(Ok(thread), Some("delete")) => db.write().unwrap().thread_del(id, thread, user).map(Some),
+							_ => Err(DbError::NotFound),
+						},
+					};
+					match result {
+						Ok(changed) => {
+							let db = db.read().unwrap();
+							let threads = db.get_threads(id, user).unwrap_or_default();
+							if let (Some(thread), Some(chunk)) = (changed, db.get_chunk(id, user)) {
+								log_ip_user_id("chunk_comment", ip.0, &user_claims.user, id.inner().into());
+								// Everyone in the thread and the owner, if they can still see the chunk
+								let chunk = chunk.read().unwrap();
+								let mut users = thread.participants();
+								users.insert(chunk.chunk().owner.clone());
+								users.retain(|u| chunk.has_access(&u.as_str().into()));
+								let m = ResourceMessage::from((format!("chunks/{id}/comments").as_str(), users, &threads));
+								*resource_id_last.write().unwrap() = m.id;
+								tx_resource.send(m).unwrap();
+							}
+							return reply((&threads).into());
+						}
+						Err(err) => {
+							return reply((MessageType::Error, &format!("{err:?}")).into());
+						}
+					}
+				} else if piece == Some("cursor") {
+					if !presence.read().unwrap().is_open(id, socket_id) {
+						return reply((MessageType::Error, &"Join presence first".to_string()).into());
+					}
+					let mut cursor = m
+						.value
+						.as_ref()
+						.and_then(|v| serde_json::from_str::<PresenceCursor>(v).ok())
+						.unwrap_or_default();
+					cursor.user = user.clone();
+					let throttled = cursor_last.read().unwrap().get(&id).map(|t| t.elapsed() < CURSOR_THROTTLE) == Some(true);
+					if throttled {
+						// Only the latest one matters
+						cursor_pending.write().unwrap().insert(id, cursor);
+						return None;
+					}
+					cursor_pending.write().unwrap().remove(&id);
+					if relay_cursor(id, &cursor) {
+						return reply(MessageType::Ok.into());
+					}
 				} else if piece.is_none() {
 					if let Some(v) = db.read().unwrap().get_chunk(id, user) {
 						return reply((&ChunkView::from((v, user.as_str(), ViewType::Edit))).into());
@@ -213,15 +476,50 @@ async fn handle_socket(
 		} else if piece == Some("views") {
 			piece = res.pop_front();
 			let root_id = res.pop_front().map(|id| ChunkId::from_quint(id).expect("a ChunkId."));
+			// Views are paged when a CursorQuery is sent
+			let query = m.value.as_ref().and_then(|v| serde_json::from_str::<CursorQuery>(v).ok());
 			if piece == Some("notes") {
-				return reply((&get_notes()).into());
+				return reply((&get_notes(query)).into());
 			} else if piece == Some("well") {
-				return reply((&get_subtree(root_id, ViewType::Well)).into());
+				return reply((&get_subtree(root_id, ViewType::Well, query)).into());
 			} else if piece == Some("graph") {
-				return reply((&get_subtree(root_id, ViewType::Graph)).into());
+				if !resource_query.is_empty() {
+					// Bounded multi level graph
+					let query = serde_urlencoded::from_str::<GraphQuery>(resource_query).unwrap_or_default();
+					return reply((&db.read().unwrap().graph(root_id, user, query)).into());
+				}
+				return reply((&get_subtree(root_id, ViewType::Graph, None)).into());
+			} else if piece == Some("templates") {
+				let templates = db
+					.read()
+					.unwrap()
+					.get_templates(user)
+					.into_iter()
+					.map(|v| ChunkView::from((v, user.as_str(), ViewType::Notes)))
+					.collect::<Vec<_>>();
+				return reply((&json!(templates)).into());
+			} else if piece == Some("board") {
+				let query = serde_urlencoded::from_str::<BoardQuery>(resource_query).unwrap_or_default();
+				return reply((&db.read().unwrap().board(root_id, user, query)).into());
+			} else if piece == Some("table") {
+				let query = serde_urlencoded::from_str::<TableQuery>(resource_query).unwrap_or_default();
+				return reply((&db.read().unwrap().table(root_id, user, query)).into());
+			} else if piece == Some("schemas") {
+				return reply((&db.read().unwrap().get_schemas(user)).into());
 			}
 			error!("View needs name");
 			return None;
+		} else if piece == Some("search") {
+			let search = m
+				.value
+				.as_ref()
+				.and_then(|v| serde_json::from_str::<SearchQuery>(v).ok())
+				.unwrap_or_default();
+			let result = db.read().unwrap().search(user, &search.term);
+			return match result {
+				Ok(chunks) => reply((&maybe_paginate(Some(search.query), chunks, ViewType::Well)).into()),
+				Err(err) => reply((MessageType::Error, &format!("{err:?}")).into()),
+			};
 		} else if piece == Some("user") {
 			let mut user = json!(&user_claims);
 			if let Value::Object(mut user_o) = user {
@@ -284,6 +582,7 @@ async fn handle_socket(
 		Ok(messages)
 	};
 	loop {
+		let due = cursor_due();
 		tokio::select! {
 			// Handles Websocket incomming
 			m = rx_socket.next() => {
@@ -328,6 +627,10 @@ async fn handle_socket(
 			_ = shutdown_rx.changed() => {
 				break;
 			}
+			// Relays cursors held back by the throttle
+			_ = time::sleep_until(due.unwrap_or_else(Instant::now).into()), if due.is_some() => {
+				flush_cursors();
+			}
 			// Send a ping message
 			_ = time::sleep(Duration::from_secs(20u64)) => {
 				tx_socket.send(Message::Ping(vec![50u8])).await.unwrap();
@@ -336,5 +639,22 @@ async fn handle_socket(
 		}
 	}
 
+	// Whatever we had open, we don't anymore
+	let left = presence.write().unwrap().leave_all(socket_id);
+	for (id, user) in left {
+		if let Some(chunk) = db.read().unwrap().get_chunk_(id) {
+			tx_resource
+				.send(ResourceMessage::from((
+					format!("chunks/{id}/presence").as_str(),
+					chunk.read().unwrap().access_users(),
+					&PresenceEvent {
+						user,
+						_type: PresenceType::Leave,
+					},
+				)))
+				.unwrap();
+		}
+	}
+
 	info!("Closed socket with {user} on {address}");
 }
@@ -0,0 +1,182 @@
+use std::{net::SocketAddr, time::Duration};
+
+use common::utils::{get_secs, LockedAtomic};
+use hmac::{Hmac, Mac};
+use log::{error, info};
+use sha2::Sha256;
+use tokio::{
+	sync::{mpsc, watch},
+	time,
+};
+
+use crate::db::{
+	chunk::ChunkId,
+	webhook::{is_public, Delivery, WebhookEvent, WebhookJob},
+	DB,
+};
+
+pub type WebhookSender = mpsc::UnboundedSender<WebhookJob>;
+
+/// Attempts per payload, waits 2^attempt seconds in between
+const ATTEMPTS_MAX: u32 = 5;
+
+/// `sha256=<hex>` signature of the body, sent as `X-Chunk-Signature`
+pub fn sign(secret: &str, body: &[u8]) -> String {
+	let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes any key size");
+	mac.update(body);
+	let hex = mac
+		.finalize()
+		.into_bytes()
+		.iter()
+		.map(|b| format!("{b:02x}"))
+		.collect::<String>();
+	format!("sha256={hex}")
+}
+
+/// Queues payloads for every webhook interested in this event
+pub fn dispatch(
+	db: &DB,
+	tx: &WebhookSender,
+	id: ChunkId,
+	event: WebhookEvent,
+	user: &str,
+	diff: Option<&Vec<String>>,
+) {
+	for job in db.webhook_jobs(id, event, user, diff) {
+		if tx.send(job).is_err() {
+			error!("Webhook service isn't running, dropping payload.");
+		}
+	}
+}
+
+/// Client for `url` that only connects to the public addresses its host resolves to, pinned so a
+/// later lookup can't point it elsewhere. Redirects aren't followed, they could lead anywhere.
+async fn client_for(url: &str) -> Result<reqwest::Client, String> {
+	let url = reqwest::Url::parse(url).map_err(|err| err.to_string())?;
+	let host = url.host_str().ok_or("No host")?;
+	let port = url.port_or_known_default().ok_or("No port")?;
+	let addrs = tokio::net::lookup_host((host.trim_matches(['[', ']']), port))
+		.await
+		.map_err(|err| format!("Couldn't resolve {host}: {err}"))?
+		.collect::<Vec<SocketAddr>>();
+	if addrs.is_empty() {
+		return Err(format!("{host} doesn't resolve"));
+	}
+	if let Some(addr) = addrs.iter().find(|a| !is_public(a.ip())) {
+		return Err(format!("{host} resolves to {}, which isn't public", addr.ip()));
+	}
+	reqwest::Client::builder()
+		.timeout(Duration::from_secs(10))
+		.redirect(reqwest::redirect::Policy::none())
+		.resolve_to_addrs(host, &addrs)
+		.build()
+		.map_err(|err| err.to_string())
+}
+
+async fn deliver(db: LockedAtomic<DB>, job: WebhookJob) {
+	let body = serde_json::to_vec(&job.payload).unwrap();
+	let signature = sign(&job.secret, &body);
+	let mut delivery = Delivery {
+		event: job.event,
+		chunk: job.chunk,
+		time: get_secs(),
+		attempts: 0,
+		status: None,
+		error: None,
+		delivered: false,
+	};
+
+	while delivery.attempts < ATTEMPTS_MAX {
+		if delivery.attempts > 0 {
+			time::sleep(Duration::from_secs(2u64.pow(delivery.attempts))).await;
+		}
+		delivery.attempts += 1;
+
+		let client = match client_for(&job.url).await {
+			Ok(client) => client,
+			Err(err) => {
+				delivery.status = None;
+				delivery.error = Some(err);
+				continue;
+			}
+		};
+		let response = client
+			.post(&job.url)
+			.header("Content-Type", "application/json")
+			.header("X-Chunk-Event", serde_json::to_value(job.event).unwrap().as_str().unwrap())
+			.header("X-Chunk-Signature", &signature)
+			.body(body.clone())
+			.send()
+			.await;
+		match response {
+			Ok(response) => {
+				delivery.status = Some(response.status().as_u16());
+				delivery.error = None;
+				if response.status().is_success() {
+					delivery.delivered = true;
+					break;
+				}
+			}
+			Err(err) => {
+				delivery.status = None;
+				delivery.error = Some(err.to_string());
+			}
+		}
+	}
+
+	if !delivery.delivered {
+		info!("Webhook {} gave up on {} after {} attempts.", job.webhook, job.url, delivery.attempts);
+	}
+	db.write().unwrap().webhook_delivered(job.webhook, delivery);
+}
+
+/// Delivers queued payloads, each on its own task so retries don't hold up the rest
+pub async fn webhook_service(
+	db: LockedAtomic<DB>,
+	mut rx: mpsc::UnboundedReceiver<WebhookJob>,
+	mut shutdown_rx: watch::Receiver<()>,
+) {
+	loop {
+		tokio::select! {
+			job = rx.recv() => {
+				if let Some(job) = job {
+					tokio::spawn(deliver(db.clone(), job));
+				} else {
+					break;
+				}
+			}
+			_ = shutdown_rx.changed() => {
+				break;
+			}
+		}
+	}
+	info!("Webhook service shut down.");
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{client_for, sign};
+
+	#[test]
+	fn signature() {
+		// RFC 4231, test case 2
+		assert_eq!(
+			sign("Jefe", b"what do ya want for nothing?"),
+			"sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+		);
+	}
+
+	#[tokio::test]
+	async fn private_addresses() {
+		for url in [
+			"http://localhost:4002/x",
+			"http://127.0.0.1/",
+			"http://[::1]/",
+			"http://169.254.169.254/",
+			"http://10.1.2.3/",
+		] {
+			assert!(client_for(url).await.is_err(), "{url}");
+		}
+		assert!(client_for("http://93.184.215.14/").await.is_ok());
+	}
+}
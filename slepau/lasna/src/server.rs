@@ -1,6 +1,5 @@
 use std::{net::SocketAddr, time::Duration};
 
-use auth::validate::KPR;
 use axum::{
 	error_handling::HandleErrorLayer, response::IntoResponse, BoxError,
 	Extension, Json, Router,
@@ -34,7 +33,7 @@ async fn get_devices() -> impl IntoResponse {
 pub async fn spawn_server(mut shutdown_rx: tokio::sync::watch::Receiver<()>) {
 	{
 		// Check that keys exist
-		lazy_static::initialize(&KPR);
+		auth::validate::init_keys();
 	}
 
 	let governor_conf = Box::new(
@@ -52,7 +51,9 @@ pub async fn spawn_server(mut shutdown_rx: tokio::sync::watch::Receiver<()>) {
 		.route("/", axum::routing::get(get_devices))
 		.layer(axum::middleware::from_fn(auth::validate::flow::only_supers))
 		// .route("/:device_id", axum::routing::get(get_device))
+		.layer(axum::middleware::from_fn_with_state("lasna", auth::validate::flow::scoped))
 		.layer(axum::middleware::from_fn(auth::validate::authenticate))
+		.layer(axum::middleware::from_fn(auth::validate::refresh::refresh))
 		// The request limiter :)
 		.layer(
 			ServiceBuilder::new()
@@ -3,7 +3,8 @@ use super::{
 	version::{VersionReference, VersionString},
 	Media, MediaId, MediaStats, DB,
 };
-use common::utils::{get_secs, DbError, LockedAtomic};
+use common::utils::{get_secs, DbError, LockedAtomic, CACHE_FOLDER};
+use futures::{future::join_all, join};
 use log::info;
 use media::MEDIA_FOLDER;
 use serde::{Deserialize, Serialize};
@@ -157,6 +158,10 @@ impl DB {
 			.map(|v| v.iter().filter_map(|v| v.upgrade()).collect())
 			.unwrap_or_default()
 	}
+	/// Everyone with media
+	pub fn owners(&self) -> Vec<String> {
+		self.by_owner.keys().cloned().collect()
+	}
 	pub fn user_stats(&self, user: &str) -> MediaStats {
 		self.by_owner.get(user).map(MediaStats::from_iter).unwrap_or_default()
 	}
@@ -205,6 +210,23 @@ impl DB {
 	}
 }
 
+/// Removes the original and every version of a deleted media from disk
+pub async fn remove_files(media: &Media) -> Result<(), DbError> {
+	let cache = std::path::Path::new(CACHE_FOLDER.as_str());
+	let removes = media
+		.versions
+		.keys()
+		.map(|version| {
+			tokio::fs::remove_file(cache.join(VersionReference::from((media.id, version.clone())).filename_out()))
+		})
+		.collect::<Vec<_>>();
+	let removes = join_all(removes);
+	let original = tokio::fs::remove_file(VersionReference::to_path_in(media.id));
+
+	let (_removes, original) = join!(removes, original);
+	original.map_err(|_| DbError::from("File not found"))
+}
+
 pub fn load_existing(db: LockedAtomic<DB>) {
 	let path = std::path::Path::new(MEDIA_FOLDER.as_str());
 	if let Ok(entries) = std::fs::read_dir(path) {
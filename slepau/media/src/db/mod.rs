@@ -37,6 +37,9 @@ pub struct Media {
 	///
 	/// Note: this is not the image's metadata creation time
 	pub created: u64,
+	/// When the gc first found no chunk referencing it (in secs), cleared once one does
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub unreferenced: Option<u64>,
 }
 #[derive(Default, Serialize)]
 pub struct MediaStats {
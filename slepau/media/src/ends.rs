@@ -11,10 +11,9 @@ use axum::{
 };
 use common::{
 	socket::ResourceSender,
-	utils::{DbError, LockedAtomic, CACHE_FOLDER},
+	utils::{DbError, LockedAtomic},
 	vreji::log_ip_user_id,
 };
-use futures::{future::join_all, join};
 use hyper::StatusCode;
 
 use log::info;
@@ -29,6 +28,7 @@ use axum_client_ip::InsecureClientIp;
 type ClientIp = InsecureClientIp;
 
 use crate::db::{
+	def::remove_files,
 	task::Task,
 	version::{Version, VersionReference},
 	DBStats, Media, MediaId, DB,
@@ -195,31 +195,21 @@ pub async fn media_delete(
 ) -> Result<impl IntoResponse, DbError> {
 	let media = Media::from(db.write().unwrap().del(id, &user_claims.user)?);
 
-	let cache = std::path::Path::new(CACHE_FOLDER.as_str());
-	let removes = media
-		.versions
-		.iter()
-		.map(|version| {
-			tokio::fs::remove_file(
-				cache.join(VersionReference::from((media.id, version.0.clone())).filename_out()),
-			)
-		})
-		.collect::<Vec<_>>();
-
-	let removes = join_all(removes);
-
-	let path = std::path::Path::new(MEDIA_FOLDER.as_str()).join(media.id.to_quint());
-	let original = tokio::fs::remove_file(path);
-
-	let (_removes, original) = join!(removes, original);
-
-	original.map_err(|_| DbError::from("File not found"))?;
+	remove_files(&media).await?;
 
 	log_ip_user_id("media_del", ip.0, &user_claims.user, id.inner());
 
 	Ok(Json(media))
 }
 
+/// The user's media that no chunk references anymore
+pub async fn media_unreferenced(
+	Extension(user_claims): Extension<UserClaims>,
+	Extension(db): Extension<LockedAtomic<DB>>,
+) -> Result<impl IntoResponse, DbError> {
+	Ok(Json(crate::refs::unreferenced(&db, &user_claims.user).await?))
+}
+
 #[derive(Deserialize, Default)]
 #[serde(default)]
 pub struct MediaPatch {
@@ -4,6 +4,12 @@ lazy_static! {
 	pub static ref MEDIA_FOLDER: String = std::env::var("MEDIA_FOLDER").unwrap_or_else(|_| "media_".into());
 	pub static ref MEDIA_VIDEO_CONVERSION: bool = std::env::var("MEDIA_VIDEO_CONVERSION").unwrap_or_default().parse::<bool>().unwrap_or(true);
 	pub static ref MEDIA_IMAGE_CONVERSION: bool = std::env::var("MEDIA_IMAGE_CONVERSION").unwrap_or_default().parse::<bool>().unwrap_or(true);
+	/// Chunk slepau, asked which media is still referenced
+	pub static ref CHUNK_URL: String = std::env::var("CHUNK_URL").unwrap_or_else(|_| "http://localhost:4002".into());
+	/// Days unreferenced media is kept before it's deleted, no garbage collection if unset.
+	///
+	/// Only chunk is asked, so leave it unset if other apps embed media too.
+	pub static ref MEDIA_GC_DAYS: Option<u64> = std::env::var("MEDIA_GC_DAYS").ok().and_then(|v| v.parse().ok());
 }
 
 use serde::{Deserialize, Serialize};
@@ -28,6 +28,7 @@ use tower_http::timeout::TimeoutLayer;
 
 pub mod db;
 pub mod ends;
+pub mod refs;
 mod socket;
 
 #[tokio::main]
@@ -53,6 +54,11 @@ pub async fn main() {
 	"
 	);
 
+	{
+		// Check that keys exist
+		auth::validate::init_keys();
+	}
+
 	let (shutdown_tx, mut shutdown_rx) = watch::channel(());
 	// What media has changed, mainly used to inform the UI
 	// let (media_tx, media_rx) = broadcast::channel(5);
@@ -80,13 +86,19 @@ pub async fn main() {
 			"/media/:id",
 			get(ends::media_get).patch(ends::media_patch).delete(ends::media_delete),
 		)
+		.route(
+			"/unreferenced",
+			get(ends::media_unreferenced).layer(from_fn(auth::validate::flow::auth_required)),
+		)
 		.route(
 			"/stream",
 			get(socket::websocket_handler).layer(from_fn(auth::validate::flow::auth_required)),
 		)
 		.route("/stats", get(ends::stats))
 		.route("/media", post(ends::media_post))
+		.layer(axum::middleware::from_fn_with_state("media", auth::validate::flow::scoped))
 		.layer(axum::middleware::from_fn(auth::validate::authenticate))
+		.layer(axum::middleware::from_fn(auth::validate::refresh::refresh))
 		.layer(TimeoutLayer::new(Duration::from_secs(30)))
 		.layer(
 			tower::ServiceBuilder::new()
@@ -105,6 +117,8 @@ pub async fn main() {
 		task_rx,
 	));
 
+	let gc_service = tokio::spawn(refs::gc_service(db.clone(), shutdown_rx.clone()));
+
 	// Create server
 	let server = axum::Server::bind(&SOCKET)
 		.serve(app.into_make_service_with_connect_info::<SocketAddr>())
@@ -136,7 +150,7 @@ pub async fn main() {
 	shutdown_tx.send(()).unwrap();
 
 	info!("Waiting for everyone to shutdown.");
-	let _server_r = join!(server, conversion_service, load_existing_handle);
+	let _server_r = join!(server, conversion_service, gc_service, load_existing_handle);
 	info!("Everyone's shut down!");
 
 	save_db(&db, true);
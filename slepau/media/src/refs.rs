@@ -0,0 +1,181 @@
+use auth::validate::service::service_token;
+use common::utils::{get_secs, DbError, LockedAtomic, SECS_IN_DAY, SECS_IN_HOUR};
+use log::{error, info};
+use media::{CHUNK_URL, MEDIA_GC_DAYS};
+use std::{collections::BTreeMap, time::Duration};
+use tokio::sync::watch;
+
+use crate::db::{def::remove_files, Media, MediaId, DB};
+
+/// Host of an url, `https://a.b.com:4000/x` is `a.b.com`
+fn host(url: &str) -> &str {
+	let url = url.split_once("://").map(|(_, u)| u).unwrap_or(url);
+	url.split(['/', ':']).next().unwrap_or_default()
+}
+
+/// Asks chunk which of `ids` are referenced by any chunk, as `user`
+async fn referenced(
+	client: &reqwest::Client,
+	user: &str,
+	ids: &[MediaId],
+) -> Result<BTreeMap<String, usize>, String> {
+	let ids = ids.iter().map(|id| id.to_quint()).collect::<Vec<_>>();
+	let token = service_token(user, host(&CHUNK_URL), "chunk:read", 60).await?;
+	let response = client
+		.post(format!("{}/refs/media", CHUNK_URL.trim_end_matches('/')))
+		.header(reqwest::header::COOKIE, format!("auth={token}"))
+		.header(reqwest::header::CONTENT_TYPE, "application/json")
+		.body(serde_json::to_vec(&ids).map_err(|err| err.to_string())?)
+		.send()
+		.await
+		.map_err(|err| err.to_string())?;
+	let status = response.status();
+	let body = response.bytes().await.map_err(|err| err.to_string())?;
+	if !status.is_success() {
+		return Err(format!("{status} {}", String::from_utf8_lossy(&body)));
+	}
+	serde_json::from_slice(&body).map_err(|err| err.to_string())
+}
+
+/// `user`'s media, each with whether a chunk references it
+async fn references(
+	client: &reqwest::Client,
+	db: &LockedAtomic<DB>,
+	user: &str,
+) -> Result<Vec<(LockedAtomic<Media>, bool)>, String> {
+	let all = db.read().unwrap().get_all(user);
+	if all.is_empty() {
+		return Ok(vec![]);
+	}
+	let ids = all.iter().map(|m| m.read().unwrap().id).collect::<Vec<_>>();
+	let refs = referenced(client, user, &ids).await?;
+	Ok(all
+		.into_iter()
+		.map(|m| {
+			let referenced = refs.contains_key(&m.read().unwrap().id.to_quint());
+			(m, referenced)
+		})
+		.collect())
+}
+
+/// `user`'s media that no chunk references
+pub async fn unreferenced(db: &LockedAtomic<DB>, user: &str) -> Result<Vec<Media>, DbError> {
+	let media = references(&reqwest::Client::new(), db, user)
+		.await
+		.map_err(|err| DbError::Custom(format!("Couldn't reach chunk: {err}")))?;
+	Ok(media
+		.into_iter()
+		.filter(|(_, referenced)| !referenced)
+		.map(|(m, _)| m.read().unwrap().clone())
+		.collect())
+}
+
+/// Marks since when media isn't referenced by anyone, unmarks media that is again.
+///
+/// Returns the media unreferenced since before `before` (in secs), with an owner to delete it as.
+fn sweep(
+	seen: BTreeMap<MediaId, (String, LockedAtomic<Media>, bool)>,
+	now: u64,
+	before: u64,
+) -> Vec<(String, MediaId)> {
+	seen
+		.into_iter()
+		.filter_map(|(id, (owner, media, referenced))| {
+			let mut media = media.write().unwrap();
+			if referenced {
+				media.unreferenced = None;
+				return None;
+			}
+			let since = *media.unreferenced.get_or_insert(now);
+			(since < before).then_some((owner, id))
+		})
+		.collect()
+}
+
+/// Deletes media nobody references after `MEDIA_GC_DAYS`, a run is skipped if chunk can't be reached
+pub async fn gc_service(db: LockedAtomic<DB>, mut shutdown_rx: watch::Receiver<()>) {
+	let Some(days) = *MEDIA_GC_DAYS else {
+		return;
+	};
+	info!("Deleting media unreferenced for {days} days.");
+	let client = reqwest::Client::new();
+	loop {
+		let owners = db.read().unwrap().owners();
+		// Shared media shows up once per owner, it's referenced if any of them references it
+		let mut seen = BTreeMap::new();
+		let mut reached = true;
+		for owner in owners {
+			match references(&client, &db, &owner).await {
+				Ok(media) => media.into_iter().for_each(|(m, referenced)| {
+					let id = m.read().unwrap().id;
+					seen.entry(id).or_insert((owner.clone(), m, false)).2 |= referenced;
+				}),
+				Err(err) => {
+					error!("Couldn't ask chunk about {owner}'s media, skipping collection: {err}");
+					reached = false;
+					break;
+				}
+			}
+		}
+		let garbage = if reached {
+			let now = get_secs();
+			sweep(seen, now, now.saturating_sub(days * SECS_IN_DAY))
+		} else {
+			vec![]
+		};
+
+		let mut deleted = 0;
+		for (owner, id) in garbage {
+			let media = db.write().unwrap().del(id, &owner);
+			if let Ok(media) = media {
+				let media = Media::from(media);
+				if let Err(err) = remove_files(&media).await {
+					error!("Couldn't remove files of {id}: {err:?}");
+				}
+				deleted += 1;
+			}
+		}
+		info!("Deleted {deleted} unreferenced media.");
+
+		tokio::select! {
+			_ = tokio::time::sleep(Duration::from_secs(SECS_IN_HOUR * 6)) => {}
+			_ = shutdown_rx.changed() => {
+				break;
+			}
+		}
+	}
+	info!("Media gc service shut down.");
+}
+
+#[cfg(test)]
+mod tests {
+	use super::sweep;
+	use crate::db::{Media, MediaId};
+	use std::{
+		collections::BTreeMap,
+		sync::{Arc, RwLock},
+	};
+
+	#[test]
+	fn grace_counts_from_unreferenced() {
+		let id = MediaId::from(7);
+		// Uploaded long ago, but only unreferenced from now on
+		let media = Arc::new(RwLock::new(Media {
+			id,
+			created: 10,
+			..Default::default()
+		}));
+		let seen = |referenced| BTreeMap::from([(id, ("nina".to_owned(), media.clone(), referenced))]);
+
+		assert!(sweep(seen(false), 1000, 500).is_empty());
+		assert_eq!(media.read().unwrap().unreferenced, Some(1000));
+		assert!(sweep(seen(false), 1200, 900).is_empty(), "Still within the grace");
+		assert_eq!(media.read().unwrap().unreferenced, Some(1000));
+
+		// Referenced again starts it over
+		assert!(sweep(seen(true), 1300, 1100).is_empty());
+		assert_eq!(media.read().unwrap().unreferenced, None);
+		assert!(sweep(seen(false), 1400, 1300).is_empty());
+		assert_eq!(sweep(seen(false), 2000, 1500), vec![("nina".to_owned(), id)]);
+	}
+}
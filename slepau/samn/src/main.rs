@@ -1,6 +1,5 @@
 #![feature(linked_list_remove)]
 #![feature(linked_list_retain)]
-use auth::validate::KPR;
 use axum::{
 	error_handling::HandleErrorLayer,
 	routing::{get, post},
@@ -65,7 +64,7 @@ async fn main() {
 	{
 		// Check that keys exist
 		// lazy_static::initialize(&KP);
-		lazy_static::initialize(&KPR);
+		auth::validate::init_keys();
 	}
 
 	// Read cache
@@ -82,11 +81,20 @@ async fn main() {
 	// Build router
 	let app = Router::new()
 		.route("/:key", get(ends::log_get))
-		.route("/command", post(ends::command))
-		.route("/command/wait", post(ends::command_response))
 		.route("/stream", get(socket::websocket_handler))
+		.route_layer(axum::middleware::from_fn_with_state("samn:read", auth::validate::flow::only_scope))
+		.merge(
+			Router::new()
+				.route("/command", post(ends::command))
+				.route("/command/wait", post(ends::command_response))
+				.route_layer(axum::middleware::from_fn_with_state(
+					"samn:command",
+					auth::validate::flow::only_scope,
+				)),
+		)
 		.layer(axum::middleware::from_fn(auth::validate::flow::only_supers))
 		.layer(axum::middleware::from_fn(auth::validate::authenticate))
+		.layer(axum::middleware::from_fn(auth::validate::refresh::refresh))
 		.layer(TimeoutLayer::new(Duration::from_secs(30)))
 		.layer(
 			tower::ServiceBuilder::new()
@@ -1,4 +1,3 @@
-use auth::validate::KPR;
 use axum::{error_handling::HandleErrorLayer, routing::get, Extension, Router};
 
 use common::{
@@ -43,7 +42,7 @@ async fn main() {
 	{
 		// Check that keys exist
 		// lazy_static::initialize(&KP);
-		lazy_static::initialize(&KPR);
+		auth::validate::init_keys();
 	}
 
 	// DB Init
@@ -59,7 +58,9 @@ async fn main() {
 		.route("/by_user", get(ends::by_user))
 		.route("/stats", get(ends::stats))
 		.layer(axum::middleware::from_fn(auth::validate::flow::only_supers))
+		.layer(axum::middleware::from_fn_with_state("vreji", auth::validate::flow::scoped))
 		.layer(axum::middleware::from_fn(auth::validate::authenticate))
+		.layer(axum::middleware::from_fn(auth::validate::refresh::refresh))
 		.layer(TimeoutLayer::new(Duration::from_secs(30)))
 		.layer(
 			tower::ServiceBuilder::new()